@@ -0,0 +1,175 @@
+use serde_json::{Map, Value};
+use std::path::PathBuf;
+
+/// What the binary was asked to do
+#[derive(Debug)]
+pub enum Command {
+    /// Run the server (the default)
+    Serve,
+    /// `config show [--effective]`
+    ConfigShow { effective: bool },
+    /// `config migrate`
+    ConfigMigrate,
+    /// `send <FILE> [--server URL]`
+    Send { file: PathBuf, server: Option<String> },
+    /// `receive <CODE> [--server URL] [--output DIR]`
+    Receive {
+        code: String,
+        server: Option<String>,
+        output: Option<PathBuf>,
+    },
+    /// `sync <DIR> [--folder NAME] [--server URL] [--once] [--interval SECS]`
+    Sync {
+        dir: PathBuf,
+        folder: Option<String>,
+        server: Option<String>,
+        once: bool,
+        interval: u64,
+    },
+}
+
+/// Parsed command line
+#[derive(Debug)]
+pub struct Cli {
+    pub command: Command,
+    /// `--config <file>`, used instead of the XDG user config
+    pub config_path: Option<PathBuf>,
+    /// Settings given as flags, layered on top of every config file
+    pub overrides: Value,
+}
+
+pub const USAGE: &str = "\
+Usage: noplacelike [OPTIONS] [COMMAND]
+
+Commands:
+  config show [--effective]  Print the user config file, or the merged
+                             config with the source of each value
+  config migrate             Rewrite the user config file in the current
+                             format
+  send <FILE>                Send a file with a one-time code
+  receive <CODE>             Receive a file sent with a code
+  sync <DIR>                 Keep DIR in sync with a folder on the server
+
+Options:
+  --config <FILE>            Use FILE as the user config
+  --host <HOST>              Address to listen on
+  --port <PORT>              Port to listen on
+  --upload-folder <DIR>      Folder for shared files
+  --download-folder <DIR>    Folder for files pushed to this machine
+  --log-level <FILTER>       Log level or filter, e.g. debug or info,actix_web=warn
+  --log-format <FORMAT>      Log format: text or json
+  --log-file <DIR>           Also write rotating log files to DIR
+  --server <URL>             Server to relay send/receive or sync with
+                             [default: http://127.0.0.1:<port>]
+  --output <DIR>             Where receive saves files
+                             [default: the download folder]
+  --folder <NAME>            Folder on the server sync uses
+                             [default: the name of DIR]
+  --once                     Sync once and exit instead of watching
+  --interval <SECS>          Seconds between syncs [default: 10]
+  --help                     Show this message";
+
+/// Very simple argument parsing (could use clap for more robust parsing)
+pub fn parse_args(args: &[String]) -> Result<Cli, String> {
+    let mut overrides = Map::new();
+    let mut config_path = None;
+    let mut effective = false;
+    let mut server = None;
+    let mut output = None;
+    let mut folder = None;
+    let mut once = false;
+    let mut interval = 10;
+    let mut words = Vec::new();
+
+    let mut i = 1;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        let mut value = || {
+            i += 1;
+            args.get(i)
+                .cloned()
+                .ok_or_else(|| format!("Missing value for {}", arg))
+        };
+
+        match arg {
+            "--host" => {
+                overrides.insert("host".to_string(), Value::from(value()?));
+            }
+            "--port" => {
+                let port = value()?;
+                let port: u16 = port
+                    .parse()
+                    .map_err(|_| format!("Invalid port: {}", port))?;
+                overrides.insert("port".to_string(), Value::from(port));
+            }
+            "--upload-folder" => {
+                overrides.insert("upload_folder".to_string(), Value::from(value()?));
+            }
+            "--download-folder" => {
+                overrides.insert("download_folder".to_string(), Value::from(value()?));
+            }
+            "--log-level" => set(&mut overrides, "logging", "level", value()?),
+            "--log-format" => set(&mut overrides, "logging", "format", value()?),
+            "--log-file" => set(&mut overrides, "logging", "file", value()?),
+            "--config" => config_path = Some(PathBuf::from(value()?)),
+            "--effective" => effective = true,
+            "--server" => server = Some(value()?),
+            "--output" => output = Some(PathBuf::from(value()?)),
+            "--folder" => folder = Some(value()?),
+            "--once" => once = true,
+            "--interval" => {
+                let secs = value()?;
+                interval = secs
+                    .parse()
+                    .ok()
+                    .filter(|&secs| secs > 0)
+                    .ok_or_else(|| format!("Invalid interval: {}", secs))?;
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            flag if flag.starts_with('-') => {
+                return Err(format!("Unknown option: {}\n\n{}", flag, USAGE))
+            }
+            word => words.push(word.to_string()),
+        }
+        i += 1;
+    }
+
+    let command = match words.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        [] => Command::Serve,
+        ["config", "show"] => Command::ConfigShow { effective },
+        ["config", "migrate"] => Command::ConfigMigrate,
+        ["send", file] => Command::Send {
+            file: PathBuf::from(file),
+            server,
+        },
+        ["receive", code] => Command::Receive {
+            code: code.to_string(),
+            server,
+            output,
+        },
+        ["sync", dir] => Command::Sync {
+            dir: PathBuf::from(dir),
+            folder,
+            server,
+            once,
+            interval,
+        },
+        _ => return Err(format!("Unknown command: {}\n\n{}", words.join(" "), USAGE)),
+    };
+
+    Ok(Cli {
+        command,
+        config_path,
+        overrides: Value::Object(overrides),
+    })
+}
+
+/// Set `section.key` in the overrides document
+fn set(overrides: &mut Map<String, Value>, section: &str, key: &str, value: String) {
+    let table = overrides
+        .entry(section)
+        .or_insert_with(|| Value::Object(Map::new()));
+    if let Value::Object(table) = table {
+        table.insert(key.to_string(), Value::from(value));
+    }
+}
@@ -0,0 +1,652 @@
+use dirs::home_dir;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::{merge_patch, Config, CONFIG};
+use crate::error::{lock_recovering, AppError};
+
+/// Schema version written by this build. Bump it and append to `MIGRATIONS`
+/// whenever the on-disk layout changes.
+pub const CURRENT_CONFIG_VERSION: u32 = 2;
+
+/// Upgrade steps between config versions. `MIGRATIONS[n]` turns a version `n`
+/// document into a version `n + 1` document.
+const MIGRATIONS: &[fn(&mut Value)] = &[
+    // v0 -> v1: the original unversioned layout. The fields are unchanged,
+    // the document just gains a version number.
+    |_| {},
+    // v1 -> v2: `trusted_devices` moved out of the config into the device
+    // registry, as devices with the `trusted` trust level. The server moves
+    // the list over when it starts, see `take_trusted_devices`.
+    |_| {},
+];
+
+const _: () = assert!(MIGRATIONS.len() == CURRENT_CONFIG_VERSION as usize);
+
+/// File names looked up in each config directory, in order of preference
+const CONFIG_FILE_NAMES: &[&str] = &["config.toml", "config.yaml", "config.yml", "config.json"];
+
+const SOURCE_DEFAULT: &str = "default";
+const SOURCE_CLI: &str = "command line";
+
+/// Supported on-disk config formats, picked by file extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("yaml") | Some("yml") => Format::Yaml,
+            _ => Format::Json,
+        }
+    }
+
+    fn parse(self, content: &str) -> Result<Value, String> {
+        match self {
+            Format::Json => serde_json::from_str(content).map_err(|e| e.to_string()),
+            Format::Toml => toml::from_str(content).map_err(|e| e.to_string()),
+            Format::Yaml => serde_yaml::from_str(content).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn serialize(self, document: &Value) -> Result<String, String> {
+        match self {
+            Format::Json => serde_json::to_string_pretty(document).map_err(|e| e.to_string()),
+            Format::Toml => toml::to_string_pretty(document).map_err(|e| e.to_string()),
+            Format::Yaml => serde_yaml::to_string(document).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// How the effective config was assembled, kept so saves only touch the user layer
+#[derive(Debug, Default)]
+struct LoadState {
+    /// The file admin changes are written to
+    target: Option<PathBuf>,
+    /// Defaults and every layer below the target file, merged
+    lower: Value,
+    /// The target file's own settings, migrated, including any this build
+    /// doesn't know
+    user: Value,
+    /// The target file is missing or from an older version, and is written
+    /// out by `serve` or `config migrate`
+    outdated: bool,
+    /// Overrides passed on the command line
+    cli: Value,
+    /// Which layer each effective value came from, keyed by dotted path
+    sources: BTreeMap<String, String>,
+}
+
+lazy_static::lazy_static! {
+    static ref LOAD_STATE: Mutex<LoadState> = Mutex::new(LoadState::default());
+    static ref EXPLICIT_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// System-wide config directory, lowest-precedence file layer
+fn system_config_dir() -> PathBuf {
+    PathBuf::from("/etc/noplacelike")
+}
+
+/// Per-user config directory following the XDG base directory spec
+fn user_config_dir() -> PathBuf {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .unwrap_or_else(|| home_dir().unwrap_or_else(|| PathBuf::from(".")).join(".config"));
+    base.join("noplacelike")
+}
+
+/// The single-file location used before configs moved to the XDG directory
+fn legacy_config_path() -> PathBuf {
+    let mut path = home_dir().unwrap_or_else(|| PathBuf::from("."));
+    path.push(".noplacelikeconfig.json");
+    path
+}
+
+fn find_config_file(dir: &Path) -> Option<PathBuf> {
+    let mut found = CONFIG_FILE_NAMES
+        .iter()
+        .map(|name| dir.join(name))
+        .filter(|path| path.is_file());
+    let first = found.next()?;
+    for ignored in found {
+        tracing::warn!(
+            "Ignoring {} because {} takes precedence",
+            ignored.display(),
+            first.display()
+        );
+    }
+    Some(first)
+}
+
+/// Use a specific file as the user config instead of searching for one
+pub fn set_config_path(path: PathBuf) {
+    *lock_recovering(&EXPLICIT_PATH) = Some(path);
+}
+
+/// The file config changes are written to.
+///
+/// This is the `--config` file when one was given, otherwise the XDG user
+/// config, falling back to the legacy `~/.noplacelikeconfig.json` when only
+/// that exists. New installs get `$XDG_CONFIG_HOME/noplacelike/config.toml`.
+pub fn get_config_path() -> PathBuf {
+    if let Some(path) = lock_recovering(&EXPLICIT_PATH).clone() {
+        return path;
+    }
+
+    let user_dir = user_config_dir();
+    if let Some(path) = find_config_file(&user_dir) {
+        return path;
+    }
+
+    let legacy = legacy_config_path();
+    if legacy.is_file() {
+        return legacy;
+    }
+
+    user_dir.join("config.toml")
+}
+
+/// Config files below the user file, lowest precedence first
+fn lower_layer_paths(target: &Path) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+
+    if let Some(system) = find_config_file(&system_config_dir()) {
+        paths.push(system);
+    }
+
+    // The legacy file still applies underneath a newer XDG config, but an
+    // explicit --config replaces the whole user level
+    let legacy = legacy_config_path();
+    if lock_recovering(&EXPLICIT_PATH).is_none() && legacy.is_file() && legacy != target {
+        tracing::warn!(
+            "Both {} and {} exist; settings in the latter take precedence",
+            legacy.display(),
+            target.display()
+        );
+        paths.push(legacy);
+    }
+
+    paths
+}
+
+/// Load the effective config by merging, in increasing precedence: built-in
+/// defaults, `/etc/noplacelike/`, the legacy `~/.noplacelikeconfig.json`,
+/// `$XDG_CONFIG_HOME/noplacelike/` (or the `--config` file), then the
+/// command-line overrides.
+///
+/// Each file is migrated to the current version on its own, in memory only:
+/// see [`save_migrated_config`]. A file that can't be read or parsed is
+/// reported as an error so a typo never wipes the user's settings.
+pub fn load_config(cli: Value) -> Result<Config, String> {
+    let target = get_config_path();
+
+    let mut sources = BTreeMap::new();
+    let mut lower = serde_json::to_value(Config::default()).map_err(|e| e.to_string())?;
+    record_sources(&lower, "", SOURCE_DEFAULT, &mut sources);
+
+    for path in lower_layer_paths(&target) {
+        let (document, from_version) = read_layer(&path)?;
+        if from_version < CURRENT_CONFIG_VERSION {
+            tracing::warn!(
+                "Migrated {} from version {} to {} in memory; update the file to silence this",
+                path.display(),
+                from_version,
+                CURRENT_CONFIG_VERSION
+            );
+        }
+        record_sources(&document, "", &path.display().to_string(), &mut sources);
+        deep_merge(&mut lower, document);
+    }
+
+    let (user, outdated) = if target.is_file() {
+        let (document, from_version) = read_layer(&target)?;
+        (document, from_version < CURRENT_CONFIG_VERSION)
+    } else {
+        (Value::Object(Map::new()), true)
+    };
+    record_sources(&user, "", &target.display().to_string(), &mut sources);
+    record_sources(&cli, "", SOURCE_CLI, &mut sources);
+
+    let mut effective = lower.clone();
+    deep_merge(&mut effective, user.clone());
+    deep_merge(&mut effective, cli.clone());
+    effective["version"] = Value::from(CURRENT_CONFIG_VERSION);
+
+    let config = serde_json::from_value::<Config>(effective).map_err(|e| {
+        format!(
+            "Invalid configuration: {}. Fix the config files or move them aside to start with defaults.",
+            e
+        )
+    })?;
+
+    *lock_recovering(&LOAD_STATE) = LoadState {
+        target: Some(target),
+        lower,
+        user,
+        outdated,
+        sources,
+        cli,
+    };
+    *lock_recovering(&CONFIG) = config.clone();
+
+    Ok(config)
+}
+
+/// Write the user file out at the current version if it was missing or
+/// older, keeping everything in it. Returns the file written, if any.
+pub fn save_migrated_config() -> Result<Option<PathBuf>, AppError> {
+    let (path, document) = {
+        let mut state = LOAD_STATE.lock()?;
+        if !state.outdated {
+            return Ok(None);
+        }
+        state.outdated = false;
+        let path = state.target.clone().unwrap_or_else(get_config_path);
+        let mut document = state.user.clone();
+        document["version"] = Value::from(CURRENT_CONFIG_VERSION);
+        (path, document)
+    };
+
+    if path.exists() {
+        tracing::info!("Migrated {} to version {}", path.display(), CURRENT_CONFIG_VERSION);
+    }
+    write_user_file(&path, &document)?;
+    Ok(Some(path))
+}
+
+/// Read, parse and migrate a single config file, returning the document and
+/// the version it was written with
+fn read_layer(path: &Path) -> Result<(Value, u32), String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Error reading config file {}: {}", path.display(), e))?;
+    let mut document = Format::from_path(path).parse(&content).map_err(|e| {
+        format!(
+            "Error parsing config file {}: {}. Fix the file or move it aside to start with defaults.",
+            path.display(),
+            e
+        )
+    })?;
+
+    let from_version = migrate_config(&mut document)
+        .map_err(|e| format!("Error migrating config file {}: {}", path.display(), e))?;
+
+    Ok((document, from_version))
+}
+
+/// Run every migration between the document's version and the current one,
+/// returning the version the document started at
+fn migrate_config(document: &mut Value) -> Result<u32, String> {
+    if !document.is_object() {
+        return Err("expected a table of settings at the top level".to_string());
+    }
+
+    let from_version = match document.get("version") {
+        None => 0,
+        Some(v) => v
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| format!("invalid version {}", v))?,
+    };
+
+    if from_version > CURRENT_CONFIG_VERSION {
+        tracing::warn!(
+            "Config file version {} is newer than this build supports ({}); unknown settings will be ignored",
+            from_version, CURRENT_CONFIG_VERSION
+        );
+        return Ok(from_version);
+    }
+
+    for (version, migrate) in MIGRATIONS.iter().enumerate().skip(from_version as usize) {
+        migrate(document);
+        document["version"] = Value::from(version as u32 + 1);
+    }
+
+    Ok(from_version)
+}
+
+/// Take the `trusted_devices` lists left in the config files from before
+/// version 2, so the user file is written without its list
+pub fn take_trusted_devices() -> Vec<Value> {
+    let mut state = lock_recovering(&LOAD_STATE);
+    let mut lists = Vec::new();
+    if let Some(list) = state.lower.as_object_mut().and_then(|d| d.remove("trusted_devices")) {
+        lists.push(list);
+    }
+    if let Some(list) = state.user.as_object_mut().and_then(|d| d.remove("trusted_devices")) {
+        lists.push(list);
+        state.outdated = true;
+    }
+    lists
+}
+
+/// Persist a config to the user file.
+///
+/// Only the settings that changed are written into the file, so everything
+/// else in it stays as it was, including settings this build doesn't know.
+/// Values that came from the command line keep whatever the file had
+/// before, so a one-off `--port` never ends up saved.
+pub fn save_config(config: &Config) -> Result<(), AppError> {
+    let previous = std::mem::replace(&mut *CONFIG.lock()?, config.clone());
+
+    let (path, document) = {
+        let mut state = LOAD_STATE.lock()?;
+        let path = state.target.clone().unwrap_or_else(get_config_path);
+
+        let to_value = |config: &Config| {
+            serde_json::to_value(config)
+                .map_err(|e| AppError::Internal(format!("Error serializing config: {}", e)))
+        };
+        let (previous, mut updated) = (to_value(&previous)?, to_value(config)?);
+        let mut cli_paths = Vec::new();
+        collect_leaf_paths(&state.cli, "", &mut cli_paths);
+        for key in cli_paths {
+            match lookup(&previous, &key) {
+                Some(value) => assign(&mut updated, &key, value.clone()),
+                None => remove(&mut updated, &key),
+            }
+        }
+        let patch = changes(&previous, &updated).unwrap_or_else(|| Value::Object(Map::new()));
+
+        let mut written = Vec::new();
+        collect_leaf_paths(&patch, "", &mut written);
+        let source = path.display().to_string();
+        for key in written {
+            state.sources.insert(key, source.clone());
+        }
+
+        let mut document = state.user.clone();
+        merge_patch(&mut document, patch);
+        document["version"] = Value::from(config.version);
+        state.user = document.clone();
+        state.outdated = false;
+
+        (path, document)
+    };
+
+    write_user_file(&path, &document)
+}
+
+/// Write the user file, keeping a backup of the previous one
+fn write_user_file(path: &Path, document: &Value) -> Result<(), AppError> {
+    // Create parent directories if they don't exist
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let content = Format::from_path(path)
+        .serialize(document)
+        .map_err(|e| AppError::Internal(format!("Error serializing config: {}", e)))?;
+
+    // Keep a copy of the previous file before replacing it
+    if path.exists() {
+        let backup = sibling_path(path, ".bak");
+        fs::copy(path, &backup)?;
+    }
+
+    // Write to a temporary file first so a crash never leaves a half-written config
+    let tmp = sibling_path(path, ".tmp");
+    fs::write(&tmp, content).and_then(|_| fs::rename(&tmp, path))?;
+
+    tracing::info!(path = %path.display(), "Saved config");
+    Ok(())
+}
+
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Print the user config file, or with `effective` the merged config with
+/// the layer each value came from
+pub fn print_config(effective: bool) {
+    let state = lock_recovering(&LOAD_STATE);
+
+    if !effective {
+        let path = state.target.clone().unwrap_or_else(get_config_path);
+        println!("# {}", path.display());
+        match fs::read_to_string(&path) {
+            Ok(content) => println!("{}", content.trim_end()),
+            Err(e) => eprintln!("Error reading config file: {}", e),
+        }
+        return;
+    }
+
+    let config = lock_recovering(&CONFIG).clone();
+    let document = serde_json::to_value(&config).unwrap_or_default();
+    let mut keys = Vec::new();
+    collect_leaf_paths(&document, "", &mut keys);
+
+    let rendered: Vec<(String, String)> = keys
+        .into_iter()
+        .map(|key| {
+            let value = lookup(&document, &key).cloned().unwrap_or_default();
+            (format!("{} = {}", key, value), key)
+        })
+        .collect();
+    let width = rendered.iter().map(|(line, _)| line.len()).max().unwrap_or(0);
+
+    for (line, key) in rendered {
+        let source = find_source(&state.sources, &key).unwrap_or(SOURCE_DEFAULT);
+        println!("{:width$}  # {}", line, source, width = width);
+    }
+}
+
+/// Find the source recorded for a key or its closest recorded parent
+fn find_source<'a>(sources: &'a BTreeMap<String, String>, key: &str) -> Option<&'a str> {
+    let mut key = key;
+    loop {
+        if let Some(source) = sources.get(key) {
+            return Some(source);
+        }
+        key = &key[..key.rfind('.')?];
+    }
+}
+
+fn record_sources(document: &Value, prefix: &str, source: &str, out: &mut BTreeMap<String, String>) {
+    let mut keys = Vec::new();
+    collect_leaf_paths(document, prefix, &mut keys);
+    for key in keys {
+        // A layer that replaces a whole table also owns everything under it
+        out.retain(|existing, _| !existing.starts_with(&format!("{}.", key)));
+        out.insert(key, source.to_string());
+    }
+}
+
+/// Collect dotted paths to every non-table value in a document
+fn collect_leaf_paths(value: &Value, prefix: &str, out: &mut Vec<String>) {
+    match value {
+        Value::Object(map) => {
+            for (key, child) in map {
+                let path = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                collect_leaf_paths(child, &path, out);
+            }
+        }
+        _ if !prefix.is_empty() => out.push(prefix.to_string()),
+        _ => {}
+    }
+}
+
+fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, |v, key| v.get(key))
+}
+
+fn assign(value: &mut Value, path: &str, new_value: Value) {
+    let mut current = value;
+    for key in path.split('.') {
+        if !current.is_object() {
+            *current = Value::Object(Map::new());
+        }
+        current = current
+            .as_object_mut()
+            .unwrap()
+            .entry(key)
+            .or_insert(Value::Null);
+    }
+    *current = new_value;
+}
+
+fn remove(value: &mut Value, path: &str) {
+    let (parent, key) = match path.rsplit_once('.') {
+        Some((parent, key)) => (lookup_mut(value, parent), key),
+        None => (Some(value), path),
+    };
+    if let Some(Value::Object(map)) = parent {
+        map.remove(key);
+    }
+}
+
+fn lookup_mut<'a>(value: &'a mut Value, path: &str) -> Option<&'a mut Value> {
+    path.split('.').try_fold(value, |v, key| v.get_mut(key))
+}
+
+/// Recursively merge `overlay` into `target`. Tables are merged key by key,
+/// anything else is replaced.
+pub(crate) fn deep_merge(target: &mut Value, overlay: Value) {
+    match (target, overlay) {
+        (Value::Object(target), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match target.get_mut(&key) {
+                    Some(existing) => deep_merge(existing, value),
+                    None => {
+                        target.insert(key, value);
+                    }
+                }
+            }
+        }
+        (target, overlay) => *target = overlay,
+    }
+}
+
+/// A JSON merge patch (RFC 7386) turning `old` into `new`, or `None` if
+/// they're equal
+fn changes(old: &Value, new: &Value) -> Option<Value> {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let removed = old
+                .keys()
+                .filter(|key| !new.contains_key(*key))
+                .map(|key| (key.clone(), Value::Null));
+            let changed = new.iter().filter_map(|(key, v)| match old.get(key) {
+                Some(o) => changes(o, v).map(|c| (key.clone(), c)),
+                None => Some((key.clone(), v.clone())),
+            });
+            let patch: Map<String, Value> = removed.chain(changed).collect();
+            (!patch.is_empty()).then_some(Value::Object(patch))
+        }
+        (old, new) if old == new => None,
+        (_, new) => Some(new.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn unversioned_configs_are_migrated_without_losing_anything() {
+        let mut document = json!({
+            "port": 9000,
+            "trusted_devices": [{"id": "phone", "name": "Phone"}],
+            "from_a_newer_build": true,
+        });
+        assert_eq!(migrate_config(&mut document).unwrap(), 0);
+        assert_eq!(document["version"], json!(CURRENT_CONFIG_VERSION));
+        assert_eq!(document["port"], json!(9000));
+        // Moved into the device registry when the server starts, not here
+        assert_eq!(document["trusted_devices"][0]["id"], json!("phone"));
+        assert_eq!(document["from_a_newer_build"], json!(true));
+    }
+
+    #[test]
+    fn current_and_newer_configs_are_left_alone() {
+        let mut current = json!({"version": CURRENT_CONFIG_VERSION, "port": 1});
+        let before = current.clone();
+        assert_eq!(migrate_config(&mut current).unwrap(), CURRENT_CONFIG_VERSION);
+        assert_eq!(current, before);
+
+        let mut newer = json!({"version": CURRENT_CONFIG_VERSION + 1, "future": {}});
+        let before = newer.clone();
+        assert_eq!(migrate_config(&mut newer).unwrap(), CURRENT_CONFIG_VERSION + 1);
+        assert_eq!(newer, before);
+    }
+
+    #[test]
+    fn unreadable_versions_are_refused() {
+        assert!(migrate_config(&mut json!(["not", "a", "table"])).is_err());
+        assert!(migrate_config(&mut json!({"version": "two"})).is_err());
+        assert!(migrate_config(&mut json!({"version": -1})).is_err());
+    }
+
+    #[test]
+    fn every_format_reads_the_same_settings() {
+        let toml = Format::from_path(Path::new("config.toml"))
+            .parse("port = 9000\n[quota]\nevict_oldest = true\n")
+            .unwrap();
+        let yaml = Format::from_path(Path::new("config.yml"))
+            .parse("port: 9000\nquota:\n  evict_oldest: true\n")
+            .unwrap();
+        let json = Format::from_path(Path::new("config.json"))
+            .parse(r#"{"port": 9000, "quota": {"evict_oldest": true}}"#)
+            .unwrap();
+        assert_eq!(toml, yaml);
+        assert_eq!(toml, json);
+    }
+
+    #[test]
+    fn saves_patch_only_what_changed() {
+        let old = json!({
+            "port": 1,
+            "quota": {"max_file_size": 5, "evict_oldest": false},
+            "logging": {"file": "/var/log"},
+        });
+        let new = json!({
+            "port": 1,
+            "quota": {"max_file_size": 9, "evict_oldest": false},
+            "logging": {},
+        });
+        let patch = changes(&old, &new).unwrap();
+        assert_eq!(patch, json!({"quota": {"max_file_size": 9}, "logging": {"file": null}}));
+        assert_eq!(changes(&old, &old), None);
+
+        // Applied to the user's file, settings this build doesn't know stay
+        let mut user = json!({
+            "version": 2,
+            "quota": {"max_file_size": 5, "unknown": "kept"},
+            "logging": {"file": "/var/log"},
+        });
+        merge_patch(&mut user, patch);
+        let expected = json!({
+            "version": 2,
+            "quota": {"max_file_size": 9, "unknown": "kept"},
+            "logging": {},
+        });
+        assert_eq!(user, expected);
+    }
+
+    #[test]
+    fn sources_point_at_the_layer_that_set_a_value() {
+        let mut sources = BTreeMap::new();
+        let system = json!({"quota": {"max_file_size": 1, "evict_oldest": true}});
+        record_sources(&system, "", "system", &mut sources);
+        record_sources(&json!({"quota": {"max_file_size": 2}}), "", "user", &mut sources);
+        assert_eq!(find_source(&sources, "quota.max_file_size"), Some("user"));
+        assert_eq!(find_source(&sources, "quota.evict_oldest"), Some("system"));
+        assert_eq!(find_source(&sources, "port"), None);
+    }
+}
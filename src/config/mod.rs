@@ -0,0 +1,1068 @@
+use dirs::home_dir;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use utoipa::ToSchema;
+
+use crate::error::{lock_recovering, AppError};
+use crate::services::events::{self, Topic};
+
+mod loader;
+
+pub use loader::{
+    get_config_path, load_config, print_config, save_config, save_migrated_config,
+    set_config_path, take_trusted_devices, CURRENT_CONFIG_VERSION,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct Config {
+    pub version: u32,
+    pub host: String,
+    pub port: u16,
+    pub upload_folder: String,
+    /// Where the files in the upload folder are kept
+    pub storage: StorageConfig,
+    pub download_folder: String,
+    pub audio_folders: Vec<String>,
+    pub logging: LoggingConfig,
+    /// How long a shutdown waits for in-flight requests before cutting them off
+    pub shutdown_timeout_secs: u64,
+    /// Uploads and pushes allowed to run at once, 0 for no limit
+    pub max_concurrent_uploads: usize,
+    /// Downloads and audio streams allowed to run at once, 0 for no limit
+    pub max_concurrent_downloads: usize,
+    pub quota: QuotaConfig,
+    pub rate_limit: RateLimitConfig,
+    pub throttle: ThrottleConfig,
+    pub e2e: E2eConfig,
+    pub federation: FederationConfig,
+    pub sync: SyncConfig,
+    pub drop_folders: DropFoldersConfig,
+    pub webhooks: WebhooksConfig,
+    pub hooks: HooksConfig,
+}
+
+/// Where the files in the upload folder are kept
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// Most bytes the `memory` backend holds at once, 0 for no limit
+    pub memory_max_bytes: u64,
+    /// Used when `backend` is `s3`
+    pub s3: S3Config,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::default(),
+            memory_max_bytes: 256 * 1024 * 1024,
+            s3: S3Config::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// Files in `upload_folder`
+    #[default]
+    Local,
+    /// Files in memory, gone when the server stops
+    Memory,
+    /// Objects in an S3-compatible bucket, such as one on a MinIO server
+    S3,
+}
+
+/// An S3-compatible bucket to keep files in
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct S3Config {
+    /// Base URL of the server, e.g. `http://nas.local:9000` or
+    /// `https://s3.eu-west-1.amazonaws.com`
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Put before every object name, e.g. `noplacelike/`
+    pub prefix: String,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            endpoint: String::new(),
+            bucket: String::new(),
+            region: "us-east-1".to_string(),
+            access_key: String::new(),
+            secret_key: String::new(),
+            prefix: String::new(),
+        }
+    }
+}
+
+/// Limits on what clients can store. Sizes are in bytes, 0 means no limit.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct QuotaConfig {
+    /// Largest single upload or push
+    pub max_file_size: u64,
+    /// Total size of the files one device may keep in `upload_folder`
+    pub max_device_bytes: u64,
+    /// Total size of `upload_folder`
+    pub max_total_bytes: u64,
+    /// Free space to leave on the disk holding the folder being written to
+    pub min_free_bytes: u64,
+    /// Delete the oldest unpinned uploads to stay under `max_total_bytes`
+    /// instead of refusing new ones
+    pub evict_oldest: bool,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            max_file_size: 0,
+            max_device_bytes: 0,
+            max_total_bytes: 0,
+            min_free_bytes: 256 * 1024 * 1024,
+            evict_oldest: false,
+        }
+    }
+}
+
+/// Limits applied to each client on its own. Clients are told apart by their
+/// IP address. 0 means no limit.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Sustained request rate
+    pub requests_per_minute: u32,
+    /// Requests that can be made in a quick burst before the rate applies
+    pub burst: u32,
+    /// Sustained upload plus download rate, in bytes per second
+    pub bandwidth_bytes_per_sec: u64,
+    /// Downloads and audio streams running at once
+    pub max_streams: usize,
+    /// WebSocket sessions open at once
+    pub max_websockets: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_minute: 600,
+            burst: 60,
+            bandwidth_bytes_per_sec: 0,
+            max_streams: 4,
+            max_websockets: 4,
+        }
+    }
+}
+
+/// Bandwidth caps for uploads, downloads and audio streams, in bytes per
+/// second. 0 means no cap.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct ThrottleConfig {
+    /// Cap on all transfers together
+    pub global_bytes_per_sec: u64,
+    /// Cap on each transfer on its own
+    pub per_connection_bytes_per_sec: u64,
+    /// Which transfers get the global bandwidth first when there isn't
+    /// enough for everyone
+    pub priority: TransferPriority,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            global_bytes_per_sec: 0,
+            per_connection_bytes_per_sec: 0,
+            priority: TransferPriority::Streams,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferPriority {
+    /// Audio streams first, so playback doesn't stutter during big transfers
+    Streams,
+    /// Uploads, pushes and downloads first
+    Bulk,
+    /// Everything shares alike
+    None,
+}
+
+/// End-to-end encryption of clipboard items and shared files
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct E2eConfig {
+    /// Have the web UI encrypt clipboard items and uploads with the paired
+    /// devices' group key, and refuse clipboard items that aren't encrypted
+    pub enabled: bool,
+}
+
+/// Linking up with other noplacelike servers on the LAN
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct FederationConfig {
+    pub enabled: bool,
+    /// Name other servers show for this one, the host name when empty
+    pub name: String,
+    /// Address other servers reach this one at, e.g. `http://192.168.1.5:8000`,
+    /// or an `https://` address of a proxy in front of it. Worked out from
+    /// the local IP address and `port` when empty.
+    pub url: String,
+    /// Base URLs of servers to link up with. They still have to be trusted
+    /// in the Admin Panel, like discovered ones.
+    pub peers: Vec<String>,
+    /// Announce this server and look for others with UDP multicast
+    pub discovery: bool,
+    /// Pass clipboard changes to and from trusted servers that also have
+    /// this on
+    pub merge_clipboard: bool,
+}
+
+impl Default for FederationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            name: String::new(),
+            url: String::new(),
+            peers: Vec::new(),
+            discovery: true,
+            merge_clipboard: false,
+        }
+    }
+}
+
+/// Folders kept in sync with directories on devices
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct SyncConfig {
+    pub enabled: bool,
+    /// Where synced folders are kept, `sync` in the upload folder when empty
+    pub folder: String,
+}
+
+/// Host folders whose new and changed files are published into
+/// `upload_folder`
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct DropFoldersConfig {
+    /// Folders to watch. Subfolders aren't watched. Can only be changed in
+    /// the config file.
+    pub folders: Vec<String>,
+    /// Glob patterns for file names to leave alone, e.g. `*.tmp`
+    pub ignore: Vec<String>,
+    /// How long to wait after a file last changed before publishing it, so
+    /// files still being written are left alone
+    pub debounce_ms: u64,
+    /// Remove published files from `upload_folder` after this long, 0 to
+    /// keep them
+    pub expire_after_secs: u64,
+}
+
+impl Default for DropFoldersConfig {
+    fn default() -> Self {
+        Self {
+            folders: Vec::new(),
+            ignore: [".*", "*~", "*.tmp", "*.part", "*.crdownload"]
+                .map(String::from)
+                .to_vec(),
+            debounce_ms: 1000,
+            expire_after_secs: 0,
+        }
+    }
+}
+
+/// URLs that each matching event is POSTed to as JSON
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct WebhooksConfig {
+    pub hooks: Vec<WebhookConfig>,
+    /// Hosts that hooks added or changed through the admin API may point at.
+    /// Can only be changed in the config file.
+    pub allowed_hosts: Vec<String>,
+    /// Tries per delivery, counting the first, before giving up on it
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled before each one after it
+    pub retry_delay_secs: u64,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            hooks: Vec::new(),
+            allowed_hosts: Vec::new(),
+            max_attempts: 5,
+            retry_delay_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct WebhookConfig {
+    /// An `http://` or `https://` URL
+    pub url: String,
+    /// Event types such as `file_added`, or topics such as `files`, to
+    /// send. Empty for every event but progress reports.
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// Key for the HMAC-SHA256 signature in `X-Noplacelike-Signature`, made
+    /// over `X-Noplacelike-Timestamp`, a `.` and the body. Deliveries aren't
+    /// signed when empty.
+    #[serde(default)]
+    pub secret: String,
+}
+
+impl WebhooksConfig {
+    /// Whether the host of `url` is one of the allowed hosts
+    pub fn is_allowed(&self, url: &str) -> bool {
+        url_host(url).is_some_and(|host| {
+            self.allowed_hosts
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(host))
+        })
+    }
+}
+
+/// Commands run on the host when something arrives
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct HooksConfig {
+    /// Programs hooks may run. Hooks whose command isn't listed are refused.
+    /// Can only be changed in the config file.
+    pub allowed_commands: Vec<String>,
+    /// Run for each file added to `upload_folder`. Can only be changed in
+    /// the config file.
+    pub on_upload: Vec<HookCommand>,
+    /// Run each time the shared clipboard changes. Can only be changed in
+    /// the config file.
+    pub on_clipboard: Vec<HookCommand>,
+    /// Hooks running at once; the rest wait their turn. 0 for no limit.
+    pub max_concurrent: usize,
+    /// Kill a hook still running after this long
+    pub timeout_secs: u64,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            allowed_commands: Vec::new(),
+            on_upload: Vec::new(),
+            on_clipboard: Vec::new(),
+            max_concurrent: 2,
+            timeout_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct HookCommand {
+    /// Path of the program, which has to be in `hooks.allowed_commands`
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// For uploads, glob patterns the file name has to match, e.g. `*.pdf`.
+    /// Empty for every file.
+    #[serde(default)]
+    pub files: Vec<String>,
+}
+
+/// Log output settings. `NOPLACELIKE_LOG` or `RUST_LOG` override `level`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// A level or filter directive, e.g. `info` or `info,actix_web=warn`
+    pub level: String,
+    pub format: LogFormat,
+    /// Directory to also write log files to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    pub rotation: LogRotation,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+            file: None,
+            rotation: LogRotation::Daily,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            version: CURRENT_CONFIG_VERSION,
+            host: "0.0.0.0".to_string(),
+            port: 8000,
+            upload_folder: "~/noplacelike/uploads".to_string(),
+            storage: StorageConfig::default(),
+            download_folder: "~/Downloads".to_string(),
+            audio_folders: Vec::new(),
+            logging: LoggingConfig::default(),
+            shutdown_timeout_secs: 30,
+            max_concurrent_uploads: 8,
+            max_concurrent_downloads: 16,
+            quota: QuotaConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            throttle: ThrottleConfig::default(),
+            e2e: E2eConfig::default(),
+            federation: FederationConfig::default(),
+            sync: SyncConfig::default(),
+            drop_folders: DropFoldersConfig::default(),
+            webhooks: WebhooksConfig::default(),
+            hooks: HooksConfig::default(),
+        }
+    }
+}
+
+/// Shown in place of secrets when the config is sent to clients. Sent back
+/// in an update, it keeps the secret already stored.
+pub const REDACTED: &str = "********";
+
+impl Config {
+    /// A copy safe to show clients, with its secrets replaced by [`REDACTED`]
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        for hook in &mut config.webhooks.hooks {
+            if !hook.secret.is_empty() {
+                hook.secret = REDACTED.to_string();
+            }
+        }
+        if !config.storage.s3.secret_key.is_empty() {
+            config.storage.s3.secret_key = REDACTED.to_string();
+        }
+        config
+    }
+
+    /// Put back the secrets of `stored` wherever this config has
+    /// [`REDACTED`]. A hook only keeps its secret while its URL stays the same.
+    fn restore_secrets(&mut self, stored: &Config) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        for (i, hook) in self.webhooks.hooks.iter_mut().enumerate() {
+            if hook.secret != REDACTED {
+                continue;
+            }
+            match stored.webhooks.hooks.iter().find(|old| old.url == hook.url) {
+                Some(old) => hook.secret = old.secret.clone(),
+                None => errors.push(FieldError::new(
+                    format!("webhooks.hooks[{}].secret", i),
+                    "Set a new secret for a new URL",
+                )),
+            }
+        }
+        if self.storage.s3.secret_key == REDACTED {
+            self.storage.s3.secret_key = stored.storage.s3.secret_key.clone();
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+// Store config globally for ease of access
+lazy_static::lazy_static! {
+    pub static ref CONFIG: Arc<Mutex<Config>> = Arc::new(Mutex::new(Config::default()));
+}
+
+// Held while a config change is validated and saved
+static CHANGING: Mutex<()> = Mutex::new(());
+
+/// The host of an `http://` or `https://` URL, without its port
+pub fn url_host(url: &str) -> Option<&str> {
+    let rest = url
+        .strip_prefix("http://")
+        .or_else(|| url.strip_prefix("https://"))?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let authority = authority.rsplit('@').next()?;
+    let host = match authority.strip_prefix('[') {
+        Some(v6) => v6.split(']').next()?,
+        None => authority.split(':').next()?,
+    };
+    Some(host).filter(|host| !host.is_empty())
+}
+
+pub fn expand_path(path: &str) -> PathBuf {
+    match path.strip_prefix("~/") {
+        Some(rest) => home_dir().unwrap_or_else(|| PathBuf::from(".")).join(rest),
+        None => PathBuf::from(path),
+    }
+}
+
+/// A validation failure tied to a single config field
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl HooksConfig {
+    /// Whether `command` is one of the allowed commands
+    pub fn is_allowed(&self, command: &str) -> bool {
+        let command = expand_path(command);
+        self.allowed_commands
+            .iter()
+            .any(|allowed| expand_path(allowed) == command)
+    }
+}
+
+/// Validate a config replacing `current`, checking that its folders exist,
+/// are usable and don't overlap
+pub fn validate_config(config: &Config, current: &Config) -> Result<(), Vec<FieldError>> {
+    let mut errors = Vec::new();
+
+    let upload = check_dir("upload_folder", &config.upload_folder, true, &mut errors);
+    check_dir("download_folder", &config.download_folder, true, &mut errors);
+
+    let mut seen = HashSet::new();
+    for (i, folder) in config.audio_folders.iter().enumerate() {
+        let field = format!("audio_folders[{}]", i);
+        let Some(audio) = check_read_dir(&field, folder, &current.audio_folders, &mut errors) else {
+            continue;
+        };
+
+        if !seen.insert(audio.clone()) {
+            errors.push(FieldError::new(field, "Duplicate audio folder"));
+            continue;
+        }
+
+        if let Some(upload) = &upload {
+            if upload.starts_with(&audio) || audio.starts_with(upload) {
+                errors.push(FieldError::new(
+                    field,
+                    "Audio folder overlaps with the upload folder",
+                ));
+            }
+        }
+    }
+
+    if tracing_subscriber::EnvFilter::try_new(&config.logging.level).is_err() {
+        errors.push(FieldError::new("logging.level", "Invalid log level or filter"));
+    }
+    if let Some(dir) = &config.logging.file {
+        check_dir("logging.file", dir, true, &mut errors);
+    }
+
+    if config.rate_limit.requests_per_minute > 0 && config.rate_limit.burst == 0 {
+        errors.push(FieldError::new(
+            "rate_limit.burst",
+            "Must be at least 1 while requests are limited",
+        ));
+    }
+
+    let federation = &config.federation;
+    let urls = std::iter::once(("federation.url".to_string(), &federation.url))
+        .filter(|(_, url)| !url.is_empty())
+        .chain(
+            federation
+                .peers
+                .iter()
+                .enumerate()
+                .map(|(i, url)| (format!("federation.peers[{}]", i), url)),
+        );
+    if config.storage.backend == StorageBackend::S3 {
+        validate_s3(&config.storage.s3, &mut errors);
+    }
+
+    for (field, url) in urls {
+        if url_host(url).is_none() {
+            errors.push(FieldError::new(field, "Must be an http:// or https:// URL"));
+        }
+    }
+    for (i, hook) in config.webhooks.hooks.iter().enumerate() {
+        if url_host(&hook.url).is_none() {
+            errors.push(FieldError::new(
+                format!("webhooks.hooks[{}].url", i),
+                "Must be an http:// or https:// URL",
+            ));
+        }
+    }
+    for (i, hook) in config.webhooks.hooks.iter().enumerate() {
+        for (j, name) in hook.events.iter().enumerate() {
+            if !events::EVENT_TYPES.contains(&name.as_str()) && Topic::parse(name).is_none() {
+                errors.push(FieldError::new(
+                    format!("webhooks.hooks[{}].events[{}]", i, j),
+                    "Unknown event type or topic",
+                ));
+            }
+        }
+    }
+    if config.webhooks.max_attempts == 0 {
+        errors.push(FieldError::new("webhooks.max_attempts", "Must be at least 1"));
+    }
+
+    let hooks = &config.hooks;
+    for (i, command) in hooks.allowed_commands.iter().enumerate() {
+        if !expand_path(command).is_absolute() {
+            errors.push(FieldError::new(
+                format!("hooks.allowed_commands[{}]", i),
+                "Must be an absolute path",
+            ));
+        }
+    }
+    let commands = hooks
+        .on_upload
+        .iter()
+        .enumerate()
+        .map(|(i, hook)| ("on_upload", i, hook))
+        .chain(
+            hooks
+                .on_clipboard
+                .iter()
+                .enumerate()
+                .map(|(i, hook)| ("on_clipboard", i, hook)),
+        );
+    for (trigger, i, hook) in commands {
+        if !hooks.is_allowed(&hook.command) {
+            errors.push(FieldError::new(
+                format!("hooks.{}[{}].command", trigger, i),
+                "Not in hooks.allowed_commands",
+            ));
+        }
+        for (j, pattern) in hook.files.iter().enumerate() {
+            if glob::Pattern::new(pattern).is_err() {
+                errors.push(FieldError::new(
+                    format!("hooks.{}[{}].files[{}]", trigger, i, j),
+                    "Invalid glob pattern",
+                ));
+            }
+        }
+    }
+    if hooks.timeout_secs == 0 {
+        errors.push(FieldError::new("hooks.timeout_secs", "Must be at least 1"));
+    }
+
+    let mut watched = HashSet::new();
+    for (i, folder) in config.drop_folders.folders.iter().enumerate() {
+        let field = format!("drop_folders.folders[{}]", i);
+        let known = &current.drop_folders.folders;
+        let Some(drop) = check_read_dir(&field, folder, known, &mut errors) else {
+            continue;
+        };
+        if !watched.insert(drop.clone()) {
+            errors.push(FieldError::new(field, "Duplicate drop folder"));
+        } else if upload
+            .as_ref()
+            .is_some_and(|upload| upload.starts_with(&drop) || drop.starts_with(upload))
+        {
+            errors.push(FieldError::new(
+                field,
+                "Drop folder overlaps with the upload folder",
+            ));
+        }
+    }
+    for (i, pattern) in config.drop_folders.ignore.iter().enumerate() {
+        if glob::Pattern::new(pattern).is_err() {
+            errors.push(FieldError::new(
+                format!("drop_folders.ignore[{}]", i),
+                "Invalid glob pattern",
+            ));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn validate_s3(s3: &S3Config, errors: &mut Vec<FieldError>) {
+    let host = s3
+        .endpoint
+        .strip_prefix("http://")
+        .or_else(|| s3.endpoint.strip_prefix("https://"))
+        .map(|rest| rest.trim_end_matches('/'));
+    if host.is_none_or(|host| host.is_empty() || host.contains('/')) {
+        errors.push(FieldError::new(
+            "storage.s3.endpoint",
+            "Must be an http:// or https:// URL without a path",
+        ));
+    }
+    let bucket_chars = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.';
+    if s3.bucket.len() < 3 || !s3.bucket.chars().all(bucket_chars) {
+        errors.push(FieldError::new(
+            "storage.s3.bucket",
+            "Must be a bucket name of lowercase letters, digits, dots and hyphens",
+        ));
+    }
+    for (field, value) in [
+        ("storage.s3.region", &s3.region),
+        ("storage.s3.access_key", &s3.access_key),
+        ("storage.s3.secret_key", &s3.secret_key),
+    ] {
+        if value.trim().is_empty() {
+            errors.push(FieldError::new(field, "Must not be empty"));
+        }
+    }
+    if s3.prefix.starts_with('/') {
+        errors.push(FieldError::new("storage.s3.prefix", "Must not start with /"));
+    }
+}
+
+/// Split `path` into the closest folder above it (or itself) that exists and
+/// the part below that doesn't
+fn existing_ancestor(path: &Path) -> (&Path, PathBuf) {
+    let mut missing = Vec::new();
+    let mut current = path;
+    while !current.exists() {
+        match (current.parent(), current.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                current = if parent.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    parent
+                };
+            }
+            _ => break,
+        }
+    }
+    (current, missing.into_iter().rev().collect())
+}
+
+/// Check that a configured folder is a directory, returning its canonical
+/// path. Folders the server writes to may be missing as long as they can be
+/// created; [`update_config`] creates them once the change is saved.
+fn check_dir(
+    field: &str,
+    value: &str,
+    writable: bool,
+    errors: &mut Vec<FieldError>,
+) -> Option<PathBuf> {
+    if value.trim().is_empty() {
+        errors.push(FieldError::new(field, "Path must not be empty"));
+        return None;
+    }
+
+    let path = expand_path(value);
+    let (existing, missing) = if writable {
+        existing_ancestor(&path)
+    } else {
+        (path.as_path(), PathBuf::new())
+    };
+    let canonical = match fs::canonicalize(existing) {
+        Ok(p) if p.is_dir() => p,
+        Ok(_) if missing.as_os_str().is_empty() => {
+            errors.push(FieldError::new(field, "Path is not a directory"));
+            return None;
+        }
+        Ok(p) => {
+            let message = format!("Directory can't be created: {} is not a directory", p.display());
+            errors.push(FieldError::new(field, message));
+            return None;
+        }
+        Err(_) => {
+            errors.push(FieldError::new(field, "Directory does not exist"));
+            return None;
+        }
+    };
+
+    if writable && !is_writable(&canonical) {
+        let message = if missing.as_os_str().is_empty() {
+            "Directory is not writable".to_string()
+        } else {
+            format!("Directory can't be created: {} is not writable", canonical.display())
+        };
+        errors.push(FieldError::new(field, message));
+        return None;
+    }
+
+    Some(canonical.join(missing))
+}
+
+/// Create the folders the server writes to, once a config naming them has
+/// been saved
+fn create_dirs(config: &Config) {
+    let dirs = [
+        Some(&config.upload_folder),
+        Some(&config.download_folder),
+        config.logging.file.as_ref(),
+    ];
+    for dir in dirs.into_iter().flatten() {
+        let path = expand_path(dir);
+        if let Err(e) = fs::create_dir_all(&path) {
+            tracing::error!(path = %path.display(), "Failed to create directory: {}", e);
+        }
+    }
+}
+
+/// Check a folder the server only reads, like [`check_dir`]. One that is
+/// already in `known` may just be unmounted for now, so problems with it
+/// are logged instead of blocking other changes.
+fn check_read_dir(
+    field: &str,
+    value: &str,
+    known: &[String],
+    errors: &mut Vec<FieldError>,
+) -> Option<PathBuf> {
+    let mut found = Vec::new();
+    let dir = check_dir(field, value, false, &mut found);
+    if known.iter().any(|folder| folder == value) {
+        for error in found {
+            tracing::warn!(field = %error.field, path = value, "Keeping folder: {}", error.message);
+        }
+    } else {
+        errors.extend(found);
+    }
+    dir
+}
+
+/// Probe a directory for write access by creating and removing a scratch file
+pub fn is_writable(dir: &Path) -> bool {
+    let probe = dir.join(format!(".noplacelike-probe-{}", uuid::Uuid::new_v4()));
+    match fs::write(&probe, b"") {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// A snapshot of the current config
+pub fn current_config() -> Result<Config, AppError> {
+    Ok(CONFIG.lock()?.clone())
+}
+
+/// Make a change to the current config, then validate and persist the
+/// result. All config changes should go through here. Changes are made one
+/// at a time, so two made at once can't undo each other.
+fn modify_config(
+    change: impl FnOnce(Config) -> Result<Config, AppError>,
+) -> Result<Config, AppError> {
+    let _changing = lock_recovering(&CHANGING);
+    let current = current_config()?;
+    let config = change(current.clone())?;
+    apply_config(config, &current)
+}
+
+/// Replace the config with a new one
+pub fn update_config(config: Config) -> Result<Config, AppError> {
+    modify_config(|_| Ok(config))
+}
+
+fn apply_config(mut config: Config, current: &Config) -> Result<Config, AppError> {
+    config.restore_secrets(current)?;
+    // Hooks run commands on the host, webhooks are sent from it and drop
+    // folders publish its files, so only whoever can edit the file decides
+    let file_only: Vec<FieldError> = [
+        (
+            "hooks.allowed_commands",
+            config.hooks.allowed_commands != current.hooks.allowed_commands,
+        ),
+        ("hooks.on_upload", config.hooks.on_upload != current.hooks.on_upload),
+        ("hooks.on_clipboard", config.hooks.on_clipboard != current.hooks.on_clipboard),
+        (
+            "webhooks.allowed_hosts",
+            config.webhooks.allowed_hosts != current.webhooks.allowed_hosts,
+        ),
+        (
+            "drop_folders.folders",
+            config.drop_folders.folders != current.drop_folders.folders,
+        ),
+    ]
+    .into_iter()
+    .filter(|(_, changed)| *changed)
+    .map(|(field, _)| FieldError::new(field, "Can only be changed in the config file"))
+    .collect();
+    if !file_only.is_empty() {
+        return Err(AppError::Validation(file_only));
+    }
+    let new_targets: Vec<FieldError> = config
+        .webhooks
+        .hooks
+        .iter()
+        .enumerate()
+        .filter(|(_, hook)| {
+            !current.webhooks.hooks.iter().any(|old| old.url == hook.url)
+                && !config.webhooks.is_allowed(&hook.url)
+        })
+        .map(|(i, _)| {
+            FieldError::new(
+                format!("webhooks.hooks[{}].url", i),
+                "Host not in webhooks.allowed_hosts",
+            )
+        })
+        .collect();
+    if !new_targets.is_empty() {
+        return Err(AppError::Validation(new_targets));
+    }
+    validate_config(&config, current)?;
+    save_config(&config)?;
+    create_dirs(&config);
+    Ok(config)
+}
+
+/// Apply a JSON merge patch (RFC 7386) to the current config
+pub fn patch_config(patch: Value) -> Result<Config, AppError> {
+    modify_config(|current| {
+        let mut merged =
+            serde_json::to_value(current).map_err(|e| AppError::Internal(e.to_string()))?;
+        merge_patch(&mut merged, patch);
+
+        serde_json::from_value::<Config>(merged)
+            .map_err(|e| AppError::Validation(vec![FieldError::new("config", e.to_string())]))
+    })
+}
+
+pub(crate) fn merge_patch(target: &mut Value, patch: Value) {
+    match patch {
+        Value::Object(fields) => {
+            if !target.is_object() {
+                *target = Value::Object(Default::default());
+            }
+            let map = target.as_object_mut().unwrap();
+            for (key, value) in fields {
+                if value.is_null() {
+                    map.remove(&key);
+                } else {
+                    merge_patch(map.entry(key).or_insert(Value::Null), value);
+                }
+            }
+        }
+        other => *target = other,
+    }
+}
+
+pub fn ensure_upload_folder() -> Result<PathBuf, AppError> {
+    let path = expand_path(&CONFIG.lock()?.upload_folder);
+    fs::create_dir_all(&path).map_err(|e| {
+        tracing::error!(path = %path.display(), "Failed to create upload directory: {}", e);
+        e
+    })?;
+    Ok(path)
+}
+
+pub fn ensure_sync_folder() -> Result<PathBuf, AppError> {
+    let folder = CONFIG.lock()?.sync.folder.clone();
+    let path = match folder.as_str() {
+        "" => ensure_upload_folder()?.join("sync"),
+        folder => expand_path(folder),
+    };
+    fs::create_dir_all(&path).map_err(|e| {
+        tracing::error!(path = %path.display(), "Failed to create sync directory: {}", e);
+        e
+    })?;
+    Ok(path)
+}
+
+pub fn ensure_download_folder() -> Result<PathBuf, AppError> {
+    let path = expand_path(&CONFIG.lock()?.download_folder);
+    fs::create_dir_all(&path).map_err(|e| {
+        tracing::error!(path = %path.display(), "Failed to create download directory: {}", e);
+        e
+    })?;
+    Ok(path)
+}
+
+pub fn get_audio_folders() -> Result<Vec<PathBuf>, AppError> {
+    let config = CONFIG.lock()?;
+    let mut folders = Vec::new();
+    
+    for folder in &config.audio_folders {
+        let path = expand_path(folder);
+        // Ensure directory exists
+        if let Err(e) = fs::create_dir_all(&path) {
+            tracing::error!(path = %path.display(), "Failed to create audio directory: {}", e);
+        }
+        folders.push(path);
+    }
+    
+    // Add default folder if none specified
+    if folders.is_empty() {
+        let default_path = expand_path("~/noplacelike/audio");
+        fs::create_dir_all(&default_path).unwrap_or_else(|e| {
+            tracing::error!(path = %default_path.display(), "Failed to create default audio directory: {}", e);
+        });
+        folders.push(default_path);
+    }
+    
+    Ok(folders)
+}
+
+pub fn add_audio_folder(folder: String) -> Result<(), AppError> {
+    modify_config(|mut config| {
+        if !config.audio_folders.contains(&folder) {
+            config.audio_folders.push(folder);
+        }
+        Ok(config)
+    })?;
+    Ok(())
+}
+
+pub fn remove_audio_folder(folder: &str) -> Result<(), AppError> {
+    modify_config(|mut config| {
+        config.audio_folders.retain(|f| f != folder);
+        Ok(config)
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_dir_accepts_missing_folders_without_creating_them() {
+        let root = std::env::temp_dir().join(format!("noplacelike-dir-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        let missing = root.join("a").join("b");
+        let value = missing.display().to_string();
+
+        let mut errors = Vec::new();
+        let checked = check_dir("upload_folder", &value, true, &mut errors);
+        assert!(errors.is_empty());
+        assert_eq!(checked, Some(fs::canonicalize(&root).unwrap().join("a").join("b")));
+        assert!(!root.join("a").exists());
+
+        // Folders the server only reads have to exist already
+        check_dir("audio_folders[0]", &value, false, &mut errors);
+        assert_eq!(errors.len(), 1);
+
+        fs::write(root.join("file"), b"").unwrap();
+        let mut errors = Vec::new();
+        let under_file = root.join("file").join("x").display().to_string();
+        assert!(check_dir("download_folder", &under_file, true, &mut errors).is_none());
+        assert!(errors[0].message.starts_with("Directory can't be created"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use std::fmt;
+use std::io;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use crate::config::FieldError;
+
+/// The error type for everything a request can fail with.
+///
+/// Every variant maps to an HTTP status and a stable machine-readable code,
+/// and renders as the same JSON envelope:
+///
+/// ```json
+/// {"status": "error", "code": "not_found", "error": "File not found"}
+/// ```
+///
+/// Validation errors also carry a `fields` list of per-field messages.
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    /// Missing or wrong credentials, from another server
+    Unauthorized(String),
+    NotFound(String),
+    Forbidden(String),
+    Timeout(String),
+    /// An upload is over a size limit or quota
+    PayloadTooLarge(String),
+    /// Not enough free disk space to store an upload
+    InsufficientStorage(String),
+    Validation(Vec<FieldError>),
+    /// The request clashes with something that already exists
+    Conflict(String),
+    /// A client went over one of its rate limits and should come back after
+    /// `retry_after`
+    TooManyRequests {
+        message: String,
+        retry_after: Duration,
+    },
+    Unavailable(String),
+    /// Another server this one relies on failed or couldn't be reached
+    BadGateway(String),
+    Io(io::Error),
+    /// A mutex was poisoned by a panic while it was held
+    LockPoisoned,
+    Internal(String),
+}
+
+/// The JSON body of every error response
+#[derive(Debug, Serialize, utoipa::ToSchema)]
+pub struct ErrorResponse {
+    /// Always `"error"`
+    #[schema(example = "error")]
+    status: String,
+    /// Stable machine-readable error code
+    #[schema(example = "not_found")]
+    code: String,
+    /// Human-readable description
+    #[schema(example = "File not found")]
+    error: String,
+    /// Per-field messages, only for `validation_failed`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<FieldError>,
+}
+
+impl AppError {
+    /// Stable identifier clients can match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::NotFound(_) => "not_found",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::Timeout(_) => "timeout",
+            AppError::PayloadTooLarge(_) => "payload_too_large",
+            AppError::InsufficientStorage(_) => "insufficient_storage",
+            AppError::Validation(_) => "validation_failed",
+            AppError::Conflict(_) => "conflict",
+            AppError::TooManyRequests { .. } => "rate_limited",
+            AppError::Unavailable(_) => "unavailable",
+            AppError::BadGateway(_) => "bad_gateway",
+            AppError::Io(e) => match e.kind() {
+                io::ErrorKind::NotFound => "not_found",
+                io::ErrorKind::PermissionDenied => "permission_denied",
+                io::ErrorKind::StorageFull => "insufficient_storage",
+                _ => "io_error",
+            },
+            AppError::LockPoisoned => "lock_poisoned",
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::BadRequest(msg)
+            | AppError::Unauthorized(msg)
+            | AppError::NotFound(msg)
+            | AppError::Forbidden(msg)
+            | AppError::Timeout(msg)
+            | AppError::PayloadTooLarge(msg)
+            | AppError::InsufficientStorage(msg)
+            | AppError::Conflict(msg)
+            | AppError::Unavailable(msg)
+            | AppError::BadGateway(msg)
+            | AppError::Internal(msg)
+            | AppError::TooManyRequests { message: msg, .. } => f.write_str(msg),
+            AppError::Validation(errors) => {
+                let fields: Vec<String> = errors
+                    .iter()
+                    .map(|e| format!("{}: {}", e.field, e.message))
+                    .collect();
+                write!(f, "Invalid configuration: {}", fields.join("; "))
+            }
+            AppError::Io(e) => write!(f, "I/O error: {}", e),
+            AppError::LockPoisoned => f.write_str("Internal state is unavailable after an earlier failure"),
+        }
+    }
+}
+
+impl std::error::Error for AppError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AppError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::Timeout(_) => StatusCode::REQUEST_TIMEOUT,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::InsufficientStorage(_) => StatusCode::INSUFFICIENT_STORAGE,
+            AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            AppError::Io(e) => match e.kind() {
+                io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+                io::ErrorKind::StorageFull => StatusCode::INSUFFICIENT_STORAGE,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            AppError::LockPoisoned | AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        if status.is_server_error() {
+            tracing::error!(code = self.code(), "{}", self);
+        }
+
+        let fields = match self {
+            AppError::Validation(errors) => errors.clone(),
+            _ => Vec::new(),
+        };
+
+        let mut response = HttpResponse::build(status);
+        if let AppError::TooManyRequests { retry_after, .. } = self {
+            // Whole seconds, rounded up so a client retrying on time succeeds
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response.insert_header((RETRY_AFTER, secs.max(1)));
+        }
+
+        response.json(ErrorResponse {
+            status: "error".to_string(),
+            code: self.code().to_string(),
+            error: self.to_string(),
+            fields,
+        })
+    }
+}
+
+impl From<io::Error> for AppError {
+    fn from(e: io::Error) -> Self {
+        AppError::Io(e)
+    }
+}
+
+impl<T> From<PoisonError<T>> for AppError {
+    fn from(_: PoisonError<T>) -> Self {
+        AppError::LockPoisoned
+    }
+}
+
+impl From<Vec<FieldError>> for AppError {
+    fn from(errors: Vec<FieldError>) -> Self {
+        AppError::Validation(errors)
+    }
+}
+
+impl From<actix_multipart::MultipartError> for AppError {
+    fn from(e: actix_multipart::MultipartError) -> Self {
+        AppError::BadRequest(format!("Invalid upload: {}", e))
+    }
+}
+
+impl From<actix_web::error::BlockingError> for AppError {
+    fn from(_: actix_web::error::BlockingError) -> Self {
+        AppError::Unavailable("Server is too busy to handle the request".to_string())
+    }
+}
+
+/// Turn extractor failures (bad JSON, query strings or path segments) into
+/// the same envelope as every other error
+pub fn extractor_error(err: impl fmt::Display) -> actix_web::Error {
+    AppError::BadRequest(err.to_string()).into()
+}
+
+/// Lock a mutex, carrying on with its data if a previous holder panicked.
+///
+/// Only for state that can't be left half-updated and for callers with no
+/// request to fail, like actors, drop guards and shutdown hooks.
+pub fn lock_recovering<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,192 @@
+//! The HTTP client shared by requests to other programs, such as webhook
+//! deliveries, linked servers and `noplacelike sync`. `https://` URLs are
+//! checked against the bundled root certificates.
+
+use actix_web::web::Bytes;
+use futures::Stream;
+use std::io;
+use std::time::Duration;
+
+/// Longest wait for a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest wait for a whole request, answer included
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+lazy_static::lazy_static! {
+    static ref CLIENT: reqwest::Client = builder()
+        .timeout(TIMEOUT)
+        .build()
+        .expect("Failed to build the HTTP client");
+    static ref STREAMING_CLIENT: reqwest::Client = builder()
+        .read_timeout(TIMEOUT)
+        .build()
+        .expect("Failed to build the HTTP client");
+}
+
+fn builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .user_agent(concat!("noplacelike/", env!("CARGO_PKG_VERSION")))
+        .connect_timeout(CONNECT_TIMEOUT)
+        // A redirect could send the request somewhere it wasn't allowed to go
+        .redirect(reqwest::redirect::Policy::none())
+}
+
+pub fn client() -> &'static reqwest::Client {
+    &CLIENT
+}
+
+/// Like [`client`], for answers that can take a while to arrive in full,
+/// like files passed on from another server. Only each wait for more of the
+/// answer is limited.
+pub fn streaming_client() -> &'static reqwest::Client {
+    &STREAMING_CLIENT
+}
+
+/// Read a whole body, failing if it is over `limit` bytes
+pub async fn read_limited(mut response: reqwest::Response, limit: usize) -> Result<Vec<u8>, String> {
+    if response.content_length().is_some_and(|len| len > limit as u64) {
+        return Err("Response is too large".to_string());
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|e| describe(&e))? {
+        if body.len() + chunk.len() > limit {
+            return Err("Response is too large".to_string());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// A body as it arrives
+pub fn body_stream(response: reqwest::Response) -> impl Stream<Item = io::Result<Bytes>> {
+    futures::stream::unfold(Some(response), |response| async move {
+        let mut response = response?;
+        match response.chunk().await {
+            Ok(Some(chunk)) => Some((Ok(chunk), Some(response))),
+            Ok(None) => None,
+            Err(e) => Some((Err(io::Error::other(describe(&e))), None)),
+        }
+    })
+}
+
+/// An error with its causes, e.g. `error sending request: connection refused`
+pub fn describe(error: &(dyn std::error::Error + 'static)) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+/// A local HTTP server for tests that answers with scripted responses and
+/// records what it was sent
+#[cfg(test)]
+pub mod stand_in {
+    use std::collections::HashMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task::JoinHandle;
+
+    /// A request as the stand-in received it. Header names are lowercase.
+    pub struct Received {
+        pub method: String,
+        /// The path and query
+        pub target: String,
+        pub headers: HashMap<String, String>,
+        pub body: Vec<u8>,
+    }
+
+    pub struct Reply {
+        pub status: u16,
+        pub headers: Vec<(&'static str, String)>,
+        pub body: Vec<u8>,
+    }
+
+    impl Reply {
+        pub fn status(status: u16) -> Self {
+            Self {
+                status,
+                headers: Vec::new(),
+                body: Vec::new(),
+            }
+        }
+
+        pub fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+            self.headers.push((name, value.into()));
+            self
+        }
+
+        pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+            self.body = body.into();
+            self
+        }
+    }
+
+    /// Read one request: the head, then as much body as `Content-Length` says
+    async fn read_request(stream: &mut TcpStream) -> Received {
+        let mut data = Vec::new();
+        let mut chunk = vec![0u8; 64 * 1024];
+        let head_end = loop {
+            let n = stream.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "connection closed before the request head ended");
+            data.extend_from_slice(&chunk[..n]);
+            if let Some(i) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+        };
+        let head = String::from_utf8_lossy(&data[..head_end]).to_string();
+        let mut lines = head.lines();
+        let mut request_line = lines.next().unwrap_or_default().split(' ');
+        let method = request_line.next().unwrap_or_default().to_string();
+        let target = request_line.next().unwrap_or_default().to_string();
+        let headers: HashMap<String, String> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.to_ascii_lowercase(), value.trim().to_string()))
+            .collect();
+        let length: usize = headers
+            .get("content-length")
+            .map_or(0, |length| length.parse().unwrap());
+        while data.len() < head_end + length {
+            let n = stream.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "connection closed before the request body ended");
+            data.extend_from_slice(&chunk[..n]);
+        }
+        Received {
+            method,
+            target,
+            headers,
+            body: data[head_end..head_end + length].to_vec(),
+        }
+    }
+
+    /// Start a stand-in answering each request with the next of `replies`,
+    /// returning its base URL and, once all have been answered, what it got
+    pub async fn start(replies: Vec<Reply>) -> (String, JoinHandle<Vec<Received>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let mut received = Vec::new();
+            for reply in replies {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                received.push(read_request(&mut stream).await);
+                let mut head = format!(
+                    "HTTP/1.1 {} Stand-in\r\nContent-Length: {}\r\nConnection: close\r\n",
+                    reply.status,
+                    reply.body.len()
+                );
+                for (name, value) in &reply.headers {
+                    head.push_str(&format!("{}: {}\r\n", name, value));
+                }
+                head.push_str("\r\n");
+                stream.write_all(head.as_bytes()).await.unwrap();
+                stream.write_all(&reply.body).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+            received
+        });
+        (url, handle)
+    }
+}
@@ -0,0 +1,77 @@
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+use crate::config::{expand_path, LogFormat, LogRotation, LoggingConfig};
+
+/// Environment variables that override the configured log level, in order
+const LOG_ENV_VARS: &[&str] = &["NOPLACELIKE_LOG", "RUST_LOG"];
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// A plain stderr subscriber for the messages emitted while the config (and
+/// with it the real logging setup) is still being loaded
+pub fn bootstrap_subscriber() -> impl tracing::Subscriber + Send + Sync {
+    tracing_subscriber::fmt()
+        .with_env_filter(env_filter("info"))
+        .with_writer(std::io::stderr)
+        .finish()
+}
+
+/// Install the global subscriber described by the config.
+///
+/// Spans are logged when they close, which gives one access log line per
+/// request (with its request ID, status and latency) and one per WebSocket
+/// session. The returned guard must be kept alive so buffered file output is
+/// flushed on exit.
+pub fn init(config: &LoggingConfig) -> Option<WorkerGuard> {
+    let mut layers: Vec<BoxedLayer> = vec![fmt_layer(config.format, std::io::stdout, true)];
+
+    let guard = config.file.as_ref().map(|dir| {
+        let dir = expand_path(dir);
+        let appender = match config.rotation {
+            LogRotation::Hourly => rolling::hourly(&dir, "noplacelike.log"),
+            LogRotation::Daily => rolling::daily(&dir, "noplacelike.log"),
+            LogRotation::Never => rolling::never(&dir, "noplacelike.log"),
+        };
+        let (writer, guard) = tracing_appender::non_blocking(appender);
+        layers.push(fmt_layer(config.format, writer, false));
+        guard
+    });
+
+    let result = tracing_subscriber::registry()
+        .with(layers.with_filter(env_filter(&config.level)))
+        .try_init();
+    if let Err(e) = result {
+        eprintln!("Failed to initialize logging: {}", e);
+    }
+
+    guard
+}
+
+fn fmt_layer<W>(format: LogFormat, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'w> tracing_subscriber::fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_span_events(FmtSpan::CLOSE);
+
+    match format {
+        LogFormat::Text => layer.with_ansi(ansi).boxed(),
+        LogFormat::Json => layer.json().with_current_span(true).boxed(),
+    }
+}
+
+/// Build a filter from the environment if set, falling back to `level`
+fn env_filter(level: &str) -> EnvFilter {
+    LOG_ENV_VARS
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find_map(|directives| EnvFilter::try_new(directives).ok())
+        .or_else(|| EnvFilter::try_new(level).ok())
+        .unwrap_or_else(|| EnvFilter::new("info"))
+}
@@ -1,43 +1,115 @@
 use std::io;
+use std::time::Duration;
 
+mod cli;
 mod config;
+mod error;
+mod http;
+mod logging;
+mod metrics;
+mod rate_limit;
 mod routes;
 mod server;
 mod services;
+mod shutdown;
+mod storage;
+mod sync;
 mod templates;
+mod throttle;
+mod wormhole;
 
 #[actix_web::main]
 async fn main() -> io::Result<()> {
     // Parse command line arguments
     let args: Vec<String> = std::env::args().collect();
-    let mut host = "0.0.0.0".to_string();
-    let mut port = 8000;
-
-    // Very simple argument parsing (could use clap for more robust parsing)
-    let mut i = 1;
-    while i < args.len() {
-        match args[i].as_str() {
-            "--host" => {
-                if i + 1 < args.len() {
-                    host = args[i + 1].clone();
-                    i += 1;
-                }
+    let cli = match cli::parse_args(&args) {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    if let Some(path) = cli.config_path {
+        config::set_config_path(path);
+    }
+
+    // Initialize config, logging to stderr until the configured logging is set up
+    let loaded = tracing::subscriber::with_default(logging::bootstrap_subscriber(), || {
+        config::load_config(cli.overrides)
+    });
+    let config = match loaded {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    match cli.command {
+        cli::Command::ConfigShow { effective } => {
+            config::print_config(effective);
+            Ok(())
+        }
+        cli::Command::ConfigMigrate => match config::save_migrated_config() {
+            Ok(Some(path)) => {
+                println!("Wrote {} at version {}", path.display(), config::CURRENT_CONFIG_VERSION);
+                Ok(())
             }
-            "--port" => {
-                if i + 1 < args.len() {
-                    port = args[i + 1].parse().unwrap_or(8000);
-                    i += 1;
-                }
+            Ok(None) => {
+                println!("{} is already at version {}", config::get_config_path().display(), config.version);
+                Ok(())
             }
-            _ => {}
+            Err(e) => finish(Err(e.to_string())),
+        },
+        cli::Command::Send { file, server } => {
+            let server = server.unwrap_or_else(|| local_server(config.port));
+            finish(wormhole::client::send(&server, &file).await)
+        }
+        cli::Command::Receive {
+            code,
+            server,
+            output,
+        } => {
+            let server = server.unwrap_or_else(|| local_server(config.port));
+            let output = match output {
+                Some(output) => output,
+                None => config::ensure_download_folder().map_err(io::Error::other)?,
+            };
+            finish(wormhole::client::receive(&server, &code, &output).await)
+        }
+        cli::Command::Sync {
+            dir,
+            folder,
+            server,
+            once,
+            interval,
+        } => {
+            let server = server.unwrap_or_else(|| local_server(config.port));
+            let interval = Duration::from_secs(interval);
+            finish(sync::client::run(&server, &dir, folder.as_deref(), once, interval).await)
+        }
+        cli::Command::Serve => {
+            let _log_guard = logging::init(&config.logging);
+
+            // Start server
+            tracing::info!("Starting noplacelike server...");
+            let (host, port) = (config.host.clone(), config.port);
+            server::run_server(host, port, config).await
         }
-        i += 1;
     }
+}
 
-    // Initialize config
-    let config = config::load_config();
-    
-    // Start server
-    println!("Starting noplacelike server...");
-    server::run_server(host, port, config).await
+/// The server on this machine, for commands that talk to one
+fn local_server(port: u16) -> String {
+    format!("http://127.0.0.1:{}", port)
+}
+
+/// Exit with an error message if a client command failed
+fn finish(result: Result<(), String>) -> io::Result<()> {
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    Ok(())
 }
@@ -0,0 +1,205 @@
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::web::Bytes;
+use actix_web::Error;
+use prometheus::{
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, Registry, TextEncoder,
+};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+lazy_static::lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new();
+
+    pub static ref HTTP_REQUESTS: IntCounterVec = register(IntCounterVec::new(
+        prometheus::opts!("noplacelike_http_requests_total", "HTTP requests by route and status"),
+        &["method", "route", "status"],
+    ));
+
+    pub static ref HTTP_DURATION: HistogramVec = register(HistogramVec::new(
+        prometheus::histogram_opts!(
+            "noplacelike_http_request_duration_seconds",
+            "Time to produce a response, by route"
+        ),
+        &["method", "route"],
+    ));
+
+    pub static ref UPLOADED_BYTES: IntCounter = register(IntCounter::new(
+        "noplacelike_uploaded_bytes_total",
+        "Bytes received through uploads and pushes",
+    ));
+
+    pub static ref DOWNLOADED_BYTES: IntCounter = register(IntCounter::new(
+        "noplacelike_downloaded_bytes_total",
+        "Bytes sent for file downloads",
+    ));
+
+    pub static ref STREAMED_BYTES: IntCounter = register(IntCounter::new(
+        "noplacelike_streamed_bytes_total",
+        "Bytes sent for audio streams",
+    ));
+
+    pub static ref ACTIVE_AUDIO_STREAMS: IntGauge = register(IntGauge::new(
+        "noplacelike_audio_streams_active",
+        "Audio streams currently being sent",
+    ));
+
+    pub static ref WEBSOCKET_CLIENTS: IntGauge = register(IntGauge::new(
+        "noplacelike_websocket_clients",
+        "Connected clipboard WebSocket clients",
+    ));
+
+    pub static ref RATE_LIMITED: IntCounterVec = register(IntCounterVec::new(
+        prometheus::opts!("noplacelike_rate_limited_total", "Requests refused by a per-client limit"),
+        &["limit"],
+    ));
+
+    pub static ref UPLOAD_FOLDER_BYTES: IntGauge = register(IntGauge::new(
+        "noplacelike_upload_folder_bytes",
+        "Total size of the files in the upload folder",
+    ));
+
+    pub static ref UPLOAD_FOLDER_FILES: IntGauge = register(IntGauge::new(
+        "noplacelike_upload_folder_files",
+        "Number of files in the upload folder",
+    ));
+}
+
+/// Register every metric up front so scrapes show them before first use
+pub fn init() {
+    lazy_static::initialize(&HTTP_REQUESTS);
+    lazy_static::initialize(&HTTP_DURATION);
+    lazy_static::initialize(&UPLOADED_BYTES);
+    lazy_static::initialize(&DOWNLOADED_BYTES);
+    lazy_static::initialize(&STREAMED_BYTES);
+    lazy_static::initialize(&ACTIVE_AUDIO_STREAMS);
+    lazy_static::initialize(&WEBSOCKET_CLIENTS);
+    lazy_static::initialize(&RATE_LIMITED);
+    lazy_static::initialize(&UPLOAD_FOLDER_BYTES);
+    lazy_static::initialize(&UPLOAD_FOLDER_FILES);
+}
+
+fn register<M>(metric: prometheus::Result<M>) -> M
+where
+    M: prometheus::core::Collector + Clone + 'static,
+{
+    let metric = metric.expect("metric definition is valid");
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric is only registered once");
+    metric
+}
+
+/// Render every metric in the Prometheus text exposition format
+pub fn render() -> Result<String, String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buffer)
+        .map_err(|e| e.to_string())?;
+    String::from_utf8(buffer).map_err(|e| e.to_string())
+}
+
+/// Middleware (for `App::wrap_fn`) counting requests and timing them per route
+pub fn track_request<S>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<BoxBody>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error>,
+{
+    let started = Instant::now();
+    let method = req.method().to_string();
+    // Use the route pattern rather than the raw path so file names don't
+    // blow up the label cardinality
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+    let response = srv.call(req);
+
+    async move {
+        let response = response.await?;
+        let status = response.status().as_u16().to_string();
+        HTTP_REQUESTS
+            .with_label_values(&[&method, &route, &status])
+            .inc();
+        HTTP_DURATION
+            .with_label_values(&[&method, &route])
+            .observe(started.elapsed().as_secs_f64());
+        Ok(response)
+    }
+}
+
+/// Increments a gauge while alive
+pub struct GaugeGuard(IntGauge);
+
+impl GaugeGuard {
+    pub fn new(gauge: &IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge.clone())
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// A response body that counts the bytes actually sent, optionally holding
+/// guards until the body is finished or dropped
+pub struct MeteredBody {
+    inner: BoxBody,
+    counter: IntCounter,
+    on_chunk: Vec<Box<dyn Fn(u64)>>,
+    _guard: Option<GaugeGuard>,
+    _held: Vec<Box<dyn std::any::Any>>,
+}
+
+impl MeteredBody {
+    pub fn new(inner: BoxBody, counter: &IntCounter, guard: Option<GaugeGuard>) -> Self {
+        Self {
+            inner,
+            counter: counter.clone(),
+            on_chunk: Vec::new(),
+            _guard: guard,
+            _held: Vec::new(),
+        }
+    }
+
+    /// Keep `value` alive for as long as the body is being sent
+    pub fn hold(mut self, value: impl std::any::Any) -> Self {
+        self._held.push(Box::new(value));
+        self
+    }
+
+    /// Also pass the size of every chunk sent to `f`
+    pub fn on_chunk(mut self, f: impl Fn(u64) + 'static) -> Self {
+        self.on_chunk.push(Box::new(f));
+        self
+    }
+}
+
+impl MessageBody for MeteredBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            let len = chunk.len() as u64;
+            self.counter.inc_by(len);
+            for f in &self.on_chunk {
+                f(len);
+            }
+        }
+        poll
+    }
+}
@@ -0,0 +1,348 @@
+use actix_web::body::BoxBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::{Error, HttpRequest, ResponseError};
+use futures::future::{self, Either};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::{RateLimitConfig, CONFIG};
+use crate::error::{lock_recovering, AppError};
+use crate::metrics;
+
+/// Seconds of traffic a client can send or receive in one go before the
+/// bandwidth rate applies
+const BANDWIDTH_BURST_SECS: f64 = 10.0;
+
+/// How long to tell a client to wait when it has too many streams or
+/// sessions open, since there is no telling when one will close
+const BUSY_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// Clients idle for this long with nothing open are forgotten
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Look for idle clients to forget once this many are tracked
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Most clients tracked at once. When it is reached the client seen longest
+/// ago with nothing open is forgotten, and if every client has something
+/// open, new ones share one allowance.
+const MAX_CLIENTS: usize = 4096;
+
+/// Key for new clients that arrive while `MAX_CLIENTS` are busy
+const OVERFLOW_CLIENT: &str = "overflow";
+
+/// Paths that are never rate limited, so monitoring keeps working while a
+/// client is being throttled
+const EXEMPT_PATHS: [&str; 3] = ["/metrics", "/healthz", "/readyz"];
+
+/// A token bucket refilled continuously at some rate up to a capacity.
+/// Rate and capacity are passed in on each use so config changes apply at
+/// once.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new() -> Self {
+        // Clamped to the capacity on first use, so new clients start full
+        Self {
+            tokens: f64::MAX,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, rate: f64, capacity: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(capacity);
+        self.updated = now;
+    }
+
+    /// Take `cost` tokens, or say how long until there will be enough
+    fn take(&mut self, cost: f64, rate: f64, capacity: f64) -> Result<(), Duration> {
+        self.refill(rate, capacity);
+        if self.tokens >= cost {
+            self.tokens -= cost;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((cost - self.tokens) / rate))
+        }
+    }
+
+    /// Take `cost` tokens even if that leaves the bucket in debt, returning
+    /// what is left
+    pub(crate) fn charge(&mut self, cost: f64, rate: f64, capacity: f64) -> f64 {
+        self.refill(rate, capacity);
+        self.tokens -= cost;
+        self.tokens
+    }
+}
+
+/// Everything tracked about one client
+#[derive(Debug)]
+struct ClientState {
+    requests: TokenBucket,
+    /// Allowed to go negative: a transfer in progress is never cut off, the
+    /// client just has to wait for the debt to be paid back before starting
+    /// another
+    bandwidth: TokenBucket,
+    streams: usize,
+    websockets: usize,
+    last_seen: Instant,
+}
+
+impl ClientState {
+    fn new() -> Self {
+        Self {
+            requests: TokenBucket::new(),
+            bandwidth: TokenBucket::new(),
+            streams: 0,
+            websockets: 0,
+            last_seen: Instant::now(),
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref CLIENTS: Mutex<HashMap<String, ClientState>> = Mutex::new(HashMap::new());
+}
+
+/// Make room for one more client, returning false when every tracked
+/// client has something open
+fn make_room(clients: &mut HashMap<String, ClientState>) -> bool {
+    if clients.len() >= PRUNE_THRESHOLD {
+        clients.retain(|_, state| {
+            state.streams > 0 || state.websockets > 0 || state.last_seen.elapsed() < IDLE_TIMEOUT
+        });
+    }
+    if clients.len() < MAX_CLIENTS {
+        return true;
+    }
+    let oldest = clients
+        .iter()
+        .filter(|(_, state)| state.streams == 0 && state.websockets == 0)
+        .min_by_key(|(_, state)| state.last_seen)
+        .map(|(client, _)| client.clone());
+    match oldest {
+        Some(client) => {
+            clients.remove(&client);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Run `f` on the state of a client from [`client_key`], creating it again
+/// if it was forgotten since
+fn with_client<R>(client: &str, f: impl FnOnce(&mut ClientState) -> R) -> R {
+    let mut clients = lock_recovering(&CLIENTS);
+    let state = clients
+        .entry(client.to_string())
+        .or_insert_with(ClientState::new);
+    state.last_seen = Instant::now();
+    f(state)
+}
+
+fn limits() -> Result<RateLimitConfig, AppError> {
+    Ok(CONFIG.lock()?.rate_limit.clone())
+}
+
+/// Who a request counts against: the peer's IP address. Headers are up to
+/// the client, so keying on them would hand out a fresh allowance with
+/// every new value.
+pub fn client_key(req: &HttpRequest) -> String {
+    let client = match req.peer_addr() {
+        Some(addr) => format!("ip:{}", addr.ip()),
+        None => "unknown".to_string(),
+    };
+    let mut clients = lock_recovering(&CLIENTS);
+    if clients.contains_key(&client) || make_room(&mut clients) {
+        clients
+            .entry(client.clone())
+            .or_insert_with(ClientState::new);
+        client
+    } else {
+        OVERFLOW_CLIENT.to_string()
+    }
+}
+
+fn limited(limit: &str, client: &str, message: &str, retry_after: Duration) -> AppError {
+    tracing::debug!(client, limit, ?retry_after, "Rate limit hit");
+    metrics::RATE_LIMITED.with_label_values(&[limit]).inc();
+    AppError::TooManyRequests {
+        message: message.to_string(),
+        retry_after,
+    }
+}
+
+/// Take one request from the client's request budget
+fn check_request(req: &HttpRequest) -> Result<(), AppError> {
+    let limits = limits()?;
+    if limits.requests_per_minute == 0 {
+        return Ok(());
+    }
+
+    let client = client_key(req);
+    let rate = f64::from(limits.requests_per_minute) / 60.0;
+    let capacity = f64::from(limits.burst);
+    with_client(&client, |state| state.requests.take(1.0, rate, capacity))
+        .map_err(|wait| limited("requests", &client, "Too many requests, slow down", wait))
+}
+
+/// Middleware (for `App::wrap_fn`) refusing requests from clients that are
+/// over their request budget with a 429 and `Retry-After`
+pub fn limit_requests<S>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<BoxBody>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error>,
+{
+    if EXEMPT_PATHS.contains(&req.path()) {
+        return Either::Left(srv.call(req));
+    }
+    match check_request(req.request()) {
+        Ok(()) => Either::Left(srv.call(req)),
+        Err(e) => Either::Right(future::ready(Ok(req.into_response(e.error_response())))),
+    }
+}
+
+/// A client's bandwidth budget, charged as a transfer moves data
+#[derive(Debug, Clone)]
+pub struct Bandwidth {
+    client: String,
+    rate: f64,
+}
+
+impl Bandwidth {
+    /// Start a transfer for the client behind `req`, failing while it is
+    /// still paying back earlier transfers
+    pub fn start(req: &HttpRequest) -> Result<Self, AppError> {
+        let client = client_key(req);
+        let rate = limits()?.bandwidth_bytes_per_sec as f64;
+        if rate > 0.0 {
+            with_client(&client, |state| {
+                state.bandwidth.take(0.0, rate, rate * BANDWIDTH_BURST_SECS)
+            })
+            .map_err(|wait| {
+                limited("bandwidth", &client, "Bandwidth limit reached, try again later", wait)
+            })?;
+        }
+        Ok(Self { client, rate })
+    }
+
+    /// Count `bytes` as transferred
+    pub fn charge(&self, bytes: u64) {
+        if self.rate == 0.0 {
+            return;
+        }
+        with_client(&self.client, |state| {
+            state
+                .bandwidth
+                .charge(bytes as f64, self.rate, self.rate * BANDWIDTH_BURST_SECS)
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotKind {
+    /// Downloads and audio streams
+    Stream,
+    WebSocket,
+}
+
+/// One of a client's streams or WebSocket sessions, given back on drop
+#[derive(Debug)]
+pub struct ClientSlot {
+    client: String,
+    kind: SlotKind,
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        with_client(&self.client, |state| match self.kind {
+            SlotKind::Stream => state.streams = state.streams.saturating_sub(1),
+            SlotKind::WebSocket => state.websockets = state.websockets.saturating_sub(1),
+        });
+    }
+}
+
+/// Take one of the streams or WebSocket sessions the client behind `req` may
+/// have open at once
+pub fn acquire(req: &HttpRequest, kind: SlotKind) -> Result<ClientSlot, AppError> {
+    let limits = limits()?;
+    let client = client_key(req);
+    let acquired = with_client(&client, |state| {
+        let (open, limit) = match kind {
+            SlotKind::Stream => (&mut state.streams, limits.max_streams),
+            SlotKind::WebSocket => (&mut state.websockets, limits.max_websockets),
+        };
+        if limit > 0 && *open >= limit {
+            return false;
+        }
+        *open += 1;
+        true
+    });
+
+    if !acquired {
+        return Err(match kind {
+            SlotKind::Stream => limited(
+                "streams",
+                &client,
+                "Too many downloads or streams open from this device",
+                BUSY_RETRY_AFTER,
+            ),
+            SlotKind::WebSocket => limited(
+                "websockets",
+                &client,
+                "Too many connections open from this device",
+                BUSY_RETRY_AFTER,
+            ),
+        });
+    }
+    Ok(ClientSlot { client, kind })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_new_bucket_starts_full() {
+        let mut bucket = TokenBucket::new();
+        for _ in 0..3 {
+            assert!(bucket.take(1.0, 1.0, 3.0).is_ok());
+        }
+        let wait = bucket.take(1.0, 1.0, 3.0).unwrap_err();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1), "{:?}", wait);
+    }
+
+    #[test]
+    fn tokens_come_back_at_the_rate_up_to_the_capacity() {
+        let mut bucket = TokenBucket::new();
+        bucket.take(4.0, 2.0, 4.0).unwrap();
+
+        bucket.updated -= Duration::from_secs(1);
+        assert!(bucket.take(2.0, 2.0, 4.0).is_ok());
+        assert!(bucket.take(1.0, 2.0, 4.0).is_err());
+
+        bucket.updated -= Duration::from_secs(60);
+        assert!(bucket.take(4.0, 2.0, 4.0).is_ok());
+        assert!(bucket.take(1.0, 2.0, 4.0).is_err());
+    }
+
+    #[test]
+    fn charging_can_go_into_debt() {
+        let mut bucket = TokenBucket::new();
+        let left = bucket.charge(150.0, 10.0, 100.0);
+        assert!((-50.1..=-49.9).contains(&left), "{}", left);
+
+        // The debt is paid back before anything can be taken
+        let wait = bucket.take(10.0, 10.0, 100.0).unwrap_err();
+        assert!(wait > Duration::from_millis(5900) && wait <= Duration::from_secs(6), "{:?}", wait);
+    }
+}
@@ -1,73 +1,504 @@
-use actix_web::{delete, get, post, web, HttpResponse, Scope};
+use actix_web::{delete, get, patch, post, put, web, HttpRequest, HttpResponse};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::{OpenApi, ToSchema};
 
-use crate::config::{add_audio_folder, load_config, remove_audio_folder};
+use crate::config::{
+    add_audio_folder, current_config, get_config_path, patch_config, remove_audio_folder,
+    update_config, Config,
+};
+use crate::error::{AppError, ErrorResponse};
+use crate::routes::{require_host, ws};
+use crate::services::devices::{self, Device, TrustLevel};
+use crate::services::files;
+use crate::services::e2e;
+use crate::services::events::{self, ConfigEvent};
+use crate::services::federation::{self, Peer};
+use crate::services::quota::{self, UploadedFile};
+use crate::services::transfers::{self, Decision, PendingTransfer};
+use crate::services::webhooks;
 use crate::templates;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 struct DirsResponse {
     dirs: Vec<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = SuccessResponse)]
 struct StatusResponse {
+    #[schema(example = "success")]
     status: String,
-    error: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+impl StatusResponse {
+    fn success() -> HttpResponse {
+        HttpResponse::Ok().json(StatusResponse {
+            status: "success".to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct DecisionRequest {
+    decision: Decision,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct TrustRequest {
+    trust: TrustLevel,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct RenameRequest {
+    /// New name for the file
+    name: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct RenameResponse {
+    /// The new name, as stored
+    name: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct WebhookTestResponse {
+    /// The HTTP status the hook answered with
+    status: u16,
+    /// Whether that status counts as delivered (2xx)
+    delivered: bool,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
 struct DirRequest {
     dir: String,
 }
 
-// Create admin scope
-pub fn admin_scope() -> Scope {
-    web::scope("/admin")
-        .service(admin_panel)
-        .service(get_dirs)
+#[derive(OpenApi)]
+#[openapi(paths(
+    get_dirs,
+    add_dir,
+    remove_dir,
+    get_config,
+    put_config,
+    patch_config_route,
+    list_transfers,
+    decide_transfer,
+    list_uploads,
+    pin_upload,
+    unpin_upload,
+    delete_upload,
+    rename_upload,
+    set_device_trust,
+    forget_device,
+    list_peers,
+    set_peer_trust,
+    forget_peer,
+    test_webhook
+))]
+pub struct ApiDoc;
+
+// Register the admin panel page and its WebSocket
+pub fn configure_ui(cfg: &mut web::ServiceConfig) {
+    cfg.service(admin_panel)
+        .route("/ws", web::get().to(ws::admin_ws));
+}
+
+// Register admin API routes, under both `/api/v1/admin` and the legacy `/admin`
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_dirs)
         .service(add_dir)
         .service(remove_dir)
+        .service(get_config)
+        .service(put_config)
+        .service(patch_config_route)
+        .service(list_transfers)
+        .service(decide_transfer)
+        .service(list_uploads)
+        .service(pin_upload)
+        .service(unpin_upload)
+        .service(delete_upload)
+        .service(rename_upload)
+        .service(set_device_trust)
+        .service(forget_device)
+        .service(list_peers)
+        .service(set_peer_trust)
+        .service(forget_peer)
+        .service(test_webhook);
 }
 
 #[get("/")]
-async fn admin_panel() -> HttpResponse {
-    let template = templates::AdminTemplate {};
+async fn admin_panel() -> Result<HttpResponse, AppError> {
+    let template = templates::AdminTemplate {
+        config_path: get_config_path().display().to_string(),
+    };
     templates::render_template(&template)
 }
 
+/// List the configured audio folders
+#[utoipa::path(
+    tag = "admin",
+    responses((status = 200, body = DirsResponse), (status = 500, body = ErrorResponse))
+)]
 #[get("/dirs")]
-async fn get_dirs() -> HttpResponse {
-    let config = load_config();
-    
-    HttpResponse::Ok().json(DirsResponse {
+async fn get_dirs() -> Result<HttpResponse, AppError> {
+    let config = current_config()?;
+
+    Ok(HttpResponse::Ok().json(DirsResponse {
         dirs: config.audio_folders,
-    })
+    }))
 }
 
+/// Add an audio folder
+#[utoipa::path(
+    tag = "admin",
+    request_body = DirRequest,
+    responses(
+        (status = 200, body = StatusResponse),
+        (status = 422, description = "The folder is unusable", body = ErrorResponse),
+    )
+)]
 #[post("/dirs")]
-async fn add_dir(req: web::Json<DirRequest>) -> HttpResponse {
-    match add_audio_folder(req.dir.clone()) {
-        Ok(_) => HttpResponse::Ok().json(StatusResponse {
-            status: "success".to_string(),
-            error: None,
-        }),
-        Err(e) => HttpResponse::BadRequest().json(StatusResponse {
-            status: "error".to_string(),
-            error: Some(e),
-        }),
-    }
+async fn add_dir(req: web::Json<DirRequest>) -> Result<HttpResponse, AppError> {
+    let dir = req.into_inner().dir;
+    web::block(move || change_config(|| add_audio_folder(dir))).await??;
+    Ok(StatusResponse::success())
 }
 
+/// Remove an audio folder
+#[utoipa::path(
+    tag = "admin",
+    request_body = DirRequest,
+    responses(
+        (status = 200, body = StatusResponse),
+        (status = 422, body = ErrorResponse),
+    )
+)]
 #[delete("/dirs")]
-async fn remove_dir(req: web::Json<DirRequest>) -> HttpResponse {
-    match remove_audio_folder(&req.dir) {
-        Ok(_) => HttpResponse::Ok().json(StatusResponse {
-            status: "success".to_string(),
-            error: None,
-        }),
-        Err(e) => HttpResponse::BadRequest().json(StatusResponse {
-            status: "error".to_string(),
-            error: Some(e),
-        }),
+async fn remove_dir(req: web::Json<DirRequest>) -> Result<HttpResponse, AppError> {
+    let dir = req.into_inner().dir;
+    web::block(move || change_config(|| remove_audio_folder(&dir))).await??;
+    Ok(StatusResponse::success())
+}
+
+/// Get the current config, with its secrets redacted
+#[utoipa::path(
+    tag = "admin",
+    responses((status = 200, body = Config), (status = 500, body = ErrorResponse))
+)]
+#[get("/config")]
+async fn get_config() -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok().json(current_config()?.redacted()))
+}
+
+/// Replace the config. Secrets sent back redacted keep their stored values.
+#[utoipa::path(
+    tag = "admin",
+    request_body = Config,
+    responses(
+        (status = 200, description = "The saved config, with its secrets redacted", body = Config),
+        (status = 422, description = "The config is invalid", body = ErrorResponse),
+    )
+)]
+#[put("/config")]
+async fn put_config(req: web::Json<Config>) -> Result<HttpResponse, AppError> {
+    let config = web::block(move || change_config(|| update_config(req.into_inner()))).await??;
+    Ok(HttpResponse::Ok().json(config.redacted()))
+}
+
+/// Update part of the config with a JSON merge patch (RFC 7386)
+#[utoipa::path(
+    tag = "admin",
+    request_body(content = Object, description = "Fields to change; `null` resets a field"),
+    responses(
+        (status = 200, description = "The saved config, with its secrets redacted", body = Config),
+        (status = 422, description = "The resulting config is invalid", body = ErrorResponse),
+    )
+)]
+#[patch("/config")]
+async fn patch_config_route(req: web::Json<Value>) -> Result<HttpResponse, AppError> {
+    let config = web::block(move || change_config(|| patch_config(req.into_inner()))).await??;
+    Ok(HttpResponse::Ok().json(config.redacted()))
+}
+
+/// Make a config change, then publish which top-level fields it touched
+fn change_config<T>(change: impl FnOnce() -> Result<T, AppError>) -> Result<T, AppError> {
+    let before = serde_json::to_value(current_config()?).unwrap_or_default();
+    let result = change()?;
+    let after = serde_json::to_value(current_config()?).unwrap_or_default();
+    if let (Value::Object(before), Value::Object(after)) = (before, after) {
+        let fields: Vec<String> = after
+            .into_iter()
+            .filter(|(field, value)| before.get(field) != Some(value))
+            .map(|(field, _)| field)
+            .collect();
+        if !fields.is_empty() {
+            events::publish(ConfigEvent::Changed { fields });
+        }
     }
+    Ok(result)
+}
+
+/// List pushes waiting for approval
+#[utoipa::path(
+    tag = "admin",
+    responses(
+        (status = 200, body = Vec<PendingTransfer>),
+        (status = 500, body = ErrorResponse),
+    )
+)]
+#[get("/transfers")]
+async fn list_transfers() -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok().json(transfers::pending_transfers()?))
+}
+
+/// Accept, reject or trust the device behind a pending push
+#[utoipa::path(
+    tag = "admin",
+    params(("id" = u64, Path, description = "Transfer ID")),
+    request_body = DecisionRequest,
+    responses(
+        (status = 200, body = StatusResponse),
+        (status = 400, description = "Anonymous devices can't be trusted", body = ErrorResponse),
+        (status = 403, description = "Not sent from the host", body = ErrorResponse),
+        (status = 404, description = "No such pending transfer", body = ErrorResponse),
+    )
+)]
+#[post("/transfers/{id}")]
+async fn decide_transfer(
+    http_req: HttpRequest,
+    id: web::Path<u64>,
+    req: web::Json<DecisionRequest>,
+) -> Result<HttpResponse, AppError> {
+    require_host(&http_req)?;
+    // Trusting a device writes the device registry
+    let (id, decision) = (id.into_inner(), req.decision);
+    web::block(move || transfers::decide(id, decision)).await??;
+    Ok(StatusResponse::success())
+}
+
+/// List the files in the upload folder with their owners and pins
+#[utoipa::path(
+    tag = "admin",
+    responses(
+        (status = 200, body = Vec<UploadedFile>),
+        (status = 500, body = ErrorResponse),
+    )
+)]
+#[get("/uploads")]
+async fn list_uploads() -> Result<HttpResponse, AppError> {
+    let files = quota::uploaded_files().await?;
+    Ok(HttpResponse::Ok().json(files))
+}
+
+/// Protect an uploaded file from eviction
+#[utoipa::path(
+    tag = "admin",
+    params(("filename" = String, Path, description = "Name of the file")),
+    responses(
+        (status = 200, body = StatusResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+#[put("/uploads/{filename}/pin")]
+async fn pin_upload(filename: web::Path<String>) -> Result<HttpResponse, AppError> {
+    set_pinned(filename.into_inner(), true).await
+}
+
+/// Let an uploaded file be evicted again
+#[utoipa::path(
+    tag = "admin",
+    params(("filename" = String, Path, description = "Name of the file")),
+    responses(
+        (status = 200, body = StatusResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+#[delete("/uploads/{filename}/pin")]
+async fn unpin_upload(filename: web::Path<String>) -> Result<HttpResponse, AppError> {
+    set_pinned(filename.into_inner(), false).await
+}
+
+/// Delete a file from the upload folder
+#[utoipa::path(
+    tag = "admin",
+    params(("filename" = String, Path, description = "Name of the file")),
+    responses(
+        (status = 200, body = StatusResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+#[delete("/uploads/{filename}")]
+async fn delete_upload(filename: web::Path<String>) -> Result<HttpResponse, AppError> {
+    files::delete_file(&filename).await?;
+    Ok(StatusResponse::success())
+}
+
+/// Rename a file in the upload folder. Another file is never replaced.
+#[utoipa::path(
+    tag = "admin",
+    params(("filename" = String, Path, description = "Name of the file")),
+    request_body = RenameRequest,
+    responses(
+        (status = 200, body = RenameResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 409, description = "A file with the new name already exists", body = ErrorResponse),
+    )
+)]
+#[post("/uploads/{filename}/rename")]
+async fn rename_upload(
+    filename: web::Path<String>,
+    req: web::Json<RenameRequest>,
+) -> Result<HttpResponse, AppError> {
+    let new_name = req.into_inner().name;
+    let name = files::rename_file(&filename, &new_name).await?;
+    Ok(HttpResponse::Ok().json(RenameResponse { name }))
+}
+
+async fn set_pinned(filename: String, pinned: bool) -> Result<HttpResponse, AppError> {
+    let name = sanitize_filename::sanitize(filename);
+    quota::set_pinned(name, pinned).await?;
+    Ok(StatusResponse::success())
+}
+
+/// Set how far a device is trusted. Blocking a device also revokes its
+/// encryption keys.
+#[utoipa::path(
+    tag = "admin",
+    params(("id" = String, Path, description = "Device ID")),
+    request_body = TrustRequest,
+    responses(
+        (status = 200, description = "The updated device", body = Device),
+        (status = 403, description = "Not sent from the host", body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+#[put("/devices/{id}/trust")]
+async fn set_device_trust(
+    http_req: HttpRequest,
+    id: web::Path<String>,
+    req: web::Json<TrustRequest>,
+) -> Result<HttpResponse, AppError> {
+    require_host(&http_req)?;
+    let (id, trust) = (id.into_inner(), req.trust);
+    let device = web::block(move || {
+        let device = devices::set_trust(&id, trust)?;
+        if trust == TrustLevel::Blocked {
+            e2e::revoke(&id)?;
+        }
+        Ok::<_, AppError>(device)
+    })
+    .await??;
+    Ok(HttpResponse::Ok().json(device))
+}
+
+/// Forget a device and revoke its encryption keys. It is registered again,
+/// untrusted, if it comes back.
+#[utoipa::path(
+    tag = "admin",
+    params(("id" = String, Path, description = "Device ID")),
+    responses(
+        (status = 200, body = StatusResponse),
+        (status = 403, description = "Not sent from the host", body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+#[delete("/devices/{id}")]
+async fn forget_device(
+    http_req: HttpRequest,
+    id: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    require_host(&http_req)?;
+    let id = id.into_inner();
+    web::block(move || {
+        devices::remove(&id)?;
+        e2e::revoke(&id)
+    })
+    .await??;
+    Ok(StatusResponse::success())
+}
+
+/// List every server this one knows about, trusted or not
+#[utoipa::path(
+    tag = "admin",
+    responses(
+        (status = 200, body = Vec<Peer>),
+        (status = 500, body = ErrorResponse),
+    )
+)]
+#[get("/peers")]
+async fn list_peers() -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok().json(web::block(federation::list).await??))
+}
+
+/// Set how far another server is trusted. Only trusted servers are shown to
+/// users, can read this server's files and clipboard, or merge clipboards.
+#[utoipa::path(
+    tag = "admin",
+    params(("id" = String, Path, description = "Server ID")),
+    request_body = TrustRequest,
+    responses(
+        (status = 200, description = "The updated server", body = Peer),
+        (status = 403, description = "Not sent from the host", body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+#[put("/peers/{id}/trust")]
+async fn set_peer_trust(
+    http_req: HttpRequest,
+    id: web::Path<String>,
+    req: web::Json<TrustRequest>,
+) -> Result<HttpResponse, AppError> {
+    require_host(&http_req)?;
+    let (id, trust) = (id.into_inner(), req.trust);
+    let peer = web::block(move || federation::set_trust(&id, trust)).await??;
+    Ok(HttpResponse::Ok().json(peer))
+}
+
+/// Forget another server and the secret shared with it. It can link up
+/// again, untrusted.
+#[utoipa::path(
+    tag = "admin",
+    params(("id" = String, Path, description = "Server ID")),
+    responses(
+        (status = 200, body = StatusResponse),
+        (status = 403, description = "Not sent from the host", body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+#[delete("/peers/{id}")]
+async fn forget_peer(
+    http_req: HttpRequest,
+    id: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    require_host(&http_req)?;
+    let id = id.into_inner();
+    web::block(move || federation::remove(&id)).await??;
+    Ok(StatusResponse::success())
+}
+
+/// Send a `ping` event to a configured webhook once, without retrying
+#[utoipa::path(
+    tag = "admin",
+    params(("index" = usize, Path, description = "Position of the hook in `webhooks.hooks`")),
+    responses(
+        (status = 200, description = "The hook answered", body = WebhookTestResponse),
+        (status = 404, description = "No hook at that position", body = ErrorResponse),
+        (status = 502, description = "The hook couldn't be reached", body = ErrorResponse),
+    )
+)]
+#[post("/webhooks/{index}/test")]
+async fn test_webhook(index: web::Path<usize>) -> Result<HttpResponse, AppError> {
+    let hook = current_config()?
+        .webhooks
+        .hooks
+        .get(index.into_inner())
+        .cloned()
+        .ok_or_else(|| AppError::NotFound("No webhook at that position".to_string()))?;
+    let status = webhooks::ping(&hook).await.map_err(AppError::BadGateway)?;
+    Ok(HttpResponse::Ok().json(WebhookTestResponse {
+        status,
+        delivered: (200..300).contains(&status),
+    }))
 }
@@ -1,188 +1,568 @@
-use actix_files::NamedFile;
-use actix_multipart::{Field, Multipart, MultipartError};
-use actix_web::{get, post, web, Error, HttpRequest, HttpResponse, Result, Scope, ResponseError};
+use actix_files::HttpRange;
+use actix_multipart::{Field, Multipart};
+use actix_web::body::{BoxBody, SizedStream};
+use actix_web::http::header::{
+    ContentDisposition, DispositionParam, DispositionType, HttpDate, ACCEPT_RANGES,
+    CONTENT_RANGE, CONTENT_TYPE, LAST_MODIFIED, RANGE,
+};
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
 use arboard::Clipboard;
 use futures::{StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use std::{fs, io::Write, path::Path, sync::Mutex, fmt};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{path::Path, sync::Mutex};
+use utoipa::{IntoParams, OpenApi, ToSchema};
 
-use crate::config::ensure_upload_folder;
+use crate::config::{current_config, ensure_download_folder};
+use crate::error::{AppError, ErrorResponse};
+use crate::metrics::{self, MeteredBody};
+use crate::rate_limit::{self, Bandwidth, SlotKind};
+use crate::routes::{current_device, device_headers};
+use crate::services::devices;
+use crate::services::events::{self, ClipboardEvent};
+use crate::services::federation;
+use crate::services::files::{self, FileEvent, Progress};
+use crate::services::limits::{self, TransferKind};
+use crate::services::quota::{self, Destination, UploadBudget};
+use crate::services::transfers::{self, Outcome};
+use crate::shutdown;
+use crate::storage::{self, local, ByteStream};
+use crate::throttle::{Throttle, ThrottleClass, ThrottledBody};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 struct ClipboardRequest {
     text: String,
+    /// Whether `text` is ciphertext from a paired device
+    #[serde(default)]
+    encrypted: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
+struct ClipboardResponse {
+    text: String,
+    encrypted: bool,
+    /// The device that set it, when known
+    device_id: Option<String>,
+    device_name: Option<String>,
+    /// The server it was merged from, when another one set it
+    peer_name: Option<String>,
+    /// When it was set, in seconds since the Unix epoch
+    updated_at: Option<u64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 struct FileListResponse {
     files: Vec<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 struct StatusResponse {
+    #[schema(example = "success")]
     status: String,
+    /// Name the file was saved under, for uploads and pushes
     filename: Option<String>,
-    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct UploadQuery {
+    /// Size of the file in bytes, so size limits can be checked before the
+    /// upload starts. Pushes also show it when asking for approval.
+    pub size: Option<u64>,
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(get_clipboard, post_clipboard, list_files, upload_file, download_file, push_file))]
+pub struct ApiDoc;
+
+/// The shared clipboard and who last set it
+#[derive(Debug, Clone, Default)]
+pub struct ClipboardEntry {
+    text: String,
+    encrypted: bool,
+    device_id: Option<String>,
+    peer_name: Option<String>,
+    updated_at: Option<u64>,
 }
 
 // Static clipboard storage
-pub type ClipboardData = Mutex<String>;
+pub type ClipboardData = Mutex<ClipboardEntry>;
 
-// Create API scope
-pub fn api_scope() -> Scope {
-    web::scope("/api")
-        .service(get_clipboard)
+// Register API routes, under both `/api/v1` and the legacy `/api`
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_clipboard)
         .service(post_clipboard)
         .service(list_files)
         .service(upload_file)
         .service(download_file)
+        .service(push_file);
 }
 
+/// Get the shared clipboard
+#[utoipa::path(
+    tag = "clipboard",
+    responses(
+        (status = 200, body = ClipboardResponse),
+        (status = 500, body = ErrorResponse),
+    )
+)]
 #[get("/clipboard")]
-async fn get_clipboard(clipboard_data: web::Data<ClipboardData>) -> Result<HttpResponse> {
-    let text = clipboard_data.lock().unwrap().clone();
+async fn get_clipboard(clipboard_data: web::Data<ClipboardData>) -> Result<HttpResponse, AppError> {
+    let entry = clipboard_data.lock()?.clone();
+    let device_name = entry.device_id.as_deref().and_then(devices::name_of);
     
-    Ok(HttpResponse::Ok().json(ClipboardRequest { text }))
+    Ok(HttpResponse::Ok().json(ClipboardResponse {
+        text: entry.text,
+        encrypted: entry.encrypted,
+        device_id: entry.device_id,
+        device_name,
+        peer_name: entry.peer_name,
+        updated_at: entry.updated_at,
+    }))
 }
 
+/// Replace the shared clipboard, and the host's system clipboard unless the
+/// text is encrypted
+pub(crate) async fn set_clipboard(
+    clipboard_data: &ClipboardData,
+    text: String,
+    encrypted: bool,
+    device_id: Option<String>,
+    peer_name: Option<String>,
+) -> Result<(), AppError> {
+    *clipboard_data.lock()? = ClipboardEntry {
+        text: text.clone(),
+        encrypted,
+        device_id: device_id.clone(),
+        peer_name: peer_name.clone(),
+        updated_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs()),
+    };
+    events::publish(ClipboardEvent::Updated {
+        text: text.clone(),
+        encrypted,
+        device_id,
+        peer_name,
+    });
+    
+    // Try to update system clipboard if available, unless the host can't
+    // read it anyway. This can block on the display server, so keep it off
+    // the worker thread.
+    if !encrypted {
+        web::block(move || match Clipboard::new() {
+            Ok(mut clipboard) => {
+                if let Err(e) = clipboard.set_text(text) {
+                    tracing::warn!("Failed to update system clipboard: {}", e);
+                }
+            }
+            Err(e) => {
+                tracing::warn!("Failed to access system clipboard: {}", e);
+            }
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+/// Replace the shared clipboard, and the host's system clipboard when available.
+/// Encrypted text is only stored and passed on; plain text is also passed to
+/// servers that merge clipboards with this one.
+#[utoipa::path(
+    tag = "clipboard",
+    params(
+        ("X-Device-Id" = Option<String>, Header, description = "Stable ID of the device setting it"),
+        ("X-Device-Token" = Option<String>, Header, description = "Token the device was given when it registered"),
+    ),
+    request_body = ClipboardRequest,
+    responses(
+        (status = 200, body = StatusResponse),
+        (status = 400, description = "The text isn't encrypted while end-to-end encryption is on", body = ErrorResponse),
+        (status = 500, body = ErrorResponse),
+    )
+)]
 #[post("/clipboard")]
 async fn post_clipboard(
+    http_req: HttpRequest,
     clipboard_data: web::Data<ClipboardData>,
     req: web::Json<ClipboardRequest>,
-) -> Result<HttpResponse> {
-    let text = req.text.clone();
-    
-    // Update in-memory clipboard
-    {
-        let mut clipboard = clipboard_data.lock().unwrap();
-        *clipboard = text.clone();
+) -> Result<HttpResponse, AppError> {
+    let device_id = current_device(&http_req);
+    if current_config()?.e2e.enabled && !req.encrypted {
+        return Err(AppError::BadRequest(
+            "End-to-end encryption is on, so clipboard items must be encrypted".to_string(),
+        ));
     }
-    
-    // Try to update system clipboard if available
-    match Clipboard::new() {
-        Ok(mut clipboard) => {
-            if let Err(e) = clipboard.set_text(text) {
-                eprintln!("Failed to update system clipboard: {}", e);
-            }
-        }
-        Err(e) => {
-            eprintln!("Failed to access system clipboard: {}", e);
-        }
+    let ClipboardRequest { text, encrypted } = req.into_inner();
+    if !encrypted {
+        federation::share_clipboard(&text);
     }
+    set_clipboard(&clipboard_data, text, encrypted, device_id, None).await?;
     
     Ok(HttpResponse::Ok().json(StatusResponse {
         status: "success".to_string(),
         filename: None,
-        error: None,
     }))
 }
 
+/// List the files in the upload folder
+#[utoipa::path(
+    tag = "files",
+    responses(
+        (status = 200, body = FileListResponse),
+        (status = 500, body = ErrorResponse),
+    )
+)]
 #[get("/files")]
-async fn list_files() -> Result<HttpResponse> {
-    let upload_path = ensure_upload_folder();
-    
-    let mut files = Vec::new();
-    
-    match fs::read_dir(upload_path) {
-        Ok(entries) => {
-            for entry in entries {
-                if let Ok(entry) = entry {
-                    if let Ok(file_type) = entry.file_type() {
-                        if file_type.is_file() {
-                            if let Some(file_name) = entry.file_name().to_str() {
-                                files.push(file_name.to_string());
-                            }
-                        }
-                    }
-                }
-            }
-        }
-        Err(e) => {
-            eprintln!("Failed to read upload directory: {}", e);
-        }
-    }
+async fn list_files() -> Result<HttpResponse, AppError> {
+    let files = files::list_files().await?;
     
     Ok(HttpResponse::Ok().json(FileListResponse { files }))
 }
 
+/// Upload a file to the upload folder, replacing any file with the same name
+#[utoipa::path(
+    tag = "files",
+    params(
+        UploadQuery,
+        ("X-Device-Id" = Option<String>, Header, description = "Stable ID of the uploading device, for per-device quotas"),
+        ("X-Device-Token" = Option<String>, Header, description = "Token the device was given when it registered"),
+    ),
+    request_body(content_type = "multipart/form-data", description = "A single file field"),
+    responses(
+        (status = 200, body = StatusResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 413, description = "The file is over a size limit or quota", body = ErrorResponse),
+        (status = 429, description = "This device is over its bandwidth budget", body = ErrorResponse),
+        (status = 503, description = "The server is shutting down or busy", body = ErrorResponse),
+        (status = 507, description = "Not enough free disk space", body = ErrorResponse),
+    )
+)]
 #[post("/files")]
-async fn upload_file(mut payload: Multipart) -> Result<HttpResponse, Error> {
-    let upload_path = ensure_upload_folder();
-    
-    // Process multipart upload
-    while let Some(field) = payload.try_next().await? {
-        let content_disposition = field.content_disposition();
-        let filename = content_disposition.get_filename().unwrap_or("unnamed_file");
-        let sanitized_filename = sanitize_filename::sanitize(filename);
-        
-        let file_path = upload_path.join(&sanitized_filename);
-        
-        // Save file
-        if let Err(e) = save_file(field, file_path).await {
-            return Ok(HttpResponse::InternalServerError().json(StatusResponse {
-                status: "error".to_string(),
-                filename: Some(sanitized_filename),
-                error: Some(format!("Failed to save file: {}", e)),
-            }));
-        }
-        
-        return Ok(HttpResponse::Ok().json(StatusResponse {
-            status: "success".to_string(),
-            filename: Some(sanitized_filename),
-            error: None,
-        }));
+async fn upload_file(
+    req: HttpRequest,
+    query: web::Query<UploadQuery>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, AppError> {
+    reject_if_shutting_down()?;
+    let bandwidth = Bandwidth::start(&req)?;
+    let _slot = limits::acquire(TransferKind::Upload)?;
+    let device_id = current_device(&req);
+
+    let Some(field) = payload.try_next().await? else {
+        return Err(AppError::BadRequest("No file provided".to_string()));
+    };
+
+    let content_disposition = field.content_disposition();
+    let filename = content_disposition.get_filename().unwrap_or("unnamed_file");
+    let sanitized_filename = sanitize_filename::sanitize(filename);
+
+    let storage = storage::current()?;
+    if storage.stat(&sanitized_filename).await?.is_some() {
+        tracing::warn!(name = %sanitized_filename, "Upload replaces an existing file");
     }
+
+    let budget = UploadBudget::upload(&sanitized_filename, device_id.as_deref(), query.size).await?;
+
+    // Save file
+    let progress = Progress::upload(&sanitized_filename, device_id.as_deref(), query.size);
+    let data = metered_upload(field, budget, bandwidth, Some(progress));
+    let bytes = storage.put(&sanitized_filename, data).await.map_err(|e| {
+        tracing::warn!(name = %sanitized_filename, "Failed to save upload: {}", e);
+        e
+    })?;
+    tracing::info!(name = %sanitized_filename, bytes, "Saved upload");
+
+    let name = sanitized_filename.clone();
+    let owner = device_id.clone();
+    web::block(move || quota::record_upload(&name, owner.as_deref())).await??;
+    files::notify(FileEvent::Added {
+        name: sanitized_filename.clone(),
+        size: bytes,
+        device_id,
+    });
     
-    Ok(HttpResponse::BadRequest().json(StatusResponse {
-        status: "error".to_string(),
-        filename: None,
-        error: Some("No file provided".to_string()),
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        status: "success".to_string(),
+        filename: Some(sanitized_filename),
     }))
 }
 
+/// Download a file from the upload folder. A `Range` header gets just that
+/// part of it.
+#[utoipa::path(
+    tag = "files",
+    params(("filename" = String, Path, description = "Name of the file")),
+    responses(
+        (status = 200, description = "The file contents", content_type = "application/octet-stream"),
+        (status = 206, description = "The requested part of the file", content_type = "application/octet-stream"),
+        (status = 404, body = ErrorResponse),
+        (status = 416, description = "The range is outside the file"),
+        (status = 429, description = "This device has too many downloads open or is over its bandwidth budget", body = ErrorResponse),
+        (status = 502, description = "The storage backend failed", body = ErrorResponse),
+        (status = 503, description = "Too many downloads in progress", body = ErrorResponse),
+    )
+)]
 #[get("/files/{filename}")]
-async fn download_file(req: HttpRequest, filename: web::Path<String>) -> Result<HttpResponse, Error> {
+async fn download_file(req: HttpRequest, filename: web::Path<String>) -> Result<HttpResponse, AppError> {
     let sanitized_filename = sanitize_filename::sanitize(filename.as_str());
-    let file_path = ensure_upload_folder().join(&sanitized_filename);
-    
-    match NamedFile::open(&file_path) {
-        Ok(file) => {
-            let file_name = file_path.file_name()
-                .unwrap_or_default()
-                .to_string_lossy()
-                .to_string();
-            
-            let disposition = actix_web::http::header::ContentDisposition {
-                disposition: actix_web::http::header::DispositionType::Attachment,
-                parameters: vec![
-                    actix_web::http::header::DispositionParam::Filename(file_name)
-                ],
-            };
-            
-            Ok(file
-                .use_last_modified(true)
-                .set_content_disposition(disposition)
-                .into_response(&req))
+    let storage = storage::current()?;
+    let Some(file) = storage.stat(&sanitized_filename).await? else {
+        tracing::warn!(name = %sanitized_filename, "Download not found");
+        return Err(AppError::NotFound("File not found".to_string()));
+    };
+
+    // Like browsers and download managers expect, only the first of several
+    // ranges is sent
+    let range = match req.headers().get(RANGE).and_then(|v| v.to_str().ok()) {
+        Some(header) => match HttpRange::parse(header, file.size) {
+            Ok(ranges) => ranges.first().map(|r| (r.start, r.length)),
+            Err(_) => {
+                return Ok(HttpResponse::RangeNotSatisfiable()
+                    .insert_header((CONTENT_RANGE, format!("bytes */{}", file.size)))
+                    .finish());
+            }
         },
-        Err(_) => {
-            Ok(HttpResponse::NotFound().json(StatusResponse {
-                status: "error".to_string(),
-                filename: None,
-                error: Some("File not found".to_string()),
-            }))
-        }
+        None => None,
+    };
+    let (offset, len) = range.unwrap_or((0, file.size));
+
+    // Held until the whole body has been sent
+    let client_slot = rate_limit::acquire(&req, SlotKind::Stream)?;
+    let bandwidth = Bandwidth::start(&req)?;
+    let slot = limits::acquire(TransferKind::Download)?;
+    let device_id = current_device(&req);
+    let data = storage.get(&sanitized_filename, offset, Some(len)).await?;
+    let progress = Progress::download(&sanitized_filename, device_id.as_deref(), Some(file.size));
+
+    let mime = Path::new(&sanitized_filename)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map_or("application/octet-stream".to_string(), |e| {
+            actix_files::file_extension_to_mime(e).to_string()
+        });
+    let modified = UNIX_EPOCH + Duration::from_secs(file.modified);
+    let mut response = HttpResponse::build(match range {
+        Some(_) => StatusCode::PARTIAL_CONTENT,
+        None => StatusCode::OK,
+    });
+    response
+        .insert_header((CONTENT_TYPE, mime))
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(sanitized_filename.clone())],
+        })
+        .insert_header((ACCEPT_RANGES, "bytes"))
+        .insert_header((LAST_MODIFIED, HttpDate::from(modified)));
+    if range.is_some() {
+        response.insert_header((
+            CONTENT_RANGE,
+            format!("bytes {}-{}/{}", offset, offset + len - 1, file.size),
+        ));
     }
+
+    tracing::info!(name = %sanitized_filename, offset, bytes = len, "Serving download");
+    let body = BoxBody::new(SizedStream::new(len, data));
+    Ok(response.body(
+        MeteredBody::new(
+            BoxBody::new(ThrottledBody::new(body, ThrottleClass::Bulk)),
+            &metrics::DOWNLOADED_BYTES,
+            None,
+        )
+        .on_chunk(move |len| bandwidth.charge(len))
+        .on_chunk(move |len| progress.advance(len))
+        .hold(slot)
+        .hold(client_slot),
+    ))
 }
 
-async fn save_file(mut field: Field, file_path: impl AsRef<Path>) -> std::io::Result<()> {
-    let mut file = fs::File::create(file_path)?;
-    
-    while let Some(chunk_result) = field.next().await {
-        match chunk_result {
-            Ok(data) => file.write_all(&data)?,
-            Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())),
+/// Push a file straight to the host's download folder, once someone on the
+/// host accepts it or the sending device is trusted
+#[utoipa::path(
+    tag = "files",
+    params(
+        UploadQuery,
+        ("X-Device-Id" = Option<String>, Header, description = "Stable ID of the sending device"),
+        ("X-Device-Token" = Option<String>, Header, description = "Token the device was given when it registered; without it the push is always prompted for"),
+        ("X-Device-Name" = Option<String>, Header, description = "Name shown when asking for approval, for devices that aren't registered"),
+    ),
+    request_body(content_type = "multipart/form-data", description = "A single file field"),
+    responses(
+        (status = 200, body = StatusResponse),
+        (status = 403, description = "The transfer was rejected", body = ErrorResponse),
+        (status = 408, description = "Nobody answered in time", body = ErrorResponse),
+        (status = 413, description = "The file is over the size limit", body = ErrorResponse),
+        (status = 429, description = "This device is over its bandwidth budget", body = ErrorResponse),
+        (status = 503, description = "The server is shutting down or busy", body = ErrorResponse),
+        (status = 507, description = "Not enough free disk space", body = ErrorResponse),
+    )
+)]
+#[post("/push")]
+async fn push_file(
+    req: HttpRequest,
+    query: web::Query<UploadQuery>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, AppError> {
+    reject_if_shutting_down()?;
+
+    // Only a device that proved who it is can skip the prompt, and only its
+    // registered name is shown as its own
+    let device_id = current_device(&req).unwrap_or_default();
+    let device_name = if device_id.is_empty() {
+        device_headers(&req).1.map(|name| format!("{} (unverified)", name))
+    } else {
+        devices::name_of(&device_id)
+    }
+    .unwrap_or_else(|| "an unknown device".to_string());
+
+    let Some(field) = payload.try_next().await? else {
+        return Err(AppError::BadRequest("No file provided".to_string()));
+    };
+
+    let filename = field.content_disposition().get_filename().unwrap_or("unnamed_file");
+    let sanitized_filename = sanitize_filename::sanitize(filename);
+
+    let outcome =
+        transfers::request_approval(&sanitized_filename, query.size, &device_id, &device_name)
+            .await?;
+    match outcome {
+        Outcome::Accepted => {}
+        Outcome::Rejected => {
+            return Err(AppError::Forbidden("Transfer was rejected".to_string()));
+        }
+        Outcome::TimedOut => {
+            return Err(AppError::Timeout(
+                "Nobody answered the transfer request".to_string(),
+            ));
         }
     }
-    
+
+    let bandwidth = Bandwidth::start(&req)?;
+    let _slot = limits::acquire(TransferKind::Upload)?;
+    let (dir, file_path) = web::block(move || {
+        let dir = ensure_download_folder()?;
+        let path = unique_path(&dir, &sanitized_filename);
+        Ok::<_, AppError>((dir, path))
+    })
+    .await??;
+    let saved_name = file_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    let budget = UploadBudget::new(&dir, &saved_name, None, Destination::Downloads, query.size).await?;
+
+    let bytes = save_file(field, &file_path, budget, &bandwidth, None).await.map_err(|e| {
+        tracing::warn!(path = %file_path.display(), "Failed to save pushed file: {}", e);
+        e
+    })?;
+    tracing::info!(path = %file_path.display(), bytes, device = %device_name, "Saved pushed file");
+
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        status: "success".to_string(),
+        filename: Some(saved_name),
+    }))
+}
+
+pub(crate) fn reject_if_shutting_down() -> Result<(), AppError> {
+    if shutdown::is_shutting_down() {
+        return Err(AppError::Unavailable("Server is shutting down".to_string()));
+    }
     Ok(())
 }
+
+/// Pick a path in `dir` that doesn't clobber an existing file, adding
+/// " (1)", " (2)", ... before the extension as needed
+pub(crate) fn unique_path(dir: &Path, filename: &str) -> std::path::PathBuf {
+    let candidate = dir.join(filename);
+    if !candidate.exists() {
+        return candidate;
+    }
+
+    let path = Path::new(filename);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+
+    (1..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, extension)))
+        .find(|p| !p.exists())
+        .expect("some numbered name is free")
+}
+
+/// Stream a multipart field to disk, returning the number of bytes written.
+/// The file is removed again if the upload fails, is cut off or goes over a
+/// limit in `budget`. Bytes received are charged to the sender's `bandwidth`
+/// and counted by `progress`, if given.
+pub(crate) async fn save_file(
+    field: Field,
+    file_path: impl AsRef<Path>,
+    budget: UploadBudget,
+    bandwidth: &Bandwidth,
+    progress: Option<Progress>,
+) -> Result<u64, AppError> {
+    let data = metered_upload(field, budget, bandwidth.clone(), progress);
+    local::write_file(file_path.as_ref(), data).await
+}
+
+/// A multipart field as it is read, failing once it goes over a limit in
+/// `budget`. Bytes received are charged to the sender's `bandwidth` and
+/// counted by `progress`, if given.
+///
+/// The next chunk is only read once the previous one has been stored and
+/// any bandwidth cap allows it, so slow storage or a cap slows the sender
+/// down instead of piling data up in memory.
+pub(crate) fn metered_upload(
+    field: Field,
+    budget: UploadBudget,
+    bandwidth: Bandwidth,
+    progress: Option<Progress>,
+) -> ByteStream {
+    let upload = MeteredUpload {
+        field,
+        budget,
+        bandwidth,
+        progress,
+        throttle: Throttle::new(ThrottleClass::Bulk),
+    };
+    futures::stream::unfold(upload, |mut upload| async move {
+        let chunk = upload.next_chunk().await?;
+        Some((chunk, upload))
+    })
+    .boxed_local()
+}
+
+struct MeteredUpload {
+    field: Field,
+    budget: UploadBudget,
+    bandwidth: Bandwidth,
+    progress: Option<Progress>,
+    throttle: Throttle,
+}
+
+impl MeteredUpload {
+    async fn next_chunk(&mut self) -> Option<Result<Bytes, AppError>> {
+        let data = match self.field.next().await? {
+            Ok(data) => data,
+            Err(e) => return Some(Err(e.into())),
+        };
+        let len = data.len() as u64;
+        if self.budget.charge(len) {
+            if let Err(e) = self.budget.check().await {
+                return Some(Err(e));
+            }
+        }
+        self.bandwidth.charge(len);
+        if let Some(progress) = &self.progress {
+            progress.advance(len);
+        }
+        metrics::UPLOADED_BYTES.inc_by(len);
+        self.throttle.pace(len).await;
+        Some(Ok(data))
+    }
+}
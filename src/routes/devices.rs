@@ -0,0 +1,148 @@
+use actix_web::http::header::USER_AGENT;
+use actix_web::{get, post, put, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use utoipa::{OpenApi, ToSchema};
+
+use crate::error::{AppError, ErrorResponse};
+use crate::routes::{current_device, require_device};
+use crate::services::devices::{self, Device};
+use crate::services::e2e;
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct RegisterRequest {
+    /// The ID the device wants, usually a random UUID it keeps. Only letters,
+    /// digits, `-` and `_` are allowed.
+    id: String,
+    name: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct RegisterResponse {
+    device: Device,
+    /// Send as `X-Device-Token` with every request from now on. Only given
+    /// out once; absent when the request already carried a valid token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct RenameRequest {
+    name: String,
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(register_device, list_devices, get_device, rename_device))]
+pub struct ApiDoc;
+
+// Register device registry routes under `/api/v1`
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(register_device)
+        .service(list_devices)
+        .service(get_device)
+        .service(rename_device);
+}
+
+/// Register this device, getting the token that proves it is this device
+/// from then on. A device that sends its current token along gets itself
+/// back without a new one.
+#[utoipa::path(
+    tag = "devices",
+    request_body = RegisterRequest,
+    responses(
+        (status = 200, body = RegisterResponse),
+        (status = 400, description = "The ID is empty, too long or has other characters than letters, digits, - and _, or the name contains markup", body = ErrorResponse),
+        (status = 409, description = "Another device registered that ID", body = ErrorResponse),
+        (status = 503, description = "Too many devices are registered", body = ErrorResponse),
+    )
+)]
+#[post("/devices")]
+async fn register_device(
+    http_req: HttpRequest,
+    req: web::Json<RegisterRequest>,
+) -> Result<HttpResponse, AppError> {
+    let RegisterRequest { id, name } = req.into_inner();
+    if current_device(&http_req).as_deref() == Some(id.trim()) {
+        let device = web::block(move || devices::get(id.trim())).await??;
+        return Ok(HttpResponse::Ok().json(RegisterResponse {
+            device,
+            token: None,
+        }));
+    }
+
+    let platform = http_req
+        .headers()
+        .get(USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let (device, token) = web::block(move || {
+        let registered = devices::register(&id, name.as_deref(), platform.as_deref())?;
+        // An ID registered before tokens existed can be claimed by anyone,
+        // so whatever holds it now has to be paired again
+        e2e::revoke(&registered.0.id)?;
+        Ok::<_, AppError>(registered)
+    })
+    .await??;
+    Ok(HttpResponse::Ok().json(RegisterResponse {
+        device,
+        token: Some(token),
+    }))
+}
+
+/// List every known device, most recently seen first, with whether it is
+/// online right now
+#[utoipa::path(
+    tag = "devices",
+    responses(
+        (status = 200, body = Vec<Device>),
+        (status = 500, body = ErrorResponse),
+    )
+)]
+#[get("/devices")]
+async fn list_devices() -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok().json(devices::list()?))
+}
+
+/// Get one device
+#[utoipa::path(
+    tag = "devices",
+    params(("id" = String, Path, description = "Device ID")),
+    responses(
+        (status = 200, body = Device),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+#[get("/devices/{id}")]
+async fn get_device(id: web::Path<String>) -> Result<HttpResponse, AppError> {
+    Ok(HttpResponse::Ok().json(devices::get(&id)?))
+}
+
+/// Rename this device
+#[utoipa::path(
+    tag = "devices",
+    params(
+        ("id" = String, Path, description = "Device ID"),
+        ("X-Device-Id" = String, Header, description = "Stable ID of this device"),
+        ("X-Device-Token" = String, Header, description = "Token this device was given when it registered"),
+    ),
+    request_body = RenameRequest,
+    responses(
+        (status = 200, description = "The renamed device", body = Device),
+        (status = 400, description = "The name is empty or contains markup", body = ErrorResponse),
+        (status = 401, description = "The device isn't registered or sent a wrong token", body = ErrorResponse),
+        (status = 403, description = "Devices can only rename themselves", body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+#[put("/devices/{id}")]
+async fn rename_device(
+    http_req: HttpRequest,
+    id: web::Path<String>,
+    req: web::Json<RenameRequest>,
+) -> Result<HttpResponse, AppError> {
+    let (id, name) = (id.into_inner(), req.into_inner().name);
+    if require_device(&http_req)? != id {
+        return Err(AppError::Forbidden("Devices can only rename themselves".to_string()));
+    }
+    let device = web::block(move || devices::rename(&id, &name)).await??;
+    Ok(HttpResponse::Ok().json(device))
+}
@@ -0,0 +1,117 @@
+use actix_web::{get, post, put, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use utoipa::{OpenApi, ToSchema};
+
+use crate::config::current_config;
+use crate::error::{AppError, ErrorResponse};
+use crate::routes::require_device;
+use crate::services::e2e::{self, KeyState};
+
+#[derive(Debug, Serialize, ToSchema)]
+struct KeysResponse {
+    /// Whether `e2e.enabled` is set, so clients should encrypt
+    enabled: bool,
+    #[serde(flatten)]
+    state: KeyState,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct PublicKeyRequest {
+    /// Base64 of the device's raw P-256 public key
+    public_key: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct PublishKeysRequest {
+    /// The current epoch to pair new devices, or the next one to replace the
+    /// group key
+    epoch: u64,
+    /// The group key wrapped for each device, by device ID
+    keys: BTreeMap<String, String>,
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(get_keys, set_public_key, publish_keys))]
+pub struct ApiDoc;
+
+// Register end-to-end encryption key routes under `/api/v1`
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_keys)
+        .service(set_public_key)
+        .service(publish_keys);
+}
+
+/// Get the group's public keys and the group keys wrapped for this device
+#[utoipa::path(
+    tag = "e2e",
+    params(
+        ("X-Device-Id" = String, Header, description = "Stable ID of this device"),
+        ("X-Device-Token" = String, Header, description = "Token this device was given when it registered"),
+    ),
+    responses(
+        (status = 200, body = KeysResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 401, description = "This device isn't registered, or its token is wrong", body = ErrorResponse),
+    )
+)]
+#[get("/e2e/keys")]
+async fn get_keys(req: HttpRequest) -> Result<HttpResponse, AppError> {
+    let device = require_device(&req)?;
+    let enabled = current_config()?.e2e.enabled;
+    let state = web::block(move || e2e::state_for(&device)).await??;
+    Ok(HttpResponse::Ok().json(KeysResponse { enabled, state }))
+}
+
+/// Register this device's public key, so members can pair it. Only the
+/// device itself, proven by its token, can set or replace its key.
+#[utoipa::path(
+    tag = "e2e",
+    params(
+        ("X-Device-Id" = String, Header, description = "Stable ID of this device"),
+        ("X-Device-Token" = String, Header, description = "Token this device was given when it registered"),
+    ),
+    request_body = PublicKeyRequest,
+    responses(
+        (status = 204, description = "The key was registered"),
+        (status = 400, body = ErrorResponse),
+        (status = 401, description = "This device isn't registered, or its token is wrong", body = ErrorResponse),
+    )
+)]
+#[put("/e2e/public-key")]
+async fn set_public_key(
+    req: HttpRequest,
+    body: web::Json<PublicKeyRequest>,
+) -> Result<HttpResponse, AppError> {
+    let device = require_device(&req)?;
+    let key = body.into_inner().public_key;
+    web::block(move || e2e::set_public_key(&device, &key)).await??;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Hand out the group key: to new devices for the current epoch, or to every
+/// member for the next one when creating or replacing it
+#[utoipa::path(
+    tag = "e2e",
+    params(
+        ("X-Device-Id" = String, Header, description = "Stable ID of this device"),
+        ("X-Device-Token" = String, Header, description = "Token this device was given when it registered"),
+    ),
+    request_body = PublishKeysRequest,
+    responses(
+        (status = 204, description = "The keys were stored"),
+        (status = 400, description = "A key is invalid or the epoch is stale", body = ErrorResponse),
+        (status = 401, description = "This device isn't registered, or its token is wrong", body = ErrorResponse),
+        (status = 403, description = "This device doesn't hold the group key", body = ErrorResponse),
+    )
+)]
+#[post("/e2e/keys")]
+async fn publish_keys(
+    req: HttpRequest,
+    body: web::Json<PublishKeysRequest>,
+) -> Result<HttpResponse, AppError> {
+    let device = require_device(&req)?;
+    let PublishKeysRequest { epoch, keys } = body.into_inner();
+    web::block(move || e2e::publish_keys(&device, epoch, keys)).await??;
+    Ok(HttpResponse::NoContent().finish())
+}
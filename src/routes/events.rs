@@ -0,0 +1,299 @@
+use actix_web::http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use actix_web::web::Bytes;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::sync::watch;
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+use crate::error::{AppError, ErrorResponse};
+use crate::rate_limit::{self, ClientSlot, SlotKind};
+use crate::routes::device_headers;
+use crate::routes::ws::{connect_device, streaming_device};
+use crate::services::devices::Presence;
+use crate::services::events::{self, BusEvent, Topic};
+use crate::shutdown::{self, ShutdownNotice};
+
+/// How long an event stream can go quiet before a keepalive comment is sent
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+/// How long browsers wait before reconnecting, in milliseconds
+const RECONNECT_DELAY_MS: u64 = 3000;
+const DEFAULT_POLL_TIMEOUT_SECS: u64 = 25;
+const MAX_POLL_TIMEOUT_SECS: u64 = 60;
+
+#[derive(OpenApi)]
+#[openapi(paths(event_stream, poll_events), components(schemas(PollResponse)))]
+pub struct ApiDoc;
+
+// Register event routes under `/api/v1`. The long-poll route has to come
+// first, or `/events` would answer for it.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(poll_events).service(event_stream);
+}
+
+/// Who is following events and which ones
+#[derive(Debug, Deserialize, IntoParams)]
+struct StreamQuery {
+    /// Comma-separated topics to follow instead of the defaults (every
+    /// topic but `progress`)
+    topics: Option<String>,
+    /// ID of the device following, for its inbox events. `EventSource`
+    /// can't set headers, so `X-Device-Id` and `X-Device-Token` also work
+    /// but aren't required.
+    device_id: Option<String>,
+    /// The token the device was given when it registered
+    device_token: Option<String>,
+    device_name: Option<String>,
+    /// Resume after this event, for clients that can't set `Last-Event-ID`
+    last_event_id: Option<u64>,
+}
+
+/// Follow clipboard, device, inbox, file and other events as Server-Sent
+/// Events. Each event's `data` is the JSON a WebSocket client gets, and its
+/// `id` can be sent back as `Last-Event-ID` on reconnect to get whatever was
+/// missed. If that is too far back, a `resync` event tells the client to
+/// reload its state instead.
+#[utoipa::path(
+    tag = "events",
+    params(
+        StreamQuery,
+        ("Last-Event-ID" = Option<u64>, Header, description = "Resume after this event"),
+    ),
+    responses(
+        (status = 200, description = "A stream of events", content_type = "text/event-stream", body = String),
+        (status = 400, description = "Unknown topic or bad event ID", body = ErrorResponse),
+        (status = 401, description = "Wrong device token", body = ErrorResponse),
+        (status = 403, description = "The device is blocked", body = ErrorResponse),
+        (status = 429, description = "Too many open connections", body = ErrorResponse),
+    )
+)]
+#[get("/events")]
+async fn event_stream(
+    req: HttpRequest,
+    query: web::Query<StreamQuery>,
+) -> Result<HttpResponse, AppError> {
+    let query = query.into_inner();
+    let topics = Topic::from_query(query.topics.as_deref())?;
+    let after = match last_event_id(&req)?.or(query.last_event_id) {
+        Some(id) => id,
+        None => events::latest_id(),
+    };
+    let device = streaming_device(&req, query.device_id.as_deref(), query.device_token.as_deref())?;
+    let name = query.device_name.or_else(|| device_headers(&req).1);
+
+    let slot = rate_limit::acquire(&req, SlotKind::WebSocket)?;
+    let presence = match device.as_deref() {
+        Some(id) => Some(connect_device(&req, id, name.as_deref())?),
+        None => None,
+    };
+
+    let mut queue = VecDeque::new();
+    // Gives clients that start from now an ID to resume from
+    queue.push_back(Bytes::from(format!(
+        "retry: {}\nid: {}\n\n",
+        RECONNECT_DELAY_MS, after
+    )));
+    let follower = Follower {
+        after,
+        topics,
+        device,
+        watch: events::watch(),
+        queue,
+        shutdown: Box::pin(shutdown::notice_stream()),
+        _slot: slot,
+        _presence: presence,
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, "text/event-stream"))
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        // Keeps reverse proxies like nginx from holding events back
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(follower.into_stream()))
+}
+
+/// The `Last-Event-ID` header browsers send when an event stream reconnects
+fn last_event_id(req: &HttpRequest) -> Result<Option<u64>, AppError> {
+    let Some(value) = req.headers().get("Last-Event-ID") else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .map(Some)
+        .ok_or_else(|| AppError::BadRequest("Invalid Last-Event-ID".to_string()))
+}
+
+/// An open event stream
+struct Follower {
+    after: u64,
+    topics: HashSet<Topic>,
+    device: Option<String>,
+    watch: watch::Receiver<u64>,
+    queue: VecDeque<Bytes>,
+    shutdown: Pin<Box<dyn Stream<Item = ShutdownNotice>>>,
+    // Counts against the client's connection limit until the stream ends
+    _slot: ClientSlot,
+    // Shows the device as online until the stream ends
+    _presence: Option<Presence>,
+}
+
+impl Follower {
+    fn into_stream(self) -> impl Stream<Item = Result<Bytes, AppError>> {
+        futures::stream::unfold(self, |mut follower| async move {
+            loop {
+                if let Some(chunk) = follower.queue.pop_front() {
+                    return Some((Ok(chunk), follower));
+                }
+                follower.watch.borrow_and_update();
+                follower.catch_up();
+                if !follower.queue.is_empty() {
+                    continue;
+                }
+                tokio::select! {
+                    changed = follower.watch.changed() => {
+                        if changed.is_err() {
+                            return None;
+                        }
+                    }
+                    _ = tokio::time::sleep(KEEPALIVE_INTERVAL) => {
+                        return Some((Ok(Bytes::from_static(b": keepalive\n\n")), follower));
+                    }
+                    _ = follower.shutdown.next() => return None,
+                }
+            }
+        })
+    }
+
+    /// Queue the events published since the last one sent
+    fn catch_up(&mut self) {
+        match events::since(self.after, &self.topics, self.device.as_deref()) {
+            Some((events, latest)) => {
+                for event in events {
+                    self.queue.push_back(frame(event.id, &event.event));
+                }
+                self.after = latest;
+            }
+            None => {
+                let latest = events::latest_id();
+                tracing::debug!(after = self.after, latest, "Event stream resyncing");
+                self.queue.push_back(frame(latest, &resync_message()));
+                self.after = latest;
+            }
+        }
+    }
+}
+
+fn frame(id: u64, message: &impl Serialize) -> Bytes {
+    let data = serde_json::to_string(message).unwrap_or_default();
+    Bytes::from(format!("id: {}\ndata: {}\n\n", id, data))
+}
+
+/// Tells a client that events it hasn't seen are gone
+fn resync_message() -> serde_json::Value {
+    serde_json::json!({ "type": "resync", "data": null })
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+struct PollQuery {
+    /// The `last_id` of the previous response. Without it the current
+    /// `last_id` comes back straight away, to start from.
+    since: Option<u64>,
+    /// Seconds to wait for an event (default 25, at most 60)
+    timeout: Option<u64>,
+    /// Comma-separated topics to follow instead of the defaults
+    topics: Option<String>,
+    /// ID of the device polling, for its inbox events. `X-Device-Id` and
+    /// `X-Device-Token` work too.
+    device_id: Option<String>,
+    /// The token the device was given when it registered
+    device_token: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct PollResponse {
+    /// Events in the order they happened, each the JSON a WebSocket client
+    /// gets plus its `id`
+    #[schema(value_type = Vec<Object>)]
+    events: Vec<serde_json::Value>,
+    /// Send as `since` in the next poll
+    last_id: u64,
+    /// Events were missed and the client should reload its state
+    resync: bool,
+}
+
+impl PollResponse {
+    fn new(events: Vec<BusEvent>, last_id: u64) -> Self {
+        Self {
+            events: events.iter().map(BusEvent::to_json).collect(),
+            last_id,
+            resync: false,
+        }
+    }
+
+    fn resync() -> Self {
+        Self {
+            events: Vec::new(),
+            last_id: events::latest_id(),
+            resync: true,
+        }
+    }
+}
+
+/// Wait for the same events as `/events`, for clients that can't hold a
+/// stream open. Returns as soon as there is at least one event after
+/// `since`, or with none once `timeout` passes.
+#[utoipa::path(
+    tag = "events",
+    params(PollQuery),
+    responses(
+        (status = 200, description = "Events after `since`", body = PollResponse),
+        (status = 400, description = "Unknown topic", body = ErrorResponse),
+        (status = 401, description = "Wrong device token", body = ErrorResponse),
+    )
+)]
+#[get("/events/poll")]
+async fn poll_events(
+    req: HttpRequest,
+    query: web::Query<PollQuery>,
+) -> Result<HttpResponse, AppError> {
+    let query = query.into_inner();
+    let topics = Topic::from_query(query.topics.as_deref())?;
+    let device = streaming_device(&req, query.device_id.as_deref(), query.device_token.as_deref())?;
+    let Some(since) = query.since else {
+        return Ok(HttpResponse::Ok().json(PollResponse::new(Vec::new(), events::latest_id())));
+    };
+    let timeout = Duration::from_secs(
+        query
+            .timeout
+            .unwrap_or(DEFAULT_POLL_TIMEOUT_SECS)
+            .min(MAX_POLL_TIMEOUT_SECS),
+    );
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut watch = events::watch();
+    let mut shutdown = Box::pin(shutdown::notice_stream());
+
+    loop {
+        watch.borrow_and_update();
+        let Some((found, latest)) = events::since(since, &topics, device.as_deref()) else {
+            return Ok(HttpResponse::Ok().json(PollResponse::resync()));
+        };
+        if !found.is_empty() {
+            return Ok(HttpResponse::Ok().json(PollResponse::new(found, latest)));
+        }
+        tokio::select! {
+            changed = watch.changed() => {
+                if changed.is_ok() {
+                    continue;
+                }
+            }
+            _ = tokio::time::sleep_until(deadline) => {}
+            _ = shutdown.next() => {}
+        }
+        return Ok(HttpResponse::Ok().json(PollResponse::new(Vec::new(), latest)));
+    }
+}
@@ -0,0 +1,266 @@
+use actix_web::body::BoxBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, RANGE};
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse, ResponseError};
+use futures::future::{self, Either};
+use futures::StreamExt;
+use std::future::Future;
+use utoipa::OpenApi;
+
+use crate::config::current_config;
+use crate::error::{AppError, ErrorResponse};
+use crate::http;
+use crate::rate_limit::{self, SlotKind};
+use crate::routes::api::{set_clipboard, ClipboardData};
+use crate::services::devices::TrustLevel;
+use crate::services::federation::{self, ClipboardUpdate, Hello, Peer, PeerStatus};
+
+/// Response headers passed on from a peer's response
+const FORWARDED_HEADERS: [&str; 4] = [
+    "content-type",
+    "content-range",
+    "accept-ranges",
+    "content-disposition",
+];
+
+#[derive(OpenApi)]
+#[openapi(paths(hello, status, merge_clipboard, list_peers, proxy))]
+pub struct ApiDoc;
+
+// Register federation routes under `/api/v1`
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(hello)
+        .service(status)
+        .service(merge_clipboard)
+        .service(list_peers)
+        .service(proxy);
+}
+
+fn require_enabled() -> Result<(), AppError> {
+    if federation::is_enabled() {
+        Ok(())
+    } else {
+        Err(AppError::Forbidden("Federation is off".to_string()))
+    }
+}
+
+/// The `X-Instance-Id` header and bearer token, when set
+fn peer_credentials(req: &HttpRequest) -> (Option<&str>, Option<&str>) {
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+    (
+        header("X-Instance-Id"),
+        header("Authorization").and_then(|v| v.strip_prefix("Bearer ")),
+    )
+}
+
+fn authenticate(req: &HttpRequest) -> Result<Peer, AppError> {
+    let (id, secret) = peer_credentials(req);
+    federation::authenticate(id, secret)
+}
+
+/// Middleware (for `App::wrap_fn`) refusing requests from other servers that
+/// don't prove who they are, or that aren't trusted. The federation routes
+/// check for themselves.
+pub fn check_peer<S>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<BoxBody>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error>,
+{
+    let (Some(_), _) = peer_credentials(req.request()) else {
+        return Either::Left(srv.call(req));
+    };
+    if req.path().starts_with("/api/v1/federation/") {
+        return Either::Left(srv.call(req));
+    }
+    let refused = match authenticate(req.request()) {
+        Ok(peer) if peer.trust == TrustLevel::Trusted => None,
+        Ok(_) => Some(AppError::Forbidden("This server isn't trusted".to_string())),
+        Err(e) => Some(e),
+    };
+    match refused {
+        None => Either::Left(srv.call(req)),
+        Some(error) => Either::Right(future::ready(Ok(req.into_response(error.error_response())))),
+    }
+}
+
+/// Introduce another server. It gets this server's own introduction back,
+/// and the two share the secret it sent from then on. It isn't trusted until
+/// an admin says so.
+#[utoipa::path(
+    tag = "federation",
+    request_body = Hello,
+    responses(
+        (status = 200, description = "This server", body = Hello),
+        (status = 400, description = "The introduction is incomplete or clashes with one already under way", body = ErrorResponse),
+        (status = 401, description = "The server is linked already and the introduction didn't prove the shared secret", body = ErrorResponse),
+        (status = 403, description = "Federation is off or the server is blocked", body = ErrorResponse),
+    )
+)]
+#[post("/federation/hello")]
+async fn hello(req: web::Json<Hello>) -> Result<HttpResponse, AppError> {
+    require_enabled()?;
+    let me = web::block(move || federation::accept_hello(req.into_inner())).await??;
+    Ok(HttpResponse::Ok().json(me))
+}
+
+/// How this server sees the linked server asking
+#[utoipa::path(
+    tag = "federation",
+    params(
+        ("X-Instance-Id" = String, Header, description = "ID of the server asking"),
+        ("Authorization" = String, Header, description = "`Bearer` and the secret the two servers share"),
+    ),
+    responses(
+        (status = 200, body = PeerStatus),
+        (status = 401, description = "Unknown server or wrong secret", body = ErrorResponse),
+        (status = 403, description = "Federation is off or the server is blocked", body = ErrorResponse),
+    )
+)]
+#[get("/federation/status")]
+async fn status(req: HttpRequest) -> Result<HttpResponse, AppError> {
+    require_enabled()?;
+    let peer = authenticate(&req)?;
+    Ok(HttpResponse::Ok().json(federation::status_for(&peer)?))
+}
+
+/// Take a clipboard change from a trusted server merging clipboards with this
+/// one. It isn't passed on to other servers.
+#[utoipa::path(
+    tag = "federation",
+    params(
+        ("X-Instance-Id" = String, Header, description = "ID of the server sending it"),
+        ("Authorization" = String, Header, description = "`Bearer` and the secret the two servers share"),
+    ),
+    request_body = ClipboardUpdate,
+    responses(
+        (status = 204, description = "The clipboard was replaced"),
+        (status = 401, description = "Unknown server or wrong secret", body = ErrorResponse),
+        (status = 403, description = "The server isn't trusted, or this server doesn't merge clipboards", body = ErrorResponse),
+    )
+)]
+#[post("/federation/clipboard")]
+async fn merge_clipboard(
+    req: HttpRequest,
+    clipboard_data: web::Data<ClipboardData>,
+    body: web::Json<ClipboardUpdate>,
+) -> Result<HttpResponse, AppError> {
+    require_enabled()?;
+    let peer = authenticate(&req)?;
+    if peer.trust != TrustLevel::Trusted {
+        return Err(AppError::Forbidden("This server isn't trusted".to_string()));
+    }
+    let config = current_config()?;
+    if !config.federation.merge_clipboard || config.e2e.enabled {
+        return Err(AppError::Forbidden(
+            "This server doesn't merge clipboards".to_string(),
+        ));
+    }
+
+    tracing::info!(peer = %peer.id, "Clipboard merged from another server");
+    let text = body.into_inner().text;
+    set_clipboard(&clipboard_data, text, false, None, Some(peer.name)).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// List the trusted servers whose files and audio can be browsed from here
+#[utoipa::path(
+    tag = "federation",
+    responses(
+        (status = 200, body = Vec<Peer>),
+        (status = 500, body = ErrorResponse),
+    )
+)]
+#[get("/peers")]
+async fn list_peers() -> Result<HttpResponse, AppError> {
+    if !federation::is_enabled() {
+        return Ok(HttpResponse::Ok().json(Vec::<Peer>::new()));
+    }
+    let peers: Vec<Peer> = web::block(federation::list)
+        .await??
+        .into_iter()
+        .filter(|p| p.trust == TrustLevel::Trusted)
+        .collect();
+    Ok(HttpResponse::Ok().json(peers))
+}
+
+/// Whether a route under `/api/v1` may be read through the proxy
+fn proxied(path: &str) -> bool {
+    matches!(
+        path.split('/').collect::<Vec<_>>().as_slice(),
+        ["clipboard"] | ["files"] | ["stream", "list"] | ["stream", "play"]
+    ) || path
+        .strip_prefix("files/")
+        .is_some_and(|name| !name.is_empty() && !name.contains('/'))
+}
+
+/// Read a trusted server's clipboard, files or audio. `path` is the route on
+/// that server, under `/api/v1`: `clipboard`, `files`, `files/{filename}`,
+/// `stream/list` or `stream/play`. The query string and `Range` header are
+/// passed on, and the response comes back as the server sent it.
+#[utoipa::path(
+    tag = "federation",
+    params(
+        ("id" = String, Path, description = "Server ID"),
+        ("path" = String, Path, description = "Route on that server"),
+    ),
+    responses(
+        (status = 200, description = "The server's response"),
+        (status = 206, description = "The requested range of a file"),
+        (status = 403, description = "Federation is off", body = ErrorResponse),
+        (status = 404, description = "Not a linked, trusted server or not a route that can be proxied", body = ErrorResponse),
+        (status = 429, description = "This device has too many streams open", body = ErrorResponse),
+        (status = 502, description = "The server can't be reached", body = ErrorResponse),
+    )
+)]
+#[get("/peers/{id}/{path:.*}")]
+async fn proxy(
+    req: HttpRequest,
+    params: web::Path<(String, String)>,
+) -> Result<HttpResponse, AppError> {
+    let id = &params.0;
+    // The raw path, so file names stay encoded as the client sent them
+    let path = req
+        .path()
+        .split_once(&format!("/peers/{}/", id))
+        .map(|(_, path)| path)
+        .unwrap_or_default();
+    if !proxied(path) {
+        return Err(AppError::NotFound(
+            "That route can't be read from another server".to_string(),
+        ));
+    }
+    let path_and_query = match req.query_string() {
+        "" => path.to_string(),
+        query => format!("{}?{}", path, query),
+    };
+    let headers: Vec<(String, String)> = req
+        .headers()
+        .get(RANGE)
+        .and_then(|v| v.to_str().ok())
+        .map(|range| ("Range".to_string(), range.to_string()))
+        .into_iter()
+        .collect();
+
+    let client_slot = rate_limit::acquire(&req, SlotKind::Stream)?;
+    let response = federation::fetch(id, &path_and_query, &headers).await?;
+
+    let code = StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let mut builder = HttpResponse::build(code);
+    for name in FORWARDED_HEADERS {
+        if let Some(value) = response.headers().get(name).and_then(|v| v.to_str().ok()) {
+            builder.insert_header((HeaderName::from_static(name), value));
+        }
+    }
+    if let Some(len) = response.content_length() {
+        builder.no_chunking(len);
+    }
+    // Held until the whole body has been passed on
+    let body = http::body_stream(response).map(move |chunk| {
+        let _ = &client_slot;
+        chunk
+    });
+    Ok(builder.streaming(body))
+}
@@ -0,0 +1,294 @@
+use actix_files::NamedFile;
+use actix_multipart::Multipart;
+use actix_web::body::BoxBody;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse};
+use futures::TryStreamExt;
+use serde::Deserialize;
+use utoipa::{OpenApi, ToSchema};
+
+use crate::error::{AppError, ErrorResponse};
+use crate::metrics::{self, MeteredBody};
+use crate::rate_limit::{self, Bandwidth, SlotKind};
+use crate::routes::api::{reject_if_shutting_down, save_file, UploadQuery};
+use crate::routes::require_device;
+use crate::services::inbox::{self, InboxItem, ItemContent, ItemStatus};
+use crate::services::limits::{self, TransferKind};
+use crate::services::quota::{Destination, UploadBudget};
+use crate::throttle::{ThrottleClass, ThrottledBody};
+
+/// A text or link to send
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum SendRequest {
+    Text { text: String },
+    Url { url: String },
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(
+    send_item,
+    send_file,
+    list_inbox,
+    accept_item,
+    decline_item,
+    download_item,
+    acknowledge_item
+))]
+pub struct ApiDoc;
+
+// Register inbox routes under `/api/v1`
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(send_item)
+        .service(send_file)
+        .service(list_inbox)
+        .service(accept_item)
+        .service(decline_item)
+        .service(download_item)
+        .service(acknowledge_item);
+}
+
+/// Send a text or link to one device. It shows up in that device's inbox
+/// and, if it is connected, as an `inbox_item` WebSocket event.
+#[utoipa::path(
+    tag = "inbox",
+    params(
+        ("id" = String, Path, description = "ID of the receiving device"),
+        ("X-Device-Id" = String, Header, description = "Stable ID of the sending device"),
+        ("X-Device-Token" = String, Header, description = "Token the sending device was given when it registered"),
+    ),
+    request_body = SendRequest,
+    responses(
+        (status = 200, description = "The item as delivered", body = InboxItem),
+        (status = 400, body = ErrorResponse),
+        (status = 401, description = "This device isn't registered, or its token is wrong", body = ErrorResponse),
+        (status = 404, description = "No device with that ID", body = ErrorResponse),
+        (status = 413, description = "The text is too long", body = ErrorResponse),
+    )
+)]
+#[post("/devices/{id}/inbox")]
+async fn send_item(
+    req: HttpRequest,
+    to: web::Path<String>,
+    body: web::Json<SendRequest>,
+) -> Result<HttpResponse, AppError> {
+    let from = require_device(&req)?;
+    let content = match body.into_inner() {
+        SendRequest::Text { text } => ItemContent::Text { text },
+        SendRequest::Url { url } => ItemContent::Url { url: url.trim().to_string() },
+    };
+
+    let item = web::block(move || inbox::deliver(inbox::new_item(&from, &to, content)?)).await??;
+    Ok(HttpResponse::Ok().json(item))
+}
+
+/// Send a file to one device. It is kept on the host until the device
+/// declines it or is done with it.
+#[utoipa::path(
+    tag = "inbox",
+    params(
+        ("id" = String, Path, description = "ID of the receiving device"),
+        UploadQuery,
+        ("X-Device-Id" = String, Header, description = "Stable ID of the sending device"),
+        ("X-Device-Token" = String, Header, description = "Token the sending device was given when it registered"),
+    ),
+    request_body(content_type = "multipart/form-data", description = "A single file field"),
+    responses(
+        (status = 200, description = "The item as delivered", body = InboxItem),
+        (status = 400, body = ErrorResponse),
+        (status = 401, description = "This device isn't registered, or its token is wrong", body = ErrorResponse),
+        (status = 404, description = "No device with that ID", body = ErrorResponse),
+        (status = 413, description = "The file is over the size limit", body = ErrorResponse),
+        (status = 429, description = "This device is over its bandwidth budget", body = ErrorResponse),
+        (status = 503, description = "The server is shutting down or busy", body = ErrorResponse),
+        (status = 507, description = "Not enough free disk space", body = ErrorResponse),
+    )
+)]
+#[post("/devices/{id}/inbox/file")]
+async fn send_file(
+    req: HttpRequest,
+    to: web::Path<String>,
+    query: web::Query<UploadQuery>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, AppError> {
+    reject_if_shutting_down()?;
+    let from = require_device(&req)?;
+    let bandwidth = Bandwidth::start(&req)?;
+    let _slot = limits::acquire(TransferKind::Upload)?;
+
+    let Some(field) = payload.try_next().await? else {
+        return Err(AppError::BadRequest("No file provided".to_string()));
+    };
+    let filename = field.content_disposition().get_filename().unwrap_or("unnamed_file");
+    let name = sanitize_filename::sanitize(filename);
+
+    let size = query.size;
+    let sender = from.clone();
+    let (mut item, file_path) = web::block(move || {
+        let content = ItemContent::File { name, size: 0 };
+        let item = inbox::new_item(&sender, &to, content)?;
+        let path = inbox::file_path(&item.id)?;
+        Ok::<_, AppError>((item, path))
+    })
+    .await??;
+    let dir = file_path.parent().unwrap_or(&file_path);
+    let budget = UploadBudget::new(dir, &item.id, Some(&from), Destination::Inbox, size).await?;
+
+    let bytes = save_file(field, &file_path, budget, &bandwidth, None).await.map_err(|e| {
+        tracing::warn!(path = %file_path.display(), "Failed to save inbox file: {}", e);
+        e
+    })?;
+    if let ItemContent::File { size, .. } = &mut item.content {
+        *size = bytes;
+    }
+
+    let item = web::block(move || inbox::deliver(item)).await??;
+    Ok(HttpResponse::Ok().json(item))
+}
+
+/// List what has been sent to this device, oldest first
+#[utoipa::path(
+    tag = "inbox",
+    params(
+        ("X-Device-Id" = String, Header, description = "Stable ID of this device"),
+        ("X-Device-Token" = String, Header, description = "Token this device was given when it registered"),
+    ),
+    responses(
+        (status = 200, body = Vec<InboxItem>),
+        (status = 400, body = ErrorResponse),
+        (status = 401, description = "This device isn't registered, or its token is wrong", body = ErrorResponse),
+    )
+)]
+#[get("/inbox")]
+async fn list_inbox(req: HttpRequest) -> Result<HttpResponse, AppError> {
+    let device = require_device(&req)?;
+    let items = web::block(move || inbox::items_for(&device)).await??;
+    Ok(HttpResponse::Ok().json(items))
+}
+
+/// Accept an item. It stays in the inbox until acknowledged, and the sender
+/// is told through an `inbox_answered` WebSocket event.
+#[utoipa::path(
+    tag = "inbox",
+    params(
+        ("id" = String, Path, description = "Item ID"),
+        ("X-Device-Id" = String, Header, description = "Stable ID of this device"),
+        ("X-Device-Token" = String, Header, description = "Token this device was given when it registered"),
+    ),
+    responses(
+        (status = 200, description = "The accepted item", body = InboxItem),
+        (status = 400, description = "The item was already accepted", body = ErrorResponse),
+        (status = 401, description = "This device isn't registered, or its token is wrong", body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+#[post("/inbox/{id}/accept")]
+async fn accept_item(req: HttpRequest, id: web::Path<String>) -> Result<HttpResponse, AppError> {
+    let device = require_device(&req)?;
+    let item = web::block(move || inbox::answer(&device, &id, true)).await??;
+    Ok(HttpResponse::Ok().json(item))
+}
+
+/// Decline an item, removing it and any file that came with it
+#[utoipa::path(
+    tag = "inbox",
+    params(
+        ("id" = String, Path, description = "Item ID"),
+        ("X-Device-Id" = String, Header, description = "Stable ID of this device"),
+        ("X-Device-Token" = String, Header, description = "Token this device was given when it registered"),
+    ),
+    responses(
+        (status = 204, description = "The item was declined"),
+        (status = 400, description = "The item was already accepted", body = ErrorResponse),
+        (status = 401, description = "This device isn't registered, or its token is wrong", body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+#[post("/inbox/{id}/decline")]
+async fn decline_item(req: HttpRequest, id: web::Path<String>) -> Result<HttpResponse, AppError> {
+    let device = require_device(&req)?;
+    web::block(move || inbox::answer(&device, &id, false)).await??;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Download the file sent with an accepted item
+#[utoipa::path(
+    tag = "inbox",
+    params(
+        ("id" = String, Path, description = "Item ID"),
+        ("X-Device-Id" = String, Header, description = "Stable ID of this device"),
+        ("X-Device-Token" = String, Header, description = "Token this device was given when it registered"),
+    ),
+    responses(
+        (status = 200, description = "The file contents", content_type = "application/octet-stream"),
+        (status = 400, description = "The item isn't an accepted file", body = ErrorResponse),
+        (status = 401, description = "This device isn't registered, or its token is wrong", body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 429, description = "This device has too many downloads open or is over its bandwidth budget", body = ErrorResponse),
+        (status = 503, description = "Too many downloads in progress", body = ErrorResponse),
+    )
+)]
+#[get("/inbox/{id}/file")]
+async fn download_item(req: HttpRequest, id: web::Path<String>) -> Result<HttpResponse, AppError> {
+    let device = require_device(&req)?;
+    let item = web::block(move || inbox::get(&device, &id)).await??;
+    let ItemContent::File { name, .. } = &item.content else {
+        return Err(AppError::BadRequest("Item has no file".to_string()));
+    };
+    if item.status != ItemStatus::Accepted {
+        return Err(AppError::BadRequest("Accept the item first".to_string()));
+    }
+
+    let file_path = inbox::file_path(&item.id)?;
+    let file = NamedFile::open_async(&file_path).await.map_err(|e| {
+        tracing::warn!(path = %file_path.display(), "Inbox file not found: {}", e);
+        AppError::NotFound("File not found".to_string())
+    })?;
+    let disposition = ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(name.clone())],
+    };
+
+    // Held until the whole body has been sent
+    let client_slot = rate_limit::acquire(&req, SlotKind::Stream)?;
+    let bandwidth = Bandwidth::start(&req)?;
+    let slot = limits::acquire(TransferKind::Download)?;
+
+    tracing::info!(id = %item.id, "Serving inbox file");
+    Ok(file
+        .set_content_disposition(disposition)
+        .into_response(&req)
+        .map_body(|_, body| {
+            BoxBody::new(
+                MeteredBody::new(
+                    BoxBody::new(ThrottledBody::new(body, ThrottleClass::Bulk)),
+                    &metrics::DOWNLOADED_BYTES,
+                    None,
+                )
+                .on_chunk(move |len| bandwidth.charge(len))
+                .hold(slot)
+                .hold(client_slot),
+            )
+        }))
+}
+
+/// Acknowledge an item, removing it and any file that came with it
+#[utoipa::path(
+    tag = "inbox",
+    params(
+        ("id" = String, Path, description = "Item ID"),
+        ("X-Device-Id" = String, Header, description = "Stable ID of this device"),
+        ("X-Device-Token" = String, Header, description = "Token this device was given when it registered"),
+    ),
+    responses(
+        (status = 204, description = "The item was removed"),
+        (status = 401, description = "This device isn't registered, or its token is wrong", body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+#[delete("/inbox/{id}")]
+async fn acknowledge_item(req: HttpRequest, id: web::Path<String>) -> Result<HttpResponse, AppError> {
+    let device = require_device(&req)?;
+    web::block(move || inbox::acknowledge(&device, &id)).await??;
+    Ok(HttpResponse::NoContent().finish())
+}
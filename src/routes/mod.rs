@@ -1,5 +1,191 @@
+use actix_web::body::BoxBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue, LINK, USER_AGENT};
+use actix_web::{web, Error, HttpMessage, HttpRequest, ResponseError};
+use futures::future::{self, Either};
+use std::future::Future;
+
+use crate::error::AppError;
+use crate::services::devices::TrustLevel;
+
 pub mod admin;
 pub mod api;
+pub mod devices;
+pub mod e2e;
+pub mod events;
+pub mod federation;
+pub mod inbox;
+pub mod monitoring;
+pub mod openapi;
 pub mod streaming;
+pub mod sync;
 pub mod ui;
+pub mod wormhole;
 pub mod ws; // Add WebSocket routes
+
+// Register the REST API under `/api/v1`, plus the pre-v1 paths as deprecated
+// aliases. The versioned scope has to come first, or `/api` would swallow it.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(openapi::swagger_ui())
+        .service(
+            web::scope("/api/v1")
+                .service(web::scope("/stream").configure(streaming::configure))
+                .service(web::scope("/admin").configure(admin::configure))
+                .configure(devices::configure)
+                .configure(e2e::configure)
+                .configure(events::configure)
+                .configure(federation::configure)
+                .configure(inbox::configure)
+                .configure(sync::configure)
+                .configure(api::configure),
+        )
+        .service(
+            web::scope("/api")
+                .wrap_fn(|req, srv| deprecated(req, srv, "/api", "/api/v1"))
+                .configure(api::configure),
+        )
+        .service(
+            web::scope("/stream")
+                .wrap_fn(|req, srv| deprecated(req, srv, "/stream", "/api/v1/stream"))
+                .configure(streaming::configure),
+        )
+        .service(
+            web::scope("/admin")
+                .configure(admin::configure_ui)
+                .service(
+                    web::scope("")
+                        .wrap_fn(|req, srv| deprecated(req, srv, "/admin", "/api/v1/admin"))
+                        .configure(admin::configure),
+                ),
+        );
+}
+
+/// Middleware marking responses from a legacy path as deprecated (RFC 9745)
+/// and linking to the same route under its `/api/v1` prefix
+fn deprecated<S>(
+    req: ServiceRequest,
+    srv: &S,
+    old_prefix: &'static str,
+    new_prefix: &'static str,
+) -> impl Future<Output = Result<ServiceResponse<BoxBody>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error>,
+{
+    let successor = format!(
+        "<{}{}>; rel=\"successor-version\"",
+        new_prefix,
+        req.path().strip_prefix(old_prefix).unwrap_or_default()
+    );
+    let response = srv.call(req);
+
+    async move {
+        let mut response = response.await?;
+        let headers = response.headers_mut();
+        headers.insert(
+            HeaderName::from_static("deprecation"),
+            HeaderValue::from_static("true"),
+        );
+        if let Ok(link) = HeaderValue::from_str(&successor) {
+            headers.insert(LINK, link);
+        }
+        Ok(response)
+    }
+}
+
+/// The `X-Device-Id` and `X-Device-Name` headers, when set
+pub fn device_headers(req: &HttpRequest) -> (Option<String>, Option<String>) {
+    let header = |name: &str| header_value(req, name);
+    (header("X-Device-Id"), header("X-Device-Name"))
+}
+
+fn header_value(req: &HttpRequest, name: &str) -> Option<String> {
+    req.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+}
+
+/// A device that proved who it is with its token, set by [`track_device`]
+#[derive(Debug, Clone)]
+struct AuthenticatedDevice(String);
+
+/// The ID of the device behind a request, when it sent a valid
+/// `X-Device-Token` along with its `X-Device-Id`
+pub fn current_device(req: &HttpRequest) -> Option<String> {
+    req.extensions()
+        .get::<AuthenticatedDevice>()
+        .map(|device| device.0.clone())
+}
+
+/// The device behind a request, for routes that act on behalf of one
+pub fn require_device(req: &HttpRequest) -> Result<String, AppError> {
+    current_device(req).ok_or_else(|| {
+        AppError::Unauthorized(
+            "Register this device with POST /api/v1/devices and send its X-Device-Id and X-Device-Token headers"
+                .to_string(),
+        )
+    })
+}
+
+/// Refuse requests that don't come from the host itself. Trust levels and
+/// pending pushes decide what other devices may do, so only the machine
+/// running the server may change them, whatever devices can reach the
+/// Admin Panel.
+pub fn require_host(req: &HttpRequest) -> Result<(), AppError> {
+    match req.peer_addr() {
+        Some(addr) if addr.ip().to_canonical().is_loopback() => Ok(()),
+        _ => Err(AppError::Forbidden(
+            "Only the host running the server can do this; open the Admin Panel there".to_string(),
+        )),
+    }
+}
+
+/// Middleware (for `App::wrap_fn`) recognising requests from registered
+/// devices by their token, keeping their last-seen times current and
+/// refusing those from blocked devices. Requests without a valid token are
+/// served as if they named no device.
+pub fn track_device<S>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<BoxBody>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error>,
+{
+    let (Some(id), name) = device_headers(req.request()) else {
+        return Either::Left(srv.call(req));
+    };
+    let Some(token) = header_value(req.request(), "X-Device-Token") else {
+        return Either::Left(srv.call(req));
+    };
+    let platform = req
+        .headers()
+        .get(USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+
+    let device = crate::services::devices::authenticate(&id, &token).and_then(|device| match device {
+        Some(_) => crate::services::devices::touch(&id, name.as_deref(), platform.as_deref()).map(Some),
+        None => Ok(None),
+    });
+    match device {
+        Ok(Some(device)) if device.trust == TrustLevel::Blocked => {
+            let error = AppError::Forbidden("This device is blocked".to_string());
+            Either::Right(future::ready(Ok(req.into_response(error.error_response()))))
+        }
+        Ok(Some(device)) => {
+            req.extensions_mut().insert(AuthenticatedDevice(device.id));
+            Either::Left(srv.call(req))
+        }
+        Ok(None) => {
+            tracing::debug!(device = %id, "Ignoring device with a wrong token");
+            Either::Left(srv.call(req))
+        }
+        Err(e) => {
+            // Not knowing who sent it is no reason to fail the request
+            tracing::error!(device = %id, "Failed to record device: {}", e);
+            Either::Left(srv.call(req))
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::config::{current_config, expand_path, is_writable, StorageBackend};
+use crate::error::AppError;
+use crate::metrics;
+use crate::routes::ws::ClipboardState;
+use crate::storage;
+
+#[derive(Debug, Serialize)]
+struct HealthResponse {
+    status: String,
+    checks: BTreeMap<String, String>,
+}
+
+// Monitoring endpoints live at the root so scrapers and probes don't need
+// to know the app layout
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(metrics_endpoint)
+        .service(healthz)
+        .service(readyz);
+}
+
+#[get("/metrics")]
+async fn metrics_endpoint(
+    clipboard_state: web::Data<ClipboardState>,
+) -> Result<HttpResponse, AppError> {
+    // Gauges that are cheaper to compute on scrape than to keep up to date
+    metrics::WEBSOCKET_CLIENTS.set(clipboard_state.client_count() as i64);
+
+    let files = storage::current()?.list().await.unwrap_or_default();
+    let bytes: u64 = files.iter().map(|file| file.size).sum();
+    metrics::UPLOAD_FOLDER_FILES.set(files.len() as i64);
+    metrics::UPLOAD_FOLDER_BYTES.set(bytes as i64);
+
+    let body = metrics::render()
+        .map_err(|e| AppError::Internal(format!("Failed to render metrics: {}", e)))?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
+
+/// Liveness: the process is up and serving requests
+#[get("/healthz")]
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(HealthResponse {
+        status: "ok".to_string(),
+        checks: BTreeMap::new(),
+    })
+}
+
+/// Readiness: the folders and storage the server depends on are usable
+#[get("/readyz")]
+async fn readyz() -> HttpResponse {
+    // Readiness reports problems in its body rather than as an error envelope
+    let Ok(config) = current_config() else {
+        return HttpResponse::ServiceUnavailable().json(HealthResponse {
+            status: "unavailable".to_string(),
+            checks: BTreeMap::from([("config".to_string(), "unavailable".to_string())]),
+        });
+    };
+    let (upload_folder, audio_folders) = (config.upload_folder, config.audio_folders);
+
+    let mut checks = web::block(move || {
+        let mut checks = BTreeMap::new();
+        checks.insert(
+            "upload_folder".to_string(),
+            check_folder(&expand_path(&upload_folder), true),
+        );
+        for folder in audio_folders {
+            let status = check_folder(&expand_path(&folder), false);
+            checks.insert(format!("audio_folder:{}", folder), status);
+        }
+        checks
+    })
+    .await
+    .unwrap_or_default();
+    if config.storage.backend != StorageBackend::Local {
+        let status = match storage::current() {
+            Ok(storage) => match storage.list().await {
+                Ok(_) => "ok".to_string(),
+                Err(e) => format!("unavailable: {}", e),
+            },
+            Err(e) => format!("unavailable: {}", e),
+        };
+        checks.insert("storage".to_string(), status);
+    }
+
+    let ready = !checks.is_empty() && checks.values().all(|status| status == "ok");
+    let body = HealthResponse {
+        status: if ready { "ok" } else { "unavailable" }.to_string(),
+        checks,
+    };
+
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+fn check_folder(path: &Path, writable: bool) -> String {
+    if let Err(e) = fs::read_dir(path) {
+        return format!("not readable: {}", e);
+    }
+    if writable && !is_writable(path) {
+        return "not writable".to_string();
+    }
+    "ok".to_string()
+}
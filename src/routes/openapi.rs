@@ -0,0 +1,51 @@
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::routes::{admin, api, devices, e2e, events, federation, inbox, streaming, sync};
+
+/// The OpenAPI document for `/api/v1`, assembled from each route module's
+/// handler annotations
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "noplacelike",
+        description = "Share clipboard, files, and audio across devices on your local network"
+    ),
+    servers((url = "/api/v1")),
+    nest(
+        (path = "/stream", api = streaming::ApiDoc),
+        (path = "/admin", api = admin::ApiDoc),
+    ),
+    tags(
+        (name = "clipboard", description = "The shared clipboard"),
+        (name = "files", description = "Uploads, downloads and pushes to the host"),
+        (name = "audio", description = "Audio streaming"),
+        (name = "devices", description = "The devices that have used this server"),
+        (name = "inbox", description = "Texts, links and files sent to one device"),
+        (name = "e2e", description = "Keys for end-to-end encryption between paired devices"),
+        (name = "events", description = "Server-Sent Events and long polling for clients without WebSockets"),
+        (name = "sync", description = "Folders kept in sync with directories on devices"),
+        (name = "federation", description = "Links with other noplacelike servers on the LAN"),
+        (name = "admin", description = "Configuration, push approvals, and device and server trust"),
+    )
+)]
+pub struct ApiDoc;
+
+/// The full document. Top-level API routes are merged rather than nested,
+/// since they sit directly under the server URL.
+pub fn document() -> utoipa::openapi::OpenApi {
+    ApiDoc::openapi()
+        .merge_from(api::ApiDoc::openapi())
+        .merge_from(devices::ApiDoc::openapi())
+        .merge_from(inbox::ApiDoc::openapi())
+        .merge_from(e2e::ApiDoc::openapi())
+        .merge_from(events::ApiDoc::openapi())
+        .merge_from(federation::ApiDoc::openapi())
+        .merge_from(sync::ApiDoc::openapi())
+}
+
+/// Serves the document at `/api/v1/openapi.json` and an interactive viewer
+/// at `/api/v1/docs/`
+pub fn swagger_ui() -> SwaggerUi {
+    SwaggerUi::new("/api/v1/docs/{_:.*}").url("/api/v1/openapi.json", document())
+}
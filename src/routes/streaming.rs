@@ -1,46 +1,65 @@
-use actix_web::{get, web, Error, HttpResponse, Scope};
+use actix_files::NamedFile;
+use actix_web::body::BoxBody;
+use actix_web::{get, web, HttpRequest, HttpResponse};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs::{self, File};
-use std::io::Read;
-use std::path::{Path, PathBuf};
+use utoipa::{IntoParams, OpenApi, ToSchema};
 
 use crate::config::get_audio_folders;
+use crate::error::{AppError, ErrorResponse};
+use crate::metrics::{self, GaugeGuard, MeteredBody};
+use crate::rate_limit::{self, Bandwidth, SlotKind};
+use crate::throttle::{ThrottleClass, ThrottledBody};
+use crate::services::limits::{self, TransferKind};
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 struct AudioFilesResponse {
+    /// File names keyed by the folder they are in
     files: HashMap<String, Vec<String>>,
 }
 
-#[derive(Debug, Serialize)]
-struct ErrorResponse {
-    error: String,
-}
-
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 struct StreamQueryParams {
+    /// Name of the file, in any of the audio folders
     file: String,
 }
 
-// Create streaming scope
-pub fn stream_scope() -> Scope {
-    web::scope("/stream")
-        .service(list_audio)
-        .service(stream_audio)
+#[derive(OpenApi)]
+#[openapi(paths(list_audio, stream_audio))]
+pub struct ApiDoc;
+
+// Register streaming routes, under both `/api/v1/stream` and the legacy `/stream`
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_audio).service(stream_audio);
 }
 
+/// List the files in every audio folder
+#[utoipa::path(
+    tag = "audio",
+    responses(
+        (status = 200, body = AudioFilesResponse),
+        (status = 500, body = ErrorResponse),
+    )
+)]
 #[get("/list")]
-async fn list_audio() -> Result<HttpResponse, Error> {
-    let audio_folders = get_audio_folders();
+async fn list_audio() -> Result<HttpResponse, AppError> {
+    let files_by_dir = web::block(list_audio_folders).await??;
+    
+    Ok(HttpResponse::Ok().json(AudioFilesResponse { files: files_by_dir }))
+}
+
+fn list_audio_folders() -> Result<HashMap<String, Vec<String>>, AppError> {
+    let audio_folders = get_audio_folders()?;
     let mut files_by_dir = HashMap::new();
     
     for folder in audio_folders {
         if let Some(folder_str) = folder.to_str() {
             let mut files = Vec::new();
             
-            if let Ok(entries) = fs::read_dir(&folder) {
-                for entry in entries {
-                    if let Ok(entry) = entry {
+            match folder.read_dir() {
+                Ok(entries) => {
+                    for entry in entries.flatten() {
                         if let Ok(file_type) = entry.file_type() {
                             if file_type.is_file() {
                                 if let Some(file_name) = entry.file_name().to_str() {
@@ -50,70 +69,69 @@ async fn list_audio() -> Result<HttpResponse, Error> {
                         }
                     }
                 }
+                // One unreadable folder shouldn't hide the others
+                Err(e) => tracing::warn!(path = %folder.display(), "Failed to read audio folder: {}", e),
             }
             
             files_by_dir.insert(folder_str.to_string(), files);
         }
     }
     
-    Ok(HttpResponse::Ok().json(AudioFilesResponse { files: files_by_dir }))
+    Ok(files_by_dir)
 }
 
+/// Stream an audio file. Range requests are supported for seeking.
+#[utoipa::path(
+    tag = "audio",
+    params(StreamQueryParams),
+    responses(
+        (status = 200, description = "The audio data", content_type = "audio/*"),
+        (status = 206, description = "The requested range of the audio data", content_type = "audio/*"),
+        (status = 404, body = ErrorResponse),
+        (status = 429, description = "This device has too many streams open or is over its bandwidth budget", body = ErrorResponse),
+        (status = 503, description = "Too many downloads in progress", body = ErrorResponse),
+    )
+)]
 #[get("/play")]
-async fn stream_audio(query: web::Query<StreamQueryParams>) -> Result<HttpResponse, Error> {
-    let file_name = &query.file;
-    let audio_folders = get_audio_folders();
+async fn stream_audio(
+    req: HttpRequest,
+    query: web::Query<StreamQueryParams>,
+) -> Result<HttpResponse, AppError> {
+    let file_name = sanitize_filename::sanitize(&query.file);
     
     // Find the file in one of the audio folders
-    let mut file_path = None;
-    for folder in audio_folders {
-        let path = folder.join(file_name);
-        if path.exists() {
-            file_path = Some(path);
-            break;
-        }
-    }
+    let path = web::block(move || {
+        Ok::<_, AppError>(
+            get_audio_folders()?
+                .into_iter()
+                .map(|folder| folder.join(&file_name))
+                .find(|path| path.is_file()),
+        )
+    })
+    .await??
+    .ok_or_else(|| AppError::NotFound("File not found".to_string()))?;
     
-    // If file found, stream it
-    if let Some(path) = file_path {
-        match read_file_content(&path) {
-            Ok(content) => {
-                // Get content type based on extension
-                let content_type = get_content_type(&path);
-                
-                return Ok(HttpResponse::Ok()
-                    .content_type(content_type)
-                    .body(content));
-            }
-            Err(e) => {
-                return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
-                    error: format!("Failed to read file: {}", e),
-                }));
-            }
-        }
-    }
+    let file = NamedFile::open_async(&path).await?;
+    let client_slot = rate_limit::acquire(&req, SlotKind::Stream)?;
+    let bandwidth = Bandwidth::start(&req)?;
+    let slot = limits::acquire(TransferKind::Download)?;
     
-    Ok(HttpResponse::NotFound().json(ErrorResponse {
-        error: "File not found".to_string(),
-    }))
-}
-
-fn read_file_content(path: &PathBuf) -> std::io::Result<Vec<u8>> {
-    let mut file = File::open(path)?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
-    Ok(buffer)
-}
-
-fn get_content_type(path: &PathBuf) -> String {
-    if let Some(extension) = path.extension() {
-        match extension.to_str() {
-            Some("mp3") => return "audio/mpeg".to_string(),
-            Some("wav") => return "audio/wav".to_string(),
-            Some("ogg") => return "audio/ogg".to_string(),
-            Some("flac") => return "audio/flac".to_string(),
-            _ => {}
-        }
-    }
-    "application/octet-stream".to_string()
+    // The content type is guessed from the extension. Count the stream as
+    // active until the body has been sent; the file is read in chunks off the
+    // worker thread as the client consumes it.
+    Ok(file
+        .disable_content_disposition()
+        .into_response(&req)
+        .map_body(|_, body| {
+            BoxBody::new(
+                MeteredBody::new(
+                    BoxBody::new(ThrottledBody::new(body, ThrottleClass::Stream)),
+                    &metrics::STREAMED_BYTES,
+                    Some(GaugeGuard::new(&metrics::ACTIVE_AUDIO_STREAMS)),
+                )
+                .on_chunk(move |len| bandwidth.charge(len))
+                .hold(slot)
+                .hold(client_slot),
+            )
+        }))
 }
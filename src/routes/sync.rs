@@ -0,0 +1,237 @@
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse};
+use futures::StreamExt;
+use serde::Deserialize;
+use utoipa::{IntoParams, OpenApi};
+
+use crate::error::{AppError, ErrorResponse};
+use crate::rate_limit::Bandwidth;
+use crate::routes::require_device;
+use crate::services::quota::{Destination, UploadBudget};
+use crate::services::sync::{self, Commit};
+use crate::sync::{ChunkList, CommitRequest, FileEntry, FolderIndex, MAX_CHUNK_SIZE};
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct IndexQuery {
+    /// Only list files changed after this sequence number
+    #[serde(default)]
+    since: u64,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DeleteQuery {
+    /// Path of the file inside the folder
+    path: String,
+    /// Version the file was deleted at; refused if it changed since
+    base_version: u64,
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(
+    get_index,
+    find_missing,
+    upload_chunk,
+    download_chunk,
+    commit_file,
+    delete_file
+))]
+pub struct ApiDoc;
+
+// Register folder sync routes under `/api/v1`
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_index)
+        .service(find_missing)
+        .service(upload_chunk)
+        .service(download_chunk)
+        .service(commit_file)
+        .service(delete_file);
+}
+
+fn commit_response(commit: Commit) -> HttpResponse {
+    match commit {
+        Commit::Done(entry) => HttpResponse::Ok().json(entry),
+        Commit::Conflict(entry) => HttpResponse::Conflict().json(entry),
+    }
+}
+
+/// List the files in a synced folder that changed after `since`, deleted
+/// ones included. The folder is created on first use.
+#[utoipa::path(
+    tag = "sync",
+    params(("folder" = String, Path, description = "Folder name"), IndexQuery),
+    responses(
+        (status = 200, body = FolderIndex),
+        (status = 400, description = "Invalid folder name", body = ErrorResponse),
+        (status = 401, description = "This device isn't registered, or its token is wrong", body = ErrorResponse),
+        (status = 403, description = "Folder sync is off", body = ErrorResponse),
+    )
+)]
+#[get("/sync/{folder}")]
+async fn get_index(
+    req: HttpRequest,
+    folder: web::Path<String>,
+    query: web::Query<IndexQuery>,
+) -> Result<HttpResponse, AppError> {
+    require_device(&req)?;
+    let since = query.since;
+    let index = web::block(move || sync::index(&folder, since)).await??;
+    Ok(HttpResponse::Ok().json(index))
+}
+
+/// Find which of the given chunks the server still needs
+#[utoipa::path(
+    tag = "sync",
+    params(("folder" = String, Path, description = "Folder name")),
+    request_body = ChunkList,
+    responses(
+        (status = 200, description = "The chunks to upload", body = ChunkList),
+        (status = 401, description = "This device isn't registered, or its token is wrong", body = ErrorResponse),
+        (status = 403, description = "Folder sync is off", body = ErrorResponse),
+    )
+)]
+#[post("/sync/{folder}/missing")]
+async fn find_missing(
+    http_req: HttpRequest,
+    folder: web::Path<String>,
+    req: web::Json<ChunkList>,
+) -> Result<HttpResponse, AppError> {
+    require_device(&http_req)?;
+    let hashes = req.into_inner().hashes;
+    let hashes = web::block(move || sync::missing(&folder, hashes)).await??;
+    Ok(HttpResponse::Ok().json(ChunkList { hashes }))
+}
+
+/// Upload a chunk for a later commit. The body is the raw chunk, which has to
+/// match its SHA-256. Chunks count against the quotas until they're used.
+#[utoipa::path(
+    tag = "sync",
+    params(
+        ("folder" = String, Path, description = "Folder name"),
+        ("hash" = String, Path, description = "SHA-256 of the chunk, in hex"),
+    ),
+    request_body(content_type = "application/octet-stream"),
+    responses(
+        (status = 204, description = "The chunk was stored"),
+        (status = 400, description = "The chunk doesn't match its hash", body = ErrorResponse),
+        (status = 401, description = "This device isn't registered, or its token is wrong", body = ErrorResponse),
+        (status = 413, description = "The chunk is too large, or a quota is used up", body = ErrorResponse),
+        (status = 507, description = "Not enough free disk space on the host", body = ErrorResponse),
+    )
+)]
+#[put("/sync/{folder}/chunks/{hash}")]
+async fn upload_chunk(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    mut payload: web::Payload,
+) -> Result<HttpResponse, AppError> {
+    let device = require_device(&req)?;
+    let bandwidth = Bandwidth::start(&req)?;
+    let mut data = Vec::new();
+    while let Some(bytes) = payload.next().await {
+        let bytes = bytes.map_err(|e| AppError::BadRequest(e.to_string()))?;
+        if data.len() + bytes.len() > MAX_CHUNK_SIZE {
+            return Err(AppError::PayloadTooLarge(format!(
+                "Chunks are at most {} bytes",
+                MAX_CHUNK_SIZE
+            )));
+        }
+        bandwidth.charge(bytes.len() as u64);
+        data.extend_from_slice(&bytes);
+    }
+    let hash = path.into_inner().1;
+    let dir = web::block(sync::chunk_dir).await??;
+    let size = Some(data.len() as u64);
+    let budget = UploadBudget::new(&dir, &hash, Some(&device), Destination::Sync, size).await?;
+    web::block(move || sync::stage_chunk(&hash, &data)).await??;
+    drop(budget);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Download a chunk of one of the folder's files
+#[utoipa::path(
+    tag = "sync",
+    params(
+        ("folder" = String, Path, description = "Folder name"),
+        ("hash" = String, Path, description = "SHA-256 of the chunk, in hex"),
+    ),
+    responses(
+        (status = 200, description = "The raw chunk", content_type = "application/octet-stream"),
+        (status = 401, description = "This device isn't registered, or its token is wrong", body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+    )
+)]
+#[get("/sync/{folder}/chunks/{hash}")]
+async fn download_chunk(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, AppError> {
+    require_device(&req)?;
+    let bandwidth = Bandwidth::start(&req)?;
+    let (folder, hash) = path.into_inner();
+    let data = web::block(move || sync::read_chunk(&folder, &hash)).await??;
+    bandwidth.charge(data.len() as u64);
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .body(data))
+}
+
+/// Create or replace a file from chunks the server has. Refused with the
+/// file as it is now when it changed since `base_version`.
+#[utoipa::path(
+    tag = "sync",
+    params(("folder" = String, Path, description = "Folder name")),
+    request_body = CommitRequest,
+    responses(
+        (status = 200, description = "The file as written", body = FileEntry),
+        (status = 400, description = "Invalid path, or a chunk hasn't been uploaded", body = ErrorResponse),
+        (status = 401, description = "This device isn't registered, or its token is wrong", body = ErrorResponse),
+        (status = 409, description = "The file changed since `base_version`", body = FileEntry),
+        (status = 413, description = "The file is over the size limit, or a quota is used up", body = ErrorResponse),
+        (status = 507, description = "Not enough free disk space on the host", body = ErrorResponse),
+    )
+)]
+#[put("/sync/{folder}/files")]
+async fn commit_file(
+    http_req: HttpRequest,
+    folder: web::Path<String>,
+    req: web::Json<CommitRequest>,
+) -> Result<HttpResponse, AppError> {
+    let device = require_device(&http_req)?;
+    let request = req.into_inner();
+    let folder = folder.into_inner();
+    let name = folder.clone();
+    let dir = web::block(move || sync::folder_dir(&name)).await??;
+
+    let name = sync::stored_name(&folder, &request.path);
+    let size = request.chunks.iter().map(|c| c.size).sum();
+    let staged = request.chunks.iter().map(|c| c.hash.clone()).collect();
+    let budget = UploadBudget::sync_commit(&dir, &name, &device, size, staged).await?;
+    let commit = web::block(move || sync::commit(&folder, request, &device)).await??;
+    drop(budget);
+    Ok(commit_response(commit))
+}
+
+/// Delete a file. Refused with the file as it is now when it changed since
+/// `base_version`.
+#[utoipa::path(
+    tag = "sync",
+    params(("folder" = String, Path, description = "Folder name"), DeleteQuery),
+    responses(
+        (status = 200, description = "The deleted file's tombstone", body = FileEntry),
+        (status = 401, description = "This device isn't registered, or its token is wrong", body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 409, description = "The file changed since `base_version`", body = FileEntry),
+    )
+)]
+#[delete("/sync/{folder}/files")]
+async fn delete_file(
+    req: HttpRequest,
+    folder: web::Path<String>,
+    query: web::Query<DeleteQuery>,
+) -> Result<HttpResponse, AppError> {
+    require_device(&req)?;
+    let DeleteQuery { path, base_version } = query.into_inner();
+    let commit = web::block(move || sync::delete(&folder, &path, base_version)).await??;
+    Ok(commit_response(commit))
+}
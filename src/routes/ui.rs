@@ -1,6 +1,8 @@
 use actix_web::{get, web, HttpResponse, Scope};
 
+use crate::error::AppError;
 use crate::templates;
+use crate::wormhole;
 
 // Create UI scope
 pub fn ui_scope() -> Scope {
@@ -9,8 +11,10 @@ pub fn ui_scope() -> Scope {
 }
 
 #[get("/")]
-async fn home() -> HttpResponse {
-    let template = templates::HomeTemplate {};
+async fn home() -> Result<HttpResponse, AppError> {
+    let template = templates::HomeTemplate {
+        wormhole_words: wormhole::WORDS.join(" "),
+    };
     templates::render_template(&template)
 }
 
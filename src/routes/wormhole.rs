@@ -0,0 +1,259 @@
+//! Relay for code-phrase transfers; see [`crate::wormhole`] for the protocol.
+//!
+//! A sender connects without a nameplate and is given the smallest free one.
+//! A receiver connects with it, which takes it off the list, so each code
+//! gets exactly one guess. From then on every frame from one side is passed
+//! to the other as it is; the relay can't read them and keeps nothing.
+
+use actix::{Actor, ActorContext, Addr, AsyncContext, Handler, Message, StreamHandler};
+use actix_web::web::Bytes;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::{lock_recovering, AppError};
+use crate::rate_limit::{self, Bandwidth, ClientSlot, SlotKind};
+use crate::routes::ws::{close_for_shutdown, session_span, NEXT_CLIENT_ID};
+use crate::shutdown::{self, ShutdownNotice};
+use crate::wormhole::{RelayEvent, CHUNK_SIZE};
+
+/// How long a sender waits for its receiver before the code expires
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Largest frame relayed: a file chunk plus its tag, with room to spare
+const MAX_FRAME_SIZE: usize = 2 * CHUNK_SIZE;
+
+lazy_static::lazy_static! {
+    // Senders waiting for a receiver, by nameplate
+    static ref WAITING: Mutex<BTreeMap<u32, Addr<WormholeSession>>> = Mutex::new(BTreeMap::new());
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WormholeQuery {
+    /// The nameplate from the code, when receiving
+    nameplate: Option<u32>,
+}
+
+/// Tells a waiting sender its receiver has arrived
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Paired(Addr<WormholeSession>);
+
+/// A frame from the other side
+#[derive(Message)]
+#[rtype(result = "()")]
+enum Relay {
+    Text(String),
+    Binary(Bytes),
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct PeerLeft;
+
+struct WormholeSession {
+    /// Set for a sender until it is claimed
+    nameplate: Option<u32>,
+    peer: Option<Addr<WormholeSession>>,
+    last_heartbeat: Instant,
+    bandwidth: Bandwidth,
+    span: tracing::Span,
+    _slot: ClientSlot,
+}
+
+impl WormholeSession {
+    fn send_event(&self, event: &RelayEvent, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.text(serde_json::to_string(event).unwrap_or_default());
+    }
+
+    fn relay(&self, frame: Relay, len: usize) {
+        match &self.peer {
+            Some(peer) => {
+                self.bandwidth.charge(len as u64);
+                peer.do_send(frame);
+            }
+            None => tracing::debug!("Dropping a frame sent before the peer joined"),
+        }
+    }
+}
+
+impl Actor for WormholeSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let _enter = self.span.enter();
+        ctx.add_stream(shutdown::notice_stream());
+        ctx.run_interval(Duration::from_secs(15), |act, ctx| {
+            if Instant::now().duration_since(act.last_heartbeat) > Duration::from_secs(30) {
+                act.span
+                    .in_scope(|| tracing::info!("Wormhole client timed out"));
+                ctx.stop();
+                return;
+            }
+            ctx.ping(b"");
+        });
+
+        match self.nameplate {
+            Some(nameplate) => {
+                tracing::info!(nameplate, "Wormhole opened");
+                let event = RelayEvent::Allocated {
+                    nameplate: nameplate.to_string(),
+                };
+                self.send_event(&event, ctx);
+                ctx.run_later(CLAIM_TIMEOUT, |act, ctx| {
+                    if act.peer.is_none() {
+                        act.span
+                            .in_scope(|| tracing::info!("Wormhole code expired"));
+                        ctx.close(Some(ws::CloseReason {
+                            code: ws::CloseCode::Normal,
+                            description: Some("The code expired".to_string()),
+                        }));
+                        ctx.stop();
+                    }
+                });
+            }
+            None => {
+                tracing::info!("Wormhole claimed");
+                self.send_event(&RelayEvent::PeerJoined, ctx);
+            }
+        }
+    }
+
+    fn stopping(&mut self, ctx: &mut Self::Context) -> actix::Running {
+        let _enter = self.span.enter();
+        if let Some(nameplate) = self.nameplate.take() {
+            let mut waiting = lock_recovering(&WAITING);
+            if waiting.get(&nameplate) == Some(&ctx.address()) {
+                waiting.remove(&nameplate);
+            }
+        }
+        if let Some(peer) = self.peer.take() {
+            peer.do_send(PeerLeft);
+        }
+        tracing::info!("Wormhole client disconnected");
+        actix::Running::Stop
+    }
+}
+
+impl Handler<Paired> for WormholeSession {
+    type Result = ();
+
+    fn handle(&mut self, Paired(peer): Paired, ctx: &mut Self::Context) {
+        self.nameplate = None;
+        self.peer = Some(peer);
+        self.send_event(&RelayEvent::PeerJoined, ctx);
+    }
+}
+
+impl Handler<Relay> for WormholeSession {
+    type Result = ();
+
+    fn handle(&mut self, frame: Relay, ctx: &mut Self::Context) {
+        match frame {
+            Relay::Text(text) => ctx.text(text),
+            Relay::Binary(bytes) => ctx.binary(bytes),
+        }
+    }
+}
+
+impl Handler<PeerLeft> for WormholeSession {
+    type Result = ();
+
+    fn handle(&mut self, _: PeerLeft, ctx: &mut Self::Context) {
+        self.peer = None;
+        self.send_event(&RelayEvent::PeerLeft, ctx);
+        ctx.close(Some(ws::CloseCode::Normal.into()));
+        ctx.stop();
+    }
+}
+
+impl StreamHandler<ShutdownNotice> for WormholeSession {
+    fn handle(&mut self, _: ShutdownNotice, ctx: &mut Self::Context) {
+        close_for_shutdown(ctx);
+    }
+
+    fn finished(&mut self, _: &mut Self::Context) {}
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WormholeSession {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let _enter = self.span.clone().entered();
+        match msg {
+            Ok(ws::Message::Ping(msg)) => {
+                self.last_heartbeat = Instant::now();
+                ctx.pong(&msg);
+            }
+            Ok(ws::Message::Pong(_)) => {
+                self.last_heartbeat = Instant::now();
+            }
+            Ok(ws::Message::Text(text)) => {
+                let len = text.len();
+                self.relay(Relay::Text(text.to_string()), len);
+            }
+            Ok(ws::Message::Binary(bytes)) => {
+                let len = bytes.len();
+                self.relay(Relay::Binary(bytes), len);
+            }
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Err(e) => {
+                tracing::debug!("Wormhole protocol error: {}", e);
+                ctx.stop();
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Open a wormhole as the sender, or join one as the receiver by passing
+/// `nameplate`
+pub async fn wormhole_ws(
+    req: HttpRequest,
+    stream: web::Payload,
+    query: web::Query<WormholeQuery>,
+) -> Result<HttpResponse, Error> {
+    let slot = rate_limit::acquire(&req, SlotKind::WebSocket)?;
+    let bandwidth = Bandwidth::start(&req)?;
+    let client_id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut session = WormholeSession {
+        nameplate: None,
+        peer: None,
+        last_heartbeat: Instant::now(),
+        bandwidth,
+        span: session_span("wormhole", client_id, None, &req),
+        _slot: slot,
+    };
+
+    // Holding the lock until the session is registered keeps two senders
+    // from getting the same nameplate
+    let mut waiting = lock_recovering(&WAITING);
+    match query.nameplate {
+        Some(nameplate) => {
+            let sender = waiting.remove(&nameplate).ok_or_else(|| {
+                AppError::NotFound("No one is sending with that code".to_string())
+            })?;
+            drop(waiting);
+            session.peer = Some(sender.clone());
+            let (addr, response) = ws::WsResponseBuilder::new(session, &req, stream)
+                .frame_size(MAX_FRAME_SIZE)
+                .start_with_addr()?;
+            sender.do_send(Paired(addr));
+            Ok(response)
+        }
+        None => {
+            let nameplate = (1..).find(|n| !waiting.contains_key(n)).unwrap_or_default();
+            session.nameplate = Some(nameplate);
+            let (addr, response) = ws::WsResponseBuilder::new(session, &req, stream)
+                .frame_size(MAX_FRAME_SIZE)
+                .start_with_addr()?;
+            waiting.insert(nameplate, addr);
+            Ok(response)
+        }
+    }
+}
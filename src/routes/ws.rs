@@ -1,17 +1,29 @@
-use actix::{Actor, ActorContext, AsyncContext, Handler, StreamHandler};
+use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix_web::http::header::USER_AGENT;
 use actix_web::{web, Error, HttpRequest, HttpResponse, Scope};
 use actix_web_actors::ws;
+use futures::Stream;
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+use crate::config::current_config;
+use crate::error::{lock_recovering, AppError};
+use crate::rate_limit::{self, ClientSlot, SlotKind};
+use crate::routes::{current_device, wormhole};
+use crate::services::devices::{self, Presence, TrustLevel};
+use crate::services::events::{self, BusEvent, ClipboardEvent, Event, Topic};
+use crate::services::transfers::{self, TransferEvent};
+use crate::shutdown::{self, ShutdownNotice};
 
 // Static counter for connected clients
-static NEXT_CLIENT_ID: AtomicUsize = AtomicUsize::new(1);
+pub(crate) static NEXT_CLIENT_ID: AtomicUsize = AtomicUsize::new(1);
 
 // Connection ID type
-type ClientId = usize;
+pub(crate) type ClientId = usize;
 
 // Shared state for all WebSocket connections
 #[derive(Clone, Debug)]
@@ -28,34 +40,36 @@ impl ClipboardState {
         }
     }
 
-    pub fn update_content(&self, content: &str) -> Vec<ClientId> {
-        let mut content_guard = self.content.lock().unwrap();
+    /// Store new content and pass it to every session, the sender's included
+    pub fn update_content(&self, content: &str) {
+        let mut content_guard = lock_recovering(&self.content);
         *content_guard = content.to_string();
-
-        // Return all connected clients (to broadcast to them)
-        let clients = self.clients.lock().unwrap();
-        clients.iter().copied().collect()
+        events::publish(ClipboardEvent::Shared(content.to_string()));
     }
 
     pub fn register_client(&self, client_id: ClientId) -> String {
         // Add client to connected clients
         {
-            let mut clients = self.clients.lock().unwrap();
+            let mut clients = lock_recovering(&self.clients);
             clients.insert(client_id);
         }
 
         // Return current clipboard content
-        let content = self.content.lock().unwrap();
+        let content = lock_recovering(&self.content);
         content.clone()
     }
 
     pub fn unregister_client(&self, client_id: ClientId) {
-        let mut clients = self.clients.lock().unwrap();
+        let mut clients = lock_recovering(&self.clients);
         clients.remove(&client_id);
     }
 
+    pub fn client_count(&self) -> usize {
+        lock_recovering(&self.clients).len()
+    }
+
     pub fn get_current_content(&self) -> String {
-        let content = self.content.lock().unwrap();
+        let content = lock_recovering(&self.content);
         content.clone()
     }
 }
@@ -65,6 +79,28 @@ struct WsClipboardSession {
     id: ClientId,
     last_heartbeat: Instant,
     clipboard_state: ClipboardState,
+    // Lives as long as the session, so closing it logs the session's duration
+    span: tracing::Span,
+    // Counts against the client's WebSocket limit until the session ends
+    _slot: ClientSlot,
+    // Shows the device as online until the session ends
+    _presence: Option<Presence>,
+    // The device behind the session, whose inbox notifications it gets
+    device: Option<String>,
+    // What the client wants to hear about
+    topics: HashSet<Topic>,
+}
+
+/// Identifies the device behind a WebSocket, since browsers can't set
+/// headers on one
+#[derive(Debug, Deserialize)]
+pub struct DeviceQuery {
+    device_id: Option<String>,
+    /// The token the device was given when it registered
+    device_token: Option<String>,
+    device_name: Option<String>,
+    /// Comma-separated topics to subscribe to instead of the defaults
+    topics: Option<String>,
 }
 
 // Message types for WebSocket communication
@@ -75,26 +111,42 @@ enum WsMessage {
     Clipboard(String),
     Sync,
     Heartbeat,
+    /// Start getting events for these topics
+    Subscribe(Vec<Topic>),
+    /// Stop getting events for these topics
+    Unsubscribe(Vec<Topic>),
+    /// Sent back after a change, with every topic now subscribed to
+    Subscribed(Vec<Topic>),
 }
 
 impl Actor for WsClipboardSession {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        let _enter = self.span.enter();
+        tracing::info!("Clipboard client connected");
+
         // Schedule regular heartbeat checks
         self.heartbeat(ctx);
+        ctx.add_stream(shutdown::notice_stream());
+        ctx.add_stream(broadcast_stream(events::subscribe()));
 
         // Register this client
         let current_content = self.clipboard_state.register_client(self.id);
 
         // Send current clipboard content
-        ctx.text(
-            serde_json::to_string(&WsMessage::Clipboard(current_content))
-                .unwrap_or_else(|_| String::from("{\"type\":\"error\"}")),
-        );
+        if self.topics.contains(&Topic::Clipboard) {
+            ctx.text(
+                serde_json::to_string(&WsMessage::Clipboard(current_content))
+                    .unwrap_or_else(|_| String::from("{\"type\":\"error\"}")),
+            );
+        }
     }
 
     fn stopping(&mut self, _: &mut Self::Context) -> actix::Running {
+        let _enter = self.span.enter();
+        tracing::info!("Clipboard client disconnected");
+
         // Unregister on disconnect
         self.clipboard_state.unregister_client(self.id);
         actix::Running::Stop
@@ -102,14 +154,33 @@ impl Actor for WsClipboardSession {
 }
 
 impl WsClipboardSession {
-    fn new(clipboard_state: ClipboardState) -> Self {
+    fn new(
+        clipboard_state: ClipboardState,
+        req: &HttpRequest,
+        slot: ClientSlot,
+        presence: Option<Presence>,
+        device: Option<&str>,
+        topics: HashSet<Topic>,
+    ) -> Self {
+        let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
         Self {
-            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            id,
             last_heartbeat: Instant::now(),
             clipboard_state,
+            span: session_span("clipboard", id, device, req),
+            _slot: slot,
+            _presence: presence,
+            device: device.map(str::to_string),
+            topics,
         }
     }
 
+    fn send_topics(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        let mut topics: Vec<Topic> = self.topics.iter().copied().collect();
+        topics.sort_by_key(|t| *t as u8);
+        ctx.text(serde_json::to_string(&WsMessage::Subscribed(topics)).unwrap_or_default());
+    }
+
     // Heartbeat to keep connection alive and detect disconnects
     fn heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
         // Schedule a heartbeat check every 15 seconds
@@ -117,6 +188,8 @@ impl WsClipboardSession {
             // Check if we've received a heartbeat recently
             if Instant::now().duration_since(act.last_heartbeat) > Duration::from_secs(30) {
                 // No recent heartbeat, disconnect
+                let _enter = act.span.enter();
+                tracing::info!("Clipboard client timed out");
                 act.clipboard_state.unregister_client(act.id);
                 ctx.stop();
                 return;
@@ -128,9 +201,34 @@ impl WsClipboardSession {
     }
 }
 
+impl StreamHandler<ShutdownNotice> for WsClipboardSession {
+    fn handle(&mut self, _: ShutdownNotice, ctx: &mut Self::Context) {
+        close_for_shutdown(ctx);
+    }
+
+    fn finished(&mut self, _: &mut Self::Context) {}
+}
+
+// Events on the client's topics, and inbox events for its own device
+impl StreamHandler<Result<BusEvent, Missed>> for WsClipboardSession {
+    fn handle(&mut self, event: Result<BusEvent, Missed>, ctx: &mut Self::Context) {
+        match event {
+            Ok(event) => {
+                if event.visible(&self.topics, self.device.as_deref()) {
+                    ctx.text(serde_json::to_string(&event.event).unwrap_or_default());
+                }
+            }
+            Err(missed) => missed.report(&self.span, ctx),
+        }
+    }
+
+    fn finished(&mut self, _: &mut Self::Context) {}
+}
+
 // Handler for WebSocket messages
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsClipboardSession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let _enter = self.span.clone().entered();
         match msg {
             Ok(ws::Message::Ping(msg)) => {
                 self.last_heartbeat = Instant::now();
@@ -146,15 +244,17 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsClipboardSessio
                         // Client just connected, update last heartbeat
                         self.last_heartbeat = Instant::now();
                     }
+                    Ok(WsMessage::Clipboard(_))
+                        if current_config().map_or(true, |config| config.e2e.enabled) =>
+                    {
+                        // These carry no `encrypted` flag, so with end-to-end
+                        // encryption on they'd be plain text on the server
+                        ctx.text(r#"{"type":"error","data":"End-to-end encryption is on, so clipboard items must be encrypted and sent to POST /api/v1/clipboard"}"#);
+                    }
                     Ok(WsMessage::Clipboard(content)) => {
-                        // Client sent new clipboard content
-                        // Update state and broadcast to all other clients
-                        let clients = self.clipboard_state.update_content(&content);
-                        let msg = serde_json::to_string(&WsMessage::Clipboard(content.clone()))
-                            .unwrap_or_default();
-
-                        // Broadcast to all clients including this one
-                        ctx.text(msg);
+                        // Client sent new clipboard content; every session
+                        // gets it back, this one included
+                        self.clipboard_state.update_content(&content);
                     }
                     Ok(WsMessage::Sync) => {
                         // Client requests current clipboard content
@@ -168,8 +268,22 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsClipboardSessio
                         // Client heartbeat, update timestamp
                         self.last_heartbeat = Instant::now();
                     }
-                    Err(_) => {
+                    Ok(WsMessage::Subscribe(topics)) => {
+                        self.topics.extend(topics);
+                        self.send_topics(ctx);
+                    }
+                    Ok(WsMessage::Unsubscribe(topics)) => {
+                        for topic in topics {
+                            self.topics.remove(&topic);
+                        }
+                        self.send_topics(ctx);
+                    }
+                    Ok(WsMessage::Subscribed(_)) => {
+                        ctx.text(r#"{"type":"error","data":"Subscribed is only sent by the server"}"#);
+                    }
+                    Err(e) => {
                         // Invalid message format
+                        tracing::debug!("Invalid message from client: {}", e);
                         ctx.text(r#"{"type":"error","data":"Invalid message format"}"#);
                     }
                 }
@@ -194,15 +308,221 @@ pub async fn clipboard_ws(
     req: HttpRequest,
     stream: web::Payload,
     clipboard_state: web::Data<ClipboardState>,
+    query: web::Query<DeviceQuery>,
 ) -> Result<HttpResponse, Error> {
+    let topics = Topic::from_query(query.topics.as_deref())?;
+    let slot = rate_limit::acquire(&req, SlotKind::WebSocket)?;
+    let device = streaming_device(&req, query.device_id.as_deref(), query.device_token.as_deref())?;
+    let presence = match device.as_deref() {
+        Some(id) => Some(connect_device(&req, id, query.device_name.as_deref())?),
+        None => None,
+    };
     ws::start(
-        WsClipboardSession::new(clipboard_state.get_ref().clone()),
+        WsClipboardSession::new(
+            clipboard_state.get_ref().clone(),
+            &req,
+            slot,
+            presence,
+            device.as_deref(),
+            topics,
+        ),
         &req,
         stream,
     )
 }
 
+/// The device behind a connection that can't send headers, named by its ID
+/// and token in the query string, or by the headers when it could send them.
+/// An ID without a token names no device.
+pub(crate) fn streaming_device(
+    req: &HttpRequest,
+    id: Option<&str>,
+    token: Option<&str>,
+) -> Result<Option<String>, AppError> {
+    if let Some(id) = current_device(req) {
+        return Ok(Some(id));
+    }
+    let (Some(id), Some(token)) = (
+        id.map(str::trim).filter(|id| !id.is_empty()),
+        token.map(str::trim).filter(|token| !token.is_empty()),
+    ) else {
+        return Ok(None);
+    };
+    match devices::authenticate(id, token)? {
+        Some(device) => Ok(Some(device.id)),
+        None => Err(AppError::Unauthorized("Wrong device token".to_string())),
+    }
+}
+
+/// Record a device opening a WebSocket and mark it online, unless it is
+/// blocked
+pub(crate) fn connect_device(req: &HttpRequest, id: &str, name: Option<&str>) -> Result<Presence, AppError> {
+    let platform = req.headers().get(USER_AGENT).and_then(|v| v.to_str().ok());
+    if devices::touch(id, name, platform)?.trust == TrustLevel::Blocked {
+        return Err(AppError::Forbidden("This device is blocked".to_string()));
+    }
+    devices::connect(id)
+}
+
+// Admin WebSocket session: pushes transfer requests so the admin panel can
+// prompt for them
+struct AdminSession {
+    last_heartbeat: Instant,
+    span: tracing::Span,
+    _slot: ClientSlot,
+}
+
+impl Actor for AdminSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.span.in_scope(|| tracing::info!("Admin client connected"));
+
+        ctx.run_interval(Duration::from_secs(15), |act, ctx| {
+            if Instant::now().duration_since(act.last_heartbeat) > Duration::from_secs(30) {
+                ctx.stop();
+                return;
+            }
+            ctx.ping(b"");
+        });
+
+        // Send anything already waiting, then follow new events
+        let pending = transfers::pending_transfers().unwrap_or_else(|e| {
+            tracing::error!("Failed to list pending transfers: {}", e);
+            Vec::new()
+        });
+        for transfer in pending {
+            let event = TransferEvent::TransferRequest(transfer);
+            ctx.text(serde_json::to_string(&event).unwrap_or_default());
+        }
+
+        ctx.add_stream(broadcast_stream(events::subscribe()));
+        ctx.add_stream(shutdown::notice_stream());
+    }
+}
+
+impl StreamHandler<ShutdownNotice> for AdminSession {
+    fn handle(&mut self, _: ShutdownNotice, ctx: &mut Self::Context) {
+        close_for_shutdown(ctx);
+    }
+
+    fn finished(&mut self, _: &mut Self::Context) {}
+}
+
+// Push requests, and device, peer and file changes without progress reports
+impl StreamHandler<Result<BusEvent, Missed>> for AdminSession {
+    fn handle(&mut self, event: Result<BusEvent, Missed>, ctx: &mut Self::Context) {
+        let event = match event {
+            Ok(event) => event,
+            Err(missed) => return missed.report(&self.span, ctx),
+        };
+        let wanted = match &event.event {
+            Event::Transfer(_) | Event::Device(_) | Event::Peer(_) | Event::Drop(_) => true,
+            Event::File(event) => !event.is_progress(),
+            _ => false,
+        };
+        if wanted {
+            ctx.text(serde_json::to_string(&event.event).unwrap_or_default());
+        }
+    }
+
+    // Keep the socket open even if the event stream ends
+    fn finished(&mut self, _: &mut Self::Context) {}
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for AdminSession {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => {
+                self.last_heartbeat = Instant::now();
+                ctx.pong(&msg);
+            }
+            Ok(ws::Message::Pong(_)) => {
+                self.last_heartbeat = Instant::now();
+            }
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            _ => (),
+        }
+    }
+}
+
+// Admin WebSocket route handler
+pub async fn admin_ws(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
+    let slot = rate_limit::acquire(&req, SlotKind::WebSocket)?;
+    ws::start(
+        AdminSession {
+            last_heartbeat: Instant::now(),
+            span: session_span("admin", NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed), None, &req),
+            _slot: slot,
+        },
+        &req,
+        stream,
+    )
+}
+
+/// Send a close frame telling the client why, then end the session
+pub(crate) fn close_for_shutdown<A>(ctx: &mut ws::WebsocketContext<A>)
+where
+    A: Actor<Context = ws::WebsocketContext<A>>,
+{
+    ctx.close(Some(ws::CloseReason {
+        code: ws::CloseCode::Away,
+        description: Some("Server is shutting down".to_string()),
+    }));
+    ctx.stop();
+}
+
+/// A span covering one WebSocket session, tagged with the peer address and
+/// the device, when known
+pub(crate) fn session_span(
+    kind: &'static str,
+    client_id: ClientId,
+    device: Option<&str>,
+    req: &HttpRequest,
+) -> tracing::Span {
+    let peer = req
+        .connection_info()
+        .realip_remote_addr()
+        .unwrap_or("unknown")
+        .to_string();
+    tracing::info_span!("ws_session", kind, client_id, %peer, device)
+}
+
+/// How many events a session skipped after falling behind the bus
+struct Missed(u64);
+
+impl Missed {
+    /// Tell the client to reload its state, like an event stream that
+    /// can't resume
+    fn report<A>(&self, span: &tracing::Span, ctx: &mut ws::WebsocketContext<A>)
+    where
+        A: Actor<Context = ws::WebsocketContext<A>>,
+    {
+        span.in_scope(|| tracing::warn!(missed = self.0, "Session fell behind, asking it to resync"));
+        ctx.text(r#"{"type":"resync","data":null}"#);
+    }
+}
+
+/// Follow a broadcast channel, reporting how much was skipped whenever it
+/// falls behind
+fn broadcast_stream<T: Clone + Send + 'static>(
+    rx: broadcast::Receiver<T>,
+) -> impl Stream<Item = Result<T, Missed>> {
+    futures::stream::unfold(rx, |mut rx| async move {
+        match rx.recv().await {
+            Ok(event) => Some((Ok(event), rx)),
+            Err(broadcast::error::RecvError::Lagged(missed)) => Some((Err(Missed(missed)), rx)),
+            Err(broadcast::error::RecvError::Closed) => None,
+        }
+    })
+}
+
 // Create WebSocket scope
 pub fn ws_scope() -> Scope {
-    web::scope("/ws").route("/clipboard", web::get().to(clipboard_ws))
+    web::scope("/ws")
+        .route("/clipboard", web::get().to(clipboard_ws))
+        .route("/wormhole", web::get().to(wormhole::wormhole_ws))
 }
@@ -1,41 +1,157 @@
-use actix_web::{web, App, HttpServer};
+use actix_web::dev::Service;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{web, App, HttpMessage, HttpServer};
 use local_ip_address::local_ip;
-use qr2term;
+use tracing_actix_web::{RequestId, TracingLogger};
 use std::io;
 use std::net::IpAddr;
+use std::time::Duration;
 
 use crate::config::Config;
+use crate::error;
+use crate::metrics;
+use crate::rate_limit;
 use crate::routes;
+use crate::services::{devices, drops, federation, files, hooks, transfers, webhooks};
+use crate::shutdown;
+
+/// How often last-seen times are written to the device registry
+const DEVICE_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
 
 pub async fn run_server(host: String, port: u16, config: Config) -> io::Result<()> {
     // Prepare shared data
     let shared_config = web::Data::new(config);
     
     // Create shared clipboard state
-    let clipboard_data = web::Data::new(std::sync::Mutex::new(String::new()));
+    let clipboard_data = web::Data::new(routes::api::ClipboardData::default());
+    let clipboard_state = web::Data::new(routes::ws::ClipboardState::new(String::new()));
     
+    // Make sure the upload folder exists before validating or serving anything.
+    // Failures are logged, and requests needing the folder will report them.
+    let _ = crate::config::ensure_upload_folder();
+    let _ = crate::storage::current();
+
+    metrics::init();
+
+    // Load the device registry now so requests never wait on the disk for
+    // it, then save last-seen times in the background
+    if let Err(e) = devices::flush() {
+        tracing::error!("Failed to load the device registry: {}", e);
+    }
+    // Configs from before the registry listed trusted devices, which move
+    // over before the config is written in the current format
+    devices::import_trusted();
+    if let Err(e) = crate::config::save_migrated_config() {
+        tracing::error!("Failed to save migrated config: {}", e);
+    }
+    actix_rt::spawn(async {
+        let mut interval = actix_rt::time::interval(DEVICE_FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Ok(Err(e)) = web::block(devices::flush).await {
+                tracing::error!("Failed to save the device registry: {}", e);
+            }
+        }
+    });
+
+    // Let incoming pushes be answered from this terminal
+    transfers::spawn_terminal_prompt();
+
+    // Find and keep in touch with other servers, while federation is on
+    federation::spawn();
+
+    // Publish files dropped into the configured drop folders
+    drops::spawn();
+
+    // Pass events on to the configured webhooks
+    webhooks::spawn();
+
+    // Run the configured hook commands for uploads and clipboard changes
+    hooks::spawn();
+
     // Print server URLs and QR codes
     print_server_info(port);
     
+    let shutdown_timeout = shared_config.shutdown_timeout_secs;
+
     // Start HTTP server
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
+            // Recognise registered devices and keep their last-seen times current
+            .wrap_fn(routes::track_device)
+            // Outside the device registry, so refused requests never reach
+            // it, but inside the logging so they are still counted and logged
+            .wrap_fn(rate_limit::limit_requests)
+            // Make other servers prove who they are
+            .wrap_fn(routes::federation::check_peer)
+            // Echo the request ID that tags this request's log lines
+            .wrap_fn(|req, srv| {
+                let request_id = req.extensions().get::<RequestId>().copied();
+                let response = srv.call(req);
+                async move {
+                    let mut response = response.await?;
+                    if let Some(id) = request_id {
+                        if let Ok(value) = HeaderValue::from_str(&id.to_string()) {
+                            response
+                                .headers_mut()
+                                .insert(HeaderName::from_static("x-request-id"), value);
+                        }
+                    }
+                    Ok(response)
+                }
+            })
+            .wrap_fn(|req, srv| metrics::track_request(req, srv))
+            // Open a span per request and log it when the response completes
+            .wrap(TracingLogger::default())
             .app_data(shared_config.clone())
             .app_data(clipboard_data.clone())
-            // Register API routes
-            .service(routes::api::api_scope())
+            .app_data(clipboard_state.clone())
+            // Report malformed requests in the same JSON envelope as other errors
+            .app_data(web::JsonConfig::default().error_handler(|e, _| error::extractor_error(e)))
+            .app_data(web::QueryConfig::default().error_handler(|e, _| error::extractor_error(e)))
+            .app_data(web::PathConfig::default().error_handler(|e, _| error::extractor_error(e)))
+            // Register metrics and health check routes
+            .configure(routes::monitoring::configure)
+            // Register API, streaming and admin routes
+            .configure(routes::configure)
             // Register UI routes
             .service(routes::ui::ui_scope())
-            // Register streaming routes
-            .service(routes::streaming::stream_scope())
-            // Register admin routes
-            .service(routes::admin::admin_scope())
+            // Register WebSocket routes
+            .service(routes::ws::ws_scope())
             // Add default route to redirect to UI
             .default_service(web::get().to(routes::ui::redirect_to_ui))
     })
     .bind(format!("{}:{}", host, port))?
-    .run()
-    .await
+    .shutdown_timeout(shutdown_timeout)
+    // Signals are handled below so sessions can be told before workers stop
+    .disable_signals()
+    .run();
+
+    let handle = server.handle();
+    actix_rt::spawn(async move {
+        shutdown::wait_for_signal().await;
+        tracing::info!(
+            timeout_secs = shutdown_timeout,
+            "Shutting down: no new connections, waiting for in-flight transfers"
+        );
+
+        // Close WebSocket sessions and refuse pending pushes, then let
+        // in-flight requests finish within the timeout
+        shutdown::trigger();
+        transfers::reject_all();
+        handle.stop(true).await;
+    });
+
+    let result = server.await;
+
+    // Anything still being written was cut off by the timeout
+    files::cleanup_partial_files();
+    if let Err(e) = devices::flush() {
+        tracing::error!("Failed to save the device registry: {}", e);
+    }
+    tracing::info!("Server stopped");
+
+    result
 }
 
 fn print_server_info(port: u16) {
@@ -60,7 +176,7 @@ fn print_server_info(port: u16) {
         // Generate QR code
         match qr2term::print_qr(&url) {
             Ok(_) => {},
-            Err(e) => eprintln!("Failed to generate QR code: {}", e),
+            Err(e) => tracing::warn!("Failed to generate QR code: {}", e),
         }
         
         println!("{}", "-".repeat(50));
@@ -80,7 +196,7 @@ fn get_all_ips() -> Vec<IpAddr> {
                 ips.push(ip);
             }
         },
-        Err(e) => eprintln!("Failed to get local IP: {}", e),
+        Err(e) => tracing::warn!("Failed to get local IP: {}", e),
     }
     
     // Sort IPs with local network addresses first
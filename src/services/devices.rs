@@ -0,0 +1,538 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
+
+use crate::config;
+use crate::error::{lock_recovering, AppError};
+use crate::services::events;
+use crate::services::store::JsonStore;
+
+/// Longest device name kept, in characters
+const MAX_NAME_LEN: usize = 64;
+
+/// Name given to devices that haven't said what they are called
+const DEFAULT_NAME: &str = "Unnamed device";
+
+/// Most devices that can be registered without being trusted, so clients
+/// can't fill the registry by making up IDs
+const MAX_UNTRUSTED_DEVICES: usize = 256;
+
+/// How far a device is trusted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TrustLevel {
+    /// Pushes from it have to be accepted on the host
+    #[default]
+    Known,
+    /// Pushes from it are accepted without asking
+    Trusted,
+    /// Its requests are refused
+    Blocked,
+}
+
+/// A device that has talked to this server
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Device {
+    /// Stable ID chosen by the device and sent as `X-Device-Id`, along with
+    /// the token it was given when it registered as `X-Device-Token`
+    pub id: String,
+    pub name: String,
+    /// The user agent it last connected with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    /// Seconds since the Unix epoch
+    pub first_seen: u64,
+    /// Seconds since the Unix epoch
+    pub last_seen: u64,
+    #[serde(default)]
+    pub trust: TrustLevel,
+    /// Whether it has a live connection right now
+    #[serde(default, skip_deserializing)]
+    pub online: bool,
+}
+
+/// Changes to the registry, for live presence feeds
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum DeviceEvent {
+    #[serde(rename = "device_online")]
+    Online(Device),
+    #[serde(rename = "device_offline")]
+    Offline { id: String },
+    /// A device was added, renamed or had its trust changed
+    #[serde(rename = "device_updated")]
+    Updated(Device),
+    #[serde(rename = "device_removed")]
+    Removed { id: String },
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Registry {
+    devices: BTreeMap<String, Device>,
+    /// SHA-256 of each device's token, by device ID. Devices registered
+    /// before tokens existed have none until they register again.
+    #[serde(default)]
+    tokens: BTreeMap<String, String>,
+    /// Changed by [`touch`] since the last save
+    #[serde(skip)]
+    dirty: bool,
+}
+
+static REGISTRY: JsonStore<Registry> = JsonStore::new("devices.json", "device registry");
+
+lazy_static::lazy_static! {
+    // Open connections per device ID
+    static ref CONNECTIONS: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+}
+
+/// Run `f` on the registry, saving it afterwards when `f` reports a change
+fn with_registry<R>(f: impl FnOnce(&mut Registry) -> (R, bool)) -> Result<R, AppError> {
+    REGISTRY.with(|registry| {
+        let (result, changed) = f(registry);
+        if changed {
+            registry.dirty = false;
+        }
+        (result, changed)
+    })
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn is_online(id: &str) -> bool {
+    lock_recovering(&CONNECTIONS).contains_key(id)
+}
+
+fn with_presence(mut device: Device) -> Device {
+    device.online = is_online(&device.id);
+    device
+}
+
+fn publish(event: DeviceEvent) {
+    events::publish(event);
+}
+
+/// Trim a name and cut it down to `MAX_NAME_LEN` characters
+/// A device name trimmed to [`MAX_NAME_LEN`]. Names are shown on other
+/// devices' pages, so ones that could be read as markup are refused.
+fn clean_name(name: &str) -> Result<String, AppError> {
+    let name: String = name.trim().chars().take(MAX_NAME_LEN).collect();
+    if name.is_empty() {
+        return Err(AppError::BadRequest("Device name must not be empty".to_string()));
+    }
+    if name.chars().any(|c| c.is_control() || c == '<' || c == '>') {
+        return Err(AppError::BadRequest(
+            "Device names must not contain < or > or control characters".to_string(),
+        ));
+    }
+    Ok(name)
+}
+
+/// Whether an ID is short and plain enough to be put in URLs and pages as is
+fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_NAME_LEN
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// The user agent a device sent, unless it could be read as markup
+fn clean_platform(platform: Option<&str>) -> Option<&str> {
+    platform.filter(|p| !p.chars().any(|c| c.is_control() || c == '<' || c == '>'))
+}
+
+fn new_token() -> String {
+    format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Compare hashes without giving away how much of a guess was right
+fn hashes_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+/// Register a device under the ID it chose, returning the token it has to
+/// send as `X-Device-Token` from then on.
+///
+/// An ID that already has a token is taken. A device registered before
+/// tokens existed can be claimed once, but loses its trust, since there is
+/// no telling whether it is the same device.
+pub fn register(id: &str, name: Option<&str>, platform: Option<&str>) -> Result<(Device, String), AppError> {
+    let id = id.trim();
+    if !valid_id(id) {
+        return Err(AppError::BadRequest(format!(
+            "Device IDs must be 1 to {} letters, digits, - or _",
+            MAX_NAME_LEN
+        )));
+    }
+    let name = name.filter(|n| !n.trim().is_empty()).map(clean_name).transpose()?;
+    let platform = clean_platform(platform);
+    let token = new_token();
+
+    let device = with_registry(|registry| {
+        if registry.tokens.contains_key(id) {
+            return (Err(AppError::Conflict("That device ID is taken".to_string())), false);
+        }
+        let now = now();
+        let device = match registry.devices.get_mut(id) {
+            Some(device) => {
+                if device.trust == TrustLevel::Trusted {
+                    tracing::warn!(device = id, "Device registered again, trust has to be given again");
+                    device.trust = TrustLevel::Known;
+                }
+                device
+            }
+            None => {
+                let untrusted = registry
+                    .devices
+                    .values()
+                    .filter(|d| d.trust != TrustLevel::Trusted)
+                    .count();
+                if untrusted >= MAX_UNTRUSTED_DEVICES {
+                    tracing::warn!(device = id, "Device registry is full, refusing new device");
+                    return (
+                        Err(AppError::Unavailable(
+                            "Too many devices are registered, remove some in the Admin Panel".to_string(),
+                        )),
+                        false,
+                    );
+                }
+                tracing::info!(device = id, "New device registered");
+                registry.devices.entry(id.to_string()).or_insert(Device {
+                    id: id.to_string(),
+                    name: DEFAULT_NAME.to_string(),
+                    platform: None,
+                    first_seen: now,
+                    last_seen: now,
+                    trust: TrustLevel::default(),
+                    online: false,
+                })
+            }
+        };
+        if let Some(name) = name {
+            device.name = name;
+        }
+        if let Some(platform) = platform {
+            device.platform = Some(platform.to_string());
+        }
+        device.last_seen = now;
+        let device = device.clone();
+        registry.tokens.insert(id.to_string(), hash_token(&token));
+        (Ok(device), true)
+    })??;
+
+    let device = with_presence(device);
+    publish(DeviceEvent::Updated(device.clone()));
+    Ok((device, token))
+}
+
+/// The registered device with this ID and token, if they match
+pub fn authenticate(id: &str, token: &str) -> Result<Option<Device>, AppError> {
+    let hash = hash_token(token);
+    with_registry(|registry| {
+        let device = registry
+            .tokens
+            .get(id)
+            .filter(|stored| hashes_match(stored, &hash))
+            .and_then(|_| registry.devices.get(id).cloned());
+        (device, false)
+    })
+}
+
+/// Record that a registered device made a request. A name or platform it
+/// sends replaces the stored one. Nothing is written here, so this is cheap
+/// enough for every request; [`flush`] saves the changes.
+pub fn touch(id: &str, name: Option<&str>, platform: Option<&str>) -> Result<Device, AppError> {
+    // Names sent as headers on every request are ignored rather than
+    // failing the request
+    let name = name.and_then(|n| clean_name(n).ok());
+    let platform = clean_platform(platform);
+    let (device, updated) = with_registry(|registry| {
+        let Some(device) = registry.devices.get_mut(id) else {
+            return (None, false);
+        };
+        let mut updated = false;
+        if let Some(name) = name {
+            if device.name != name {
+                device.name = name;
+                updated = true;
+            }
+        }
+        if let Some(platform) = platform {
+            if device.platform.as_deref() != Some(platform) {
+                device.platform = Some(platform.to_string());
+                updated = true;
+            }
+        }
+        device.last_seen = now();
+        registry.dirty = true;
+
+        (Some((device.clone(), updated)), false)
+    })?
+    .ok_or_else(|| AppError::NotFound("No device with that ID".to_string()))?;
+
+    let device = with_presence(device);
+    if updated {
+        publish(DeviceEvent::Updated(device.clone()));
+    }
+    Ok(device)
+}
+
+/// Save what [`touch`] recorded since the last save
+pub fn flush() -> Result<(), AppError> {
+    with_registry(|registry| ((), registry.dirty))
+}
+
+/// Every known device, most recently seen first
+pub fn list() -> Result<Vec<Device>, AppError> {
+    let mut devices: Vec<Device> =
+        with_registry(|registry| (registry.devices.values().cloned().collect(), false))?;
+    devices.sort_by_key(|d| Reverse(d.last_seen));
+    Ok(devices.into_iter().map(with_presence).collect())
+}
+
+pub fn get(id: &str) -> Result<Device, AppError> {
+    with_registry(|registry| (registry.devices.get(id).cloned(), false))?
+        .map(with_presence)
+        .ok_or_else(|| AppError::NotFound("No device with that ID".to_string()))
+}
+
+/// The name of a device, if it is registered
+pub fn name_of(id: &str) -> Option<String> {
+    get(id).ok().map(|d| d.name)
+}
+
+/// Change a device's stored fields and announce the result
+fn update(id: &str, f: impl FnOnce(&mut Device)) -> Result<Device, AppError> {
+    let device = with_registry(|registry| match registry.devices.get_mut(id) {
+        Some(device) => {
+            f(device);
+            (Some(device.clone()), true)
+        }
+        None => (None, false),
+    })?
+    .map(with_presence)
+    .ok_or_else(|| AppError::NotFound("No device with that ID".to_string()))?;
+
+    publish(DeviceEvent::Updated(device.clone()));
+    Ok(device)
+}
+
+pub fn rename(id: &str, name: &str) -> Result<Device, AppError> {
+    let name = clean_name(name)?;
+    update(id, |device| device.name = name)
+}
+
+pub fn set_trust(id: &str, trust: TrustLevel) -> Result<Device, AppError> {
+    let device = update(id, |device| device.trust = trust)?;
+    tracing::info!(device = id, ?trust, "Device trust changed");
+    Ok(device)
+}
+
+/// Forget a device and its token. It has to register again, untrusted, if
+/// it comes back.
+pub fn remove(id: &str) -> Result<(), AppError> {
+    let removed = with_registry(|registry| {
+        let removed = registry.devices.remove(id).is_some();
+        registry.tokens.remove(id);
+        (removed, removed)
+    })?;
+    if !removed {
+        return Err(AppError::NotFound("No device with that ID".to_string()));
+    }
+    publish(DeviceEvent::Removed { id: id.to_string() });
+    Ok(())
+}
+
+pub fn is_trusted(id: &str) -> Result<bool, AppError> {
+    with_registry(|registry| {
+        let trusted = registry
+            .devices
+            .get(id)
+            .is_some_and(|d| d.trust == TrustLevel::Trusted);
+        (trusted, false)
+    })
+}
+
+/// Trust a device, adding it under `name` if it isn't registered yet. A
+/// device added here has no token until it registers.
+pub fn trust(id: &str, name: &str) -> Result<(), AppError> {
+    with_registry(|registry| {
+        if !registry.devices.contains_key(id) {
+            let now = now();
+            registry.devices.insert(
+                id.to_string(),
+                Device {
+                    id: id.to_string(),
+                    name: clean_name(name).unwrap_or_else(|_| DEFAULT_NAME.to_string()),
+                    platform: None,
+                    first_seen: now,
+                    last_seen: now,
+                    trust: TrustLevel::default(),
+                    online: false,
+                },
+            );
+        }
+        ((), true)
+    })?;
+    set_trust(id, TrustLevel::Trusted)?;
+    Ok(())
+}
+
+/// Move the `trusted_devices` lists from pre-registry configs into the
+/// registry, once at startup. Failures are logged, since the server starts
+/// either way.
+pub fn import_trusted() {
+    #[derive(Deserialize)]
+    struct TrustedDevice {
+        id: String,
+        name: String,
+    }
+
+    let mut devices: Vec<TrustedDevice> = Vec::new();
+    for list in config::take_trusted_devices() {
+        match serde_json::from_value::<Vec<TrustedDevice>>(list) {
+            Ok(list) => devices.extend(list),
+            Err(e) => tracing::error!("Ignoring unreadable trusted_devices list: {}", e),
+        }
+    }
+
+    for device in &devices {
+        if !valid_id(&device.id) {
+            tracing::error!(device = %device.id, "Ignoring trusted device with an invalid ID");
+            continue;
+        }
+        if let Err(e) = trust(&device.id, &device.name) {
+            tracing::error!(device = %device.id, "Failed to import trusted device: {}", e);
+        }
+    }
+    if !devices.is_empty() {
+        tracing::info!(count = devices.len(), "Moved trusted devices into the device registry");
+    }
+}
+
+/// Marks a device as online for as long as it is alive
+#[derive(Debug)]
+pub struct Presence {
+    id: String,
+}
+
+impl Drop for Presence {
+    fn drop(&mut self) {
+        let offline = {
+            let mut connections = lock_recovering(&CONNECTIONS);
+            match connections.get_mut(&self.id) {
+                Some(count) if *count > 1 => {
+                    *count -= 1;
+                    false
+                }
+                _ => {
+                    connections.remove(&self.id);
+                    true
+                }
+            }
+        };
+        if offline {
+            publish(DeviceEvent::Offline {
+                id: self.id.clone(),
+            });
+        }
+    }
+}
+
+/// Count a live connection from a registered device, announcing it as
+/// online if it is its first
+pub fn connect(id: &str) -> Result<Presence, AppError> {
+    let device = get(id)?;
+    let first = {
+        let mut connections = lock_recovering(&CONNECTIONS);
+        let count = connections.entry(id.to_string()).or_insert(0);
+        *count += 1;
+        *count == 1
+    };
+    if first {
+        publish(DeviceEvent::Online(Device {
+            online: true,
+            ..device
+        }));
+    }
+    Ok(Presence { id: id.to_string() })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An ID no other test registers
+    fn new_id() -> String {
+        uuid::Uuid::new_v4().to_string()
+    }
+
+    #[test]
+    fn registered_device_authenticates_with_its_token_only() {
+        let id = new_id();
+        let (device, token) = register(&id, Some("Laptop"), Some("curl/8")).unwrap();
+        assert_eq!(device.name, "Laptop");
+        assert_eq!(device.trust, TrustLevel::Known);
+
+        assert_eq!(authenticate(&id, &token).unwrap().map(|d| d.id), Some(id.clone()));
+        assert!(authenticate(&id, &new_token()).unwrap().is_none());
+        assert!(authenticate(&new_id(), &token).unwrap().is_none());
+    }
+
+    #[test]
+    fn taken_ids_cannot_be_registered_again() {
+        let id = new_id();
+        let (_, token) = register(&id, None, None).unwrap();
+        assert!(matches!(register(&id, None, None), Err(AppError::Conflict(_))));
+        assert!(authenticate(&id, &token).unwrap().is_some());
+    }
+
+    #[test]
+    fn claiming_a_device_without_a_token_drops_its_trust() {
+        let id = new_id();
+        trust(&id, "Old phone").unwrap();
+        assert!(is_trusted(&id).unwrap());
+
+        let (device, _) = register(&id, None, None).unwrap();
+        assert_eq!(device.name, "Old phone");
+        assert_eq!(device.trust, TrustLevel::Known);
+    }
+
+    #[test]
+    fn ids_and_names_that_could_be_markup_are_refused() {
+        for id in ["", "a b", "x'onclick", "<img>", &"a".repeat(MAX_NAME_LEN + 1)] {
+            assert!(matches!(register(id, None, None), Err(AppError::BadRequest(_))), "{}", id);
+        }
+        let bad_name = register(&new_id(), Some("<b>Phone</b>"), None);
+        assert!(matches!(bad_name, Err(AppError::BadRequest(_))));
+
+        let id = new_id();
+        register(&id, Some("Phone"), Some("<script>")).unwrap();
+        let device = touch(&id, Some("<i>Phone</i>"), Some("agent\n")).unwrap();
+        assert_eq!(device.name, "Phone");
+        assert_eq!(device.platform, None);
+    }
+}
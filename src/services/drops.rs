@@ -0,0 +1,461 @@
+//! Drop folders: host folders whose new and changed files are copied into
+//! the upload folder and announced to connected clients.
+
+use actix_web::web;
+use notify::{EventKind, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use utoipa::ToSchema;
+
+use crate::config::{current_config, expand_path, DropFoldersConfig};
+use crate::error::AppError;
+use crate::services::events;
+use crate::services::files::{self, FileEvent};
+use crate::services::quota::{self, UploadBudget};
+use crate::services::store::JsonStore;
+use crate::storage::{self, local};
+
+/// How often files waiting out the debounce are looked at
+const TICK: Duration = Duration::from_millis(250);
+
+/// How often the config is checked for folders to start or stop watching
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How often published files are checked for expiry
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A file published from a drop folder
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SharedFile {
+    /// Name in the upload folder
+    pub name: String,
+    pub size: u64,
+    /// The drop folder it came from
+    pub folder: String,
+}
+
+/// Drop folder notifications
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum DropEvent {
+    /// A new or changed file was published
+    #[serde(rename = "file_shared")]
+    Shared(SharedFile),
+    /// A published file expired and was removed
+    #[serde(rename = "file_expired")]
+    Expired { name: String },
+}
+
+/// A file seen in a drop folder
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Source {
+    size: u64,
+    /// Modification time, in nanoseconds since the Unix epoch
+    modified: u64,
+    /// Name of the published copy, none for files that were already in the
+    /// folder when it was first watched or whose copy expired
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// Seconds since the Unix epoch
+    #[serde(default)]
+    published_at: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DropState {
+    /// Folders whose files were recorded when first watched
+    folders: BTreeSet<String>,
+    /// Keyed by the file's path
+    files: BTreeMap<String, Source>,
+}
+
+static STATE: JsonStore<DropState> = JsonStore::new("drops.json", "drop folder state");
+
+/// Run `f` on the state, saving it afterwards when `f` reports a change
+fn with_state<R>(f: impl FnOnce(&mut DropState) -> (R, bool)) -> Result<R, AppError> {
+    STATE.with(f)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn modified_nanos(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+fn ignored(name: &str, config: &DropFoldersConfig) -> bool {
+    config
+        .ignore
+        .iter()
+        .filter_map(|pattern| glob::Pattern::new(pattern).ok())
+        .any(|pattern| pattern.matches(name))
+}
+
+/// The configured folders that exist, by their canonical paths
+fn watched_folders(config: &DropFoldersConfig) -> Vec<PathBuf> {
+    let mut folders: Vec<PathBuf> = config
+        .folders
+        .iter()
+        .filter_map(|folder| fs::canonicalize(expand_path(folder)).ok())
+        .filter(|path| path.is_dir())
+        .collect();
+    folders.sort();
+    folders.dedup();
+    folders
+}
+
+/// Copy a new or changed file into the upload folder. Files that are
+/// ignored, gone or unchanged since they were last seen are skipped.
+async fn publish(path: &Path, config: &DropFoldersConfig) -> Result<Option<SharedFile>, AppError> {
+    let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else {
+        return Ok(None);
+    };
+    if ignored(file_name, config) {
+        return Ok(None);
+    }
+    let key = path.to_string_lossy().to_string();
+    let metadata = match tokio::fs::metadata(path).await {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => {
+            // Gone: forget it unless its copy may still have to expire
+            let keep = config.expire_after_secs > 0;
+            web::block(move || {
+                with_state(|state| match state.files.get(&key) {
+                    Some(source) if source.name.is_none() || !keep => {
+                        state.files.remove(&key);
+                        ((), true)
+                    }
+                    _ => ((), false),
+                })
+            })
+            .await??;
+            return Ok(None);
+        }
+    };
+    let (size, modified) = (metadata.len(), modified_nanos(&metadata));
+
+    // `Some(None)` for a new file, `Some(Some(name))` for a changed one
+    let earlier = with_state(|state| {
+        let earlier = match state.files.get(&key) {
+            Some(source) if source.size == size && source.modified == modified => None,
+            // A changed file replaces its earlier copy
+            Some(Source {
+                name: Some(name), ..
+            }) => Some(Some(name.clone())),
+            _ => Some(None),
+        };
+        (earlier, false)
+    })?;
+    let Some(earlier) = earlier else {
+        return Ok(None);
+    };
+    let storage = storage::current()?;
+    let name = match earlier {
+        Some(name) => name,
+        None => storage::unique_name(storage.as_ref(), &sanitize_filename::sanitize(file_name)).await?,
+    };
+
+    // Published files count against the same quotas and free space as
+    // uploads, and may evict old uploads to fit. Only the size checked here
+    // is copied; a file still growing is copied again once it changes.
+    let budget = UploadBudget::upload(&name, None, Some(size)).await?;
+    let file = tokio::fs::File::open(path).await?;
+    storage.put(&name, local::read_file(file, Some(size))).await?;
+
+    let published = name.clone();
+    web::block(move || {
+        quota::record_upload(&published, None)?;
+        with_state(|state| {
+            state.files.insert(
+                key,
+                Source {
+                    size,
+                    modified,
+                    name: Some(published),
+                    published_at: now(),
+                },
+            );
+            ((), true)
+        })
+    })
+    .await??;
+    // Held until the file is recorded, so it counts as used throughout
+    drop(budget);
+
+    let folder = path
+        .parent()
+        .map(|p| p.display().to_string())
+        .unwrap_or_default();
+    tracing::info!(source = %path.display(), name = %name, bytes = size, "Published file from drop folder");
+    Ok(Some(SharedFile { name, size, folder }))
+}
+
+/// The files in `folder`, and whether it is being watched for the first time
+fn scan(folder: &Path) -> Result<(Vec<PathBuf>, bool), AppError> {
+    let folder_key = folder.to_string_lossy().to_string();
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(folder)?.flatten() {
+        if entry.file_type().is_ok_and(|t| t.is_file()) {
+            paths.push(entry.path());
+        }
+    }
+    let first_time = with_state(|state| (!state.folders.contains(&folder_key), false))?;
+    Ok((paths, first_time))
+}
+
+/// Record the files already in a folder watched for the first time, without
+/// publishing them
+fn record_existing(
+    folder: &Path,
+    paths: Vec<PathBuf>,
+    config: &DropFoldersConfig,
+) -> Result<(), AppError> {
+    let seen: Vec<(String, Source)> = paths
+        .iter()
+        .filter_map(|path| {
+            let name = path.file_name()?.to_str()?;
+            let metadata = fs::metadata(path).ok()?;
+            (!ignored(name, config)).then(|| {
+                let source = Source {
+                    size: metadata.len(),
+                    modified: modified_nanos(&metadata),
+                    name: None,
+                    published_at: 0,
+                };
+                (path.to_string_lossy().to_string(), source)
+            })
+        })
+        .collect();
+    tracing::info!(folder = %folder.display(), files = seen.len(), "Watching new drop folder");
+    with_state(|state| {
+        state.folders.insert(folder.to_string_lossy().to_string());
+        state.files.extend(seen);
+        ((), true)
+    })
+}
+
+/// Publish files that appeared or changed in `folder` while it wasn't being
+/// watched. The first time a folder is watched its files are only recorded,
+/// so adding a full folder doesn't flood the upload folder.
+async fn catch_up(folder: &Path, config: &DropFoldersConfig) -> Result<Vec<SharedFile>, AppError> {
+    let dir = folder.to_path_buf();
+    let (paths, first_time) = web::block(move || scan(&dir)).await??;
+    if first_time {
+        let (dir, config) = (folder.to_path_buf(), config.clone());
+        web::block(move || record_existing(&dir, paths, &config)).await??;
+        return Ok(Vec::new());
+    }
+
+    let mut shared = Vec::new();
+    for path in paths {
+        match publish(&path, config).await {
+            Ok(Some(file)) => shared.push(file),
+            Ok(None) => {}
+            Err(e) => tracing::error!(path = %path.display(), "Failed to publish file: {}", e),
+        }
+    }
+    Ok(shared)
+}
+
+/// Forget a folder that is no longer watched, keeping what was published
+/// from it so it can still expire
+fn forget_folder(folder: &Path) -> Result<(), AppError> {
+    let folder_key = folder.to_string_lossy().to_string();
+    with_state(|state| {
+        state.folders.remove(&folder_key);
+        state.files.retain(|path, source| {
+            source.name.is_some() || Path::new(path).parent() != Some(folder)
+        });
+        ((), true)
+    })
+}
+
+/// Remove published files older than `expire_after_secs`
+async fn expire(config: &DropFoldersConfig) -> Result<Vec<String>, AppError> {
+    if config.expire_after_secs == 0 {
+        return Ok(Vec::new());
+    }
+    let cutoff = now().saturating_sub(config.expire_after_secs);
+    let due: Vec<(String, String, u64)> = with_state(|state| {
+        let due = state
+            .files
+            .iter()
+            .filter(|(_, source)| source.published_at <= cutoff)
+            .filter_map(|(key, source)| Some((key.clone(), source.name.clone()?, source.size)))
+            .collect();
+        (due, false)
+    })?;
+    if due.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let storage = storage::current()?;
+    let mut expired = Vec::new();
+    let mut done = Vec::new();
+    for (key, name, size) in due {
+        // Leave it if someone has since uploaded over it
+        if storage.stat(&name).await?.is_some_and(|file| file.size == size) {
+            match storage.delete(&name).await {
+                Ok(_) => {
+                    quota::usage_changed();
+                    tracing::info!(name = %name, "Removed expired file from drop folder");
+                    expired.push(name);
+                }
+                Err(e) => {
+                    tracing::error!(name = %name, "Failed to remove expired file: {}", e);
+                    continue;
+                }
+            }
+        }
+        done.push(key);
+    }
+
+    web::block(move || {
+        with_state(|state| {
+            for key in &done {
+                if let Some(source) = state.files.get_mut(key) {
+                    source.name = None;
+                }
+            }
+            ((), true)
+        })
+    })
+    .await??;
+    Ok(expired)
+}
+
+fn announce(shared: Vec<SharedFile>) {
+    for file in shared {
+        files::notify(FileEvent::Added {
+            name: file.name.clone(),
+            size: file.size,
+            device_id: None,
+        });
+        events::publish(DropEvent::Shared(file));
+    }
+}
+
+/// Watch the configured drop folders, following config changes
+pub fn spawn() {
+    actix_rt::spawn(async {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut watcher = match notify::recommended_watcher(move |result| {
+            let _ = tx.send(result);
+        }) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                tracing::error!("Failed to start watching drop folders: {}", e);
+                return;
+            }
+        };
+
+        let mut config = DropFoldersConfig::default();
+        let mut watched: Vec<PathBuf> = Vec::new();
+        // Files that changed, with when they last did
+        let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+        let mut tick = actix_rt::time::interval(TICK);
+        let mut reload = actix_rt::time::interval(RELOAD_INTERVAL);
+        let mut expiry = actix_rt::time::interval(EXPIRY_INTERVAL);
+
+        loop {
+            tokio::select! {
+                Some(result) = rx.recv() => match result {
+                    Ok(event) => {
+                        let event: notify::Event = event;
+                        if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)) {
+                            for path in event.paths {
+                                if path.parent().is_some_and(|p| watched.iter().any(|w| w == p)) {
+                                    pending.insert(path, Instant::now());
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => tracing::warn!("Drop folder watch error: {}", e),
+                },
+                _ = tick.tick() => {
+                    let debounce = Duration::from_millis(config.debounce_ms);
+                    let ready: Vec<PathBuf> = pending
+                        .iter()
+                        .filter(|(_, changed)| changed.elapsed() >= debounce)
+                        .map(|(path, _)| path.clone())
+                        .collect();
+                    if ready.is_empty() {
+                        continue;
+                    }
+                    for path in &ready {
+                        pending.remove(path);
+                    }
+                    let mut shared = Vec::new();
+                    for path in ready {
+                        match publish(&path, &config).await {
+                            Ok(Some(file)) => shared.push(file),
+                            Ok(None) => {}
+                            Err(e) => {
+                                tracing::error!(path = %path.display(), "Failed to publish file: {}", e)
+                            }
+                        }
+                    }
+                    announce(shared);
+                }
+                _ = reload.tick() => {
+                    let Ok(current) = current_config() else { continue };
+                    config = current.drop_folders;
+                    let folders = watched_folders(&config);
+                    if folders == watched {
+                        continue;
+                    }
+                    for folder in watched.iter().filter(|f| !folders.contains(f)) {
+                        let _ = watcher.unwatch(folder);
+                        pending.retain(|path, _| path.parent() != Some(folder.as_path()));
+                        if let Err(e) = forget_folder(folder) {
+                            tracing::error!(folder = %folder.display(), "Failed to forget drop folder: {}", e);
+                        }
+                        tracing::info!(folder = %folder.display(), "Stopped watching drop folder");
+                    }
+                    let added: Vec<PathBuf> = folders
+                        .iter()
+                        .filter(|f| !watched.contains(f))
+                        .cloned()
+                        .collect();
+                    watched = folders;
+                    for folder in added {
+                        if let Err(e) = watcher.watch(&folder, RecursiveMode::NonRecursive) {
+                            tracing::error!(folder = %folder.display(), "Failed to watch drop folder: {}", e);
+                            watched.retain(|f| f != &folder);
+                            continue;
+                        }
+                        match catch_up(&folder, &config).await {
+                            Ok(shared) => announce(shared),
+                            Err(e) => tracing::error!("Failed to scan drop folder: {}", e),
+                        }
+                    }
+                }
+                _ = expiry.tick() => {
+                    match expire(&config).await {
+                        Ok(expired) => {
+                            for name in expired {
+                                files::notify(FileEvent::Removed { name: name.clone() });
+                                events::publish(DropEvent::Expired { name });
+                            }
+                        }
+                        Err(e) => tracing::error!("Failed to expire drop folder files: {}", e),
+                    }
+                }
+            }
+        }
+    });
+}
+
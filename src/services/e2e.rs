@@ -0,0 +1,375 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
+
+use crate::error::AppError;
+use crate::services::devices::{self, TrustLevel};
+use crate::services::events;
+use crate::services::store::JsonStore;
+
+/// Longest public or wrapped key accepted, in characters. Both are a few
+/// dozen bytes of base64, so this only stops junk from piling up.
+const MAX_KEY_LEN: usize = 1024;
+
+/// The group key for one epoch, wrapped by a member for one device. Only
+/// the clients can unwrap it; to the server it is opaque.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct WrappedKey {
+    pub epoch: u64,
+    /// The device that wrapped it
+    pub wrapped_by: String,
+    /// That device's public key when it wrapped it, needed to unwrap it
+    pub wrapper_key: String,
+    pub key: String,
+}
+
+/// A device's public key and whether it holds the current group key
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DeviceKey {
+    pub id: String,
+    pub name: Option<String>,
+    /// Base64 of the raw P-256 public key
+    pub public_key: String,
+    /// Whether it has been given the current group key
+    pub member: bool,
+}
+
+/// Everything a device needs to take part in the group
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct KeyState {
+    /// The current group key epoch, 0 until a device creates the group
+    pub epoch: u64,
+    /// A member was revoked, so the next member to see this should replace
+    /// the group key
+    pub rotation_needed: bool,
+    pub devices: Vec<DeviceKey>,
+    /// Group keys wrapped for the requesting device, one per epoch it was a
+    /// member of
+    pub keys: Vec<WrappedKey>,
+}
+
+/// Sent when the group or its members change, so clients fetch their keys
+/// again
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum KeyEvent {
+    #[serde(rename = "e2e_keys_changed")]
+    Changed { epoch: u64, rotation_needed: bool },
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct KeyStore {
+    epoch: u64,
+    rotation_needed: bool,
+    /// Public key per device ID
+    public_keys: BTreeMap<String, String>,
+    /// Wrapped group keys per epoch, then per recipient device ID
+    wrapped: BTreeMap<u64, BTreeMap<String, WrappedKey>>,
+}
+
+impl KeyStore {
+    fn is_member(&self, id: &str) -> bool {
+        self.wrapped
+            .get(&self.epoch)
+            .is_some_and(|keys| keys.contains_key(id))
+    }
+
+    fn has_members(&self) -> bool {
+        self.wrapped.get(&self.epoch).is_some_and(|keys| !keys.is_empty())
+    }
+
+    fn event(&self) -> KeyEvent {
+        KeyEvent::Changed {
+            epoch: self.epoch,
+            rotation_needed: self.rotation_needed,
+        }
+    }
+
+    /// Store `keys`, wrapped by device `from` for `epoch`, returning whether
+    /// they replace the group key. See [`publish_keys`].
+    fn add_keys(
+        &mut self,
+        from: &str,
+        epoch: u64,
+        keys: BTreeMap<String, String>,
+    ) -> Result<bool, AppError> {
+        let Some(wrapper_key) = self.public_keys.get(from).cloned() else {
+            return Err(AppError::BadRequest(
+                "Register a public key for this device first".to_string(),
+            ));
+        };
+        if let Some(id) = keys.keys().find(|id| !self.public_keys.contains_key(*id)) {
+            return Err(AppError::BadRequest(format!("Device {} has no public key", id)));
+        }
+        if self.has_members() && !self.is_member(from) {
+            return Err(AppError::Forbidden(
+                "Only devices holding the group key can hand it out".to_string(),
+            ));
+        }
+
+        let rotating = epoch == self.epoch + 1;
+        if rotating && !keys.contains_key(from) {
+            return Err(AppError::BadRequest(
+                "A new group key must include a copy for the device creating it".to_string(),
+            ));
+        }
+        if !rotating && (epoch != self.epoch || !self.has_members()) {
+            return Err(AppError::BadRequest(format!(
+                "Stale key epoch {}, the current one is {}",
+                epoch, self.epoch
+            )));
+        }
+
+        let wrapped = self.wrapped.entry(epoch).or_default();
+        for (id, key) in keys {
+            wrapped.entry(id.clone()).or_insert_with(|| WrappedKey {
+                epoch,
+                wrapped_by: from.to_string(),
+                wrapper_key: wrapper_key.clone(),
+                key,
+            });
+        }
+        if rotating {
+            self.epoch = epoch;
+            self.rotation_needed = false;
+        }
+        Ok(rotating)
+    }
+}
+
+static STORE: JsonStore<KeyStore> = JsonStore::new("keys.json", "key store");
+
+/// Run `f` on the store, saving it afterwards when `f` reports a change
+fn with_store<R>(f: impl FnOnce(&mut KeyStore) -> (R, bool)) -> Result<R, AppError> {
+    STORE.with(f)
+}
+
+fn publish(event: KeyEvent) {
+    events::publish(event);
+}
+
+fn check_key(key: &str, what: &str) -> Result<(), AppError> {
+    let valid = !key.is_empty()
+        && key.len() <= MAX_KEY_LEN
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '/' | '=' | '-' | '_'));
+    if valid {
+        Ok(())
+    } else {
+        Err(AppError::BadRequest(format!("{} is not valid base64", what)))
+    }
+}
+
+/// The group as seen by device `id`
+pub fn state_for(id: &str) -> Result<KeyState, AppError> {
+    let (epoch, rotation_needed, public_keys, members, keys) = with_store(|store| {
+        let members: Vec<String> = store
+            .wrapped
+            .get(&store.epoch)
+            .map(|keys| keys.keys().cloned().collect())
+            .unwrap_or_default();
+        let keys: Vec<WrappedKey> = store
+            .wrapped
+            .values()
+            .filter_map(|keys| keys.get(id).cloned())
+            .collect();
+        let state = (
+            store.epoch,
+            store.rotation_needed,
+            store.public_keys.clone(),
+            members,
+            keys,
+        );
+        (state, false)
+    })?;
+
+    let devices = public_keys
+        .into_iter()
+        .map(|(device, public_key)| DeviceKey {
+            name: devices::name_of(&device),
+            member: members.contains(&device),
+            id: device,
+            public_key,
+        })
+        .collect();
+    Ok(KeyState {
+        epoch,
+        rotation_needed,
+        devices,
+        keys,
+    })
+}
+
+/// Register a device's public key. A device that changes its key loses the
+/// group keys wrapped for the old one and has to be paired again.
+pub fn set_public_key(id: &str, public_key: &str) -> Result<(), AppError> {
+    check_key(public_key, "Public key")?;
+    let event = with_store(|store| {
+        if store.public_keys.get(id).map(String::as_str) == Some(public_key) {
+            return (None, false);
+        }
+        store.public_keys.insert(id.to_string(), public_key.to_string());
+        for keys in store.wrapped.values_mut() {
+            keys.remove(id);
+        }
+        (Some(store.event()), true)
+    })?;
+
+    if let Some(event) = event {
+        tracing::info!(device = id, "Device registered an encryption key");
+        publish(event);
+    }
+    Ok(())
+}
+
+/// Store group keys wrapped by device `from`, one per recipient.
+///
+/// For the current epoch this pairs new devices; keys already given out are
+/// left alone. For the next epoch it replaces the group key, which is how a
+/// group is created and how it is rotated. Only members may do either, except
+/// that anyone with a public key may start a group that has no members.
+pub fn publish_keys(
+    from: &str,
+    epoch: u64,
+    keys: BTreeMap<String, String>,
+) -> Result<(), AppError> {
+    for key in keys.values() {
+        check_key(key, "Wrapped key")?;
+    }
+    for id in keys.keys() {
+        if devices::get(id).is_ok_and(|d| d.trust == TrustLevel::Blocked) {
+            return Err(AppError::BadRequest(format!("Device {} is blocked", id)));
+        }
+    }
+
+    let (rotating, event) = with_store(|store| match store.add_keys(from, epoch, keys) {
+        Ok(rotating) => (Ok((rotating, store.event())), true),
+        Err(e) => (Err(e), false),
+    })??;
+
+    if rotating {
+        tracing::info!(device = from, epoch, "Group key replaced");
+    } else {
+        tracing::info!(device = from, epoch, "Group key shared with new devices");
+    }
+    publish(event);
+    Ok(())
+}
+
+/// Drop a revoked device's keys. If it held the current group key, members
+/// are asked to replace it so the device can't read anything sent from now
+/// on.
+pub fn revoke(id: &str) -> Result<(), AppError> {
+    let event = with_store(|store| {
+        let was_member = store.is_member(id);
+        let had_key = store.public_keys.remove(id).is_some();
+        for keys in store.wrapped.values_mut() {
+            keys.remove(id);
+        }
+        if was_member {
+            store.rotation_needed = true;
+        }
+        (
+            (had_key || was_member).then(|| store.event()),
+            had_key || was_member,
+        )
+    })?;
+
+    if let Some(event) = event {
+        tracing::info!(device = id, "Device's encryption keys revoked");
+        publish(event);
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A store where `ids` registered public keys and nobody made a group
+    fn store(ids: &[&str]) -> KeyStore {
+        KeyStore {
+            public_keys: ids.iter().map(|id| (id.to_string(), format!("pub-{}", id))).collect(),
+            ..KeyStore::default()
+        }
+    }
+
+    fn keys(ids: &[&str]) -> BTreeMap<String, String> {
+        ids.iter().map(|id| (id.to_string(), format!("key-{}", id))).collect()
+    }
+
+    #[test]
+    fn anyone_with_a_key_creates_the_first_group() {
+        let mut store = store(&["a", "b"]);
+        assert!(store.add_keys("a", 1, keys(&["a", "b"])).unwrap());
+        assert_eq!(store.epoch, 1);
+        assert!(store.is_member("a") && store.is_member("b"));
+        let key = &store.wrapped[&1]["b"];
+        assert_eq!((key.wrapped_by.as_str(), key.wrapper_key.as_str()), ("a", "pub-a"));
+    }
+
+    #[test]
+    fn new_group_key_needs_a_copy_for_its_creator() {
+        let mut store = store(&["a", "b"]);
+        let refused = store.add_keys("a", 1, keys(&["b"]));
+        assert!(matches!(refused, Err(AppError::BadRequest(_))));
+        assert_eq!(store.epoch, 0);
+        assert!(store.wrapped.is_empty());
+    }
+
+    #[test]
+    fn only_members_share_or_replace_the_group_key() {
+        let mut store = store(&["a", "b", "c"]);
+        store.add_keys("a", 1, keys(&["a"])).unwrap();
+
+        let pairing = store.add_keys("c", 1, keys(&["b"]));
+        assert!(matches!(pairing, Err(AppError::Forbidden(_))));
+        let rotation = store.add_keys("c", 2, keys(&["c"]));
+        assert!(matches!(rotation, Err(AppError::Forbidden(_))));
+
+        assert!(!store.add_keys("a", 1, keys(&["b"])).unwrap());
+        assert!(store.is_member("b"));
+        store.rotation_needed = true;
+        assert!(store.add_keys("b", 2, keys(&["a", "b"])).unwrap());
+        assert_eq!(store.epoch, 2);
+        assert!(!store.rotation_needed);
+        assert!(!store.is_member("c"));
+    }
+
+    #[test]
+    fn stale_or_skipped_epochs_are_refused() {
+        let mut store = store(&["a", "b"]);
+        // Pairing into a group that doesn't exist yet
+        assert!(matches!(store.add_keys("a", 0, keys(&["a"])), Err(AppError::BadRequest(_))));
+        store.add_keys("a", 1, keys(&["a"])).unwrap();
+        store.add_keys("a", 2, keys(&["a"])).unwrap();
+
+        for epoch in [1, 4] {
+            let refused = store.add_keys("a", epoch, keys(&["b"]));
+            assert!(matches!(refused, Err(AppError::BadRequest(_))), "{}", epoch);
+        }
+        assert!(!store.wrapped[&2].contains_key("b"));
+    }
+
+    #[test]
+    fn keys_go_only_to_devices_with_public_keys() {
+        let mut store = store(&["a"]);
+        let unknown_sender = store.add_keys("b", 1, keys(&["b"]));
+        assert!(matches!(unknown_sender, Err(AppError::BadRequest(_))));
+        let unknown_recipient = store.add_keys("a", 1, keys(&["a", "b"]));
+        assert!(matches!(unknown_recipient, Err(AppError::BadRequest(_))));
+        assert!(store.wrapped.is_empty());
+    }
+
+    #[test]
+    fn keys_already_given_out_are_kept() {
+        let mut store = store(&["a", "b"]);
+        store.add_keys("a", 1, keys(&["a", "b"])).unwrap();
+        let mut again = keys(&["b"]);
+        again.insert("b".to_string(), "replaced".to_string());
+        store.add_keys("a", 1, again).unwrap();
+        assert_eq!(store.wrapped[&1]["b"].key, "key-b");
+    }
+}
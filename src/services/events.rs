@@ -0,0 +1,369 @@
+//! The event bus: every part of the server reports what happens here, and
+//! WebSocket sessions, Server-Sent Events, long polls and webhooks follow it.
+//! Events are numbered in order and kept for a while so a client that
+//! reconnects can pick up where it left off.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, watch};
+
+use crate::error::{lock_recovering, AppError};
+use crate::services::devices::DeviceEvent;
+use crate::services::drops::DropEvent;
+use crate::services::e2e::KeyEvent;
+use crate::services::federation::PeerEvent;
+use crate::services::files::FileEvent;
+use crate::services::inbox::InboxEvent;
+use crate::services::transfers::TransferEvent;
+
+/// Events kept for clients that reconnect
+const HISTORY_SIZE: usize = 1000;
+/// Progress reports kept alongside them. Each one supersedes the last, so
+/// they're kept apart and a client that misses some needn't start over.
+const PROGRESS_HISTORY_SIZE: usize = 100;
+
+/// Every event type, as in the `type` field
+pub const EVENT_TYPES: &[&str] = &[
+    "Clipboard",
+    "clipboard_updated",
+    "config_changed",
+    "device_online",
+    "device_offline",
+    "device_updated",
+    "device_removed",
+    "file_shared",
+    "file_expired",
+    "file_added",
+    "file_removed",
+    "file_renamed",
+    "file_downloaded",
+    "upload_progress",
+    "download_progress",
+    "inbox_item",
+    "inbox_answered",
+    "e2e_keys_changed",
+    "peer_updated",
+    "peer_removed",
+    "transfer_request",
+    "transfer_resolved",
+];
+
+/// Kinds of events a client can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Topic {
+    Clipboard,
+    Devices,
+    Inbox,
+    E2e,
+    Peers,
+    /// Files added to, removed from or renamed in the upload folder
+    Files,
+    /// Upload and download progress
+    Progress,
+}
+
+impl Topic {
+    /// Topics a client gets unless it asks for others. Progress is left out
+    /// as it is frequent.
+    pub fn defaults() -> HashSet<Topic> {
+        [
+            Topic::Clipboard,
+            Topic::Devices,
+            Topic::Inbox,
+            Topic::E2e,
+            Topic::Peers,
+            Topic::Files,
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    pub fn parse(name: &str) -> Option<Topic> {
+        serde_json::from_value(serde_json::Value::from(name)).ok()
+    }
+
+    /// Parse a comma-separated list of topics
+    pub fn parse_list(list: &str) -> Result<HashSet<Topic>, AppError> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                Topic::parse(name)
+                    .ok_or_else(|| AppError::BadRequest(format!("Unknown topic: {}", name)))
+            })
+            .collect()
+    }
+
+    /// The topics in `?topics=`, or the defaults
+    pub fn from_query(list: Option<&str>) -> Result<HashSet<Topic>, AppError> {
+        match list {
+            Some(list) => Topic::parse_list(list),
+            None => Ok(Topic::defaults()),
+        }
+    }
+}
+
+/// Changes to the shared clipboard
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum ClipboardEvent {
+    /// Content sent by a `/ws/clipboard` client, in the shape those clients
+    /// use
+    #[serde(rename = "Clipboard")]
+    Shared(String),
+    /// The clipboard behind `/api/v1/clipboard` was replaced
+    #[serde(rename = "clipboard_updated")]
+    Updated {
+        text: String,
+        encrypted: bool,
+        device_id: Option<String>,
+        /// The linked server it came from, when merged from one
+        peer_name: Option<String>,
+    },
+}
+
+/// Changes to the config
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum ConfigEvent {
+    /// Top-level config fields that changed. Values are left out, as some
+    /// are secrets.
+    #[serde(rename = "config_changed")]
+    Changed { fields: Vec<String> },
+}
+
+/// Anything published on the bus. Each kind serializes as
+/// `{"type": ..., "data": ...}`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Event {
+    Clipboard(ClipboardEvent),
+    Config(ConfigEvent),
+    Device(DeviceEvent),
+    Drop(DropEvent),
+    File(FileEvent),
+    Inbox(InboxEvent),
+    Key(KeyEvent),
+    Peer(PeerEvent),
+    Transfer(TransferEvent),
+}
+
+macro_rules! event_from {
+    ($($variant:ident($kind:ty)),* $(,)?) => {
+        $(impl From<$kind> for Event {
+            fn from(event: $kind) -> Self {
+                Event::$variant(event)
+            }
+        })*
+    };
+}
+
+event_from!(
+    Clipboard(ClipboardEvent),
+    Config(ConfigEvent),
+    Device(DeviceEvent),
+    Drop(DropEvent),
+    File(FileEvent),
+    Inbox(InboxEvent),
+    Key(KeyEvent),
+    Peer(PeerEvent),
+    Transfer(TransferEvent),
+);
+
+impl Event {
+    /// The topic clients subscribe to for this event. Config changes and
+    /// push approvals aren't sent to clients.
+    pub fn topic(&self) -> Option<Topic> {
+        match self {
+            Event::Clipboard(_) => Some(Topic::Clipboard),
+            Event::Device(_) => Some(Topic::Devices),
+            Event::Drop(_) => Some(Topic::Files),
+            Event::File(event) if event.is_progress() => Some(Topic::Progress),
+            Event::File(_) => Some(Topic::Files),
+            Event::Inbox(_) => Some(Topic::Inbox),
+            Event::Key(_) => Some(Topic::E2e),
+            Event::Peer(_) => Some(Topic::Peers),
+            Event::Config(_) | Event::Transfer(_) => None,
+        }
+    }
+
+    /// The only device that gets this event, for inbox events
+    pub fn recipient(&self) -> Option<&str> {
+        match self {
+            Event::Inbox(event) => Some(event.recipient()),
+            _ => None,
+        }
+    }
+}
+
+/// An event as published, with its place in the order
+#[derive(Debug, Clone)]
+pub struct BusEvent {
+    pub id: u64,
+    /// Unix time it was published, in seconds
+    pub time: u64,
+    pub event: Event,
+}
+
+impl BusEvent {
+    /// Whether a client following `topics` as `device` gets this event
+    pub fn visible(&self, topics: &HashSet<Topic>, device: Option<&str>) -> bool {
+        self.event
+            .topic()
+            .is_some_and(|topic| topics.contains(&topic))
+            && self
+                .event
+                .recipient()
+                .is_none_or(|recipient| device == Some(recipient))
+    }
+
+    /// The event's JSON, with its `id` added
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(&self.event).unwrap_or_default();
+        if let Some(fields) = value.as_object_mut() {
+            fields.insert("id".to_string(), self.id.into());
+        }
+        value
+    }
+}
+
+/// Recent events, in ID order
+struct History {
+    events: VecDeque<BusEvent>,
+    progress: VecDeque<BusEvent>,
+    /// ID of the newest event dropped from `events`. A client that last
+    /// saw an earlier one has missed something.
+    dropped: u64,
+}
+
+lazy_static::lazy_static! {
+    static ref HISTORY: Mutex<History> = Mutex::new(History {
+        events: VecDeque::with_capacity(HISTORY_SIZE),
+        progress: VecDeque::with_capacity(PROGRESS_HISTORY_SIZE),
+        dropped: *LATEST.borrow(),
+    });
+    static ref SUBSCRIBERS: broadcast::Sender<BusEvent> = broadcast::channel(1024).0;
+    // ID of the latest event. IDs start from the time the server started,
+    // in microseconds, so they keep growing across restarts and an ID from
+    // an earlier run is never mistaken for a recent one.
+    static ref LATEST: watch::Sender<u64> = watch::channel(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or_default()
+    ).0;
+}
+
+/// Put an event on the bus
+pub fn publish(event: impl Into<Event>) {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    // Held while sending so subscribers see events in ID order
+    let mut history = lock_recovering(&HISTORY);
+    let event = BusEvent {
+        id: *LATEST.borrow() + 1,
+        time,
+        event: event.into(),
+    };
+    if event.event.topic() == Some(Topic::Progress) {
+        if history.progress.len() == PROGRESS_HISTORY_SIZE {
+            history.progress.pop_front();
+        }
+        history.progress.push_back(event.clone());
+    } else {
+        if history.events.len() == HISTORY_SIZE {
+            if let Some(dropped) = history.events.pop_front() {
+                history.dropped = dropped.id;
+            }
+        }
+        history.events.push_back(event.clone());
+    }
+    let id = event.id;
+    let _ = SUBSCRIBERS.send(event);
+    LATEST.send_replace(id);
+}
+
+/// Follow events as they are published
+pub fn subscribe() -> broadcast::Receiver<BusEvent> {
+    SUBSCRIBERS.subscribe()
+}
+
+/// ID of the latest event
+pub fn latest_id() -> u64 {
+    *LATEST.borrow()
+}
+
+/// Wakes up whenever an event is published
+pub fn watch() -> watch::Receiver<u64> {
+    LATEST.subscribe()
+}
+
+/// The events after `after` that a client following `topics` as `device`
+/// gets, along with the ID to resume from next time. `None` when events
+/// after `after` have already been dropped, or `after` isn't from this run,
+/// and the client has to start over.
+pub fn since(
+    after: u64,
+    topics: &HashSet<Topic>,
+    device: Option<&str>,
+) -> Option<(Vec<BusEvent>, u64)> {
+    let history = lock_recovering(&HISTORY);
+    let latest = *LATEST.borrow();
+    if after > latest || after < history.dropped {
+        return None;
+    }
+    let mut events: Vec<BusEvent> = history
+        .events
+        .iter()
+        .chain(&history.progress)
+        .filter(|e| e.id > after && e.visible(topics, device))
+        .cloned()
+        .collect();
+    events.sort_by_key(|e| e.id);
+    Some((events, latest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::files::TransferProgress;
+
+    fn progress(bytes: u64) -> FileEvent {
+        FileEvent::UploadProgress(TransferProgress {
+            id: "events-test".to_string(),
+            name: "events-test.bin".to_string(),
+            device_id: None,
+            bytes,
+            total: None,
+            done: false,
+        })
+    }
+
+    #[test]
+    fn progress_reports_dont_push_other_events_out() {
+        let start = latest_id();
+        publish(FileEvent::Removed {
+            name: "events-test.txt".to_string(),
+        });
+        for bytes in 0..(HISTORY_SIZE + PROGRESS_HISTORY_SIZE) as u64 {
+            publish(progress(bytes));
+        }
+
+        let (events, _) = since(start, &Topic::defaults(), None).expect("nothing was missed");
+        assert!(events.iter().any(|e| matches!(
+            &e.event,
+            Event::File(FileEvent::Removed { name }) if name == "events-test.txt"
+        )));
+
+        // Only the latest progress reports are kept, in order
+        let topics = [Topic::Progress].into_iter().collect();
+        let (reports, _) = since(start, &topics, None).expect("nothing was missed");
+        assert!(reports.len() <= PROGRESS_HISTORY_SIZE);
+        assert!(reports.windows(2).all(|pair| pair[0].id < pair[1].id));
+    }
+}
@@ -0,0 +1,107 @@
+//! Finding other servers on the LAN.
+//!
+//! Every server with federation and discovery on sends a small JSON beacon to
+//! a multicast group now and then, and listens for the others'. A beacon
+//! only says where a server is; linking still goes through the hello, and
+//! nothing is served to it until an admin trusts it.
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+use super::{introduce, needs_introduction, this_server, valid_id, valid_url, PeerSource};
+use crate::config::current_config;
+
+const GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 42, 99);
+const PORT: u16 = 42424;
+
+/// How often this server announces itself
+const BEACON_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Value of `service` in every beacon, so stray traffic on the group is
+/// ignored
+const SERVICE: &str = "noplacelike";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Beacon {
+    service: String,
+    id: String,
+    name: String,
+    url: String,
+}
+
+fn bind() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Several servers on one machine all listen on the same port
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, PORT)).into())?;
+    socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn discovery_enabled() -> bool {
+    current_config().is_ok_and(|c| c.federation.enabled && c.federation.discovery)
+}
+
+/// Announce this server and listen for others, while discovery is on
+pub fn spawn() {
+    actix_rt::spawn(async {
+        let socket = match bind() {
+            Ok(socket) => socket,
+            Err(e) => {
+                tracing::warn!(port = PORT, "LAN discovery unavailable: {}", e);
+                return;
+            }
+        };
+        let target = SocketAddrV4::new(GROUP, PORT);
+        let mut interval = actix_rt::time::interval(BEACON_INTERVAL);
+        let mut buffer = vec![0u8; 2048];
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if !discovery_enabled() {
+                        continue;
+                    }
+                    let Ok(me) = this_server() else { continue };
+                    let beacon = Beacon {
+                        service: SERVICE.to_string(),
+                        id: me.id,
+                        name: me.name,
+                        url: me.url,
+                    };
+                    let Ok(packet) = serde_json::to_vec(&beacon) else { continue };
+                    if let Err(e) = socket.send_to(&packet, target).await {
+                        tracing::debug!("Failed to send a discovery beacon: {}", e);
+                    }
+                }
+                received = socket.recv_from(&mut buffer) => {
+                    let Ok((len, from)) = received else { continue };
+                    if !discovery_enabled() {
+                        continue;
+                    }
+                    let Ok(beacon) = serde_json::from_slice::<Beacon>(&buffer[..len]) else {
+                        continue;
+                    };
+                    if beacon.service != SERVICE
+                        || !valid_url(&beacon.url)
+                        || !valid_id(&beacon.id)
+                        || !needs_introduction(&beacon.id)
+                    {
+                        continue;
+                    }
+                    tracing::info!(peer = %beacon.id, name = %beacon.name, %from, "Found a server on the LAN");
+                    actix_rt::spawn(async move {
+                        introduce(&beacon.url, PeerSource::Discovery).await;
+                    });
+                }
+            }
+        }
+    });
+}
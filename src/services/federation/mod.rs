@@ -0,0 +1,870 @@
+//! Linking up with other noplacelike servers on the LAN.
+//!
+//! Servers learn about each other from `federation.peers` or by
+//! [`discovery`], then one introduces itself to the other with a hello
+//! carrying a random secret. The two share that secret from then on and send
+//! it with every request between them, as `Authorization: Bearer` next to
+//! `X-Instance-Id`. A server only serves or shows a peer that an admin has
+//! trusted, so a link takes a yes on both sides. Once linked, a new hello
+//! from the same server has to carry an HMAC of itself under the old secret
+//! before it can change the secret or the URL.
+
+use hmac::{Hmac, Mac};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
+
+use crate::config::{self, current_config};
+use crate::error::AppError;
+use crate::http;
+use crate::services::devices::TrustLevel;
+use crate::services::events;
+use crate::services::store::JsonStore;
+
+pub mod discovery;
+
+/// How often linked peers are checked on and configured ones introduced to
+const SYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Longest peer name kept, in characters
+const MAX_NAME_LEN: usize = 64;
+
+/// Largest JSON answer read from another server
+const MAX_JSON_SIZE: usize = 1024 * 1024;
+
+/// How this server learned about a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerSource {
+    /// Listed in `federation.peers`
+    Config,
+    /// Found on the LAN
+    Discovery,
+    /// It introduced itself
+    Hello,
+}
+
+/// Another server this one knows about
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Peer {
+    /// The ID the server picked for itself
+    pub id: String,
+    pub name: String,
+    /// Base URL it is reached at
+    pub url: String,
+    /// Whether this server serves it and shows it to its users
+    #[serde(default)]
+    pub trust: TrustLevel,
+    pub source: PeerSource,
+    /// Seconds since the Unix epoch, 0 if it never answered
+    #[serde(default)]
+    pub last_seen: u64,
+    /// Whether it answered the last check
+    #[serde(default, skip_deserializing)]
+    pub online: bool,
+    /// Whether it trusts this server back
+    #[serde(default, skip_deserializing)]
+    pub trusts_us: bool,
+    /// Whether it merges clipboard changes with this server
+    #[serde(default, skip_deserializing)]
+    pub merge_clipboard: bool,
+}
+
+/// Sent by a server introducing itself, and in reply
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Hello {
+    pub id: String,
+    pub name: String,
+    pub url: String,
+    /// The secret the two servers will share, only sent by the one
+    /// introducing itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    /// HMAC-SHA256 of the hello under the secret the two already share, hex
+    /// encoded. Required when the server introducing itself is linked
+    /// already, so nobody else can replace its secret or URL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proof: Option<String>,
+}
+
+/// What a peer learns when it checks on this server
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PeerStatus {
+    pub id: String,
+    pub name: String,
+    /// Whether this server trusts the one asking
+    pub trusted: bool,
+    pub merge_clipboard: bool,
+}
+
+/// Changes to the peer list, for the admin panel and the web UI
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum PeerEvent {
+    #[serde(rename = "peer_updated")]
+    Updated(Peer),
+    #[serde(rename = "peer_removed")]
+    Removed { id: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PeerRecord {
+    #[serde(flatten)]
+    peer: Peer,
+    /// Shared with the peer to authenticate requests both ways
+    secret: Option<String>,
+    /// The server that picked `secret`. When both pick one at the same time,
+    /// the one with the lower ID wins.
+    secret_from: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Store {
+    /// This server's own ID, made up on first use
+    id: String,
+    peers: BTreeMap<String, PeerRecord>,
+}
+
+static STORE: JsonStore<Store> = JsonStore::new("federation.json", "peer list");
+
+/// Run `f` on the store, saving it afterwards when `f` reports a change
+fn with_store<R>(f: impl FnOnce(&mut Store) -> (R, bool)) -> Result<R, AppError> {
+    STORE.with(|store| {
+        let new_id = store.id.is_empty();
+        if new_id {
+            store.id = uuid::Uuid::new_v4().to_string();
+        }
+        let (result, changed) = f(store);
+        (result, new_id || changed)
+    })
+}
+
+fn publish(event: PeerEvent) {
+    events::publish(event);
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn new_secret() -> String {
+    format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+/// What a hello signed with `key` carries as its proof
+fn hello_proof(key: &str, hello: &Hello) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(
+        format!(
+            "{}\n{}\n{}\n{}",
+            hello.id,
+            hello.name,
+            hello.url,
+            hello.secret.as_deref().unwrap_or_default()
+        )
+        .as_bytes(),
+    );
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Compare secrets without giving away how much of a guess was right
+fn secrets_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+/// Whether a server ID is short and plain enough to be put in URLs and
+/// pages as is. IDs are UUIDs.
+pub(crate) fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_NAME_LEN
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Whether a server's base URL can be reached with the shared HTTP client
+pub(crate) fn valid_url(url: &str) -> bool {
+    config::url_host(url).is_some()
+}
+
+fn clean_name(name: &str) -> String {
+    let name: String = name.trim().chars().take(MAX_NAME_LEN).collect();
+    if name.is_empty() {
+        "Unnamed server".to_string()
+    } else {
+        name
+    }
+}
+
+/// This machine's host name
+pub(crate) fn host_name() -> String {
+    fs::read_to_string("/etc/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "noplacelike".to_string())
+}
+
+/// How this server introduces itself
+pub fn this_server() -> Result<Hello, AppError> {
+    let config = current_config()?;
+    let id = with_store(|store| (store.id.clone(), false))?;
+    let name = match config.federation.name.trim() {
+        "" => host_name(),
+        name => name.to_string(),
+    };
+    let url = match config.federation.url.trim() {
+        "" => {
+            let ip = local_ip_address::local_ip()
+                .map(|ip| ip.to_string())
+                .unwrap_or_else(|_| "127.0.0.1".to_string());
+            format!("http://{}:{}", ip, config.port)
+        }
+        url => url.trim_end_matches('/').to_string(),
+    };
+    Ok(Hello {
+        id,
+        name,
+        url,
+        secret: None,
+        proof: None,
+    })
+}
+
+pub fn is_enabled() -> bool {
+    current_config().is_ok_and(|c| c.federation.enabled)
+}
+
+/// Every known peer
+pub fn list() -> Result<Vec<Peer>, AppError> {
+    with_store(|store| {
+        let peers = store.peers.values().map(|r| r.peer.clone()).collect();
+        (peers, false)
+    })
+}
+
+/// A peer this server trusts, with what it takes to call it
+fn trusted(id: &str) -> Result<(Peer, String), AppError> {
+    with_store(|store| {
+        let found = store
+            .peers
+            .get(id)
+            .filter(|r| r.peer.trust == TrustLevel::Trusted)
+            .and_then(|r| Some((r.peer.clone(), r.secret.clone()?)));
+        (found, false)
+    })?
+    .ok_or_else(|| AppError::NotFound("No linked server with that ID".to_string()))
+}
+
+pub fn set_trust(id: &str, trust: TrustLevel) -> Result<Peer, AppError> {
+    let peer = with_store(|store| match store.peers.get_mut(id) {
+        Some(record) => {
+            record.peer.trust = trust;
+            (Some(record.peer.clone()), true)
+        }
+        None => (None, false),
+    })?
+    .ok_or_else(|| AppError::NotFound("Server not found".to_string()))?;
+
+    tracing::info!(peer = id, ?trust, "Server trust changed");
+    publish(PeerEvent::Updated(peer.clone()));
+    Ok(peer)
+}
+
+/// Forget a peer and the secret shared with it. It can introduce itself
+/// again, untrusted.
+pub fn remove(id: &str) -> Result<(), AppError> {
+    let removed = with_store(|store| {
+        let removed = store.peers.remove(id).is_some();
+        (removed, removed)
+    })?;
+    if !removed {
+        return Err(AppError::NotFound("Server not found".to_string()));
+    }
+    tracing::info!(peer = id, "Server forgotten");
+    publish(PeerEvent::Removed { id: id.to_string() });
+    Ok(())
+}
+
+/// Record `secret`, picked by server `from`, as the one shared with `hello`'s
+/// server. Returns `None` when a secret picked by a server with a lower ID is
+/// already in place.
+fn adopt_secret(
+    store: &mut Store,
+    hello: &Hello,
+    source: PeerSource,
+    secret: &str,
+    from: &str,
+) -> Option<Peer> {
+    let record = store
+        .peers
+        .entry(hello.id.clone())
+        .or_insert_with(|| PeerRecord {
+            peer: Peer {
+                id: hello.id.clone(),
+                name: String::new(),
+                url: String::new(),
+                trust: TrustLevel::Known,
+                source,
+                last_seen: 0,
+                online: false,
+                trusts_us: false,
+                merge_clipboard: false,
+            },
+            secret: None,
+            secret_from: None,
+        });
+
+    if let (Some(current), Some(current_from)) = (&record.secret, &record.secret_from) {
+        if current != secret {
+            if from > current_from.as_str() {
+                return None;
+            }
+            // Someone else could have made up the hello, so a trusted link
+            // needs trusting again
+            if record.peer.trust == TrustLevel::Trusted {
+                tracing::warn!(peer = %hello.id, "Server changed its secret; it has to be trusted again");
+                record.peer.trust = TrustLevel::Known;
+            }
+        }
+    }
+
+    record.peer.name = clean_name(&hello.name);
+    record.peer.url = hello.url.trim_end_matches('/').to_string();
+    record.peer.last_seen = now();
+    record.peer.online = true;
+    record.secret = Some(secret.to_string());
+    record.secret_from = Some(from.to_string());
+    Some(record.peer.clone())
+}
+
+/// Answer a server introducing itself
+pub fn accept_hello(hello: Hello) -> Result<Hello, AppError> {
+    let me = this_server()?;
+    let Some(secret) = hello.secret.clone().filter(|s| s.len() >= 32) else {
+        return Err(AppError::BadRequest(
+            "A secret of at least 32 characters is required".to_string(),
+        ));
+    };
+    if !valid_id(&hello.id) || hello.id == me.id {
+        return Err(AppError::BadRequest("Invalid server ID".to_string()));
+    }
+    if !valid_url(&hello.url) {
+        return Err(AppError::BadRequest(
+            "The URL must be an http:// or https:// URL".to_string(),
+        ));
+    }
+
+    let peer = with_store(|store| {
+        if let Some(record) = store.peers.get_mut(&hello.id) {
+            if record.peer.trust == TrustLevel::Blocked {
+                return (
+                    Err(AppError::Forbidden("This server is blocked".to_string())),
+                    false,
+                );
+            }
+            // Anyone can claim to be a linked server, but only it knows
+            // the secret the two share
+            if let Some(current) = &record.secret {
+                let proven = hello
+                    .proof
+                    .as_deref()
+                    .is_some_and(|proof| secrets_match(proof, &hello_proof(current, &hello)));
+                if !proven {
+                    tracing::warn!(peer = %hello.id, "Refused an introduction that didn't prove the shared secret");
+                    return (
+                        Err(AppError::Unauthorized(
+                            "This server is linked already; the introduction has to prove the secret the two share".to_string(),
+                        )),
+                        false,
+                    );
+                }
+                record.secret = None;
+                record.secret_from = None;
+            }
+        }
+        match adopt_secret(store, &hello, PeerSource::Hello, &secret, &hello.id) {
+            Some(peer) => (Ok(peer), true),
+            None => (
+                Err(AppError::BadRequest(
+                    "A link with this server is already being set up".to_string(),
+                )),
+                false,
+            ),
+        }
+    })??;
+
+    tracing::info!(peer = %peer.id, name = %peer.name, url = %peer.url, "Server introduced itself");
+    publish(PeerEvent::Updated(peer));
+    Ok(me)
+}
+
+/// The peer behind a request, from its `X-Instance-Id` and bearer token
+pub fn authenticate(id: Option<&str>, secret: Option<&str>) -> Result<Peer, AppError> {
+    let unauthorized = || AppError::Unauthorized("Unknown server or wrong secret".to_string());
+    let (Some(id), Some(secret)) = (id, secret) else {
+        return Err(unauthorized());
+    };
+    let peer = with_store(|store| {
+        let peer = store.peers.get_mut(id).and_then(|record| {
+            let known = record
+                .secret
+                .as_deref()
+                .is_some_and(|s| secrets_match(s, secret));
+            known.then(|| {
+                record.peer.last_seen = now();
+                record.peer.clone()
+            })
+        });
+        (peer, false)
+    })?
+    .ok_or_else(unauthorized)?;
+
+    if peer.trust == TrustLevel::Blocked {
+        return Err(AppError::Forbidden("This server is blocked".to_string()));
+    }
+    Ok(peer)
+}
+
+/// How this server looks to `peer`
+pub fn status_for(peer: &Peer) -> Result<PeerStatus, AppError> {
+    let me = this_server()?;
+    Ok(PeerStatus {
+        id: me.id,
+        name: me.name,
+        trusted: peer.trust == TrustLevel::Trusted,
+        merge_clipboard: current_config()?.federation.merge_clipboard,
+    })
+}
+
+fn auth_headers(me: &str, secret: &str) -> Vec<(String, String)> {
+    vec![
+        ("X-Instance-Id".to_string(), me.to_string()),
+        ("Authorization".to_string(), format!("Bearer {}", secret)),
+    ]
+}
+
+fn with_headers(mut request: RequestBuilder, headers: &[(String, String)]) -> RequestBuilder {
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    request
+}
+
+/// Send `body` as JSON
+async fn post_json<T: Serialize>(
+    url: &str,
+    headers: &[(String, String)],
+    body: &T,
+) -> Result<reqwest::Response, String> {
+    let body = serde_json::to_vec(body).map_err(|e| e.to_string())?;
+    with_headers(http::client().post(url), headers)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| http::describe(&e))
+}
+
+/// Read a whole JSON answer
+async fn read_json<T: DeserializeOwned>(response: reqwest::Response) -> Result<T, String> {
+    let body = http::read_limited(response, MAX_JSON_SIZE).await?;
+    serde_json::from_slice(&body).map_err(|e| format!("Invalid response: {}", e))
+}
+
+/// Introduce this server to the one at `url`
+pub async fn introduce(url: &str, source: PeerSource) {
+    let url = url.trim_end_matches('/');
+    let Ok(me) = this_server() else { return };
+    let secret = new_secret();
+    let mut hello = Hello {
+        secret: Some(secret.clone()),
+        ..me.clone()
+    };
+    // A server linked at this URL before needs proof that this is the same
+    // server introducing itself again
+    let current = with_store(|store| {
+        let current = store
+            .peers
+            .values()
+            .find(|r| r.peer.url == url)
+            .and_then(|r| r.secret.clone());
+        (current, false)
+    });
+    if let Ok(Some(current)) = current {
+        hello.proof = Some(hello_proof(&current, &hello));
+    }
+
+    let reply =
+        match post_json(&format!("{}/api/v1/federation/hello", url), &[], &hello).await {
+            Ok(response) if response.status() == StatusCode::OK => read_json::<Hello>(response).await,
+            Ok(response) if response.status() == StatusCode::UNAUTHORIZED => {
+                tracing::warn!(
+                    url,
+                    "Server is linked with this one under another secret; forget this server there to link again"
+                );
+                return;
+            }
+            Ok(response) => Err(format!("it answered {}", response.status().as_u16())),
+            Err(e) => Err(e),
+        };
+    let reply = match reply {
+        Ok(reply) if reply.id != me.id && valid_id(&reply.id) => reply,
+        Ok(_) => return,
+        Err(e) => {
+            tracing::debug!(url, "Failed to introduce this server: {}", e);
+            return;
+        }
+    };
+
+    // Keep the URL that worked rather than the one it reports
+    let hello = Hello {
+        url: url.to_string(),
+        ..reply
+    };
+    let peer = with_store(|store| {
+        let peer = adopt_secret(store, &hello, source, &secret, &me.id);
+        let changed = peer.is_some();
+        (peer, changed)
+    });
+    match peer {
+        Ok(Some(peer)) => {
+            tracing::info!(peer = %peer.id, name = %peer.name, url, "Linked with server");
+            publish(PeerEvent::Updated(peer));
+        }
+        Ok(None) => {}
+        Err(e) => tracing::error!("Failed to save the peer list: {}", e),
+    }
+}
+
+/// Check on a linked peer, introducing this server again if it has
+/// forgotten it
+async fn check(id: &str, url: &str, source: PeerSource, secret: &str, me: &str) {
+    let request = http::client().get(format!("{}/api/v1/federation/status", url));
+    let response = with_headers(request, &auth_headers(me, secret)).send().await;
+    let status = match response {
+        Ok(response) if response.status() == StatusCode::OK => {
+            read_json::<PeerStatus>(response).await.ok()
+        }
+        Ok(response) if response.status() == StatusCode::UNAUTHORIZED => {
+            tracing::info!(
+                peer = id,
+                "Server no longer knows this one, introducing it again"
+            );
+            let _ = with_store(|store| {
+                if let Some(record) = store.peers.get_mut(id) {
+                    record.secret = None;
+                    record.secret_from = None;
+                }
+                ((), true)
+            });
+            introduce(url, source).await;
+            return;
+        }
+        _ => None,
+    };
+
+    let updated = with_store(|store| {
+        let Some(record) = store.peers.get_mut(id) else {
+            return (None, false);
+        };
+        let before = (
+            record.peer.online,
+            record.peer.trusts_us,
+            record.peer.merge_clipboard,
+            record.peer.name.clone(),
+        );
+        match &status {
+            Some(status) => {
+                record.peer.online = true;
+                record.peer.trusts_us = status.trusted;
+                record.peer.merge_clipboard = status.merge_clipboard;
+                record.peer.name = clean_name(&status.name);
+                record.peer.last_seen = now();
+            }
+            None => record.peer.online = false,
+        }
+        let after = (
+            record.peer.online,
+            record.peer.trusts_us,
+            record.peer.merge_clipboard,
+            record.peer.name.clone(),
+        );
+        let changed = before != after;
+        (changed.then(|| record.peer.clone()), status.is_some())
+    });
+    if let Ok(Some(peer)) = updated {
+        publish(PeerEvent::Updated(peer));
+    }
+}
+
+/// Introduce this server to configured peers it isn't linked with yet and
+/// check on the linked ones
+async fn sync() {
+    let Ok(config) = current_config() else { return };
+    if !config.federation.enabled {
+        return;
+    }
+    let Ok(me) = this_server() else { return };
+    let Ok(linked) = with_store(|store| {
+        let linked: Vec<(String, String, PeerSource, String)> = store
+            .peers
+            .values()
+            .filter(|r| r.peer.trust != TrustLevel::Blocked)
+            .filter_map(|r| {
+                let secret = r.secret.clone()?;
+                Some((r.peer.id.clone(), r.peer.url.clone(), r.peer.source, secret))
+            })
+            .collect();
+        (linked, false)
+    }) else {
+        return;
+    };
+
+    for url in &config.federation.peers {
+        let url = url.trim_end_matches('/');
+        if !linked.iter().any(|(_, linked_url, _, _)| linked_url == url) {
+            introduce(url, PeerSource::Config).await;
+        }
+    }
+    for (id, url, source, secret) in linked {
+        check(&id, &url, source, &secret, &me.id).await;
+    }
+}
+
+/// Whether a server seen on the LAN still needs introducing to
+pub fn needs_introduction(id: &str) -> bool {
+    with_store(|store| {
+        let needed = id != store.id
+            && store.peers.get(id).is_none_or(|r| {
+                r.secret.is_none() && r.peer.trust != TrustLevel::Blocked
+            });
+        (needed, false)
+    })
+    .unwrap_or(false)
+}
+
+/// Keep links with other servers up in the background, while federation is
+/// on
+pub fn spawn() {
+    actix_rt::spawn(async {
+        let mut interval = actix_rt::time::interval(SYNC_INTERVAL);
+        loop {
+            interval.tick().await;
+            sync().await;
+        }
+    });
+    discovery::spawn();
+}
+
+/// Send a trusted peer a request for one of its own API routes, under
+/// `/api/v1/`. The answer's body is left to be passed on as it arrives.
+pub async fn fetch(
+    id: &str,
+    path_and_query: &str,
+    headers: &[(String, String)],
+) -> Result<reqwest::Response, AppError> {
+    if !is_enabled() {
+        return Err(AppError::Forbidden("Federation is off".to_string()));
+    }
+    let (peer, secret) = trusted(id)?;
+    let me = this_server()?;
+    let mut headers = headers.to_vec();
+    headers.extend(auth_headers(&me.id, &secret));
+    let request = http::streaming_client().get(format!("{}/api/v1/{}", peer.url, path_and_query));
+    with_headers(request, &headers)
+        .send()
+        .await
+        .map_err(|e| {
+            AppError::BadGateway(format!("{} can't be reached: {}", peer.name, http::describe(&e)))
+        })
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ClipboardUpdate {
+    pub text: String,
+}
+
+/// Pass a clipboard change on to the peers that merge clipboards with this
+/// server. What peers send here isn't passed on again.
+pub fn share_clipboard(text: &str) {
+    let Ok(config) = current_config() else { return };
+    if !config.federation.enabled || !config.federation.merge_clipboard {
+        return;
+    }
+    let (Ok(me), Ok(targets)) = (
+        this_server(),
+        with_store(|store| {
+            let targets: Vec<(String, String, String)> = store
+                .peers
+                .values()
+                .filter(|r| {
+                    r.peer.trust == TrustLevel::Trusted
+                        && r.peer.online
+                        && r.peer.trusts_us
+                        && r.peer.merge_clipboard
+                })
+                .filter_map(|r| Some((r.peer.id.clone(), r.peer.url.clone(), r.secret.clone()?)))
+                .collect();
+            (targets, false)
+        }),
+    ) else {
+        return;
+    };
+
+    for (id, url, secret) in targets {
+        let headers = auth_headers(&me.id, &secret);
+        let body = ClipboardUpdate {
+            text: text.to_string(),
+        };
+        actix_rt::spawn(async move {
+            let url = format!("{}/api/v1/federation/clipboard", url);
+            match post_json(&url, &headers, &body).await {
+                Ok(response) if response.status().as_u16() < 300 => {}
+                Ok(response) => {
+                    let status = response.status().as_u16();
+                    tracing::warn!(peer = %id, status, "Server refused the clipboard")
+                }
+                Err(e) => tracing::warn!(peer = %id, "Failed to share the clipboard: {}", e),
+            }
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(id: &str, secret: &str) -> Hello {
+        Hello {
+            id: id.to_string(),
+            name: "Other".to_string(),
+            url: "https://other.local".to_string(),
+            secret: Some(secret.to_string()),
+            proof: None,
+        }
+    }
+
+    fn new_id() -> String {
+        uuid::Uuid::new_v4().to_string()
+    }
+
+    #[test]
+    fn proof_covers_every_field() {
+        let hello = hello("a", &new_secret());
+        let proof = hello_proof("key", &hello);
+        assert_eq!(proof, hello_proof("key", &hello));
+        assert_eq!(proof.len(), 64);
+        assert_ne!(proof, hello_proof("other key", &hello));
+
+        let changed = [
+            Hello { id: "b".to_string(), ..hello.clone() },
+            Hello { name: "Another".to_string(), ..hello.clone() },
+            Hello { url: "http://evil.local".to_string(), ..hello.clone() },
+            Hello { secret: Some(new_secret()), ..hello.clone() },
+        ];
+        for other in &changed {
+            assert_ne!(proof, hello_proof("key", other));
+        }
+    }
+
+    #[test]
+    fn secret_picked_by_lower_id_wins() {
+        let mut store = Store::default();
+        let hello = hello("peer", "");
+        let peer = adopt_secret(&mut store, &hello, PeerSource::Hello, "from-b", "b").unwrap();
+        assert_eq!(peer.url, "https://other.local");
+
+        // Both servers introduced themselves at once: the one with the
+        // higher ID gives way
+        assert!(adopt_secret(&mut store, &hello, PeerSource::Hello, "from-c", "c").is_none());
+        assert_eq!(store.peers["peer"].secret.as_deref(), Some("from-b"));
+
+        store.peers.get_mut("peer").unwrap().peer.trust = TrustLevel::Trusted;
+        let peer = adopt_secret(&mut store, &hello, PeerSource::Hello, "from-a", "a").unwrap();
+        assert_eq!(store.peers["peer"].secret.as_deref(), Some("from-a"));
+        assert_eq!(peer.trust, TrustLevel::Known);
+
+        // The same secret again keeps the trust
+        store.peers.get_mut("peer").unwrap().peer.trust = TrustLevel::Trusted;
+        let peer = adopt_secret(&mut store, &hello, PeerSource::Hello, "from-a", "a").unwrap();
+        assert_eq!(peer.trust, TrustLevel::Trusted);
+    }
+
+    #[test]
+    fn hello_needs_a_long_secret_a_plain_id_and_a_web_url() {
+        let secret = new_secret();
+        let short = hello(&new_id(), "too short");
+        assert!(matches!(accept_hello(short), Err(AppError::BadRequest(_))));
+        for id in ["", "a'b", "<b>"] {
+            assert!(matches!(accept_hello(hello(id, &secret)), Err(AppError::BadRequest(_))));
+        }
+        let me = this_server().unwrap();
+        assert!(matches!(accept_hello(hello(&me.id, &secret)), Err(AppError::BadRequest(_))));
+        for url in ["ftp://other.local", "other.local", "http://"] {
+            let hello = Hello { url: url.to_string(), ..hello(&new_id(), &secret) };
+            assert!(matches!(accept_hello(hello), Err(AppError::BadRequest(_))), "{}", url);
+        }
+
+        let id = new_id();
+        assert_eq!(accept_hello(hello(&id, &secret)).unwrap().id, me.id);
+        assert_eq!(authenticate(Some(&id), Some(&secret)).unwrap().url, "https://other.local");
+    }
+
+    #[test]
+    fn linked_server_has_to_prove_the_shared_secret() {
+        let (id, first) = (new_id(), new_secret());
+        accept_hello(hello(&id, &first)).unwrap();
+
+        let second = new_secret();
+        let unproven = hello(&id, &second);
+        assert!(matches!(accept_hello(unproven.clone()), Err(AppError::Unauthorized(_))));
+        let wrong_key = Hello {
+            proof: Some(hello_proof(&new_secret(), &unproven)),
+            ..unproven.clone()
+        };
+        assert!(matches!(accept_hello(wrong_key), Err(AppError::Unauthorized(_))));
+        assert!(authenticate(Some(&id), Some(&first)).is_ok());
+
+        let proven = Hello {
+            proof: Some(hello_proof(&first, &unproven)),
+            ..unproven
+        };
+        accept_hello(proven).unwrap();
+        assert!(authenticate(Some(&id), Some(&first)).is_err());
+        assert!(authenticate(Some(&id), Some(&second)).is_ok());
+    }
+
+    #[test]
+    fn blocked_server_cannot_link_again() {
+        let id = new_id();
+        accept_hello(hello(&id, &new_secret())).unwrap();
+        set_trust(&id, TrustLevel::Blocked).unwrap();
+        assert!(matches!(
+            accept_hello(hello(&id, &new_secret())),
+            Err(AppError::Forbidden(_))
+        ));
+    }
+}
@@ -1,52 +1,248 @@
+use actix_web::web;
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
 
-use crate::config::ensure_upload_folder;
+use crate::error::{lock_recovering, AppError};
+use crate::services::{events, quota};
+use crate::storage;
 
-/// List all files in the upload folder
-pub fn list_files() -> Vec<String> {
-    let upload_path = ensure_upload_folder();
-    let mut files = Vec::new();
-
-    if let Ok(entries) = fs::read_dir(upload_path) {
-        for entry in entries {
-            if let Ok(entry) = entry {
-                if let Ok(file_type) = entry.file_type() {
-                    if file_type.is_file() {
-                        if let Some(file_name) = entry.file_name().to_str() {
-                            files.push(file_name.to_string());
-                        }
-                    }
-                }
+/// Least time between progress reports for one transfer
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+lazy_static::lazy_static! {
+    // Files currently being written, so a shutdown can remove leftovers
+    static ref PARTIAL_FILES: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+}
+
+/// How far an upload to or download from the upload folder has got
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TransferProgress {
+    /// Tells transfers of the same file apart
+    pub id: String,
+    pub name: String,
+    /// The device moving the file, when known
+    pub device_id: Option<String>,
+    pub bytes: u64,
+    /// Size of the whole file, when known
+    pub total: Option<u64>,
+    /// Set on the last report, whether or not every byte made it
+    pub done: bool,
+}
+
+/// Changes to the upload folder
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum FileEvent {
+    #[serde(rename = "file_added")]
+    Added {
+        name: String,
+        size: u64,
+        /// The device that uploaded it, when known
+        device_id: Option<String>,
+    },
+    #[serde(rename = "file_removed")]
+    Removed { name: String },
+    #[serde(rename = "file_renamed")]
+    Renamed { from: String, to: String },
+    /// A download of the whole file finished
+    #[serde(rename = "file_downloaded")]
+    Downloaded {
+        name: String,
+        size: u64,
+        /// The device that downloaded it, when known
+        device_id: Option<String>,
+    },
+    #[serde(rename = "upload_progress")]
+    UploadProgress(TransferProgress),
+    #[serde(rename = "download_progress")]
+    DownloadProgress(TransferProgress),
+}
+
+impl FileEvent {
+    /// Whether this reports transfer progress rather than a change
+    pub fn is_progress(&self) -> bool {
+        matches!(
+            self,
+            FileEvent::UploadProgress(_) | FileEvent::DownloadProgress(_)
+        )
+    }
+}
+
+/// Tell connected clients about a change to the upload folder
+pub fn notify(event: FileEvent) {
+    events::publish(event);
+}
+
+/// Reports a transfer's progress every `PROGRESS_INTERVAL` as bytes move,
+/// and once more when dropped
+pub struct Progress {
+    upload: bool,
+    state: RefCell<TransferProgress>,
+    last_report: Cell<Instant>,
+}
+
+impl Progress {
+    pub fn upload(name: &str, device_id: Option<&str>, total: Option<u64>) -> Self {
+        Self::new(true, name, device_id, total)
+    }
+
+    pub fn download(name: &str, device_id: Option<&str>, total: Option<u64>) -> Self {
+        Self::new(false, name, device_id, total)
+    }
+
+    fn new(upload: bool, name: &str, device_id: Option<&str>, total: Option<u64>) -> Self {
+        let progress = Self {
+            upload,
+            state: RefCell::new(TransferProgress {
+                id: uuid::Uuid::new_v4().to_string(),
+                name: name.to_string(),
+                device_id: device_id.map(str::to_string),
+                bytes: 0,
+                total,
+                done: false,
+            }),
+            last_report: Cell::new(Instant::now()),
+        };
+        progress.report();
+        progress
+    }
+
+    /// Count `len` more bytes as moved
+    pub fn advance(&self, len: u64) {
+        self.state.borrow_mut().bytes += len;
+        if self.last_report.get().elapsed() >= PROGRESS_INTERVAL {
+            self.report();
+        }
+    }
+
+    fn report(&self) {
+        self.last_report.set(Instant::now());
+        let state = self.state.borrow().clone();
+        notify(if self.upload {
+            FileEvent::UploadProgress(state)
+        } else {
+            FileEvent::DownloadProgress(state)
+        });
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.state.get_mut().done = true;
+        self.report();
+        let state = self.state.get_mut();
+        if !self.upload && state.total == Some(state.bytes) {
+            notify(FileEvent::Downloaded {
+                name: state.name.clone(),
+                size: state.bytes,
+                device_id: state.device_id.take(),
+            });
+        }
+    }
+}
+
+/// A file being written by an upload. Unless `complete` is called, the file
+/// is deleted when the guard is dropped, so a failed or aborted upload never
+/// leaves a truncated file behind.
+pub struct PartialFile {
+    path: PathBuf,
+    completed: bool,
+}
+
+impl PartialFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        lock_recovering(&PARTIAL_FILES).insert(path.clone());
+        Self {
+            path,
+            completed: false,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Keep the file
+    pub fn complete(mut self) {
+        self.completed = true;
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        lock_recovering(&PARTIAL_FILES).remove(&self.path);
+        if !self.completed {
+            match fs::remove_file(&self.path) {
+                Ok(_) => tracing::warn!(path = %self.path.display(), "Removed incomplete upload"),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => tracing::error!(path = %self.path.display(), "Failed to remove incomplete upload: {}", e),
             }
         }
     }
-    
-    files
 }
 
-/// Delete a file from the upload folder
-pub fn delete_file(filename: &str) -> Result<(), String> {
-    let sanitized_filename = sanitize_filename::sanitize(filename);
-    let file_path = ensure_upload_folder().join(sanitized_filename);
-    
-    if file_path.exists() {
-        match fs::remove_file(file_path) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(format!("Failed to delete file: {}", e)),
+/// Delete any files still being written. Called once the server has stopped,
+/// for uploads that were cut off without their guard being dropped.
+pub fn cleanup_partial_files() {
+    let paths: Vec<PathBuf> = lock_recovering(&PARTIAL_FILES).drain().collect();
+    for path in paths {
+        match fs::remove_file(&path) {
+            Ok(_) => tracing::warn!(path = %path.display(), "Removed incomplete upload"),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => tracing::error!(path = %path.display(), "Failed to remove incomplete upload: {}", e),
         }
-    } else {
-        Err("File not found".to_string())
     }
 }
 
-/// Get file path in the upload folder
-pub fn get_file_path(filename: &str) -> PathBuf {
-    let sanitized_filename = sanitize_filename::sanitize(filename);
-    ensure_upload_folder().join(sanitized_filename)
+/// List all files in the upload folder
+pub async fn list_files() -> Result<Vec<String>, AppError> {
+    let files = storage::current()?.list().await?;
+    Ok(files.into_iter().map(|file| file.name).collect())
+}
+
+/// Delete a file from the upload folder
+pub async fn delete_file(filename: &str) -> Result<(), AppError> {
+    let name = sanitize_filename::sanitize(filename);
+    storage::current()?.delete(&name).await?;
+    quota::usage_changed();
+    tracing::info!(name = %name, "Deleted upload");
+    notify(FileEvent::Removed { name });
+    Ok(())
 }
 
-/// Check if a file exists in the upload folder
-pub fn file_exists(filename: &str) -> bool {
-    get_file_path(filename).exists()
+/// Rename a file in the upload folder, refusing to replace another one.
+/// Returns the new name as stored.
+pub async fn rename_file(filename: &str, new_name: &str) -> Result<String, AppError> {
+    let from = sanitize_filename::sanitize(filename);
+    let to = sanitize_filename::sanitize(new_name);
+    if to.is_empty() {
+        return Err(AppError::BadRequest("Invalid file name".to_string()));
+    }
+
+    let storage = storage::current()?;
+    if storage.stat(&from).await?.is_none() {
+        return Err(AppError::NotFound("File not found".to_string()));
+    }
+    if from == to {
+        return Ok(to);
+    }
+    if storage.stat(&to).await?.is_some() {
+        return Err(AppError::Conflict(format!("{} already exists", to)));
+    }
+    storage.rename(&from, &to).await?;
+    let (old, new) = (from.clone(), to.clone());
+    web::block(move || quota::rename_record(&old, &new)).await??;
+    tracing::info!(from = %from, to = %to, "Renamed upload");
+    notify(FileEvent::Renamed {
+        from,
+        to: to.clone(),
+    });
+    Ok(to)
 }
@@ -0,0 +1,244 @@
+//! Hook commands: local programs run when a file is added to the upload
+//! folder or the clipboard changes, such as OCRing images or filing PDFs.
+//!
+//! A hook gets the event as JSON on stdin, the same body webhooks get, and
+//! the main details in `NOPLACELIKE_*` environment variables. Only commands
+//! listed in `hooks.allowed_commands` are run.
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::broadcast;
+use tokio::task::JoinSet;
+use tracing::Instrument;
+
+use crate::config::{current_config, expand_path, HookCommand, HooksConfig};
+use crate::services::events::{self, BusEvent, ClipboardEvent, Event};
+use crate::services::files::FileEvent;
+use crate::storage;
+
+/// Hooks waiting for their turn before new ones are dropped
+const MAX_QUEUED: usize = 100;
+
+/// Output kept from each stream for the log
+const MAX_LOGGED_OUTPUT: usize = 4096;
+
+/// One run of a hook command
+struct Job {
+    hook: HookCommand,
+    trigger: &'static str,
+    env: Vec<(&'static str, String)>,
+    stdin: Vec<u8>,
+}
+
+/// The runs an event calls for
+fn jobs_for(config: &HooksConfig, event: &BusEvent) -> Vec<Job> {
+    let (trigger, hooks, mut env, path) = match &event.event {
+        Event::File(FileEvent::Added {
+            name,
+            size,
+            device_id,
+        }) => {
+            // Only backends that keep files on this host have a path to give
+            let path = match storage::current() {
+                Ok(storage) => storage.local_path(name),
+                Err(e) => {
+                    tracing::error!("Failed to find the storage backend for hooks: {}", e);
+                    return Vec::new();
+                }
+            };
+            let hooks: Vec<&HookCommand> = config
+                .on_upload
+                .iter()
+                .filter(|hook| matches_file(hook, name))
+                .collect();
+            let mut env = vec![
+                ("NOPLACELIKE_FILE_NAME", name.clone()),
+                ("NOPLACELIKE_FILE_SIZE", size.to_string()),
+            ];
+            env.extend(
+                path.as_ref()
+                    .map(|path| ("NOPLACELIKE_FILE", path.display().to_string())),
+            );
+            env.extend(device_id.clone().map(|id| ("NOPLACELIKE_DEVICE_ID", id)));
+            ("upload", hooks, env, path)
+        }
+        Event::Clipboard(clipboard) => {
+            let mut env = Vec::new();
+            if let ClipboardEvent::Updated {
+                encrypted,
+                device_id,
+                peer_name,
+                ..
+            } = clipboard
+            {
+                env.push(("NOPLACELIKE_CLIPBOARD_ENCRYPTED", encrypted.to_string()));
+                env.extend(device_id.clone().map(|id| ("NOPLACELIKE_DEVICE_ID", id)));
+                env.extend(
+                    peer_name
+                        .clone()
+                        .map(|name| ("NOPLACELIKE_PEER_NAME", name)),
+                );
+            }
+            ("clipboard", config.on_clipboard.iter().collect(), env, None)
+        }
+        _ => return Vec::new(),
+    };
+    if hooks.is_empty() {
+        return Vec::new();
+    }
+
+    let mut body = event.to_json();
+    if let Some(fields) = body.as_object_mut() {
+        fields.insert("time".to_string(), event.time.into());
+        if let Some(path) = path {
+            fields.insert("path".to_string(), path.display().to_string().into());
+        }
+    }
+    let stdin = serde_json::to_vec(&body).unwrap_or_default();
+    let kind = body["type"].as_str().unwrap_or_default().to_string();
+    env.push(("NOPLACELIKE_EVENT", kind));
+    env.push(("NOPLACELIKE_EVENT_ID", event.id.to_string()));
+
+    hooks
+        .into_iter()
+        .filter(|hook| {
+            // Checked again here in case the config file was edited by hand
+            let allowed = config.is_allowed(&hook.command);
+            if !allowed {
+                tracing::warn!(command = %hook.command, "Hook command isn't in hooks.allowed_commands, skipping");
+            }
+            allowed
+        })
+        .map(|hook| Job {
+            hook: hook.clone(),
+            trigger,
+            env: env.clone(),
+            stdin: stdin.clone(),
+        })
+        .collect()
+}
+
+fn matches_file(hook: &HookCommand, name: &str) -> bool {
+    hook.files.is_empty()
+        || hook
+            .files
+            .iter()
+            .filter_map(|pattern| glob::Pattern::new(pattern).ok())
+            .any(|pattern| pattern.matches(name))
+}
+
+/// The end of some output, trimmed for the log
+fn tail(output: &[u8]) -> String {
+    let start = output.len().saturating_sub(MAX_LOGGED_OUTPUT);
+    String::from_utf8_lossy(&output[start..]).trim().to_string()
+}
+
+async fn run(job: Job, timeout: Duration) {
+    let mut command = Command::new(expand_path(&job.hook.command));
+    command
+        .args(&job.hook.args)
+        .envs(job.env.iter().map(|(k, v)| (*k, v)))
+        .env("NOPLACELIKE_HOOK", job.trigger)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    // Run where the files are, for backends that keep them on this host
+    let folder = storage::current()
+        .ok()
+        .and_then(|storage| storage.local_folder().map(Path::to_path_buf));
+    if let Some(folder) = folder.filter(|folder| folder.is_dir()) {
+        command.current_dir(folder);
+    }
+
+    let started = Instant::now();
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            tracing::error!("Failed to start hook: {}", e);
+            return;
+        }
+    };
+    let stdin = child.stdin.take();
+    let finished = tokio::time::timeout(timeout, async move {
+        if let Some(mut stdin) = stdin {
+            // A hook that doesn't read its input closes the pipe early
+            let _ = stdin.write_all(&job.stdin).await;
+        }
+        child.wait_with_output().await
+    })
+    .await;
+
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    match finished {
+        Ok(Ok(output)) => {
+            let (stdout, stderr) = (tail(&output.stdout), tail(&output.stderr));
+            if output.status.success() {
+                tracing::info!(elapsed_ms, stdout, stderr, "Hook finished");
+            } else {
+                tracing::warn!(elapsed_ms, status = %output.status, stdout, stderr, "Hook failed");
+            }
+        }
+        Ok(Err(e)) => tracing::error!(elapsed_ms, "Hook failed: {}", e),
+        // Dropping the child kills it
+        Err(_) => tracing::warn!(
+            timeout_secs = timeout.as_secs(),
+            "Hook timed out and was killed"
+        ),
+    }
+}
+
+/// Follow the event bus and run hooks for uploads and clipboard changes,
+/// at most `hooks.max_concurrent` at a time
+pub fn spawn() {
+    let mut rx = events::subscribe();
+    actix_rt::spawn(async move {
+        let mut queue: VecDeque<Job> = VecDeque::new();
+        let mut running = JoinSet::new();
+        loop {
+            tokio::select! {
+                received = rx.recv() => match received {
+                    Ok(event) => {
+                        let config = match current_config() {
+                            Ok(config) => config.hooks,
+                            Err(e) => {
+                                tracing::error!("Failed to read hook config: {}", e);
+                                continue;
+                            }
+                        };
+                        for job in jobs_for(&config, &event) {
+                            if queue.len() >= MAX_QUEUED {
+                                tracing::warn!(command = %job.hook.command, "Too many hooks waiting, skipping");
+                                continue;
+                            }
+                            queue.push_back(job);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        tracing::warn!(missed, "Hooks fell behind and skipped events");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+                Some(_) = running.join_next(), if !running.is_empty() => {}
+            }
+
+            let Ok(config) = current_config() else {
+                continue;
+            };
+            let limit = config.hooks.max_concurrent;
+            let timeout = Duration::from_secs(config.hooks.timeout_secs);
+            while limit == 0 || running.len() < limit {
+                let Some(job) = queue.pop_front() else {
+                    break;
+                };
+                let span =
+                    tracing::info_span!("hook", trigger = job.trigger, command = %job.hook.command);
+                running.spawn_local(run(job, timeout).instrument(span));
+            }
+        }
+    });
+}
@@ -0,0 +1,234 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
+
+use crate::error::AppError;
+use crate::services::store::{self, JsonStore};
+use crate::services::{devices, events};
+
+/// Longest text or URL that can be sent, in bytes
+pub const MAX_TEXT_LEN: usize = 64 * 1024;
+
+/// What was sent
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum ItemContent {
+    Text { text: String },
+    Url { url: String },
+    /// A file, stored on the host until the item is declined or acknowledged
+    File { name: String, size: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemStatus {
+    /// Waiting for the recipient to accept or decline it
+    Pending,
+    /// Accepted, and kept until the recipient acknowledges it
+    Accepted,
+}
+
+/// Something one device sent to another
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct InboxItem {
+    pub id: String,
+    /// ID of the sending device
+    pub from: String,
+    pub from_name: String,
+    /// ID of the receiving device
+    pub to: String,
+    #[serde(flatten)]
+    pub content: ItemContent,
+    /// Seconds since the Unix epoch
+    pub created_at: u64,
+    pub status: ItemStatus,
+}
+
+/// Inbox notifications, each meant for a single device
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum InboxEvent {
+    /// Something new for the recipient to accept or decline
+    #[serde(rename = "inbox_item")]
+    Item(InboxItem),
+    /// The recipient's answer, for the sender
+    #[serde(rename = "inbox_answered")]
+    Answered {
+        id: String,
+        #[serde(skip)]
+        sender: String,
+        /// ID of the device that answered
+        to: String,
+        accepted: bool,
+    },
+}
+
+impl InboxEvent {
+    /// The device this event should be delivered to
+    pub fn recipient(&self) -> &str {
+        match self {
+            InboxEvent::Item(item) => &item.to,
+            InboxEvent::Answered { sender, .. } => sender,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Inbox {
+    items: BTreeMap<String, InboxItem>,
+}
+
+static INBOX: JsonStore<Inbox> = JsonStore::new("inbox.json", "inbox");
+
+/// Where the file sent with item `id` is kept
+pub fn file_path(id: &str) -> Result<PathBuf, AppError> {
+    let dir = store::data_dir().join("inbox");
+    fs::create_dir_all(&dir)?;
+    Ok(dir.join(id))
+}
+
+/// Run `f` on the inbox, saving it afterwards when `f` reports a change
+fn with_inbox<R>(f: impl FnOnce(&mut Inbox) -> (R, bool)) -> Result<R, AppError> {
+    INBOX.with(f)
+}
+
+/// Check that a text or URL can be sent
+pub fn validate(content: &ItemContent) -> Result<(), AppError> {
+    let (value, what) = match content {
+        ItemContent::Text { text } => (text, "Text"),
+        ItemContent::Url { url } => (url, "URL"),
+        ItemContent::File { .. } => return Ok(()),
+    };
+    if value.trim().is_empty() {
+        return Err(AppError::BadRequest(format!("{} must not be empty", what)));
+    }
+    if value.len() > MAX_TEXT_LEN {
+        return Err(AppError::PayloadTooLarge(format!(
+            "{} is longer than {} bytes",
+            what, MAX_TEXT_LEN
+        )));
+    }
+    if let ItemContent::Url { url } = content {
+        if !(url.starts_with("http://") || url.starts_with("https://")) {
+            return Err(AppError::BadRequest(
+                "URL must start with http:// or https://".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Start an item from `from` to `to`, checking that the recipient exists.
+/// Files must be written to [`file_path`] for its ID before it is delivered.
+pub fn new_item(from: &str, to: &str, content: ItemContent) -> Result<InboxItem, AppError> {
+    validate(&content)?;
+    devices::get(to)?;
+
+    Ok(InboxItem {
+        id: uuid::Uuid::new_v4().to_string(),
+        from: from.to_string(),
+        from_name: devices::name_of(from).unwrap_or_else(|| "an unknown device".to_string()),
+        to: to.to_string(),
+        content,
+        created_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        status: ItemStatus::Pending,
+    })
+}
+
+/// Put an item in its recipient's inbox and notify them
+pub fn deliver(item: InboxItem) -> Result<InboxItem, AppError> {
+    with_inbox(|inbox| {
+        inbox.items.insert(item.id.clone(), item.clone());
+        ((), true)
+    })?;
+    tracing::info!(id = %item.id, from = %item.from, to = %item.to, "Delivered inbox item");
+    events::publish(InboxEvent::Item(item.clone()));
+    Ok(item)
+}
+
+/// The ID, sender and size of every file waiting in an inbox
+pub fn stored_files() -> Result<Vec<(String, String, u64)>, AppError> {
+    with_inbox(|inbox| {
+        let files = inbox
+            .items
+            .values()
+            .filter_map(|item| match &item.content {
+                ItemContent::File { size, .. } => Some((item.id.clone(), item.from.clone(), *size)),
+                _ => None,
+            })
+            .collect();
+        (files, false)
+    })
+}
+
+/// Everything in a device's inbox, oldest first
+pub fn items_for(device: &str) -> Result<Vec<InboxItem>, AppError> {
+    let mut items: Vec<InboxItem> = with_inbox(|inbox| {
+        let items = inbox.items.values().filter(|i| i.to == device).cloned().collect();
+        (items, false)
+    })?;
+    items.sort_by_key(|i| i.created_at);
+    Ok(items)
+}
+
+/// Find an item in `device`'s inbox. Items for other devices are reported
+/// as missing.
+pub fn get(device: &str, id: &str) -> Result<InboxItem, AppError> {
+    with_inbox(|inbox| (inbox.items.get(id).filter(|i| i.to == device).cloned(), false))?
+        .ok_or_else(|| AppError::NotFound("No such item in this device's inbox".to_string()))
+}
+
+/// Accept or decline a pending item. Declined items are removed straight
+/// away.
+pub fn answer(device: &str, id: &str, accept: bool) -> Result<InboxItem, AppError> {
+    let mut item = get(device, id)?;
+    if item.status != ItemStatus::Pending {
+        return Err(AppError::BadRequest("Item was already accepted".to_string()));
+    }
+
+    if accept {
+        item.status = ItemStatus::Accepted;
+        with_inbox(|inbox| {
+            inbox.items.insert(item.id.clone(), item.clone());
+            ((), true)
+        })?;
+    } else {
+        remove(&item)?;
+    }
+
+    tracing::info!(id, device, accept, "Inbox item answered");
+    events::publish(InboxEvent::Answered {
+        id: item.id.clone(),
+        sender: item.from.clone(),
+        to: item.to.clone(),
+        accepted: accept,
+    });
+    Ok(item)
+}
+
+/// Remove an item the recipient is done with
+pub fn acknowledge(device: &str, id: &str) -> Result<(), AppError> {
+    let item = get(device, id)?;
+    remove(&item)
+}
+
+fn remove(item: &InboxItem) -> Result<(), AppError> {
+    with_inbox(|inbox| {
+        inbox.items.remove(&item.id);
+        ((), true)
+    })?;
+    if let ItemContent::File { .. } = item.content {
+        let path = file_path(&item.id)?;
+        if let Err(e) = fs::remove_file(&path) {
+            tracing::warn!(path = %path.display(), "Failed to remove inbox file: {}", e);
+        }
+    }
+    Ok(())
+}
+
@@ -0,0 +1,60 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::config::current_config;
+use crate::error::AppError;
+
+static ACTIVE_UPLOADS: AtomicUsize = AtomicUsize::new(0);
+static ACTIVE_DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferKind {
+    /// Uploads and pushes
+    Upload,
+    /// Downloads and audio streams
+    Download,
+}
+
+impl TransferKind {
+    fn counter(self) -> &'static AtomicUsize {
+        match self {
+            TransferKind::Upload => &ACTIVE_UPLOADS,
+            TransferKind::Download => &ACTIVE_DOWNLOADS,
+        }
+    }
+}
+
+/// A place among the transfers allowed to run at once, given back on drop
+#[derive(Debug)]
+pub struct TransferSlot(TransferKind);
+
+impl Drop for TransferSlot {
+    fn drop(&mut self) {
+        self.0.counter().fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Take a transfer slot, or fail straight away when the configured limit is
+/// reached. The limit is read on every call so config changes apply at once.
+pub fn acquire(kind: TransferKind) -> Result<TransferSlot, AppError> {
+    let config = current_config()?;
+    let limit = match kind {
+        TransferKind::Upload => config.max_concurrent_uploads,
+        TransferKind::Download => config.max_concurrent_downloads,
+    };
+
+    kind.counter()
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+            (limit == 0 || active < limit).then_some(active + 1)
+        })
+        .map_err(|_| {
+            tracing::warn!(?kind, limit, "Transfer limit reached");
+            AppError::Unavailable(match kind {
+                TransferKind::Upload => "Too many uploads in progress, try again shortly".to_string(),
+                TransferKind::Download => {
+                    "Too many downloads in progress, try again shortly".to_string()
+                }
+            })
+        })?;
+
+    Ok(TransferSlot(kind))
+}
@@ -1,3 +1,14 @@
-pub mod audio;
-pub mod clipboard;
+pub mod devices;
+pub mod drops;
+pub mod e2e;
+pub mod events;
+pub mod federation;
 pub mod files;
+pub mod hooks;
+pub mod inbox;
+pub mod limits;
+pub mod quota;
+pub mod store;
+pub mod sync;
+pub mod transfers;
+pub mod webhooks;
@@ -0,0 +1,699 @@
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+use crate::config::{current_config, QuotaConfig};
+use crate::error::{lock_recovering, AppError};
+use crate::services::files::{self, FileEvent};
+use crate::services::{inbox, sync};
+use crate::services::store::JsonStore;
+use crate::storage;
+
+/// How much can be written between free space checks
+const SPACE_CHECK_INTERVAL: u64 = 8 * 1024 * 1024;
+
+/// How long a listing of the upload folder is trusted by quota checks, so
+/// files changed outside the server are noticed eventually
+const USAGE_TTL: Duration = Duration::from_secs(30);
+
+/// Who uploaded a file and whether it is protected from eviction
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct UploadRecord {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+    #[serde(default)]
+    pinned: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct UploadIndex {
+    files: BTreeMap<String, UploadRecord>,
+}
+
+/// The last listing of the upload folder, dropped whenever the server
+/// changes what's in it
+#[derive(Default)]
+struct UsageCache {
+    generation: u64,
+    files: Option<(Instant, Vec<UploadedFile>)>,
+}
+
+/// The bytes an upload still being written counts against the quotas
+struct Reservation {
+    destination: Destination,
+    device_id: Option<String>,
+    bytes: u64,
+}
+
+static INDEX: JsonStore<UploadIndex> = JsonStore::new("uploads.json", "upload index");
+
+lazy_static::lazy_static! {
+    static ref USAGE: Mutex<UsageCache> = Mutex::new(UsageCache::default());
+    // Keyed by `UploadBudget::id`
+    static ref IN_FLIGHT: Mutex<HashMap<u64, Reservation>> = Mutex::new(HashMap::new());
+}
+
+static NEXT_BUDGET: AtomicU64 = AtomicU64::new(0);
+
+/// A file in the upload folder
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct UploadedFile {
+    pub name: String,
+    pub size: u64,
+    /// Last modification time, in seconds since the Unix epoch
+    pub modified: u64,
+    /// The device that uploaded it, when known
+    pub device_id: Option<String>,
+    /// Pinned files are never evicted
+    pub pinned: bool,
+}
+
+/// Run `f` on the index, saving it afterwards when `f` reports a change
+fn with_index<R>(f: impl FnOnce(&mut UploadIndex) -> (R, bool)) -> Result<R, AppError> {
+    INDEX.with(|index| {
+        let (result, changed) = f(index);
+        if changed {
+            usage_changed();
+        }
+        (result, changed)
+    })
+}
+
+/// List the files in the upload folder along with their owners and pins.
+/// Index entries for files that no longer exist are dropped.
+pub async fn uploaded_files() -> Result<Vec<UploadedFile>, AppError> {
+    let generation = lock_recovering(&USAGE).generation;
+    let stored = storage::current()?.list().await?;
+
+    let files: Vec<UploadedFile> = web::block(move || {
+        with_index(|index| {
+            let before = index.files.len();
+            index
+                .files
+                .retain(|name, _| stored.iter().any(|file| file.name == *name));
+            let changed = index.files.len() != before;
+
+            let files = stored
+                .into_iter()
+                .map(|file| {
+                    let record = index.files.get(&file.name).cloned().unwrap_or_default();
+                    UploadedFile {
+                        name: file.name,
+                        size: file.size,
+                        modified: file.modified,
+                        device_id: record.device_id,
+                        pinned: record.pinned,
+                    }
+                })
+                .collect();
+            (files, changed)
+        })
+    })
+    .await??;
+
+    // Unless the folder changed while it was being listed
+    let mut usage = lock_recovering(&USAGE);
+    if usage.generation == generation {
+        usage.files = Some((Instant::now(), files.clone()));
+    }
+    Ok(files)
+}
+
+/// The files in the upload folder as last listed, listing them again when
+/// that's too old or something has changed since
+async fn current_usage() -> Result<Vec<UploadedFile>, AppError> {
+    if let Some((listed_at, files)) = &lock_recovering(&USAGE).files {
+        if listed_at.elapsed() < USAGE_TTL {
+            return Ok(files.clone());
+        }
+    }
+    uploaded_files().await
+}
+
+/// Note that files in the upload folder were added, removed or changed, so
+/// the next quota check lists them again
+pub fn usage_changed() {
+    let mut usage = lock_recovering(&USAGE);
+    usage.generation += 1;
+    usage.files = None;
+}
+
+/// Remember which device uploaded a file. A replaced file keeps its pin.
+pub fn record_upload(name: &str, device_id: Option<&str>) -> Result<(), AppError> {
+    with_index(|index| {
+        let record = index.files.entry(name.to_string()).or_default();
+        record.device_id = device_id.map(str::to_string);
+        ((), true)
+    })
+}
+
+/// Carry a file's owner and pin over to its new name
+pub fn rename_record(from: &str, to: &str) -> Result<(), AppError> {
+    with_index(|index| match index.files.remove(from) {
+        Some(record) => {
+            index.files.insert(to.to_string(), record);
+            ((), true)
+        }
+        None => ((), false),
+    })
+}
+
+/// Protect a file in the upload folder from eviction, or stop protecting it
+pub async fn set_pinned(name: String, pinned: bool) -> Result<(), AppError> {
+    if storage::current()?.stat(&name).await?.is_none() {
+        return Err(AppError::NotFound("File not found".to_string()));
+    }
+    web::block(move || {
+        with_index(|index| {
+            index.files.entry(name).or_default().pinned = pinned;
+            ((), true)
+        })
+    })
+    .await?
+}
+
+/// Where an upload is going, which decides the limits that apply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    /// The shared upload folder, subject to every quota
+    Uploads,
+    /// The host's download folder, which only has the file size and free
+    /// space limits
+    Downloads,
+    /// A device's inbox, where the device and total quotas count the files
+    /// waiting in every inbox, and nothing is evicted
+    Inbox,
+    /// A synced folder, where the device and total quotas count the files in
+    /// every synced folder and the chunks waiting to be committed, and
+    /// nothing is evicted
+    Sync,
+}
+
+/// Tracks how much an upload may still write before a limit is hit.
+///
+/// Limits are worked out up front and again whenever the upload passes its
+/// current allowance or has written another `SPACE_CHECK_INTERVAL` bytes.
+#[derive(Debug)]
+pub struct UploadBudget {
+    /// Identifies this upload's reservation in `IN_FLIGHT`
+    id: u64,
+    /// The host folder being written to, or `None` for the upload folder's
+    /// storage
+    dir: Option<PathBuf>,
+    name: String,
+    /// Stored files that stop counting once this upload is done, besides
+    /// `name` itself
+    replaces: Vec<String>,
+    device_id: Option<String>,
+    destination: Destination,
+    quota: QuotaConfig,
+    expected: Option<u64>,
+    written: u64,
+    allowance: u64,
+    next_space_check: u64,
+}
+
+impl UploadBudget {
+    /// Check the limits for a new upload of `name` into the upload folder,
+    /// using the size the client declared (if any) to refuse or make room
+    /// early
+    pub async fn upload(
+        name: &str,
+        device_id: Option<&str>,
+        expected: Option<u64>,
+    ) -> Result<Self, AppError> {
+        Self::start(None, name, device_id, Destination::Uploads, expected, Vec::new()).await
+    }
+
+    /// Check the limits for a new upload of `name` into the host folder
+    /// `dir`, like [`UploadBudget::upload`]
+    pub async fn new(
+        dir: &Path,
+        name: &str,
+        device_id: Option<&str>,
+        destination: Destination,
+        expected: Option<u64>,
+    ) -> Result<Self, AppError> {
+        let dir = Some(dir.to_path_buf());
+        Self::start(dir, name, device_id, destination, expected, Vec::new()).await
+    }
+
+    /// Check the limits for committing `name`, of `size` bytes, to the synced
+    /// folder `dir`. The `staged` chunks it's made from are counted as part
+    /// of the file rather than alongside it.
+    pub async fn sync_commit(
+        dir: &Path,
+        name: &str,
+        device_id: &str,
+        size: u64,
+        staged: Vec<String>,
+    ) -> Result<Self, AppError> {
+        let dir = Some(dir.to_path_buf());
+        Self::start(dir, name, Some(device_id), Destination::Sync, Some(size), staged).await
+    }
+
+    async fn start(
+        dir: Option<PathBuf>,
+        name: &str,
+        device_id: Option<&str>,
+        destination: Destination,
+        expected: Option<u64>,
+        replaces: Vec<String>,
+    ) -> Result<Self, AppError> {
+        let mut budget = Self {
+            id: NEXT_BUDGET.fetch_add(1, Ordering::Relaxed),
+            dir,
+            name: name.to_string(),
+            replaces,
+            device_id: device_id.map(str::to_string),
+            destination,
+            quota: current_config()?.quota,
+            expected,
+            written: 0,
+            allowance: 0,
+            next_space_check: 0,
+        };
+        budget.check().await?;
+        Ok(budget)
+    }
+
+    /// Count `len` more bytes as written. Returns true when the limits must
+    /// be checked again before writing them.
+    pub fn charge(&mut self, len: u64) -> bool {
+        self.written += len;
+        self.written > self.allowance || self.written >= self.next_space_check
+    }
+
+    /// Work out the allowance again, evicting old uploads if allowed, or fail
+    /// with the limit that was hit
+    pub async fn check(&mut self) -> Result<(), AppError> {
+        // The least this upload is known to need: the declared size, or what
+        // has been written when the client didn't say
+        let required = self.expected.unwrap_or(0).max(self.written);
+        let mut allowance = u64::MAX;
+
+        let max_file = self.quota.max_file_size;
+        if max_file > 0 {
+            if required > max_file {
+                return Err(AppError::PayloadTooLarge(format!(
+                    "File is larger than the {} upload limit",
+                    format_size(max_file)
+                )));
+            }
+            allowance = allowance.min(max_file);
+        }
+
+        if self.destination != Destination::Downloads {
+            allowance = allowance.min(self.check_quotas(required).await?);
+        }
+
+        // `min_free_bytes` is kept free on the host's disk, not in other stores
+        let (available, reserve) = match &self.dir {
+            Some(dir) => {
+                let dir = dir.clone();
+                let available = web::block(move || fs2::available_space(dir)).await??;
+                (Some(available), self.quota.min_free_bytes)
+            }
+            None => {
+                let storage = storage::current()?;
+                let reserve = match storage.local_folder() {
+                    Some(_) => self.quota.min_free_bytes,
+                    None => 0,
+                };
+                (storage.available_space().await?, reserve)
+            }
+        };
+        // Bytes already written have left the free space, so only the rest
+        // of the file has to fit
+        if let Some(available) = available {
+            let usable = available.saturating_sub(reserve);
+            if usable == 0 || usable < required - self.written {
+                return Err(AppError::InsufficientStorage(
+                    "Not enough free disk space on the host for this file".to_string(),
+                ));
+            }
+            allowance = allowance.min(self.written + usable);
+        }
+
+        self.allowance = allowance;
+        self.next_space_check = self.written + SPACE_CHECK_INTERVAL;
+        Ok(())
+    }
+
+    /// Check the device and folder quotas, returning how much this upload
+    /// may write in total
+    async fn check_quotas(&self, required: u64) -> Result<u64, AppError> {
+        let max_device = self.quota.max_device_bytes;
+        let max_total = self.quota.max_total_bytes;
+        if max_device == 0 && max_total == 0 {
+            return Ok(u64::MAX);
+        }
+
+        let reserved = self.reserve(required);
+        let others: Vec<UploadedFile> = match self.destination {
+            Destination::Inbox => web::block(inbox::stored_files)
+                .await??
+                .into_iter()
+                .map(|(id, from, size)| UploadedFile {
+                    name: id,
+                    size,
+                    modified: 0,
+                    device_id: Some(from),
+                    pinned: true,
+                })
+                .collect(),
+            Destination::Sync => web::block(sync::stored_files)
+                .await??
+                .into_iter()
+                .map(|(name, device_id, size)| UploadedFile {
+                    name,
+                    size,
+                    modified: 0,
+                    device_id,
+                    pinned: true,
+                })
+                .collect(),
+            _ => current_usage().await?,
+        };
+        let (others, used) = self.usage(others, reserved);
+
+        let mut allowance = self.device_allowance(used.device, required)?;
+        if max_total > 0 {
+            let mut used = used.total;
+            let evictable = self.destination == Destination::Uploads && self.quota.evict_oldest;
+            if used + required > max_total && evictable {
+                used -= evict(others, used + required - max_total).await?;
+            }
+            allowance = allowance.min(self.total_allowance(used, required)?);
+        }
+        Ok(allowance)
+    }
+
+    /// Hold on to what this upload needs, so uploads running alongside it
+    /// can't take the same room. Returns what the others have reserved.
+    fn reserve(&self, required: u64) -> Usage {
+        let mut in_flight = lock_recovering(&IN_FLIGHT);
+        in_flight.insert(
+            self.id,
+            Reservation {
+                destination: self.destination,
+                device_id: self.device_id.clone(),
+                bytes: required,
+            },
+        );
+        in_flight
+            .iter()
+            .filter(|(id, r)| **id != self.id && r.destination == self.destination)
+            .fold(Usage::default(), |usage, (_, r)| {
+                usage.add(r.bytes, r.device_id == self.device_id)
+            })
+    }
+
+    /// The stored files other than this one, and how much they and
+    /// `reserved` take up. The file being written (and any file it replaces)
+    /// is counted by what has been written so far instead.
+    fn usage(&self, files: Vec<UploadedFile>, reserved: Usage) -> (Vec<UploadedFile>, Usage) {
+        let others: Vec<UploadedFile> = files
+            .into_iter()
+            .filter(|f| f.name != self.name && !self.replaces.contains(&f.name))
+            .collect();
+        let used = others
+            .iter()
+            .fold(reserved, |usage, f| usage.add(f.size, f.device_id == self.device_id));
+        (others, used)
+    }
+
+    /// How much this upload may write under the device quota, with `used`
+    /// taken by the device already.
+    ///
+    /// Uploads without a registered device share one allowance. Each device
+    /// registered gets an allowance of its own, so it's `max_total_bytes`
+    /// that caps what can be stored overall.
+    fn device_allowance(&self, used: u64, required: u64) -> Result<u64, AppError> {
+        let max_device = self.quota.max_device_bytes;
+        if max_device == 0 {
+            return Ok(u64::MAX);
+        }
+        if used + required > max_device {
+            return Err(AppError::PayloadTooLarge(format!(
+                "This device has used {} of its {} upload quota",
+                format_size(used),
+                format_size(max_device)
+            )));
+        }
+        Ok(max_device - used)
+    }
+
+    /// How much this upload may write under the total quota, with `used`
+    /// taken already
+    fn total_allowance(&self, used: u64, required: u64) -> Result<u64, AppError> {
+        let max_total = self.quota.max_total_bytes;
+        if used + required > max_total {
+            let place = match self.destination {
+                Destination::Inbox => "The inboxes are",
+                Destination::Sync => "The synced folders are",
+                _ => "The upload folder is",
+            };
+            return Err(AppError::PayloadTooLarge(format!(
+                "{} full ({} of {} used)",
+                place,
+                format_size(used),
+                format_size(max_total)
+            )));
+        }
+        Ok(max_total - used)
+    }
+}
+
+/// Bytes counted against the quotas
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Usage {
+    /// By the device uploading
+    device: u64,
+    /// By everyone
+    total: u64,
+}
+
+impl Usage {
+    fn add(self, bytes: u64, same_device: bool) -> Self {
+        Self {
+            device: self.device + if same_device { bytes } else { 0 },
+            total: self.total + bytes,
+        }
+    }
+}
+
+impl Drop for UploadBudget {
+    fn drop(&mut self) {
+        lock_recovering(&IN_FLIGHT).remove(&self.id);
+        // The file is stored now, or was cleaned up when the upload failed
+        if self.destination == Destination::Uploads && self.written > 0 {
+            usage_changed();
+        }
+    }
+}
+
+/// Delete the oldest unpinned uploads until `needed` bytes are freed or
+/// nothing is left to delete, returning the bytes freed
+async fn evict(mut files: Vec<UploadedFile>, needed: u64) -> Result<u64, AppError> {
+    files.retain(|f| !f.pinned);
+    files.sort_by_key(|f| f.modified);
+
+    let storage = storage::current()?;
+    let mut freed = 0;
+    let mut evicted = Vec::new();
+    for file in files {
+        if freed >= needed {
+            break;
+        }
+        match storage.delete(&file.name).await {
+            Ok(_) => {
+                tracing::info!(name = %file.name, bytes = file.size, "Evicted upload to stay under quota");
+                freed += file.size;
+                evicted.push(file.name.clone());
+                files::notify(FileEvent::Removed { name: file.name });
+            }
+            Err(e) => {
+                tracing::error!(name = %file.name, "Failed to evict upload: {}", e);
+            }
+        }
+    }
+
+    web::block(move || {
+        with_index(|index| {
+            for name in &evicted {
+                index.files.remove(name);
+            }
+            ((), !evicted.is_empty())
+        })
+    })
+    .await??;
+    Ok(freed)
+}
+
+/// Format a byte count for error messages
+pub(crate) fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(destination: Destination, device_id: Option<&str>) -> UploadBudget {
+        UploadBudget {
+            id: NEXT_BUDGET.fetch_add(1, Ordering::Relaxed),
+            dir: None,
+            name: "new.bin".to_string(),
+            replaces: Vec::new(),
+            device_id: device_id.map(str::to_string),
+            destination,
+            quota: QuotaConfig {
+                max_device_bytes: 100,
+                max_total_bytes: 250,
+                ..QuotaConfig::default()
+            },
+            expected: None,
+            written: 0,
+            allowance: 0,
+            next_space_check: 0,
+        }
+    }
+
+    fn file(name: &str, size: u64, device_id: Option<&str>) -> UploadedFile {
+        UploadedFile {
+            name: name.to_string(),
+            size,
+            modified: 0,
+            device_id: device_id.map(str::to_string),
+            pinned: false,
+        }
+    }
+
+    #[test]
+    fn usage_counts_the_device_and_everyone() {
+        let budget = budget(Destination::Uploads, Some("phone"));
+        let files = vec![
+            file("a.txt", 30, Some("phone")),
+            file("b.txt", 50, Some("laptop")),
+            file("c.txt", 7, None),
+            // Being replaced, so counted by what has been written instead
+            file("new.bin", 1000, Some("phone")),
+        ];
+        let reserved = Usage { device: 5, total: 11 };
+
+        let (others, used) = budget.usage(files, reserved);
+        assert_eq!(others.len(), 3);
+        assert_eq!(used, Usage { device: 35, total: 98 });
+    }
+
+    #[test]
+    fn sync_commits_take_the_place_of_their_staged_chunks() {
+        let mut budget = budget(Destination::Sync, Some("phone"));
+        budget.replaces = vec!["aa".to_string()];
+        let files = vec![
+            file("docs/a.txt", 30, Some("phone")),
+            // Staged chunks belong to no device; this one becomes new.bin
+            file("aa", 40, None),
+            file("bb", 20, None),
+        ];
+
+        let (others, used) = budget.usage(files, Usage::default());
+        assert_eq!(others.len(), 2);
+        assert_eq!(used, Usage { device: 30, total: 50 });
+    }
+
+    #[test]
+    fn uploads_without_a_device_share_an_allowance() {
+        let budget = budget(Destination::Uploads, None);
+        let files = vec![file("a.txt", 60, None), file("b.txt", 60, Some("phone"))];
+        let (_, used) = budget.usage(files, Usage::default());
+        assert_eq!(used.device, 60);
+    }
+
+    #[test]
+    fn allowances_are_whats_left_under_each_quota() {
+        let upload = budget(Destination::Uploads, Some("phone"));
+        assert_eq!(upload.device_allowance(30, 10).unwrap(), 70);
+        assert_eq!(upload.total_allowance(200, 50).unwrap(), 50);
+
+        let err = upload.device_allowance(95, 10).unwrap_err();
+        assert!(matches!(&err, AppError::PayloadTooLarge(m) if m.contains("of its 100 B")));
+        let err = upload.total_allowance(200, 51).unwrap_err();
+        assert!(matches!(&err, AppError::PayloadTooLarge(m) if m.starts_with("The upload folder")));
+
+        let inbox = budget(Destination::Inbox, Some("phone"));
+        let err = inbox.total_allowance(250, 1).unwrap_err();
+        assert!(matches!(&err, AppError::PayloadTooLarge(m) if m.starts_with("The inboxes")));
+    }
+
+    #[test]
+    fn no_device_quota_means_no_device_limit() {
+        let mut budget = budget(Destination::Uploads, Some("phone"));
+        budget.quota.max_device_bytes = 0;
+        assert_eq!(budget.device_allowance(u64::MAX / 2, 10).unwrap(), u64::MAX);
+    }
+
+    #[test]
+    fn uploads_in_progress_reserve_room() {
+        // A device ID of its own keeps other tests' reservations out of the
+        // device counts
+        let first = budget(Destination::Inbox, Some("reserving-phone"));
+        let second = budget(Destination::Inbox, Some("reserving-phone"));
+        let other = budget(Destination::Inbox, Some("reserving-laptop"));
+
+        let before = second.reserve(0);
+        first.reserve(40);
+        other.reserve(25);
+        let seen = second.reserve(10);
+        assert_eq!(seen.device, 40);
+        assert!(seen.total >= before.total + 65);
+
+        // Reserving again replaces the earlier reservation
+        first.reserve(60);
+        assert_eq!(second.reserve(10).device, 60);
+
+        drop(first);
+        assert_eq!(second.reserve(10).device, 0);
+        // Uploads to other destinations don't count
+        let upload = budget(Destination::Uploads, Some("reserving-phone"));
+        upload.reserve(500);
+        assert_eq!(second.reserve(10).device, 0);
+    }
+
+    #[test]
+    fn charging_asks_for_a_check_past_the_allowance() {
+        let mut budget = budget(Destination::Downloads, None);
+        budget.allowance = 100;
+        budget.next_space_check = SPACE_CHECK_INTERVAL;
+        assert!(!budget.charge(60));
+        assert!(!budget.charge(40));
+        assert!(budget.charge(1));
+
+        budget.allowance = u64::MAX;
+        assert!(budget.charge(SPACE_CHECK_INTERVAL));
+    }
+
+    #[test]
+    fn sizes_are_formatted_in_binary_units() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+}
@@ -0,0 +1,87 @@
+//! State kept as JSON files in `$XDG_DATA_HOME/noplacelike`: loaded on first
+//! use, kept in memory behind a lock and written back whole, through a
+//! temporary file, when it changes.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::error::AppError;
+
+/// Where the server keeps its state
+#[cfg(not(test))]
+pub fn data_dir() -> PathBuf {
+    dirs::data_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("noplacelike")
+}
+
+/// Tests keep their state out of the real data directory
+#[cfg(test)]
+pub fn data_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("noplacelike-test-{}", std::process::id()))
+}
+
+/// Read `path`, starting over when it is missing or unreadable. `what` names
+/// the contents in the log.
+pub fn load<T: Default + DeserializeOwned>(path: &Path, what: &str) -> T {
+    match fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+            tracing::error!(path = %path.display(), "Ignoring unreadable {}: {}", what, e);
+            T::default()
+        }),
+        Err(_) => T::default(),
+    }
+}
+
+/// Replace `path` with `value`, so a crash leaves the old file or the new one
+pub fn save<T: Serialize>(path: &Path, what: &str, value: &T) -> Result<(), AppError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let content = serde_json::to_string_pretty(value)
+        .map_err(|e| AppError::Internal(format!("Error serializing {}: {}", what, e)))?;
+
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, content).and_then(|_| fs::rename(&tmp, path))?;
+    Ok(())
+}
+
+/// One JSON file in the data directory
+pub struct JsonStore<T> {
+    file: &'static str,
+    what: &'static str,
+    // Loaded from disk on first use
+    value: Mutex<Option<T>>,
+}
+
+impl<T> JsonStore<T> {
+    /// The store kept in `file` under [`data_dir`], holding `what`
+    pub const fn new(file: &'static str, what: &'static str) -> Self {
+        Self {
+            file,
+            what,
+            value: Mutex::new(None),
+        }
+    }
+}
+
+impl<T: Default + Serialize + DeserializeOwned> JsonStore<T> {
+    pub fn path(&self) -> PathBuf {
+        data_dir().join(self.file)
+    }
+
+    /// Run `f` on the contents, saving them afterwards when `f` reports a
+    /// change
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> (R, bool)) -> Result<R, AppError> {
+        let mut guard = self.value.lock()?;
+        let value = guard.get_or_insert_with(|| load(&self.path(), self.what));
+        let (result, changed) = f(value);
+        if changed {
+            save(&self.path(), self.what, value)?;
+        }
+        Ok(result)
+    }
+}
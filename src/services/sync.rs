@@ -0,0 +1,610 @@
+//! The server's side of folder sync; see [`crate::sync`] for the protocol.
+//!
+//! Each folder's index lives in `sync/<folder>.json` in the data directory,
+//! along with the size and modification time each file had on disk. Files
+//! whose size and time haven't changed aren't read again, so restarts don't
+//! rescan everything, while files edited on the server directly are still
+//! picked up. Uploaded chunks wait in `sync/chunks/` until a commit uses them.
+//!
+//! Synced files and staged chunks count against the `[quota]` limits like
+//! uploads do; see [`UploadBudget::sync_commit`].
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::config::{current_config, ensure_sync_folder};
+use crate::error::AppError;
+use crate::services::files::PartialFile;
+#[cfg(doc)]
+use crate::services::quota::UploadBudget;
+use crate::services::store;
+use crate::sync::{self, CommitRequest, FileEntry, FolderIndex, MAX_CHUNK_SIZE};
+
+/// Uploaded chunks no commit has used are deleted after this long
+const STAGED_CHUNK_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    #[serde(flatten)]
+    entry: FileEntry,
+    /// The device that committed this version, or `None` when it was
+    /// written on the server's disk
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+    /// Size and modification time (in nanoseconds) on disk when last indexed
+    disk_size: u64,
+    disk_modified: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Folder {
+    folder_id: String,
+    sequence: u64,
+    files: BTreeMap<String, Record>,
+}
+
+impl Default for Folder {
+    fn default() -> Self {
+        Self {
+            folder_id: uuid::Uuid::new_v4().to_string(),
+            sequence: 0,
+            files: BTreeMap::new(),
+        }
+    }
+}
+
+/// The outcome of a commit or delete
+pub enum Commit {
+    Done(FileEntry),
+    /// The file changed since the version the change was made to; this is
+    /// what it is now
+    Conflict(FileEntry),
+}
+
+lazy_static::lazy_static! {
+    // Folder indexes, loaded from disk on first use. Each has a lock of its
+    // own, so a commit to one folder doesn't hold up the others.
+    static ref FOLDERS: Mutex<HashMap<String, Arc<Mutex<Folder>>>> = Mutex::new(HashMap::new());
+}
+
+fn state_dir() -> PathBuf {
+    store::data_dir().join("sync")
+}
+
+fn staged_path(hash: &str) -> PathBuf {
+    state_dir().join("chunks").join(hash)
+}
+
+fn valid_hash(hash: &str) -> bool {
+    hash.len() == 64
+        && hash
+            .bytes()
+            .all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase())
+}
+
+fn index_path(name: &str) -> PathBuf {
+    state_dir().join(format!("{}.json", name))
+}
+
+/// How a synced file is named to the quotas. Staged chunks go by their hash,
+/// which has no `/`.
+pub fn stored_name(folder: &str, path: &str) -> String {
+    format!("{}/{}", folder, path)
+}
+
+fn check_enabled() -> Result<(), AppError> {
+    if current_config()?.sync.enabled {
+        Ok(())
+    } else {
+        Err(AppError::Forbidden("Folder sync is off".to_string()))
+    }
+}
+
+/// A folder's directory, created if needed
+pub fn folder_dir(name: &str) -> Result<PathBuf, AppError> {
+    check_enabled()?;
+    if !sync::valid_folder_name(name) {
+        return Err(AppError::BadRequest(
+            "Folder names may only use letters, digits, '-', '_' and '.'".to_string(),
+        ));
+    }
+    let dir = ensure_sync_folder()?.join(name);
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Where uploaded chunks wait for a commit, created if needed
+pub fn chunk_dir() -> Result<PathBuf, AppError> {
+    check_enabled()?;
+    let dir = state_dir().join("chunks");
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn folder_index(name: &str) -> Result<Arc<Mutex<Folder>>, AppError> {
+    let mut folders = FOLDERS.lock()?;
+    let folder = folders.entry(name.to_string()).or_insert_with(|| {
+        Arc::new(Mutex::new(store::load(&index_path(name), "sync index")))
+    });
+    Ok(folder.clone())
+}
+
+/// Run `f` on a folder's index and directory, saving the index afterwards
+/// when `f` reports a change
+fn with_folder<R>(
+    name: &str,
+    f: impl FnOnce(&mut Folder, &Path) -> Result<(R, bool), AppError>,
+) -> Result<R, AppError> {
+    let dir = folder_dir(name)?;
+    let folder = folder_index(name)?;
+    let mut folder = folder.lock()?;
+    let (result, changed) = f(&mut folder, &dir)?;
+    if changed {
+        store::save(&index_path(name), "sync index", &*folder)?;
+    }
+    Ok(result)
+}
+
+/// The name, committing device and size of every synced file and staged
+/// chunk, for the quotas
+pub fn stored_files() -> Result<Vec<(String, Option<String>, u64)>, AppError> {
+    let mut files = Vec::new();
+    let Ok(entries) = fs::read_dir(state_dir()) else {
+        return Ok(files);
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_none_or(|e| e != "json") {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if !sync::valid_folder_name(name) {
+            continue;
+        }
+        let folder = folder_index(name)?;
+        let folder = folder.lock()?;
+        for record in folder.files.values().filter(|r| !r.entry.deleted) {
+            files.push((
+                stored_name(name, &record.entry.path),
+                record.device_id.clone(),
+                record.entry.size,
+            ));
+        }
+    }
+
+    if let Ok(entries) = fs::read_dir(state_dir().join("chunks")) {
+        for entry in entries.flatten() {
+            let Some(hash) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if let (true, Ok(metadata)) = (valid_hash(&hash), entry.metadata()) {
+                files.push((hash, None, metadata.len()));
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn disk_modified(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+fn full_path(dir: &Path, path: &str) -> PathBuf {
+    path.split('/')
+        .fold(dir.to_path_buf(), |p, part| p.join(part))
+}
+
+/// Every file under `dir`, by path relative to it
+fn walk(dir: &Path, prefix: &str, found: &mut BTreeMap<String, fs::Metadata>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        let path = format!("{}{}", prefix, name);
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            walk(&entry.path(), &format!("{}/", path), found);
+        } else if file_type.is_file() && !sync::ignored(&path) {
+            if let Ok(metadata) = entry.metadata() {
+                found.insert(path, metadata);
+            }
+        }
+    }
+}
+
+/// Bring the index entry for `path` up to date with the disk. Returns whether
+/// the index changed.
+fn refresh(folder: &mut Folder, dir: &Path, path: &str, metadata: Option<&fs::Metadata>) -> bool {
+    let record = folder.files.get(path);
+    let Some(metadata) = metadata else {
+        // Gone from the disk
+        return match record {
+            Some(record) if !record.entry.deleted => {
+                folder.sequence += 1;
+                let record = folder.files.get_mut(path).expect("checked above");
+                record.entry = tombstone(path, folder.sequence);
+                true
+            }
+            _ => false,
+        };
+    };
+
+    let (size, modified) = (metadata.len(), disk_modified(metadata));
+    if record
+        .is_some_and(|r| !r.entry.deleted && r.disk_size == size && r.disk_modified == modified)
+    {
+        return false;
+    }
+    let (chunks, hash) = match sync::chunk_file(&full_path(dir, path)) {
+        Ok(result) => result,
+        Err(e) => {
+            tracing::warn!(path, "Failed to read synced file: {}", e);
+            return false;
+        }
+    };
+    if let Some(record) = folder.files.get_mut(path) {
+        if !record.entry.deleted && record.entry.hash == hash {
+            record.disk_size = size;
+            record.disk_modified = modified;
+            return true;
+        }
+    }
+
+    folder.sequence += 1;
+    let entry = FileEntry {
+        path: path.to_string(),
+        size,
+        modified: modified / 1_000_000_000,
+        hash,
+        chunks,
+        version: folder.sequence,
+        deleted: false,
+    };
+    folder.files.insert(
+        path.to_string(),
+        Record {
+            entry,
+            device_id: None,
+            disk_size: size,
+            disk_modified: modified,
+        },
+    );
+    true
+}
+
+fn tombstone(path: &str, version: u64) -> FileEntry {
+    FileEntry {
+        path: path.to_string(),
+        size: 0,
+        modified: 0,
+        hash: String::new(),
+        chunks: Vec::new(),
+        version,
+        deleted: true,
+    }
+}
+
+/// Pick up changes made on the server's disk
+fn scan(folder: &mut Folder, dir: &Path) -> bool {
+    let mut on_disk = BTreeMap::new();
+    walk(dir, "", &mut on_disk);
+    let mut changed = false;
+    for (path, metadata) in &on_disk {
+        changed |= refresh(folder, dir, path, Some(metadata));
+    }
+    let gone: Vec<String> = folder
+        .files
+        .keys()
+        .filter(|path| !on_disk.contains_key(*path))
+        .cloned()
+        .collect();
+    for path in gone {
+        changed |= refresh(folder, dir, &path, None);
+    }
+    changed
+}
+
+fn refresh_one(folder: &mut Folder, dir: &Path, path: &str) -> bool {
+    let metadata = fs::metadata(full_path(dir, path))
+        .ok()
+        .filter(|m| m.is_file());
+    refresh(folder, dir, path, metadata.as_ref())
+}
+
+/// Files in a folder that changed after `since`, which is 0 for all of them
+pub fn index(name: &str, since: u64) -> Result<FolderIndex, AppError> {
+    with_folder(name, |folder, dir| {
+        let changed = scan(folder, dir);
+        let files = folder
+            .files
+            .values()
+            .filter(|r| r.entry.version > since)
+            .map(|r| r.entry.clone())
+            .collect();
+        let index = FolderIndex {
+            folder_id: folder.folder_id.clone(),
+            sequence: folder.sequence,
+            files,
+        };
+        Ok((index, changed))
+    })
+}
+
+/// Where each chunk of the folder's files can be read from
+fn chunk_locations(folder: &Folder) -> HashMap<&str, (&str, u64, u64)> {
+    let mut locations = HashMap::new();
+    for record in folder.files.values().filter(|r| !r.entry.deleted) {
+        let mut offset = 0;
+        for chunk in &record.entry.chunks {
+            locations.entry(chunk.hash.as_str()).or_insert((
+                record.entry.path.as_str(),
+                offset,
+                chunk.size,
+            ));
+            offset += chunk.size;
+        }
+    }
+    locations
+}
+
+/// The chunks among `hashes` the server has neither staged nor in the folder
+pub fn missing(name: &str, hashes: Vec<String>) -> Result<Vec<String>, AppError> {
+    with_folder(name, |folder, _| {
+        let known = chunk_locations(folder);
+        let mut seen = HashSet::new();
+        let missing = hashes
+            .into_iter()
+            .filter(|hash| seen.insert(hash.clone()))
+            .filter(|hash| !known.contains_key(hash.as_str()) && !staged_path(hash).is_file())
+            .collect();
+        Ok((missing, false))
+    })
+}
+
+/// Keep an uploaded chunk for a later commit
+pub fn stage_chunk(hash: &str, data: &[u8]) -> Result<(), AppError> {
+    let dir = chunk_dir()?;
+    if !valid_hash(hash) {
+        return Err(AppError::BadRequest("Invalid chunk hash".to_string()));
+    }
+    if data.len() > MAX_CHUNK_SIZE {
+        return Err(AppError::PayloadTooLarge(format!(
+            "Chunks are at most {} bytes",
+            MAX_CHUNK_SIZE
+        )));
+    }
+    if sync::hash(data) != hash {
+        return Err(AppError::BadRequest(
+            "The chunk doesn't match its hash".to_string(),
+        ));
+    }
+
+    remove_stale_chunks(&dir);
+    let path = staged_path(hash);
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data).and_then(|_| fs::rename(&tmp, &path))?;
+    Ok(())
+}
+
+fn remove_stale_chunks(dir: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let stale = entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.elapsed().ok())
+            .is_some_and(|age| age > STAGED_CHUNK_TTL);
+        if stale {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+fn read_located(dir: &Path, (path, offset, size): (&str, u64, u64)) -> io::Result<Vec<u8>> {
+    let mut file = fs::File::open(full_path(dir, path))?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = vec![0u8; size as usize];
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Read a chunk from what was staged or from the folder's files, checking
+/// it still matches its hash
+fn read_chunk_from(folder: &Folder, dir: &Path, hash: &str) -> Result<Vec<u8>, AppError> {
+    if !valid_hash(hash) {
+        return Err(AppError::BadRequest("Invalid chunk hash".to_string()));
+    }
+    if let Ok(data) = fs::read(staged_path(hash)) {
+        if sync::hash(&data) == hash {
+            return Ok(data);
+        }
+    }
+    let location = chunk_locations(folder).get(hash).copied();
+    if let Some(location) = location {
+        if let Ok(data) = read_located(dir, location) {
+            if sync::hash(&data) == hash {
+                return Ok(data);
+            }
+        }
+    }
+    Err(AppError::NotFound(format!("Chunk {} not found", hash)))
+}
+
+/// A chunk of one of the folder's files
+pub fn read_chunk(name: &str, hash: &str) -> Result<Vec<u8>, AppError> {
+    with_folder(name, |folder, dir| {
+        Ok((read_chunk_from(folder, dir, hash)?, false))
+    })
+}
+
+fn check_path(path: &str) -> Result<(), AppError> {
+    if sync::valid_path(path) && !sync::ignored(path) {
+        Ok(())
+    } else {
+        Err(AppError::BadRequest(format!("Invalid path: {}", path)))
+    }
+}
+
+/// The version of a file as it is now, after picking up changes on disk
+fn current_version(folder: &mut Folder, dir: &Path, path: &str) -> (Option<u64>, bool) {
+    let changed = refresh_one(folder, dir, path);
+    (folder.files.get(path).map(|r| r.entry.version), changed)
+}
+
+/// Write a file from chunks the server has for `device_id`, unless it
+/// changed since `base_version`
+pub fn commit(name: &str, request: CommitRequest, device_id: &str) -> Result<Commit, AppError> {
+    check_path(&request.path)?;
+    let quota = current_config()?.quota.max_file_size;
+    let size: u64 = request.chunks.iter().map(|c| c.size).sum();
+    if quota > 0 && size > quota {
+        return Err(AppError::PayloadTooLarge(format!(
+            "Files are at most {} bytes",
+            quota
+        )));
+    }
+
+    with_folder(name, |folder, dir| {
+        let path = request.path.as_str();
+        let (version, refreshed) = current_version(folder, dir, path);
+        let current = folder.files.get(path).map(|r| r.entry.clone());
+        if version != request.base_version {
+            // Nothing to do if the other change made the same file
+            let same = current
+                .as_ref()
+                .is_some_and(|c| !c.deleted && c.chunks == request.chunks);
+            let current = current.expect("a version means an entry");
+            return Ok((
+                if same {
+                    Commit::Done(current)
+                } else {
+                    Commit::Conflict(current)
+                },
+                refreshed,
+            ));
+        }
+
+        let target = full_path(dir, path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file_name = target
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let partial = PartialFile::new(target.with_file_name(format!(
+            ".nplsync-{}-{}",
+            uuid::Uuid::new_v4().simple(),
+            file_name
+        )));
+
+        let mut out = io::BufWriter::new(fs::File::create(partial.path())?);
+        let mut whole = Sha256::new();
+        for chunk in &request.chunks {
+            let data = read_chunk_from(folder, dir, &chunk.hash).map_err(|_| {
+                AppError::BadRequest(format!("Chunk {} hasn't been uploaded", chunk.hash))
+            })?;
+            if data.len() as u64 != chunk.size {
+                return Err(AppError::BadRequest(format!(
+                    "Chunk {} is {} bytes, not {}",
+                    chunk.hash,
+                    data.len(),
+                    chunk.size
+                )));
+            }
+            whole.update(&data);
+            out.write_all(&data)?;
+        }
+        let file = out.into_inner().map_err(|e| e.into_error())?;
+        let modified = UNIX_EPOCH + Duration::from_secs(request.modified);
+        file.set_modified(modified)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(partial.path(), &target)?;
+        partial.complete();
+
+        for chunk in &request.chunks {
+            let _ = fs::remove_file(staged_path(&chunk.hash));
+        }
+
+        let metadata = fs::metadata(&target)?;
+        folder.sequence += 1;
+        let entry = FileEntry {
+            path: path.to_string(),
+            size,
+            modified: request.modified,
+            hash: format!("{:x}", whole.finalize()),
+            chunks: request.chunks.clone(),
+            version: folder.sequence,
+            deleted: false,
+        };
+        folder.files.insert(
+            path.to_string(),
+            Record {
+                entry: entry.clone(),
+                device_id: Some(device_id.to_string()),
+                disk_size: metadata.len(),
+                disk_modified: disk_modified(&metadata),
+            },
+        );
+        tracing::info!(
+            folder = name,
+            path,
+            version = entry.version,
+            "Synced file updated"
+        );
+        Ok((Commit::Done(entry), true))
+    })
+}
+
+/// Delete a file, unless it changed since `base_version`
+pub fn delete(name: &str, path: &str, base_version: u64) -> Result<Commit, AppError> {
+    check_path(path)?;
+    with_folder(name, |folder, dir| {
+        let (version, refreshed) = current_version(folder, dir, path);
+        let Some(current) = folder.files.get(path).map(|r| r.entry.clone()) else {
+            return Err(AppError::NotFound("File not found".to_string()));
+        };
+        if current.deleted {
+            return Ok((Commit::Done(current), refreshed));
+        }
+        if version != Some(base_version) {
+            return Ok((Commit::Conflict(current), refreshed));
+        }
+
+        fs::remove_file(full_path(dir, path))?;
+        folder.sequence += 1;
+        let entry = tombstone(path, folder.sequence);
+        if let Some(record) = folder.files.get_mut(path) {
+            record.entry = entry.clone();
+        }
+        tracing::info!(
+            folder = name,
+            path,
+            version = entry.version,
+            "Synced file deleted"
+        );
+        Ok((Commit::Done(entry), true))
+    })
+}
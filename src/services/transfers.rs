@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufRead, IsTerminal};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use utoipa::ToSchema;
+
+use crate::error::{lock_recovering, AppError};
+use crate::services::{devices, events};
+
+/// How long a push waits for someone to accept or reject it
+pub const DECISION_TIMEOUT: Duration = Duration::from_secs(120);
+
+static NEXT_TRANSFER_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A file another device wants to push to this machine's download folder
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct PendingTransfer {
+    pub id: u64,
+    pub filename: String,
+    pub size: Option<u64>,
+    pub device_id: String,
+    pub device_name: String,
+}
+
+/// An answer to a pending transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    Accept,
+    Reject,
+    /// Accept and auto-accept future pushes from the same device
+    Trust,
+}
+
+/// Notifications sent to admin listeners
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum TransferEvent {
+    TransferRequest(PendingTransfer),
+    TransferResolved { id: u64, accepted: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Accepted,
+    Rejected,
+    TimedOut,
+}
+
+struct Inbox {
+    pending: Mutex<HashMap<u64, (PendingTransfer, oneshot::Sender<Decision>)>>,
+}
+
+lazy_static::lazy_static! {
+    static ref INBOX: Inbox = Inbox {
+        pending: Mutex::new(HashMap::new()),
+    };
+}
+
+/// Wait for a push to be accepted, either automatically because the device
+/// is trusted or by someone answering the prompt. `device_id` is empty
+/// unless the device proved who it is, so pushes from anyone else are
+/// always prompted for.
+pub async fn request_approval(
+    filename: &str,
+    size: Option<u64>,
+    device_id: &str,
+    device_name: &str,
+) -> Result<Outcome, AppError> {
+    if !device_id.is_empty() && devices::is_trusted(device_id)? {
+        return Ok(Outcome::Accepted);
+    }
+
+    let transfer = PendingTransfer {
+        id: NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed),
+        filename: filename.to_string(),
+        size,
+        device_id: device_id.to_string(),
+        device_name: device_name.to_string(),
+    };
+    let id = transfer.id;
+
+    let (tx, rx) = oneshot::channel();
+    INBOX.pending.lock()?.insert(id, (transfer.clone(), tx));
+    announce(&transfer);
+
+    let outcome = match tokio::time::timeout(DECISION_TIMEOUT, rx).await {
+        Ok(Ok(Decision::Reject)) => Outcome::Rejected,
+        Ok(Ok(_)) => Outcome::Accepted,
+        // The sender is only dropped when the entry is removed without an answer
+        Ok(Err(_)) | Err(_) => Outcome::TimedOut,
+    };
+
+    INBOX.pending.lock()?.remove(&id);
+    tracing::info!(id, filename, device = device_name, ?outcome, "Push request resolved");
+    events::publish(TransferEvent::TransferResolved {
+        id,
+        accepted: outcome == Outcome::Accepted,
+    });
+
+    Ok(outcome)
+}
+
+/// Answer a pending transfer
+pub fn decide(id: u64, decision: Decision) -> Result<(), AppError> {
+    let (transfer, tx) = INBOX
+        .pending
+        .lock()?
+        .remove(&id)
+        .ok_or_else(|| AppError::NotFound("No pending transfer with that ID".to_string()))?;
+
+    if decision == Decision::Trust {
+        if transfer.device_id.is_empty() {
+            let _ = tx.send(Decision::Reject);
+            return Err(AppError::BadRequest(
+                "Anonymous devices can't be trusted".to_string(),
+            ));
+        }
+        devices::trust(&transfer.device_id, &transfer.device_name)?;
+    }
+
+    let _ = tx.send(decision);
+    Ok(())
+}
+
+/// Reject everything still waiting, so pushes don't hold up a shutdown
+pub fn reject_all() {
+    // Runs during shutdown, so get the senders out even after a panic
+    let pending: Vec<_> = lock_recovering(&INBOX.pending).drain().collect();
+    for (_, (_, tx)) in pending {
+        let _ = tx.send(Decision::Reject);
+    }
+}
+
+/// Transfers currently waiting for an answer
+pub fn pending_transfers() -> Result<Vec<PendingTransfer>, AppError> {
+    let pending = INBOX.pending.lock()?;
+    let mut transfers: Vec<_> = pending.values().map(|(t, _)| t.clone()).collect();
+    transfers.sort_by_key(|t| t.id);
+    Ok(transfers)
+}
+
+
+fn announce(transfer: &PendingTransfer) {
+    let size = transfer
+        .size
+        .map(|s| format!(" ({} bytes)", s))
+        .unwrap_or_default();
+    println!(
+        "\nIncoming file \"{}\"{} from {}. Type \"y {id}\" to accept, \"t {id}\" to accept and trust the device, or \"n {id}\" to reject.",
+        transfer.filename,
+        size,
+        transfer.device_name,
+        id = transfer.id
+    );
+
+    events::publish(TransferEvent::TransferRequest(transfer.clone()));
+}
+
+/// Read accept/reject answers typed into the server's terminal
+pub fn spawn_terminal_prompt() {
+    if !std::io::stdin().is_terminal() {
+        return;
+    }
+
+    std::thread::spawn(|| {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            let mut parts = line.split_whitespace();
+            let (Some(answer), Some(id)) = (parts.next(), parts.next()) else {
+                continue;
+            };
+
+            let decision = match answer {
+                "y" | "yes" => Decision::Accept,
+                "t" | "trust" => Decision::Trust,
+                "n" | "no" => Decision::Reject,
+                _ => continue,
+            };
+            let Ok(id) = id.parse() else {
+                eprintln!("Invalid transfer ID: {}", id);
+                continue;
+            };
+
+            if let Err(e) = decide(id, decision) {
+                eprintln!("{}", e);
+            }
+        }
+    });
+}
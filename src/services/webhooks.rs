@@ -0,0 +1,348 @@
+//! Outgoing webhooks: every event on the bus that a configured hook wants
+//! is POSTed to it as JSON, signed when the hook has a secret, and retried
+//! with backoff when the receiver is down or busy.
+//!
+//! Each hook has its own queue, delivered in order one at a time. A hook
+//! that falls [`QUEUE_SIZE`] deliveries behind misses the ones after that.
+
+use hmac::{Hmac, Mac};
+use reqwest::header::CONTENT_TYPE;
+use serde_json::Value;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::config::{current_config, WebhookConfig};
+use crate::error::lock_recovering;
+use crate::http;
+use crate::services::events::{self, BusEvent, Topic};
+use crate::shutdown;
+
+/// Longest wait between two tries of a delivery
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(600);
+/// Deliveries waiting for each hook
+const QUEUE_SIZE: usize = 256;
+
+/// An event on its way to one hook
+struct Delivery {
+    hook: WebhookConfig,
+    max_attempts: u32,
+    retry_delay: Duration,
+    id: u64,
+    kind: String,
+    body: Vec<u8>,
+}
+
+lazy_static::lazy_static! {
+    /// Each hook's queue, by URL
+    static ref QUEUES: Mutex<HashMap<String, mpsc::Sender<Delivery>>> = Mutex::new(HashMap::new());
+}
+
+/// How a delivery attempt went
+enum Attempt {
+    Delivered,
+    /// Worth trying again later
+    Retry(String),
+    Failed(String),
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// The `X-Noplacelike-Signature` value for a body sent at `timestamp`:
+/// `sha256=` and the hex HMAC-SHA256 of `<timestamp>.<body>` under the
+/// hook's secret
+fn sign(secret: &str, timestamp: u64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+/// Whether a hook wants an event of type `kind`
+fn wants(hook: &WebhookConfig, event: &BusEvent, kind: &str) -> bool {
+    let topic = event.event.topic();
+    if hook.events.is_empty() {
+        return topic != Some(Topic::Progress);
+    }
+    hook.events
+        .iter()
+        .any(|name| name == kind || (topic.is_some() && Topic::parse(name) == topic))
+}
+
+/// A delivery's body: the event as WebSocket clients get it, plus its `id`
+/// and the Unix `time` it happened
+fn payload(mut value: Value, time: u64) -> Vec<u8> {
+    if let Some(fields) = value.as_object_mut() {
+        fields.insert("time".to_string(), time.into());
+    }
+    serde_json::to_vec(&value).unwrap_or_default()
+}
+
+/// POST a body to a hook once, returning the response status
+async fn post(hook: &WebhookConfig, id: u64, kind: &str, body: &[u8]) -> Result<u16, String> {
+    let timestamp = unix_now();
+    let mut request = http::client()
+        .post(&hook.url)
+        .header(CONTENT_TYPE, "application/json")
+        .header("X-Noplacelike-Event", kind)
+        .header("X-Noplacelike-Delivery", id)
+        .header("X-Noplacelike-Timestamp", timestamp)
+        .body(body.to_vec());
+    if !hook.secret.is_empty() {
+        request = request.header(
+            "X-Noplacelike-Signature",
+            sign(&hook.secret, timestamp, body),
+        );
+    }
+    let response = request.send().await.map_err(|e| http::describe(&e))?;
+    Ok(response.status().as_u16())
+}
+
+async fn attempt(hook: &WebhookConfig, id: u64, kind: &str, body: &[u8]) -> Attempt {
+    match post(hook, id, kind, body).await {
+        Ok(200..=299) => Attempt::Delivered,
+        Ok(status @ (408 | 429 | 500..=599)) => Attempt::Retry(format!("HTTP {}", status)),
+        Ok(status) => Attempt::Failed(format!("HTTP {}", status)),
+        Err(e) => Attempt::Retry(e),
+    }
+}
+
+/// Deliver one event to one hook, retrying with backoff
+async fn deliver(delivery: Delivery) {
+    let Delivery {
+        hook,
+        max_attempts,
+        retry_delay,
+        id,
+        kind,
+        body,
+    } = delivery;
+    let mut delay = retry_delay;
+    for tries in 1..=max_attempts {
+        let error = match attempt(&hook, id, &kind, &body).await {
+            Attempt::Delivered => {
+                tracing::debug!(url = %hook.url, id, event = %kind, tries, "Delivered webhook");
+                return;
+            }
+            Attempt::Failed(error) => {
+                tracing::error!(url = %hook.url, id, event = %kind, "Webhook refused delivery: {}", error);
+                return;
+            }
+            Attempt::Retry(error) => error,
+        };
+        if tries == max_attempts || shutdown::is_shutting_down() {
+            tracing::error!(url = %hook.url, id, event = %kind, tries, "Giving up on webhook delivery: {}", error);
+            return;
+        }
+        tracing::warn!(
+            url = %hook.url,
+            id,
+            event = %kind,
+            tries,
+            retry_in = delay.as_secs(),
+            "Webhook delivery failed: {}",
+            error
+        );
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
+
+/// Deliver a hook's queue in order until the queue is dropped
+async fn drain(mut queue: mpsc::Receiver<Delivery>) {
+    while let Some(delivery) = queue.recv().await {
+        deliver(delivery).await;
+    }
+}
+
+/// Put a delivery on its hook's queue, starting the queue if needed
+fn enqueue(queues: &mut HashMap<String, mpsc::Sender<Delivery>>, delivery: Delivery) {
+    let url = delivery.hook.url.clone();
+    let queue = queues.entry(url.clone()).or_insert_with(|| {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        actix_rt::spawn(drain(rx));
+        tx
+    });
+    if let Err(e) = queue.try_send(delivery) {
+        let delivery = match e {
+            mpsc::error::TrySendError::Full(delivery) => delivery,
+            mpsc::error::TrySendError::Closed(delivery) => {
+                queues.remove(&url);
+                delivery
+            }
+        };
+        tracing::warn!(
+            url = %url,
+            id = delivery.id,
+            event = %delivery.kind,
+            "Webhook is {} deliveries behind, dropping this one",
+            QUEUE_SIZE
+        );
+    }
+}
+
+/// Queue deliveries of an event to every hook that wants it
+fn dispatch(event: BusEvent) {
+    let config = match current_config() {
+        Ok(config) => config.webhooks,
+        Err(e) => {
+            tracing::error!("Failed to read webhook config: {}", e);
+            return;
+        }
+    };
+    let mut queues = lock_recovering(&QUEUES);
+    // Dropping the queue of a removed hook lets it finish what's queued
+    queues.retain(|url, _| config.hooks.iter().any(|hook| &hook.url == url));
+    if config.hooks.is_empty() {
+        return;
+    }
+
+    let value = event.to_json();
+    let kind = value["type"].as_str().unwrap_or_default().to_string();
+    let hooks: Vec<WebhookConfig> = config
+        .hooks
+        .into_iter()
+        .filter(|hook| wants(hook, &event, &kind))
+        .collect();
+    if hooks.is_empty() {
+        return;
+    }
+    let body = payload(value, event.time);
+    let retry_delay = Duration::from_secs(config.retry_delay_secs);
+    for hook in hooks {
+        let delivery = Delivery {
+            hook,
+            max_attempts: config.max_attempts,
+            retry_delay,
+            id: event.id,
+            kind: kind.clone(),
+            body: body.clone(),
+        };
+        enqueue(&mut queues, delivery);
+    }
+}
+
+/// Send a `ping` event to a hook once, without retrying, returning the
+/// status it answered with
+pub async fn ping(hook: &WebhookConfig) -> Result<u16, String> {
+    let value = serde_json::json!({ "type": "ping", "data": null, "id": 0 });
+    post(hook, 0, "ping", &payload(value, unix_now())).await
+}
+
+/// Follow the event bus and deliver events to the configured hooks
+pub fn spawn() {
+    let mut rx = events::subscribe();
+    actix_rt::spawn(async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => dispatch(event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!(missed, "Webhooks fell behind and skipped events");
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::stand_in::{self, Reply};
+
+    fn hook(url: &str, secret: &str) -> WebhookConfig {
+        WebhookConfig {
+            url: format!("{}/hook", url),
+            events: Vec::new(),
+            secret: secret.to_string(),
+        }
+    }
+
+    #[test]
+    fn signature_covers_timestamp_and_body() {
+        let signature = sign("s3cret", 1700000000, b"{}");
+        assert!(signature.starts_with("sha256="));
+        assert_eq!(signature.len(), "sha256=".len() + 64);
+        assert_eq!(signature, sign("s3cret", 1700000000, b"{}"));
+        assert_ne!(signature, sign("s3cret", 1700000001, b"{}"));
+        assert_ne!(signature, sign("s3cret", 1700000000, b"[]"));
+        assert_ne!(signature, sign("other", 1700000000, b"{}"));
+    }
+
+    #[actix_rt::test]
+    async fn ping_is_signed_with_its_timestamp() {
+        let (url, received) = stand_in::start(vec![Reply::status(204)]).await;
+        let status = ping(&hook(&url, "s3cret")).await.unwrap();
+        assert_eq!(status, 204);
+
+        let request = received.await.unwrap().remove(0);
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.target, "/hook");
+        assert_eq!(request.headers["content-type"], "application/json");
+        assert_eq!(request.headers["x-noplacelike-event"], "ping");
+        assert_eq!(request.headers["x-noplacelike-delivery"], "0");
+        let timestamp: u64 = request.headers["x-noplacelike-timestamp"].parse().unwrap();
+        assert_eq!(
+            request.headers["x-noplacelike-signature"],
+            sign("s3cret", timestamp, &request.body)
+        );
+        let body: Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(body["type"], "ping");
+    }
+
+    #[actix_rt::test]
+    async fn unsigned_without_a_secret() {
+        let (url, received) = stand_in::start(vec![Reply::status(200)]).await;
+        ping(&hook(&url, "")).await.unwrap();
+        let request = received.await.unwrap().remove(0);
+        assert!(request.headers.contains_key("x-noplacelike-timestamp"));
+        assert!(!request.headers.contains_key("x-noplacelike-signature"));
+    }
+
+    #[actix_rt::test]
+    async fn retries_until_delivered() {
+        let replies = vec![Reply::status(503), Reply::status(429), Reply::status(200)];
+        let (url, received) = stand_in::start(replies).await;
+        deliver(Delivery {
+            hook: hook(&url, ""),
+            max_attempts: 5,
+            retry_delay: Duration::from_millis(1),
+            id: 7,
+            kind: "file_added".to_string(),
+            body: b"{}".to_vec(),
+        })
+        .await;
+        let received = received.await.unwrap();
+        assert_eq!(received.len(), 3);
+        assert!(received
+            .iter()
+            .all(|request| request.headers["x-noplacelike-delivery"] == "7"));
+    }
+
+    #[actix_rt::test]
+    async fn client_errors_are_not_retried() {
+        let (url, received) = stand_in::start(vec![Reply::status(404)]).await;
+        assert!(matches!(
+            attempt(&hook(&url, ""), 1, "ping", b"{}").await,
+            Attempt::Failed(_)
+        ));
+        assert_eq!(received.await.unwrap().len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn redirects_are_not_followed() {
+        let redirect = Reply::status(307).header("Location", "http://169.254.169.254/");
+        let (url, received) = stand_in::start(vec![redirect]).await;
+        assert_eq!(ping(&hook(&url, "")).await.unwrap(), 307);
+        assert_eq!(received.await.unwrap().len(), 1);
+    }
+}
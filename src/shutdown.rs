@@ -0,0 +1,62 @@
+use futures::Stream;
+use tokio::sync::watch;
+
+/// Sent to long-lived sessions when the server starts shutting down
+#[derive(Debug, Clone, Copy)]
+pub struct ShutdownNotice;
+
+lazy_static::lazy_static! {
+    static ref SHUTDOWN: watch::Sender<bool> = watch::channel(false).0;
+}
+
+/// Resolve on the first SIGINT (Ctrl-C) or SIGTERM
+pub async fn wait_for_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => tracing::info!("Received SIGINT"),
+        _ = terminate => tracing::info!("Received SIGTERM"),
+    }
+}
+
+/// Tell every subscriber the server is going down
+pub fn trigger() {
+    SHUTDOWN.send_replace(true);
+}
+
+pub fn is_shutting_down() -> bool {
+    *SHUTDOWN.borrow()
+}
+
+/// A stream yielding a single notice once shutdown starts, for
+/// `ctx.add_stream` in WebSocket actors
+pub fn notice_stream() -> impl Stream<Item = ShutdownNotice> {
+    let mut rx = SHUTDOWN.subscribe();
+    futures::stream::once(async move {
+        // An error means the sender is gone, which only happens at exit
+        let _ = rx.wait_for(|down| *down).await;
+        ShutdownNotice
+    })
+}
@@ -0,0 +1,201 @@
+//! Files in a folder on the host
+
+use actix_web::web::{self, Bytes};
+use async_trait::async_trait;
+use futures::StreamExt;
+use std::fs;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter};
+
+use super::{ByteStream, ObjectInfo, Storage};
+use crate::error::AppError;
+use crate::services::files::PartialFile;
+
+/// Ends the names of files still being written, which are hidden
+const PARTIAL_SUFFIX: &str = ".part";
+
+/// Size of the write buffer for each file being written
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+const READ_SIZE: usize = 64 * 1024;
+
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    async fn create_dir(&self) -> Result<(), AppError> {
+        tokio::fs::create_dir_all(&self.dir).await.map_err(|e| {
+            tracing::error!(path = %self.dir.display(), "Failed to create upload directory: {}", e);
+            e.into()
+        })
+    }
+}
+
+fn info(name: String, metadata: &fs::Metadata) -> ObjectInfo {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    ObjectInfo {
+        name,
+        size: metadata.len(),
+        modified,
+    }
+}
+
+fn not_found() -> AppError {
+    AppError::NotFound("File not found".to_string())
+}
+
+/// Write `data` to `path`, returning the number of bytes written. The file
+/// is removed again if `data` fails or the write is cut off.
+///
+/// The next chunk is only read once the previous one has been written, so a
+/// slow disk slows the sender down instead of piling data up in memory.
+pub async fn write_file(path: &Path, mut data: ByteStream) -> Result<u64, AppError> {
+    let partial = PartialFile::new(path);
+    let file = tokio::fs::File::create(partial.path()).await?;
+    let mut file = BufWriter::with_capacity(WRITE_BUFFER_SIZE, file);
+    let mut written = 0;
+
+    while let Some(chunk) = data.next().await {
+        let chunk = chunk?;
+        file.write_all(&chunk).await?;
+        written += chunk.len() as u64;
+    }
+
+    file.flush().await?;
+    partial.complete();
+    Ok(written)
+}
+
+/// Read `len` bytes of an open file, or all of the rest
+pub fn read_file(file: tokio::fs::File, len: Option<u64>) -> ByteStream {
+    futures::stream::unfold((file, len), |(mut file, left)| async move {
+        if left == Some(0) {
+            return None;
+        }
+        let want = left.map_or(READ_SIZE, |l| l.min(READ_SIZE as u64) as usize);
+        let mut chunk = vec![0u8; want];
+        match file.read(&mut chunk).await {
+            Ok(0) => None,
+            Ok(n) => {
+                chunk.truncate(n);
+                let left = left.map(|l| l - n as u64);
+                Some((Ok(Bytes::from(chunk)), (file, left)))
+            }
+            Err(e) => Some((Err(e.into()), (file, Some(0)))),
+        }
+    })
+    .boxed_local()
+}
+
+#[async_trait(?Send)]
+impl Storage for LocalStorage {
+    async fn put(&self, name: &str, data: ByteStream) -> Result<u64, AppError> {
+        self.create_dir().await?;
+        let partial = self.path(&format!(
+            ".{}.{}{}",
+            name,
+            uuid::Uuid::new_v4(),
+            PARTIAL_SUFFIX
+        ));
+        let written = write_file(&partial, data).await?;
+        if let Err(e) = tokio::fs::rename(&partial, self.path(name)).await {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(e.into());
+        }
+        Ok(written)
+    }
+
+    async fn get(&self, name: &str, offset: u64, len: Option<u64>) -> Result<ByteStream, AppError> {
+        let mut file = match tokio::fs::File::open(self.path(name)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(not_found()),
+            Err(e) => return Err(e.into()),
+        };
+        if offset > 0 {
+            file.seek(SeekFrom::Start(offset)).await?;
+        }
+        Ok(read_file(file, len))
+    }
+
+    async fn list(&self) -> Result<Vec<ObjectInfo>, AppError> {
+        let dir = self.dir.clone();
+        web::block(move || {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e.into()),
+            };
+            let mut files = Vec::new();
+            for entry in entries.flatten() {
+                let Ok(metadata) = entry.metadata() else {
+                    continue;
+                };
+                let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                    continue;
+                };
+                if metadata.is_file() && !(name.starts_with('.') && name.ends_with(PARTIAL_SUFFIX))
+                {
+                    files.push(info(name, &metadata));
+                }
+            }
+            Ok(files)
+        })
+        .await?
+    }
+
+    async fn stat(&self, name: &str) -> Result<Option<ObjectInfo>, AppError> {
+        match tokio::fs::metadata(self.path(name)).await {
+            Ok(metadata) if metadata.is_file() => Ok(Some(info(name.to_string(), &metadata))),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, name: &str) -> Result<(), AppError> {
+        if self.stat(name).await?.is_none() {
+            return Err(not_found());
+        }
+        tokio::fs::remove_file(self.path(name)).await?;
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<(), AppError> {
+        if self.stat(from).await?.is_none() {
+            return Err(not_found());
+        }
+        tokio::fs::rename(self.path(from), self.path(to)).await?;
+        Ok(())
+    }
+
+    async fn available_space(&self) -> Result<Option<u64>, AppError> {
+        self.create_dir().await?;
+        let dir = self.dir.clone();
+        let available = web::block(move || fs2::available_space(dir)).await??;
+        Ok(Some(available))
+    }
+
+    fn local_path(&self, name: &str) -> Option<PathBuf> {
+        Some(self.path(name))
+    }
+
+    fn local_folder(&self) -> Option<&Path> {
+        Some(&self.dir)
+    }
+}
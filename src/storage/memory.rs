@@ -0,0 +1,213 @@
+//! Files kept in memory, for trying things out and for tests. Everything is
+//! lost when the server stops, and `storage.memory_max_bytes` caps how much
+//! is held.
+
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use futures::StreamExt;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{ByteStream, ObjectInfo, Storage};
+use crate::error::{lock_recovering, AppError};
+
+struct MemoryFile {
+    data: Bytes,
+    modified: u64,
+}
+
+pub struct MemoryStorage {
+    files: Mutex<BTreeMap<String, MemoryFile>>,
+    /// Most bytes held at once, 0 for no limit
+    limit: AtomicU64,
+}
+
+impl MemoryStorage {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            files: Mutex::new(BTreeMap::new()),
+            limit: AtomicU64::new(limit),
+        }
+    }
+
+    pub fn set_limit(&self, limit: u64) {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+
+    /// Bytes that `name` may hold: the limit less every other file
+    fn room_for(&self, name: &str) -> u64 {
+        let limit = self.limit.load(Ordering::Relaxed);
+        if limit == 0 {
+            return u64::MAX;
+        }
+        let others: u64 = lock_recovering(&self.files)
+            .iter()
+            .filter(|(other, _)| other.as_str() != name)
+            .map(|(_, file)| file.data.len() as u64)
+            .sum();
+        limit.saturating_sub(others)
+    }
+}
+
+fn full() -> AppError {
+    AppError::InsufficientStorage("Not enough room left in memory storage for this file".to_string())
+}
+
+fn info(name: &str, file: &MemoryFile) -> ObjectInfo {
+    ObjectInfo {
+        name: name.to_string(),
+        size: file.data.len() as u64,
+        modified: file.modified,
+    }
+}
+
+fn not_found() -> AppError {
+    AppError::NotFound("File not found".to_string())
+}
+
+#[async_trait(?Send)]
+impl Storage for MemoryStorage {
+    async fn put(&self, name: &str, mut data: ByteStream) -> Result<u64, AppError> {
+        let mut contents = Vec::new();
+        while let Some(chunk) = data.next().await {
+            contents.extend_from_slice(&chunk?);
+            if contents.len() as u64 > self.room_for(name) {
+                return Err(full());
+            }
+        }
+        let size = contents.len() as u64;
+        let modified = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        // Checked again in case other files were stored meanwhile
+        if size > self.room_for(name) {
+            return Err(full());
+        }
+        lock_recovering(&self.files).insert(
+            name.to_string(),
+            MemoryFile {
+                data: Bytes::from(contents),
+                modified,
+            },
+        );
+        Ok(size)
+    }
+
+    async fn get(&self, name: &str, offset: u64, len: Option<u64>) -> Result<ByteStream, AppError> {
+        let files = lock_recovering(&self.files);
+        let data = &files.get(name).ok_or_else(not_found)?.data;
+        let start = (offset as usize).min(data.len());
+        let end = len.map_or(data.len(), |len| {
+            start.saturating_add(len as usize).min(data.len())
+        });
+        let chunk = data.slice(start..end);
+        Ok(futures::stream::once(async move { Ok(chunk) }).boxed_local())
+    }
+
+    async fn list(&self) -> Result<Vec<ObjectInfo>, AppError> {
+        Ok(lock_recovering(&self.files)
+            .iter()
+            .map(|(name, file)| info(name, file))
+            .collect())
+    }
+
+    async fn stat(&self, name: &str) -> Result<Option<ObjectInfo>, AppError> {
+        Ok(lock_recovering(&self.files)
+            .get(name)
+            .map(|file| info(name, file)))
+    }
+
+    async fn delete(&self, name: &str) -> Result<(), AppError> {
+        lock_recovering(&self.files)
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(not_found)
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<(), AppError> {
+        let mut files = lock_recovering(&self.files);
+        let file = files.remove(from).ok_or_else(not_found)?;
+        files.insert(to.to_string(), file);
+        Ok(())
+    }
+
+    async fn available_space(&self) -> Result<Option<u64>, AppError> {
+        let limit = self.limit.load(Ordering::Relaxed);
+        if limit == 0 {
+            return Ok(None);
+        }
+        let used: u64 = lock_recovering(&self.files)
+            .values()
+            .map(|file| file.data.len() as u64)
+            .sum();
+        Ok(Some(limit.saturating_sub(used)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(data: &'static [u8]) -> ByteStream {
+        futures::stream::iter(data.chunks(3).map(|chunk| Ok(Bytes::from_static(chunk))))
+            .boxed_local()
+    }
+
+    async fn read(storage: &MemoryStorage, name: &str, offset: u64, len: Option<u64>) -> Vec<u8> {
+        let mut data = storage.get(name, offset, len).await.unwrap();
+        let mut contents = Vec::new();
+        while let Some(chunk) = data.next().await {
+            contents.extend_from_slice(&chunk.unwrap());
+        }
+        contents
+    }
+
+    #[actix_rt::test]
+    async fn stores_and_reads_ranges() {
+        let storage = MemoryStorage::new(0);
+        assert_eq!(storage.put("a.txt", stream(b"hello world")).await.unwrap(), 11);
+        assert_eq!(read(&storage, "a.txt", 0, None).await, b"hello world");
+        assert_eq!(read(&storage, "a.txt", 6, Some(3)).await, b"wor");
+        assert_eq!(read(&storage, "a.txt", 6, Some(100)).await, b"world");
+        assert_eq!(storage.stat("a.txt").await.unwrap().unwrap().size, 11);
+        assert!(storage.stat("b.txt").await.unwrap().is_none());
+    }
+
+    #[actix_rt::test]
+    async fn renames_and_deletes() {
+        let storage = MemoryStorage::new(0);
+        storage.put("a.txt", stream(b"one")).await.unwrap();
+        storage.put("b.txt", stream(b"two")).await.unwrap();
+        storage.rename("a.txt", "b.txt").await.unwrap();
+        let names: Vec<String> = storage.list().await.unwrap().into_iter().map(|f| f.name).collect();
+        assert_eq!(names, ["b.txt"]);
+        assert_eq!(read(&storage, "b.txt", 0, None).await, b"one");
+
+        storage.delete("b.txt").await.unwrap();
+        assert!(matches!(storage.delete("b.txt").await, Err(AppError::NotFound(_))));
+        assert!(matches!(storage.rename("b.txt", "c.txt").await, Err(AppError::NotFound(_))));
+    }
+
+    #[actix_rt::test]
+    async fn refuses_files_past_the_limit() {
+        let storage = MemoryStorage::new(10);
+        storage.put("a.txt", stream(b"123456")).await.unwrap();
+        assert_eq!(storage.available_space().await.unwrap(), Some(4));
+        assert!(matches!(
+            storage.put("b.txt", stream(b"12345")).await,
+            Err(AppError::InsufficientStorage(_))
+        ));
+        assert!(storage.stat("b.txt").await.unwrap().is_none());
+
+        // A file being replaced doesn't count against its replacement
+        storage.put("a.txt", stream(b"1234567890")).await.unwrap();
+        assert_eq!(storage.available_space().await.unwrap(), Some(0));
+
+        storage.set_limit(0);
+        assert_eq!(storage.available_space().await.unwrap(), None);
+        storage.put("b.txt", stream(b"12345")).await.unwrap();
+    }
+}
@@ -0,0 +1,134 @@
+//! Where the files in the upload folder are kept: the folder itself, memory,
+//! or an S3-compatible bucket such as one on a MinIO server.
+//!
+//! Everything that reads or writes uploads goes through [`current`], which
+//! follows `storage` in the config. Names given to a backend are plain file
+//! names, already sanitized.
+
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use futures::stream::LocalBoxStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::config::{current_config, expand_path, StorageBackend, StorageConfig};
+use crate::error::{lock_recovering, AppError};
+
+pub mod local;
+pub mod memory;
+pub mod s3;
+
+pub use local::LocalStorage;
+pub use memory::MemoryStorage;
+pub use s3::S3Storage;
+
+/// File contents as they are read or written
+pub type ByteStream = LocalBoxStream<'static, Result<Bytes, AppError>>;
+
+/// A stored file
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub name: String,
+    pub size: u64,
+    /// Last modification time, in seconds since the Unix epoch
+    pub modified: u64,
+}
+
+/// A place to keep the upload folder's files
+#[async_trait(?Send)]
+pub trait Storage: Send + Sync {
+    /// Store `data` as `name`, returning its size. A file with that name is
+    /// only replaced once all of `data` has arrived, and nothing is stored
+    /// if `data` fails.
+    async fn put(&self, name: &str, data: ByteStream) -> Result<u64, AppError>;
+
+    /// Read `name` from byte `offset`, `len` bytes or to the end
+    async fn get(&self, name: &str, offset: u64, len: Option<u64>) -> Result<ByteStream, AppError>;
+
+    async fn list(&self) -> Result<Vec<ObjectInfo>, AppError>;
+
+    /// `None` when there is no file by that name
+    async fn stat(&self, name: &str) -> Result<Option<ObjectInfo>, AppError>;
+
+    async fn delete(&self, name: &str) -> Result<(), AppError>;
+
+    /// Rename a file, replacing any file already called `to`
+    async fn rename(&self, from: &str, to: &str) -> Result<(), AppError>;
+
+    /// Bytes that can still be stored, when the backend has a limit
+    async fn available_space(&self) -> Result<Option<u64>, AppError> {
+        Ok(None)
+    }
+
+    /// Where the file is on this host, for backends that keep files here
+    fn local_path(&self, _name: &str) -> Option<PathBuf> {
+        None
+    }
+
+    /// The folder files are kept in, for backends that keep files here
+    fn local_folder(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// The backend in use and the settings it was made from
+struct Backend {
+    config: StorageConfig,
+    upload_folder: PathBuf,
+    storage: Arc<dyn Storage>,
+}
+
+lazy_static::lazy_static! {
+    static ref BACKEND: Mutex<Option<Backend>> = Mutex::new(None);
+    // Kept across config changes, so switching away and back loses nothing
+    static ref MEMORY: Arc<MemoryStorage> = Arc::new(MemoryStorage::new(0));
+}
+
+/// The backend the config asks for
+pub fn current() -> Result<Arc<dyn Storage>, AppError> {
+    let config = current_config()?;
+    let folder = expand_path(&config.upload_folder);
+    let mut backend = lock_recovering(&BACKEND);
+    if let Some(backend) = backend.as_ref() {
+        if backend.config == config.storage && backend.upload_folder == folder {
+            return Ok(backend.storage.clone());
+        }
+    }
+
+    let storage: Arc<dyn Storage> = match config.storage.backend {
+        StorageBackend::Local => Arc::new(LocalStorage::new(folder.clone())),
+        StorageBackend::Memory => {
+            MEMORY.set_limit(config.storage.memory_max_bytes);
+            MEMORY.clone()
+        }
+        StorageBackend::S3 => Arc::new(S3Storage::new(config.storage.s3.clone())),
+    };
+    tracing::info!(backend = ?config.storage.backend, "Using storage backend");
+    *backend = Some(Backend {
+        config: config.storage,
+        upload_folder: folder,
+        storage: storage.clone(),
+    });
+    Ok(storage)
+}
+
+/// A name for a new file that doesn't clobber an existing one, adding
+/// " (1)", " (2)", ... before the extension as needed
+pub async fn unique_name(storage: &dyn Storage, name: &str) -> Result<String, AppError> {
+    if storage.stat(name).await?.is_none() {
+        return Ok(name.to_string());
+    }
+    let path = std::path::Path::new(name);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    for n in 1.. {
+        let candidate = format!("{} ({}){}", stem, n, extension);
+        if storage.stat(&candidate).await?.is_none() {
+            return Ok(candidate);
+        }
+    }
+    unreachable!("some numbered name is free")
+}
@@ -0,0 +1,511 @@
+//! Objects in an S3-compatible bucket, such as one on a MinIO server, a NAS
+//! or AWS itself, over `http://` or `https://`, through the AWS SDK.
+//!
+//! Files are uploaded in parts of `PART_SIZE` as they arrive, so a large
+//! upload never sits in memory whole. Renames copy the object and delete the
+//! original, which S3 allows for objects of up to 5 GiB.
+
+use async_trait::async_trait;
+use aws_sdk_s3::config::{
+    BehaviorVersion, Credentials, Region, RequestChecksumCalculation, ResponseChecksumValidation,
+};
+use aws_sdk_s3::error::{ProvideErrorMetadata, SdkError};
+use aws_sdk_s3::primitives::ByteStream as Body;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::Client;
+use futures::StreamExt;
+
+use super::{ByteStream, ObjectInfo, Storage};
+use crate::config::S3Config;
+use crate::error::AppError;
+use crate::http::describe;
+
+/// Size of each part of a multipart upload. Files smaller than this are
+/// uploaded in one request. S3 wants parts of at least 5 MiB.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+pub struct S3Storage {
+    config: S3Config,
+    client: Client,
+}
+
+impl S3Storage {
+    pub fn new(config: S3Config) -> Self {
+        let credentials = Credentials::new(
+            &config.access_key,
+            &config.secret_key,
+            None,
+            None,
+            "noplacelike",
+        );
+        let sdk_config = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .endpoint_url(config.endpoint.trim_end_matches('/'))
+            .region(Region::new(config.region.clone()))
+            .credentials_provider(credentials)
+            // Servers on a LAN rarely have a DNS name for each bucket
+            .force_path_style(true)
+            // Not every S3-compatible server knows the newer checksums
+            .request_checksum_calculation(RequestChecksumCalculation::WhenRequired)
+            .response_checksum_validation(ResponseChecksumValidation::WhenRequired)
+            .build();
+        Self {
+            client: Client::from_conf(sdk_config),
+            config,
+        }
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}{}", self.config.prefix, name)
+    }
+
+    /// Upload `data` after its first part, returning the bytes written and
+    /// the parts
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        first: Vec<u8>,
+        mut data: ByteStream,
+    ) -> Result<(u64, Vec<CompletedPart>), AppError> {
+        let (mut part, mut ended) = (first, false);
+        let mut written = 0;
+        let mut parts = Vec::new();
+        loop {
+            let number = parts.len() as i32 + 1;
+            let len = part.len() as u64;
+            let uploaded = self
+                .client
+                .upload_part()
+                .bucket(&self.config.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(number)
+                .body(Body::from(part))
+                .send()
+                .await
+                .map_err(|e| failed("PUT", e))?;
+            parts.push(
+                CompletedPart::builder()
+                    .part_number(number)
+                    .set_e_tag(uploaded.e_tag)
+                    .build(),
+            );
+            written += len;
+            if ended {
+                return Ok((written, parts));
+            }
+            (part, ended) = fill(&mut data).await?;
+            if part.is_empty() {
+                return Ok((written, parts));
+            }
+        }
+    }
+
+    async fn upload_multipart(
+        &self,
+        key: &str,
+        first: Vec<u8>,
+        data: ByteStream,
+    ) -> Result<u64, AppError> {
+        let upload_id = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.config.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| failed("POST", e))?
+            .upload_id
+            .ok_or_else(|| AppError::BadGateway("Object storage sent no upload ID".to_string()))?;
+
+        let result = match self.upload_parts(key, &upload_id, first, data).await {
+            Ok((written, parts)) => self
+                .client
+                .complete_multipart_upload()
+                .bucket(&self.config.bucket)
+                .key(key)
+                .upload_id(&upload_id)
+                .multipart_upload(
+                    CompletedMultipartUpload::builder()
+                        .set_parts(Some(parts))
+                        .build(),
+                )
+                .send()
+                .await
+                .map(|_| written)
+                .map_err(|e| failed("POST", e)),
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            let aborted = self
+                .client
+                .abort_multipart_upload()
+                .bucket(&self.config.bucket)
+                .key(key)
+                .upload_id(&upload_id)
+                .send()
+                .await;
+            if let Err(e) = aborted {
+                tracing::warn!(key, "Failed to abort multipart upload: {}", describe(&e));
+            }
+        }
+        result
+    }
+}
+
+#[async_trait(?Send)]
+impl Storage for S3Storage {
+    async fn put(&self, name: &str, mut data: ByteStream) -> Result<u64, AppError> {
+        let key = self.key(name);
+        let (first, ended) = fill(&mut data).await?;
+        if !ended {
+            return self.upload_multipart(&key, first, data).await;
+        }
+        let len = first.len() as u64;
+        self.client
+            .put_object()
+            .bucket(&self.config.bucket)
+            .key(&key)
+            .body(Body::from(first))
+            .send()
+            .await
+            .map_err(|e| failed("PUT", e))?;
+        Ok(len)
+    }
+
+    async fn get(&self, name: &str, offset: u64, len: Option<u64>) -> Result<ByteStream, AppError> {
+        if len == Some(0) {
+            return Ok(futures::stream::empty().boxed_local());
+        }
+        let range = (offset > 0 || len.is_some()).then(|| {
+            let end = len.map(|len| (offset + len - 1).to_string());
+            format!("bytes={}-{}", offset, end.unwrap_or_default())
+        });
+        let object = self
+            .client
+            .get_object()
+            .bucket(&self.config.bucket)
+            .key(self.key(name))
+            .set_range(range)
+            .send()
+            .await
+            .map_err(|e| failed("GET", e))?;
+        Ok(futures::stream::unfold(object.body, |mut body| async move {
+            let chunk = body.next().await?.map_err(|e| {
+                AppError::BadGateway(format!("Object storage failed: {}", describe(&e)))
+            });
+            Some((chunk, body))
+        })
+        .boxed_local())
+    }
+
+    async fn list(&self) -> Result<Vec<ObjectInfo>, AppError> {
+        let mut files = Vec::new();
+        let mut token = None;
+        loop {
+            let page = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.config.bucket)
+                .prefix(&self.config.prefix)
+                .delimiter("/")
+                .set_continuation_token(token)
+                .send()
+                .await
+                .map_err(|e| failed("GET", e))?;
+            files.extend(page.contents().iter().filter_map(|object| {
+                let name = object.key()?.strip_prefix(&self.config.prefix)?;
+                Some(ObjectInfo {
+                    name: name.to_string(),
+                    size: object.size().unwrap_or_default().max(0) as u64,
+                    modified: object
+                        .last_modified()
+                        .map_or(0, |date| date.secs().max(0) as u64),
+                })
+                .filter(|file| !file.name.is_empty())
+            }));
+            token = match page.next_continuation_token {
+                Some(next) if page.is_truncated == Some(true) => Some(next),
+                _ => return Ok(files),
+            };
+        }
+    }
+
+    async fn stat(&self, name: &str) -> Result<Option<ObjectInfo>, AppError> {
+        let head = match self
+            .client
+            .head_object()
+            .bucket(&self.config.bucket)
+            .key(self.key(name))
+            .send()
+            .await
+            .map_err(|e| failed("HEAD", e))
+        {
+            Ok(head) => head,
+            Err(AppError::NotFound(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(Some(ObjectInfo {
+            name: name.to_string(),
+            size: head.content_length.unwrap_or_default().max(0) as u64,
+            modified: head.last_modified.map_or(0, |date| date.secs().max(0) as u64),
+        }))
+    }
+
+    async fn delete(&self, name: &str) -> Result<(), AppError> {
+        // Deleting a missing object succeeds in S3
+        if self.stat(name).await?.is_none() {
+            return Err(AppError::NotFound("File not found".to_string()));
+        }
+        self.client
+            .delete_object()
+            .bucket(&self.config.bucket)
+            .key(self.key(name))
+            .send()
+            .await
+            .map_err(|e| failed("DELETE", e))?;
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<(), AppError> {
+        self.client
+            .copy_object()
+            .bucket(&self.config.bucket)
+            .key(self.key(to))
+            .copy_source(copy_source(&self.config.bucket, &self.key(from)))
+            .send()
+            .await
+            .map_err(|e| failed("PUT", e))?;
+        self.client
+            .delete_object()
+            .bucket(&self.config.bucket)
+            .key(self.key(from))
+            .send()
+            .await
+            .map_err(|e| failed("DELETE", e))?;
+        Ok(())
+    }
+}
+
+/// Read from `data` until there is a whole part or it ends, returning what
+/// was read and whether it ended
+async fn fill(data: &mut ByteStream) -> Result<(Vec<u8>, bool), AppError> {
+    let mut buffer = Vec::new();
+    while buffer.len() < PART_SIZE {
+        match data.next().await {
+            Some(chunk) => buffer.extend_from_slice(&chunk?),
+            None => return Ok((buffer, true)),
+        }
+    }
+    Ok((buffer, false))
+}
+
+/// An SDK error as the error for the client: `NotFound` for a missing
+/// object, otherwise `BadGateway` with the server's reason, a missing
+/// bucket included
+fn failed<E>(method: &str, error: SdkError<E>) -> AppError
+where
+    E: ProvideErrorMetadata + std::error::Error + 'static,
+{
+    let missing = error
+        .raw_response()
+        .is_some_and(|response| response.status().as_u16() == 404);
+    if missing && error.code() != Some("NoSuchBucket") {
+        return AppError::NotFound("File not found".to_string());
+    }
+    match &error {
+        SdkError::ServiceError(_) => {
+            let reason = error
+                .message()
+                .or(error.code())
+                .map(str::to_string)
+                .unwrap_or_else(|| describe(&error));
+            AppError::BadGateway(format!("Object storage refused {}: {}", method, reason))
+        }
+        _ => AppError::BadGateway(format!(
+            "Object storage is unreachable: {}",
+            describe(&error)
+        )),
+    }
+}
+
+/// The `x-amz-copy-source` value for an object: the bucket, a `/` and the
+/// key, URL-encoded but for the slashes that separate its parts
+fn copy_source(bucket: &str, key: &str) -> String {
+    let mut encoded = format!("{}/", bucket);
+    for byte in key.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::stand_in::{self, Reply};
+    use actix_web::web::Bytes;
+
+    fn storage(endpoint: &str) -> S3Storage {
+        S3Storage::new(S3Config {
+            endpoint: endpoint.to_string(),
+            bucket: "files".to_string(),
+            region: "us-east-1".to_string(),
+            access_key: "access".to_string(),
+            secret_key: "secret".to_string(),
+            prefix: "up/".to_string(),
+        })
+    }
+
+    /// `data` in chunks of 1 MiB, as an upload would arrive
+    fn stream(data: Vec<u8>) -> ByteStream {
+        let chunks: Vec<Result<Bytes, AppError>> = data
+            .chunks(1024 * 1024)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        futures::stream::iter(chunks).boxed_local()
+    }
+
+    /// A request target without its query
+    fn path(target: &str) -> &str {
+        target.split('?').next().unwrap_or_default()
+    }
+
+    #[test]
+    fn copy_source_encodes_the_key() {
+        assert_eq!(
+            copy_source("files", "up/a b+ü.txt"),
+            "files/up/a%20b%2B%C3%BC.txt"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn puts_small_files_in_one_request() {
+        let (url, received) = stand_in::start(vec![Reply::status(200).header("ETag", "\"x\"")]).await;
+        let size = storage(&url).put("a b.txt", stream(b"hello".to_vec())).await.unwrap();
+        assert_eq!(size, 5);
+
+        let request = received.await.unwrap().remove(0);
+        assert_eq!(request.method, "PUT");
+        assert_eq!(path(&request.target), "/files/up/a%20b.txt");
+        assert_eq!(request.body, b"hello");
+        assert!(request.headers["authorization"].starts_with("AWS4-HMAC-SHA256 Credential=access/"));
+    }
+
+    #[actix_rt::test]
+    async fn puts_large_files_in_parts() {
+        let upload = "<InitiateMultipartUploadResult><Bucket>files</Bucket><Key>up/big</Key>\
+            <UploadId>u1</UploadId></InitiateMultipartUploadResult>";
+        let complete = "<CompleteMultipartUploadResult><Key>up/big</Key><ETag>\"c\"</ETag>\
+            </CompleteMultipartUploadResult>";
+        let (url, received) = stand_in::start(vec![
+            Reply::status(200).body(upload),
+            Reply::status(200).header("ETag", "\"p1\""),
+            Reply::status(200).header("ETag", "\"p2\""),
+            Reply::status(200).body(complete),
+        ])
+        .await;
+        let data = vec![7u8; PART_SIZE + 10];
+        let size = storage(&url).put("big", stream(data)).await.unwrap();
+        assert_eq!(size, PART_SIZE as u64 + 10);
+
+        let received = received.await.unwrap();
+        assert_eq!(received[0].method, "POST");
+        assert!(received[0].target.starts_with("/files/up/big?uploads"));
+        assert_eq!(received[1].body.len(), PART_SIZE);
+        assert!(received[1].target.contains("partNumber=1"));
+        assert_eq!(received[2].body.len(), 10);
+        assert!(received[2].target.contains("partNumber=2"));
+        let body = String::from_utf8_lossy(&received[3].body);
+        assert!(received[3].target.contains("uploadId=u1"));
+        assert!(body.contains("<ETag>&quot;p1&quot;</ETag>") || body.contains("<ETag>\"p1\"</ETag>"));
+        assert!(body.contains("<PartNumber>2</PartNumber>"));
+    }
+
+    #[actix_rt::test]
+    async fn renames_by_copying_then_deleting() {
+        let copied = "<CopyObjectResult><ETag>\"x\"</ETag></CopyObjectResult>";
+        let (url, received) = stand_in::start(vec![
+            Reply::status(200).body(copied),
+            Reply::status(204),
+        ])
+        .await;
+        storage(&url).rename("a b.txt", "c.txt").await.unwrap();
+
+        let received = received.await.unwrap();
+        assert_eq!(received[0].method, "PUT");
+        assert_eq!(path(&received[0].target), "/files/up/c.txt");
+        assert_eq!(received[0].headers["x-amz-copy-source"], "files/up/a%20b.txt");
+        assert_eq!(received[1].method, "DELETE");
+        assert_eq!(path(&received[1].target), "/files/up/a%20b.txt");
+    }
+
+    #[actix_rt::test]
+    async fn lists_every_page_without_the_prefix() {
+        let first = "<ListBucketResult><IsTruncated>true</IsTruncated>\
+            <Contents><Key>up/a.txt</Key><Size>3</Size><LastModified>2024-01-02T03:04:05.000Z</LastModified></Contents>\
+            <NextContinuationToken>next</NextContinuationToken></ListBucketResult>";
+        let second = "<ListBucketResult><IsTruncated>false</IsTruncated>\
+            <Contents><Key>up/b &amp; c.txt</Key><Size>4</Size><LastModified>2024-01-02T03:04:05.000Z</LastModified></Contents>\
+            </ListBucketResult>";
+        let (url, received) = stand_in::start(vec![
+            Reply::status(200).body(first),
+            Reply::status(200).body(second),
+        ])
+        .await;
+        let files = storage(&url).list().await.unwrap();
+        let names: Vec<&str> = files.iter().map(|file| file.name.as_str()).collect();
+        assert_eq!(names, ["a.txt", "b & c.txt"]);
+        assert_eq!(files[1].size, 4);
+        assert_eq!(files[0].modified, 1704164645);
+
+        let received = received.await.unwrap();
+        assert!(received[0].target.contains("prefix=up%2F"));
+        assert!(received[1].target.contains("continuation-token=next"));
+    }
+
+    #[actix_rt::test]
+    async fn reads_ranges() {
+        let (url, received) = stand_in::start(vec![Reply::status(206).body("llo")]).await;
+        let mut data = storage(&url).get("a.txt", 2, Some(3)).await.unwrap();
+        let mut contents = Vec::new();
+        while let Some(chunk) = data.next().await {
+            contents.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(contents, b"llo");
+        assert_eq!(received.await.unwrap()[0].headers["range"], "bytes=2-4");
+    }
+
+    #[actix_rt::test]
+    async fn missing_objects_are_not_found() {
+        let (url, _) = stand_in::start(vec![Reply::status(404), Reply::status(404)]).await;
+        let storage = storage(&url);
+        assert!(storage.stat("gone.txt").await.unwrap().is_none());
+        assert!(matches!(storage.delete("gone.txt").await, Err(AppError::NotFound(_))));
+    }
+
+    #[actix_rt::test]
+    async fn a_missing_bucket_is_not_a_missing_file() {
+        let missing = "<Error><Code>NoSuchBucket</Code><Message>The specified bucket does not exist</Message></Error>";
+        let (url, _) = stand_in::start(vec![Reply::status(404).body(missing)]).await;
+        assert!(matches!(
+            storage(&url).list().await,
+            Err(AppError::BadGateway(message)) if message.contains("bucket does not exist")
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn refusals_carry_the_reason() {
+        let denied = "<Error><Code>AccessDenied</Code><Message>Access Denied</Message></Error>";
+        let (url, _) = stand_in::start(vec![Reply::status(403).body(denied)]).await;
+        match storage(&url).put("a.txt", stream(b"x".to_vec())).await {
+            Err(AppError::BadGateway(message)) => assert!(message.contains("Access Denied"), "{}", message),
+            other => panic!("expected BadGateway, got {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,806 @@
+//! `noplacelike sync`: keeps a directory in sync with a folder on a server.
+//!
+//! What was last synced is kept in `.nplsync-state.json` in the directory, so
+//! a restart only reads files whose size or modification time changed.
+
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Method, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{
+    chunk_file, conflict_name, hash, ignored, valid_folder_name, Chunk, ChunkList, CommitRequest,
+    FileEntry, FolderIndex, MAX_CHUNK_SIZE,
+};
+use crate::http;
+use crate::services::federation::host_name;
+use crate::services::files::PartialFile;
+
+const STATE_FILE: &str = ".nplsync-state.json";
+
+/// Largest folder index read
+const MAX_INDEX_SIZE: usize = 256 * 1024 * 1024;
+
+/// A file as it was when last synced
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Synced {
+    size: u64,
+    /// Modification time on this device, in nanoseconds
+    modified: u64,
+    hash: String,
+    chunks: Vec<Chunk>,
+    /// Version on the server
+    version: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    folder_id: String,
+    sequence: u64,
+    /// Sent as `X-Device-Id`, so the server can tell who changed what
+    device_id: String,
+    /// Sent as `X-Device-Token`, proving this is the device registered as
+    /// `device_id`
+    #[serde(default)]
+    device_token: String,
+    files: BTreeMap<String, Synced>,
+}
+
+/// A file found on this device
+#[derive(Debug, Clone)]
+struct Local {
+    size: u64,
+    modified: u64,
+    hash: String,
+    chunks: Vec<Chunk>,
+}
+
+enum Outcome {
+    Done,
+    Conflict(FileEntry),
+}
+
+struct Syncer {
+    base: String,
+    dir: PathBuf,
+    state: State,
+    device_name: String,
+}
+
+fn modified_nanos(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn full_path(dir: &Path, path: &str) -> PathBuf {
+    path.split('/')
+        .fold(dir.to_path_buf(), |p, part| p.join(part))
+}
+
+/// Size and modification time of a file, if it is there
+fn stat(path: &Path) -> Option<(u64, u64)> {
+    fs::metadata(path)
+        .ok()
+        .filter(|m| m.is_file())
+        .map(|m| (m.len(), modified_nanos(&m)))
+}
+
+fn walk(dir: &Path, prefix: &str, found: &mut BTreeMap<String, fs::Metadata>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)?.flatten() {
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        let path = format!("{}{}", prefix, name);
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk(&entry.path(), &format!("{}/", path), found)?;
+        } else if file_type.is_file() && !ignored(&path) {
+            found.insert(path, entry.metadata()?);
+        }
+    }
+    Ok(())
+}
+
+fn read_at(path: &Path, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = vec![0u8; size as usize];
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Files on this device, reading only those that changed since the last
+/// sync
+fn scan(dir: &Path, synced: &BTreeMap<String, Synced>) -> Result<BTreeMap<String, Local>, String> {
+    let mut found = BTreeMap::new();
+    walk(dir, "", &mut found).map_err(|e| format!("Can't read {}: {}", dir.display(), e))?;
+    let mut files = BTreeMap::new();
+    for (path, metadata) in found {
+        let (size, modified) = (metadata.len(), modified_nanos(&metadata));
+        let local = match synced.get(&path) {
+            Some(synced) if synced.size == size && synced.modified == modified => Local {
+                size,
+                modified,
+                hash: synced.hash.clone(),
+                chunks: synced.chunks.clone(),
+            },
+            _ => match chunk_file(&full_path(dir, &path)) {
+                Ok((chunks, hash)) => Local {
+                    size,
+                    modified,
+                    hash,
+                    chunks,
+                },
+                Err(e) => {
+                    eprintln!("Skipping {}: {}", path, e);
+                    continue;
+                }
+            },
+        };
+        files.insert(path, local);
+    }
+    Ok(files)
+}
+
+impl Syncer {
+    fn open(server: &str, dir: &Path, folder: &str) -> Result<Self, String> {
+        let state_path = dir.join(STATE_FILE);
+        let mut state: State = match fs::read_to_string(&state_path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| format!("Can't read {}: {}", state_path.display(), e))?,
+            Err(_) => State::default(),
+        };
+        if state.device_id.is_empty() {
+            state.device_id = uuid::Uuid::new_v4().to_string();
+        }
+        Ok(Self {
+            base: format!("{}/api/v1/sync/{}", server.trim_end_matches('/'), folder),
+            dir: dir.to_path_buf(),
+            state,
+            device_name: host_name(),
+        })
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let path = self.dir.join(STATE_FILE);
+        let content = serde_json::to_string(&self.state).map_err(|e| e.to_string())?;
+        let tmp = self.dir.join(format!("{}.tmp", STATE_FILE));
+        fs::write(&tmp, content)
+            .and_then(|_| fs::rename(&tmp, &path))
+            .map_err(|e| format!("Can't save {}: {}", path.display(), e))
+    }
+
+    fn headers(&self) -> Vec<(String, String)> {
+        vec![
+            ("X-Device-Id".to_string(), self.state.device_id.clone()),
+            ("X-Device-Token".to_string(), self.state.device_token.clone()),
+            (
+                "X-Device-Name".to_string(),
+                format!("{} (sync)", self.device_name),
+            ),
+        ]
+    }
+
+    /// Register this device with the server unless it already has, picking
+    /// a new ID if another device took this one
+    async fn register(&mut self, server: &str) -> Result<(), String> {
+        #[derive(Deserialize)]
+        struct Registered {
+            token: Option<String>,
+        }
+
+        if !self.state.device_token.is_empty() {
+            return Ok(());
+        }
+        let url = format!("{}/api/v1/devices", server.trim_end_matches('/'));
+        for _ in 0..2 {
+            let body = serde_json::to_vec(&serde_json::json!({
+                "id": self.state.device_id,
+                "name": format!("{} (sync)", self.device_name),
+            }))
+            .map_err(|e| e.to_string())?;
+            let response = http::streaming_client()
+                .post(&url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(body)
+                .send()
+                .await
+                .map_err(|e| http::describe(&e))?;
+            if response.status() == StatusCode::CONFLICT {
+                self.state.device_id = uuid::Uuid::new_v4().to_string();
+                continue;
+            }
+            let registered: Registered = Self::read(response, MAX_INDEX_SIZE).await?;
+            self.state.device_token = registered.token.unwrap_or_default();
+            return self.save();
+        }
+        Err("The server keeps refusing this device's ID".to_string())
+    }
+
+    async fn call(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Vec<u8>>,
+        json: bool,
+    ) -> Result<Response, String> {
+        let mut request = http::streaming_client().request(method, format!("{}{}", self.base, path));
+        for (name, value) in self.headers() {
+            request = request.header(name, value);
+        }
+        if json {
+            request = request.header(CONTENT_TYPE, "application/json");
+        }
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+        request.send().await.map_err(|e| http::describe(&e))
+    }
+
+    /// Read a JSON response, turning error responses into their message
+    async fn read<T: DeserializeOwned>(response: Response, limit: usize) -> Result<T, String> {
+        let status = response.status().as_u16();
+        let body = http::read_limited(response, limit).await?;
+        if status >= 400 {
+            let message = serde_json::from_slice::<serde_json::Value>(&body)
+                .ok()
+                .and_then(|v| v.get("error").and_then(|e| e.as_str()).map(str::to_string))
+                .unwrap_or_else(|| format!("The server answered {}", status));
+            return Err(message);
+        }
+        serde_json::from_slice(&body).map_err(|e| format!("Invalid response: {}", e))
+    }
+
+    /// Read a JSON response whatever its status
+    async fn parse<T: DeserializeOwned>(response: Response, limit: usize) -> Result<T, String> {
+        let body = http::read_limited(response, limit).await?;
+        serde_json::from_slice(&body).map_err(|e| format!("Invalid response: {}", e))
+    }
+
+    async fn fetch_index(&self, since: u64) -> Result<FolderIndex, String> {
+        let response = self
+            .call(Method::GET, &format!("?since={}", since), None, false)
+            .await?;
+        Self::read(response, MAX_INDEX_SIZE).await
+    }
+
+    fn remember(&mut self, path: &str, entry: &FileEntry, size: u64, modified: u64) {
+        self.state.files.insert(
+            path.to_string(),
+            Synced {
+                size,
+                modified,
+                hash: entry.hash.clone(),
+                chunks: entry.chunks.clone(),
+                version: entry.version,
+            },
+        );
+    }
+
+    /// Send a file's chunks the server lacks, then commit it
+    async fn upload(
+        &mut self,
+        path: &str,
+        local: &Local,
+        base_version: Option<u64>,
+    ) -> Result<Outcome, String> {
+        let file = full_path(&self.dir, path);
+        let hashes = ChunkList {
+            hashes: local.chunks.iter().map(|c| c.hash.clone()).collect(),
+        };
+        let body = serde_json::to_vec(&hashes).map_err(|e| e.to_string())?;
+        let response = self.call(Method::POST, "/missing", Some(body), true).await?;
+        let missing: ChunkList = Self::read(response, MAX_INDEX_SIZE).await?;
+
+        let mut offset = 0;
+        for chunk in &local.chunks {
+            if missing.hashes.contains(&chunk.hash) {
+                let data = read_at(&file, offset, chunk.size)
+                    .map_err(|e| format!("Can't read {}: {}", path, e))?;
+                if hash(&data) != chunk.hash {
+                    return Err(format!("{} changed while being sent", path));
+                }
+                let response = self
+                    .call(Method::PUT, &format!("/chunks/{}", chunk.hash), Some(data), false)
+                    .await?;
+                if response.status().as_u16() >= 300 {
+                    Self::read::<serde_json::Value>(response, MAX_CHUNK_SIZE).await?;
+                }
+            }
+            offset += chunk.size;
+        }
+
+        let request = CommitRequest {
+            path: path.to_string(),
+            chunks: local.chunks.clone(),
+            modified: local.modified / 1_000_000_000,
+            base_version,
+        };
+        let body = serde_json::to_vec(&request).map_err(|e| e.to_string())?;
+        let response = self.call(Method::PUT, "/files", Some(body), true).await?;
+        if response.status() == StatusCode::CONFLICT {
+            return Ok(Outcome::Conflict(
+                Self::parse(response, MAX_INDEX_SIZE).await?,
+            ));
+        }
+        let entry: FileEntry = Self::read(response, MAX_INDEX_SIZE).await?;
+        self.remember(path, &entry, local.size, local.modified);
+        println!("Uploaded {}", path);
+        Ok(Outcome::Done)
+    }
+
+    async fn delete_remote(&mut self, path: &str, base_version: u64) -> Result<Outcome, String> {
+        let query = format!("/files?path={}&base_version={}", encode(path), base_version);
+        let response = self.call(Method::DELETE, &query, None, false).await?;
+        if response.status() == StatusCode::CONFLICT {
+            return Ok(Outcome::Conflict(
+                Self::parse(response, MAX_INDEX_SIZE).await?,
+            ));
+        }
+        Self::read::<FileEntry>(response, MAX_INDEX_SIZE).await?;
+        self.state.files.remove(path);
+        println!("Deleted {} on the server", path);
+        Ok(Outcome::Done)
+    }
+
+    /// Write the server's version of a file, taking chunks this device
+    /// already has from its own files. Left alone if the file changes on
+    /// this device meanwhile.
+    async fn download(
+        &mut self,
+        entry: &FileEntry,
+        locals: &BTreeMap<String, Local>,
+    ) -> Result<(), String> {
+        let mut have: HashMap<&str, (&str, u64)> = HashMap::new();
+        for (path, local) in locals {
+            let mut offset = 0;
+            for chunk in &local.chunks {
+                have.entry(chunk.hash.as_str())
+                    .or_insert((path.as_str(), offset));
+                offset += chunk.size;
+            }
+        }
+
+        let target = full_path(&self.dir, &entry.path);
+        let expected = locals.get(&entry.path).map(|l| (l.size, l.modified));
+        let parent = target.parent().unwrap_or(&self.dir).to_path_buf();
+        fs::create_dir_all(&parent)
+            .map_err(|e| format!("Can't create {}: {}", parent.display(), e))?;
+        let name = target
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let partial = PartialFile::new(parent.join(format!(
+            ".nplsync-{}-{}",
+            uuid::Uuid::new_v4().simple(),
+            name
+        )));
+        let mut out = io::BufWriter::new(
+            fs::File::create(partial.path())
+                .map_err(|e| format!("Can't write {}: {}", entry.path, e))?,
+        );
+
+        for chunk in &entry.chunks {
+            let local = have.get(chunk.hash.as_str()).and_then(|(path, offset)| {
+                read_at(&full_path(&self.dir, path), *offset, chunk.size)
+                    .ok()
+                    .filter(|data| hash(data) == chunk.hash)
+            });
+            let data = match local {
+                Some(data) => data,
+                None => {
+                    let response = self
+                        .call(Method::GET, &format!("/chunks/{}", chunk.hash), None, false)
+                        .await?;
+                    if response.status() != StatusCode::OK {
+                        Self::read::<serde_json::Value>(response, MAX_CHUNK_SIZE).await?;
+                        return Err(format!("Can't download {}", entry.path));
+                    }
+                    let data = http::read_limited(response, MAX_CHUNK_SIZE).await?;
+                    if hash(&data) != chunk.hash {
+                        return Err(format!("A chunk of {} came back damaged", entry.path));
+                    }
+                    data
+                }
+            };
+            out.write_all(&data)
+                .map_err(|e| format!("Can't write {}: {}", entry.path, e))?;
+        }
+        let file = out.into_inner().map_err(|e| e.into_error().to_string())?;
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(entry.modified))
+            .and_then(|_| file.sync_all())
+            .map_err(|e| format!("Can't write {}: {}", entry.path, e))?;
+        drop(file);
+
+        if stat(&target) != expected {
+            return Err(format!(
+                "{} changed while downloading, trying again later",
+                entry.path
+            ));
+        }
+        fs::rename(partial.path(), &target)
+            .map_err(|e| format!("Can't write {}: {}", entry.path, e))?;
+        partial.complete();
+        let (size, modified) = stat(&target).unwrap_or_default();
+        self.remember(&entry.path, entry, size, modified);
+        println!("Downloaded {}", entry.path);
+        Ok(())
+    }
+
+    fn delete_local(&mut self, path: &str) -> Result<(), String> {
+        match fs::remove_file(full_path(&self.dir, path)) {
+            Ok(_) => println!("Deleted {}", path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Can't delete {}: {}", path, e)),
+        }
+        self.state.files.remove(path);
+        Ok(())
+    }
+
+    /// Both sides changed a file: keep this device's copy under a conflict
+    /// name and take the server's version
+    async fn resolve(
+        &mut self,
+        path: &str,
+        local: Option<&Local>,
+        remote: &FileEntry,
+        locals: &BTreeMap<String, Local>,
+    ) -> Result<(), String> {
+        match (local, remote.deleted) {
+            (None, true) => {
+                self.state.files.remove(path);
+            }
+            (None, false) => self.download(remote, locals).await?,
+            (Some(local), true) => {
+                // Edited here, deleted there: the edit wins
+                if let Outcome::Conflict(current) =
+                    self.upload(path, local, Some(remote.version)).await?
+                {
+                    return Err(format!(
+                        "{} keeps changing on the server ({})",
+                        path, current.version
+                    ));
+                }
+            }
+            (Some(local), false) if local.hash == remote.hash => {
+                self.remember(path, remote, local.size, local.modified);
+            }
+            (Some(local), false) => {
+                let mut copy = conflict_name(path, &self.device_name, now());
+                while full_path(&self.dir, &copy).exists() {
+                    copy = conflict_name(&copy, &self.device_name, now());
+                }
+                fs::rename(full_path(&self.dir, path), full_path(&self.dir, &copy))
+                    .map_err(|e| format!("Can't rename {}: {}", path, e))?;
+                self.state.files.remove(path);
+                println!(
+                    "Conflict: {} changed on both sides, kept this copy as {}",
+                    path, copy
+                );
+                let (size, modified) = stat(&full_path(&self.dir, &copy)).unwrap_or_default();
+                let copied = Local {
+                    size,
+                    modified,
+                    ..local.clone()
+                };
+                self.upload(&copy, &copied, None).await?;
+                let mut locals = locals.clone();
+                locals.remove(path);
+                locals.insert(copy, copied);
+                self.download(remote, &locals).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sync every file once
+    async fn cycle(&mut self) -> Result<(), String> {
+        let mut index = self.fetch_index(self.state.sequence).await?;
+        if index.folder_id != self.state.folder_id {
+            if !self.state.folder_id.is_empty() {
+                println!("The folder was reset on the server, comparing every file");
+            }
+            self.state.folder_id = index.folder_id.clone();
+            self.state.sequence = 0;
+            self.state.files.clear();
+            if index.sequence > 0 {
+                index = self.fetch_index(0).await?;
+            }
+        }
+
+        let locals = tokio::task::spawn_blocking({
+            let dir = self.dir.clone();
+            let synced = self.state.files.clone();
+            move || scan(&dir, &synced)
+        })
+        .await
+        .map_err(|e| e.to_string())??;
+
+        let remote: BTreeMap<String, FileEntry> = index
+            .files
+            .into_iter()
+            .filter(|entry| {
+                // Our own changes come back with the version we already have
+                self.state.files.get(&entry.path).map(|s| s.version) != Some(entry.version)
+            })
+            .map(|entry| (entry.path.clone(), entry))
+            .collect();
+
+        let mut paths: Vec<String> = self.state.files.keys().cloned().collect();
+        paths.extend(locals.keys().cloned());
+        paths.extend(remote.keys().cloned());
+        paths.sort();
+        paths.dedup();
+
+        let mut failed = Vec::new();
+        for path in paths {
+            let result = self.sync_file(&path, &locals, remote.get(&path)).await;
+            if let Err(e) = result {
+                eprintln!("{}: {}", path, e);
+                failed.push(path);
+            }
+        }
+
+        // Only move on once everything up to here made it, so nothing that
+        // failed is skipped next time
+        if failed.is_empty() {
+            self.state.sequence = index.sequence;
+        }
+        self.save()?;
+        match failed.len() {
+            0 => Ok(()),
+            n => Err(format!("{} file(s) couldn't be synced", n)),
+        }
+    }
+
+    async fn sync_file(
+        &mut self,
+        path: &str,
+        locals: &BTreeMap<String, Local>,
+        remote: Option<&FileEntry>,
+    ) -> Result<(), String> {
+        let synced = self.state.files.get(path).cloned();
+        let local = locals.get(path);
+        let local_changed = match (&synced, local) {
+            (Some(synced), Some(local)) => synced.hash != local.hash,
+            (None, None) => false,
+            _ => true,
+        };
+
+        match (local_changed, remote) {
+            (false, None) => Ok(()),
+            (true, None) => {
+                let outcome = match (local, &synced) {
+                    (Some(local), _) => {
+                        self.upload(path, local, synced.as_ref().map(|s| s.version))
+                            .await?
+                    }
+                    (None, Some(synced)) => self.delete_remote(path, synced.version).await?,
+                    (None, None) => return Ok(()),
+                };
+                match outcome {
+                    Outcome::Done => Ok(()),
+                    Outcome::Conflict(current) => self.resolve(path, local, &current, locals).await,
+                }
+            }
+            (false, Some(remote)) if remote.deleted => self.delete_local(path),
+            (false, Some(remote)) => self.download(remote, locals).await,
+            (true, Some(remote)) => self.resolve(path, local, remote, locals).await,
+        }
+    }
+}
+
+/// Percent-encode a path for a query string
+fn encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Keep `dir` in sync with `folder` on the server, checking every
+/// `interval`, or just once
+pub async fn run(
+    server: &str,
+    dir: &Path,
+    folder: Option<&str>,
+    once: bool,
+    interval: Duration,
+) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|e| format!("Can't create {}: {}", dir.display(), e))?;
+    let dir = dir
+        .canonicalize()
+        .map_err(|e| format!("Can't open {}: {}", dir.display(), e))?;
+    let folder = match folder {
+        Some(folder) => folder.to_string(),
+        None => dir
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default(),
+    };
+    if !valid_folder_name(&folder) {
+        return Err(format!(
+            "{:?} can't name a folder; pick one with --folder using letters, digits, '-', '_' and '.'",
+            folder
+        ));
+    }
+
+    let mut syncer = Syncer::open(server, &dir, &folder)?;
+    if let Err(e) = syncer.register(server).await {
+        eprintln!("Can't register this device, syncing without it: {}", e);
+    }
+    println!("Syncing {} with {} on {}", dir.display(), folder, server);
+    loop {
+        let result = syncer.cycle().await;
+        if once {
+            return result;
+        }
+        if let Err(e) = result {
+            eprintln!("Sync failed, retrying in {}s: {}", interval.as_secs(), e);
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::stand_in::{self, Reply};
+    use crate::sync::chunk_reader;
+
+    /// An empty directory, removed again when dropped
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new() -> Self {
+            let name = format!("nplsync-test-{}", uuid::Uuid::new_v4().simple());
+            let dir = std::env::temp_dir().join(name);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn entry(path: &str, data: &[u8], version: u64) -> FileEntry {
+        let (chunks, hash) = chunk_reader(data).unwrap();
+        FileEntry {
+            path: path.to_string(),
+            size: data.len() as u64,
+            modified: 1_700_000_000,
+            hash,
+            chunks,
+            version,
+            deleted: false,
+        }
+    }
+
+    fn json(value: &impl Serialize) -> Reply {
+        Reply::status(200)
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(value).unwrap())
+    }
+
+    /// The files in `dir` other than the state file, with their contents
+    fn contents(dir: &Path) -> BTreeMap<String, String> {
+        let mut found = BTreeMap::new();
+        walk(dir, "", &mut found).unwrap();
+        found
+            .into_keys()
+            .map(|path| {
+                let data = fs::read_to_string(full_path(dir, &path)).unwrap();
+                (path, data)
+            })
+            .collect()
+    }
+
+    #[actix_rt::test]
+    async fn a_file_changed_on_both_sides_keeps_both_copies() {
+        let scratch = Scratch::new();
+        fs::write(scratch.0.join("notes.txt"), "mine").unwrap();
+        let remote = entry("notes.txt", b"theirs", 2);
+        let copy = entry("copy", b"mine", 3);
+
+        let (url, server) = stand_in::start(vec![
+            json(&ChunkList {
+                hashes: copy.chunks.iter().map(|c| c.hash.clone()).collect(),
+            }),
+            Reply::status(200),
+            json(&copy),
+            Reply::status(200).body("theirs"),
+        ])
+        .await;
+        let mut syncer = Syncer::open(&url, &scratch.0, "docs").unwrap();
+        let locals = scan(&scratch.0, &BTreeMap::new()).unwrap();
+        syncer
+            .resolve("notes.txt", locals.get("notes.txt"), &remote, &locals)
+            .await
+            .unwrap();
+
+        let files = contents(&scratch.0);
+        assert_eq!(files.len(), 2);
+        assert_eq!(files["notes.txt"], "theirs");
+        let (kept, data) = files.iter().find(|(path, _)| *path != "notes.txt").unwrap();
+        assert!(kept.starts_with("notes (conflict from "), "{}", kept);
+        assert!(kept.ends_with(").txt"), "{}", kept);
+        assert_eq!(data, "mine");
+
+        let received = server.await.unwrap();
+        let commit: CommitRequest = serde_json::from_slice(&received[2].body).unwrap();
+        assert_eq!((received[2].method.as_str(), commit.path.as_str()), ("PUT", kept.as_str()));
+        assert_eq!(commit.base_version, None);
+        let chunk = format!("/api/v1/sync/docs/chunks/{}", remote.chunks[0].hash);
+        assert_eq!(received[3].target, chunk);
+        assert_eq!(syncer.state.files["notes.txt"].version, 2);
+        assert_eq!(syncer.state.files[kept].version, 3);
+    }
+
+    #[actix_rt::test]
+    async fn the_same_change_on_both_sides_is_no_conflict() {
+        let scratch = Scratch::new();
+        fs::write(scratch.0.join("notes.txt"), "same").unwrap();
+        let remote = entry("notes.txt", b"same", 4);
+
+        // Nothing is sent, so there is no server to talk to
+        let mut syncer = Syncer::open("http://127.0.0.1:9", &scratch.0, "docs").unwrap();
+        let locals = scan(&scratch.0, &BTreeMap::new()).unwrap();
+        syncer
+            .resolve("notes.txt", locals.get("notes.txt"), &remote, &locals)
+            .await
+            .unwrap();
+
+        assert_eq!(contents(&scratch.0).len(), 1);
+        assert_eq!(syncer.state.files["notes.txt"].version, 4);
+    }
+
+    #[actix_rt::test]
+    async fn an_edit_wins_over_a_delete() {
+        let scratch = Scratch::new();
+        fs::write(scratch.0.join("notes.txt"), "edited").unwrap();
+        let mut remote = entry("notes.txt", b"", 5);
+        remote.deleted = true;
+        let committed = entry("notes.txt", b"edited", 6);
+
+        let (url, server) = stand_in::start(vec![
+            json(&ChunkList { hashes: Vec::new() }),
+            json(&committed),
+        ])
+        .await;
+        let mut syncer = Syncer::open(&url, &scratch.0, "docs").unwrap();
+        let locals = scan(&scratch.0, &BTreeMap::new()).unwrap();
+        syncer
+            .resolve("notes.txt", locals.get("notes.txt"), &remote, &locals)
+            .await
+            .unwrap();
+
+        assert_eq!(contents(&scratch.0)["notes.txt"], "edited");
+        let received = server.await.unwrap();
+        let commit: CommitRequest = serde_json::from_slice(&received[1].body).unwrap();
+        assert_eq!(commit.base_version, Some(5));
+        assert_eq!(syncer.state.files["notes.txt"].version, 6);
+    }
+}
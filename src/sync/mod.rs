@@ -0,0 +1,302 @@
+//! Two-way folder sync between a directory on a device and a folder on the
+//! server.
+//!
+//! Files are cut into chunks where a rolling hash of the last bytes hits a
+//! pattern, so an edit only changes the chunks around it and the rest are
+//! never sent again. Chunks are named by their SHA-256.
+//!
+//! The server keeps an index of every file in a folder, with its chunks and
+//! the folder-wide sequence number of its last change; deleted files stay as
+//! tombstones. A client asks for what changed since the sequence it last
+//! saw, uploads the chunks the server lacks and commits each file against the
+//! version it started from. When someone else got there first the commit is
+//! refused, and the client keeps its copy under a conflict name next to the
+//! server's.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+use std::path::Path;
+use utoipa::ToSchema;
+
+pub mod client;
+
+/// Chunks are never cut shorter than this, except at the end of a file
+pub const MIN_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Chunks are always cut here
+pub const MAX_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Cut when the top bits of the rolling hash are all zero, which happens
+/// every 64 KiB on average
+const CUT_MASK: u64 = 0xFFFF << 48;
+
+/// Random values mixed into the rolling hash, one per byte value
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64, so the table is the same everywhere without being spelled out
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6e6f_706c_6163_656c;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// A piece of a file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Chunk {
+    /// SHA-256 of the chunk, in hex
+    pub hash: String,
+    pub size: u64,
+}
+
+/// A file in a synced folder, as the server knows it
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct FileEntry {
+    /// Path inside the folder, with `/` between components
+    pub path: String,
+    pub size: u64,
+    /// Modification time, in seconds since the Unix epoch
+    pub modified: u64,
+    /// SHA-256 of the whole file, in hex
+    pub hash: String,
+    pub chunks: Vec<Chunk>,
+    /// The folder's sequence number when this file last changed
+    pub version: u64,
+    /// Whether the file was deleted
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+/// What changed in a folder
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct FolderIndex {
+    /// Changes when the folder is reset on the server, so clients know their
+    /// sequence numbers no longer apply
+    pub folder_id: String,
+    /// Sequence number of the latest change
+    pub sequence: u64,
+    /// Files changed after the sequence number asked for
+    pub files: Vec<FileEntry>,
+}
+
+/// Replace or create a file from chunks the server has
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CommitRequest {
+    pub path: String,
+    pub chunks: Vec<Chunk>,
+    /// Modification time, in seconds since the Unix epoch
+    pub modified: u64,
+    /// Version the change was made to, none for a new file. The commit is
+    /// refused when the file has changed since.
+    pub base_version: Option<u64>,
+}
+
+/// A list of chunk hashes
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ChunkList {
+    pub hashes: Vec<String>,
+}
+
+/// SHA-256 of `data`, in hex
+pub fn hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Cut what `reader` yields into chunks, returning them with the hash of
+/// the whole
+pub fn chunk_reader(mut reader: impl Read) -> io::Result<(Vec<Chunk>, String)> {
+    let mut chunks = Vec::new();
+    let mut whole = Sha256::new();
+    let mut current = Vec::with_capacity(MAX_CHUNK_SIZE);
+    let mut rolling: u64 = 0;
+    let mut buffer = vec![0u8; 64 * 1024];
+
+    let mut cut = |current: &mut Vec<u8>, rolling: &mut u64| {
+        chunks.push(Chunk {
+            hash: hash(current),
+            size: current.len() as u64,
+        });
+        current.clear();
+        *rolling = 0;
+    };
+
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        whole.update(&buffer[..n]);
+        for &byte in &buffer[..n] {
+            current.push(byte);
+            rolling = (rolling << 1).wrapping_add(GEAR[byte as usize]);
+            if current.len() >= MAX_CHUNK_SIZE
+                || (current.len() >= MIN_CHUNK_SIZE && rolling & CUT_MASK == 0)
+            {
+                cut(&mut current, &mut rolling);
+            }
+        }
+    }
+    if !current.is_empty() {
+        cut(&mut current, &mut rolling);
+    }
+    Ok((chunks, format!("{:x}", whole.finalize())))
+}
+
+/// Cut a file into chunks, returning them with the hash of the whole file
+pub fn chunk_file(path: &Path) -> io::Result<(Vec<Chunk>, String)> {
+    chunk_reader(io::BufReader::new(std::fs::File::open(path)?))
+}
+
+/// Whether `name` can name a synced folder
+pub fn valid_folder_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Whether `path` stays inside the folder it is relative to
+pub fn valid_path(path: &str) -> bool {
+    !path.is_empty()
+        && !path.starts_with('/')
+        && !path.contains('\\')
+        && !path.contains('\0')
+        && path
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..")
+}
+
+/// Whether a file is left out of syncing: temporary files written while
+/// syncing and the client's state file
+pub fn ignored(path: &str) -> bool {
+    path.rsplit('/')
+        .next()
+        .is_some_and(|name| name.starts_with(".nplsync"))
+}
+
+/// Name for the copy of `path` kept when it changed on both sides
+pub fn conflict_name(path: &str, device: &str, unix_secs: u64) -> String {
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => (format!("{}/", dir), name),
+        None => (String::new(), path),
+    };
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    format!(
+        "{}{} (conflict from {} {}){}",
+        dir,
+        stem,
+        device,
+        date(unix_secs),
+        extension
+    )
+}
+
+/// `YYYY-MM-DD HHMMSS` in UTC
+fn date(unix_secs: u64) -> String {
+    let days = (unix_secs / 86_400) as i64;
+    let secs = unix_secs % 86_400;
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs / 3_600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_stay_inside_the_folder() {
+        for path in ["notes.txt", "docs/2024/report.pdf", ".hidden", "a..b"] {
+            assert!(valid_path(path), "{} should be allowed", path);
+        }
+        for path in [
+            "",
+            "/etc/passwd",
+            "../outside",
+            "docs/../../outside",
+            "docs/./notes.txt",
+            "docs//notes.txt",
+            "docs/",
+            "docs\\notes.txt",
+            "nul\0byte",
+        ] {
+            assert!(!valid_path(path), "{:?} should be refused", path);
+        }
+    }
+
+    #[test]
+    fn conflict_copies_keep_the_folder_and_extension() {
+        // 2023-11-14 22:13:20 UTC
+        let when = 1_700_000_000;
+        assert_eq!(
+            conflict_name("docs/notes.txt", "laptop", when),
+            "docs/notes (conflict from laptop 2023-11-14 221320).txt"
+        );
+        assert_eq!(
+            conflict_name("Makefile", "laptop", when),
+            "Makefile (conflict from laptop 2023-11-14 221320)"
+        );
+        assert_eq!(
+            conflict_name(".profile", "laptop", 0),
+            ".profile (conflict from laptop 1970-01-01 000000)"
+        );
+    }
+
+    #[test]
+    fn an_edit_only_changes_the_chunks_around_it() {
+        // Bytes that don't repeat, so chunk boundaries depend on content
+        let mut state: u64 = 1;
+        let original: Vec<u8> = (0..1024 * 1024)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect();
+        let mut edited = original.clone();
+        edited.splice(500_000..500_000, b"an insertion".iter().copied());
+
+        let (before, whole) = chunk_reader(original.as_slice()).unwrap();
+        let (after, _) = chunk_reader(edited.as_slice()).unwrap();
+        assert_eq!(whole, hash(&original));
+        assert_eq!(before.iter().map(|c| c.size).sum::<u64>(), original.len() as u64);
+        assert!(before
+            .iter()
+            .take(before.len() - 1)
+            .all(|c| (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&(c.size as usize))));
+
+        let changed = after.iter().filter(|c| !before.contains(c)).count();
+        assert!(changed <= 2, "{} of {} chunks changed", changed, after.len());
+    }
+}
@@ -1,23 +1,27 @@
 use actix_web::HttpResponse;
 use askama::Template;
 
+use crate::error::AppError;
+
 #[derive(Template)]
 #[template(path = "home.html")]
-pub struct HomeTemplate {}
+pub struct HomeTemplate {
+    /// Words for code-phrase transfer codes, space separated
+    pub wormhole_words: String,
+}
 
 #[derive(Template)]
 #[template(path = "admin.html")]
-pub struct AdminTemplate {}
+pub struct AdminTemplate {
+    pub config_path: String,
+}
 
-pub fn render_template<T>(template: &T) -> HttpResponse
+pub fn render_template<T>(template: &T) -> Result<HttpResponse, AppError>
 where
     T: Template,
 {
-    match template.render() {
-        Ok(html) => HttpResponse::Ok().content_type("text/html").body(html),
-        Err(e) => {
-            eprintln!("Template error: {}", e);
-            HttpResponse::InternalServerError().body("Template error")
-        }
-    }
+    let html = template
+        .render()
+        .map_err(|e| AppError::Internal(format!("Template error: {}", e)))?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
 }
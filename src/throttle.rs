@@ -0,0 +1,145 @@
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::web::Bytes;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::Sleep;
+
+use crate::config::{TransferPriority, CONFIG};
+use crate::error::lock_recovering;
+use crate::rate_limit::TokenBucket;
+
+/// Seconds of traffic that may pass at full speed after an idle spell
+const BURST_SECS: f64 = 0.5;
+
+/// How far the preferred transfers may run the global allowance into debt
+/// without waiting, in seconds of traffic. Everything else waits until the
+/// debt is paid back, so the preferred transfers are served first.
+const PRIORITY_HEADROOM_SECS: f64 = 1.0;
+
+lazy_static::lazy_static! {
+    // Shared by every transfer, for the global cap
+    static ref GLOBAL: Mutex<TokenBucket> = Mutex::new(TokenBucket::new());
+}
+
+/// What a transfer is, for `throttle.priority`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThrottleClass {
+    /// Audio streams
+    Stream,
+    /// Uploads, pushes and downloads
+    Bulk,
+}
+
+/// Paces one transfer to stay under the configured caps. The caps are read
+/// for every chunk, so changes apply to transfers already running.
+#[derive(Debug)]
+pub struct Throttle {
+    class: ThrottleClass,
+    connection: TokenBucket,
+}
+
+impl Throttle {
+    pub fn new(class: ThrottleClass) -> Self {
+        Self {
+            class,
+            connection: TokenBucket::new(),
+        }
+    }
+
+    /// Count `bytes` as sent and say how long to hold them back for
+    pub fn delay(&mut self, bytes: u64) -> Duration {
+        let config = lock_recovering(&CONFIG).throttle.clone();
+        let bytes = bytes as f64;
+        let mut wait: f64 = 0.0;
+
+        let per_connection = config.per_connection_bytes_per_sec as f64;
+        if per_connection > 0.0 {
+            let left = self
+                .connection
+                .charge(bytes, per_connection, per_connection * BURST_SECS);
+            wait = wait.max(-left / per_connection);
+        }
+
+        let global = config.global_bytes_per_sec as f64;
+        if global > 0.0 {
+            let preferred = matches!(
+                (config.priority, self.class),
+                (TransferPriority::Streams, ThrottleClass::Stream)
+                    | (TransferPriority::Bulk, ThrottleClass::Bulk)
+            );
+            let headroom = if preferred {
+                global * PRIORITY_HEADROOM_SECS
+            } else {
+                0.0
+            };
+            let left = lock_recovering(&GLOBAL).charge(bytes, global, global * BURST_SECS);
+            wait = wait.max(-(left + headroom) / global);
+        }
+
+        Duration::from_secs_f64(wait.max(0.0))
+    }
+
+    /// Count `bytes` as received, waiting as long as the caps require
+    pub async fn pace(&mut self, bytes: u64) {
+        let wait = self.delay(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// A response body held back chunk by chunk to stay under the caps
+pub struct ThrottledBody {
+    inner: BoxBody,
+    throttle: Throttle,
+    // A chunk waiting for its delay to pass
+    pending: Option<(Bytes, Pin<Box<Sleep>>)>,
+}
+
+impl ThrottledBody {
+    pub fn new(inner: BoxBody, class: ThrottleClass) -> Self {
+        Self {
+            inner,
+            throttle: Throttle::new(class),
+            pending: None,
+        }
+    }
+}
+
+impl MessageBody for ThrottledBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = &mut *self;
+        loop {
+            if let Some((_, sleep)) = &mut this.pending {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                let (chunk, _) = this.pending.take().expect("a chunk is pending");
+                return Poll::Ready(Some(Ok(chunk)));
+            }
+
+            match Pin::new(&mut this.inner).poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    let wait = this.throttle.delay(chunk.len() as u64);
+                    if wait.is_zero() {
+                        return Poll::Ready(Some(Ok(chunk)));
+                    }
+                    this.pending = Some((chunk, Box::pin(tokio::time::sleep(wait))));
+                }
+                poll => return poll,
+            }
+        }
+    }
+}
@@ -0,0 +1,280 @@
+//! `noplacelike send` and `noplacelike receive`
+
+use futures::{SinkExt, StreamExt};
+use std::io::Write;
+use std::path::Path;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use super::{
+    generate_code, nameplate, Incoming, Keys, Offer, Pake, PeerMessage, RelayEvent, Side,
+    CHUNK_SIZE, WINDOW,
+};
+use crate::routes::api::unique_path;
+use crate::services::files::PartialFile;
+use crate::services::quota::format_size;
+
+/// A frame from the relay or the other side
+enum Frame {
+    Event(RelayEvent),
+    Peer(PeerMessage),
+    Chunk(Vec<u8>),
+}
+
+struct Connection {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl Connection {
+    async fn open(server: &str, query: &str) -> Result<Self, String> {
+        let base = server.trim_end_matches('/');
+        let url = match base.split_once("://") {
+            Some(("https", rest)) => format!("wss://{}", rest),
+            Some((_, rest)) => format!("ws://{}", rest),
+            None => format!("ws://{}", base),
+        };
+        let (ws, _) = tokio_tungstenite::connect_async(format!("{}/ws/wormhole{}", url, query))
+            .await
+            .map_err(|e| match e {
+                tungstenite::Error::Http(response) if response.status() == 404 => {
+                    "No one is sending with that code".to_string()
+                }
+                e => format!("Can't connect to {}: {}", server, e),
+            })?;
+        Ok(Self { ws })
+    }
+
+    async fn send(&mut self, message: &PeerMessage) -> Result<(), String> {
+        let text = serde_json::to_string(message).map_err(|e| e.to_string())?;
+        self.ws.send(Message::Text(text)).await.map_err(lost)
+    }
+
+    async fn send_chunk(&mut self, chunk: Vec<u8>) -> Result<(), String> {
+        self.ws.send(Message::Binary(chunk)).await.map_err(lost)
+    }
+
+    /// The next frame, failing if the other side gave up or left
+    async fn next(&mut self) -> Result<Frame, String> {
+        loop {
+            let message = self
+                .ws
+                .next()
+                .await
+                .ok_or("The relay closed the connection")?
+                .map_err(lost)?;
+            let frame = match message {
+                Message::Text(text) => match serde_json::from_str(&text) {
+                    Ok(Incoming::Relay(event)) => Frame::Event(event),
+                    Ok(Incoming::Peer(message)) => Frame::Peer(message),
+                    Err(_) => return Err(format!("Unexpected message: {}", text)),
+                },
+                Message::Binary(bytes) => Frame::Chunk(bytes),
+                Message::Close(frame) => {
+                    return Err(frame
+                        .map(|f| f.reason.to_string())
+                        .filter(|reason| !reason.is_empty())
+                        .unwrap_or_else(|| "The relay closed the connection".to_string()))
+                }
+                _ => continue,
+            };
+            return match frame {
+                Frame::Event(RelayEvent::PeerLeft) => {
+                    Err("The other side disconnected".to_string())
+                }
+                Frame::Peer(PeerMessage::Error(reason)) => Err(reason),
+                frame => Ok(frame),
+            };
+        }
+    }
+
+    async fn next_message(&mut self) -> Result<PeerMessage, String> {
+        match self.next().await? {
+            Frame::Peer(message) => Ok(message),
+            _ => Err("Unexpected message from the other side".to_string()),
+        }
+    }
+
+    /// Run the key exchange once both sides are connected
+    async fn exchange_keys(&mut self, side: Side, code: &str) -> Result<Keys, String> {
+        let pake = Pake::start(side, code);
+        self.send(&PeerMessage::Pake(pake.message())).await?;
+        let PeerMessage::Pake(peer) = self.next_message().await? else {
+            return Err("Unexpected message from the other side".to_string());
+        };
+        let keys = pake.finish(&peer)?;
+
+        self.send(&PeerMessage::Confirm(keys.confirm.clone()))
+            .await?;
+        let PeerMessage::Confirm(confirm) = self.next_message().await? else {
+            return Err("Unexpected message from the other side".to_string());
+        };
+        if confirm != keys.expected {
+            let reason = "The code doesn't match, check it and try again".to_string();
+            let _ = self.send(&PeerMessage::Error(reason.clone())).await;
+            return Err(reason);
+        }
+        Ok(keys)
+    }
+
+    async fn close(mut self) {
+        let _ = self.ws.close(None).await;
+    }
+}
+
+fn lost(e: tungstenite::Error) -> String {
+    format!("Lost the connection: {}", e)
+}
+
+fn chunk_count(size: u64) -> u64 {
+    size.div_ceil(CHUNK_SIZE as u64).max(1)
+}
+
+fn progress(done: u64, total: u64) {
+    let percent = (done * 100).checked_div(total).unwrap_or(100);
+    eprint!(
+        "\r{} of {} ({}%)",
+        format_size(done),
+        format_size(total),
+        percent
+    );
+    let _ = std::io::stderr().flush();
+}
+
+/// Send `path` to whoever enters the code this prints
+pub async fn send(server: &str, path: &Path) -> Result<(), String> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| format!("Can't open {}: {}", path.display(), e))?;
+    let size = file.metadata().await.map_err(|e| e.to_string())?.len();
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .ok_or_else(|| format!("{} is not a file", path.display()))?;
+
+    let mut conn = Connection::open(server, "").await?;
+    let Frame::Event(RelayEvent::Allocated { nameplate }) = conn.next().await? else {
+        return Err("The relay didn't give out a code".to_string());
+    };
+    let code = generate_code(&nameplate);
+    println!("Sending {} ({})", name, format_size(size));
+    println!("Code: {}", code);
+    println!("On the other device, enter the code in the web UI or run:");
+    println!("  noplacelike receive {}", code);
+
+    let Frame::Event(RelayEvent::PeerJoined) = conn.next().await? else {
+        return Err("Unexpected message from the relay".to_string());
+    };
+    let keys = conn.exchange_keys(Side::Sender, &code).await?;
+    let offer = Offer { name, size };
+    conn.send(&PeerMessage::Offer(keys.seal_offer(&offer)))
+        .await?;
+    match conn.next_message().await? {
+        PeerMessage::Accept => {}
+        PeerMessage::Reject => return Err("The receiver declined the file".to_string()),
+        _ => return Err("Unexpected message from the other side".to_string()),
+    }
+
+    let chunks = chunk_count(size);
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let (mut sent, mut acked, mut bytes) = (0u64, 0u64, 0u64);
+    while acked < chunks {
+        if sent < chunks && sent - acked < WINDOW {
+            let len = (size - bytes).min(CHUNK_SIZE as u64) as usize;
+            file.read_exact(&mut buffer[..len])
+                .await
+                .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+            conn.send_chunk(keys.seal(sent + 1, &buffer[..len], sent + 1 == chunks))
+                .await?;
+            sent += 1;
+            bytes += len as u64;
+            continue;
+        }
+        match conn.next_message().await? {
+            PeerMessage::Ack(count) if count > acked && count <= sent => {
+                acked = count;
+                progress(bytes.min(acked * CHUNK_SIZE as u64), size);
+            }
+            _ => return Err("Unexpected message from the other side".to_string()),
+        }
+    }
+
+    let PeerMessage::Received = conn.next_message().await? else {
+        return Err("The receiver didn't confirm the file".to_string());
+    };
+    eprintln!();
+    println!("Sent");
+    conn.close().await;
+    Ok(())
+}
+
+/// Receive a file into `output` using the code the sender was given
+pub async fn receive(server: &str, code: &str, output: &Path) -> Result<(), String> {
+    let code = code.trim().to_lowercase();
+    let nameplate = nameplate(&code).ok_or("That doesn't look like a code")?;
+    let mut conn = Connection::open(server, &format!("?nameplate={}", nameplate)).await?;
+    let Frame::Event(RelayEvent::PeerJoined) = conn.next().await? else {
+        return Err("Unexpected message from the relay".to_string());
+    };
+    let keys = conn.exchange_keys(Side::Receiver, &code).await?;
+
+    let PeerMessage::Offer(offer) = conn.next_message().await? else {
+        return Err("Unexpected message from the other side".to_string());
+    };
+    let Offer { name, size } = keys.open_offer(&offer)?;
+    let name = sanitize_filename::sanitize(&name);
+    let prompt = format!("Receive {} ({})? [y/N] ", name, format_size(size));
+    let accepted = tokio::task::spawn_blocking(move || {
+        print!("{}", prompt);
+        let _ = std::io::stdout().flush();
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer).is_ok()
+            && matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+    })
+    .await
+    .unwrap_or(false);
+    if !accepted {
+        conn.send(&PeerMessage::Reject).await?;
+        conn.close().await;
+        return Err("Declined".to_string());
+    }
+
+    tokio::fs::create_dir_all(output)
+        .await
+        .map_err(|e| format!("Can't create {}: {}", output.display(), e))?;
+    let partial = PartialFile::new(unique_path(output, &name));
+    let mut file = tokio::fs::File::create(partial.path())
+        .await
+        .map_err(|e| format!("Can't create {}: {}", partial.path().display(), e))?;
+    conn.send(&PeerMessage::Accept).await?;
+
+    let chunks = chunk_count(size);
+    let mut bytes = 0u64;
+    for counter in 1..=chunks {
+        let Frame::Chunk(sealed) = conn.next().await? else {
+            return Err("Unexpected message from the other side".to_string());
+        };
+        let data = keys.open(counter, &sealed, counter == chunks)?;
+        bytes += data.len() as u64;
+        if bytes > size {
+            return Err("The sender sent more than it offered".to_string());
+        }
+        file.write_all(&data)
+            .await
+            .map_err(|e| format!("Can't write {}: {}", partial.path().display(), e))?;
+        conn.send(&PeerMessage::Ack(counter)).await?;
+        progress(bytes, size);
+    }
+    if bytes != size {
+        return Err("The sender sent less than it offered".to_string());
+    }
+    file.flush().await.map_err(|e| e.to_string())?;
+
+    eprintln!();
+    println!("Saved {}", partial.path().display());
+    partial.complete();
+    conn.send(&PeerMessage::Received).await?;
+    conn.close().await;
+    Ok(())
+}
@@ -0,0 +1,358 @@
+//! Code-phrase transfers between two devices that haven't been paired.
+//!
+//! The sender gets a nameplate from the relay at `/ws/wormhole` and makes a
+//! code like `7-maple-otter` from it and two random words. The receiver joins
+//! with the same code, which tells the relay the nameplate and nothing else.
+//! Both sides then run SPAKE2 (RFC 9382, over P-256) through the relay with
+//! the whole code as the password, confirm they got the same key, and the
+//! file is streamed through the relay encrypted with AES-GCM. The relay never
+//! stores it.
+//!
+//! This module is the protocol; the web UI has its own copy in
+//! `templates/wormhole.js` and must be kept in step with it.
+
+use aes_gcm::aead::{Aead, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hkdf::Hkdf;
+use p256::elliptic_curve::ops::Reduce;
+use p256::elliptic_curve::sec1::{FromEncodedPoint, ToEncodedPoint};
+use p256::{AffinePoint, EncodedPoint, FieldBytes, NonZeroScalar, ProjectivePoint, Scalar, U256};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub mod client;
+
+/// Plaintext bytes per file chunk
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Chunks the sender may have in flight before waiting for an `ack`
+pub const WINDOW: u64 = 16;
+
+/// Words for the secret part of a code. Two of them give 16 bits, which is
+/// plenty since each code allows a single guess.
+pub const WORDS: [&str; 256] = [
+    "acid", "acorn", "actor", "agent", "alarm", "album", "alley", "amber", "angel", "ankle",
+    "apple", "arena", "armor", "arrow", "atlas", "attic", "audio", "autumn", "badge", "bagel",
+    "baker", "bamboo", "banjo", "barn", "basil", "basin", "beach", "beacon", "bean", "bear",
+    "beetle", "bell", "bench", "berry", "bison", "blade", "blanket", "bloom", "board", "bonus",
+    "boots", "bottle", "bread", "brick", "bridge", "broom", "brush", "bucket", "buffalo", "bugle",
+    "cabin", "cactus", "camel", "candle", "canoe", "canyon", "carbon", "carpet", "castle", "cedar",
+    "cello", "chalk", "cherry", "chess", "chimney", "circus", "citrus", "clock", "cloud", "clover",
+    "cobalt", "cocoa", "comet", "compass", "copper", "coral", "cotton", "cougar", "crane",
+    "crayon", "cricket", "crown", "crystal", "cube", "daisy", "dancer", "delta", "denim", "desert",
+    "diamond", "dolphin", "donkey", "dragon", "drum", "eagle", "easel", "echo", "elbow", "ember",
+    "emerald", "engine", "falcon", "feather", "fern", "fiddle", "flame", "flute", "forest",
+    "fossil", "fox", "galaxy", "garden", "garlic", "gazelle", "geyser", "ginger", "giraffe",
+    "glacier", "globe", "goblet", "grape", "gravel", "guitar", "hammer", "harbor", "harp", "hazel",
+    "helmet", "heron", "honey", "hornet", "igloo", "indigo", "iris", "island", "ivory", "jacket",
+    "jaguar", "jasmine", "jelly", "jungle", "kayak", "kernel", "kettle", "kiwi", "koala", "ladder",
+    "lagoon", "lantern", "lemon", "leopard", "lilac", "lime", "linen", "lizard", "lobster",
+    "lotus", "magnet", "mango", "maple", "marble", "meadow", "melon", "meteor", "mint", "mirror",
+    "mitten", "monkey", "moose", "mosaic", "muffin", "nectar", "needle", "nickel", "noodle",
+    "oasis", "ocean", "olive", "onion", "orbit", "orchid", "otter", "oyster", "paddle", "panda",
+    "panther", "papaya", "parrot", "peach", "pebble", "pelican", "pepper", "piano", "pickle",
+    "pigeon", "pillow", "pine", "pirate", "planet", "plum", "pocket", "pony", "poppy", "puzzle",
+    "quartz", "quill", "rabbit", "radar", "radish", "raven", "ribbon", "river", "robin", "rocket",
+    "saddle", "salmon", "sandal", "satin", "scarf", "seal", "shadow", "shell", "silver", "sketch",
+    "sparrow", "spider", "spruce", "squash", "stable", "summit", "sunset", "swan", "tablet",
+    "teapot", "thistle", "thunder", "tiger", "timber", "tomato", "topaz", "torch", "toucan",
+    "trumpet", "tulip", "tunnel", "turtle", "umbrella", "valley", "velvet", "violet", "walnut",
+    "walrus", "willow", "window", "wizard", "wombat",
+];
+
+/// SPAKE2 blinding points for P-256, from RFC 9382
+const M: &str = "02886e2f97ace46e55ba9dd7242579f2993b64e16ef3dcab95afd497333d8fa12f";
+const N: &str = "03d8bbd6c639c62937b04d997f38c3770719c629d7014d49a24b4f98baa1292b49";
+
+/// Sent by the relay
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum RelayEvent {
+    /// The sender's nameplate, for it to build a code around
+    #[serde(rename = "wormhole_allocated")]
+    Allocated { nameplate: String },
+    /// Both sides are connected and messages are relayed from now on
+    #[serde(rename = "wormhole_peer_joined")]
+    PeerJoined,
+    #[serde(rename = "wormhole_peer_left")]
+    PeerLeft,
+}
+
+/// Sent between the two sides, through the relay. File chunks are sent as
+/// binary frames instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum PeerMessage {
+    /// This side's SPAKE2 message, base64
+    Pake(String),
+    /// Proof of the agreed key, base64
+    Confirm(String),
+    /// The encrypted [`Offer`], base64
+    Offer(String),
+    Accept,
+    Reject,
+    /// Number of chunks received so far
+    Ack(u64),
+    /// The whole file arrived
+    Received,
+    /// Why this side is giving up, such as a wrong code
+    Error(String),
+}
+
+/// Any text frame a client may get
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Incoming {
+    Relay(RelayEvent),
+    Peer(PeerMessage),
+}
+
+/// What the sender offers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Offer {
+    pub name: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Sender,
+    Receiver,
+}
+
+/// Make a code from a nameplate and two random words
+pub fn generate_code(nameplate: &str) -> String {
+    let bytes = NonZeroScalar::random(&mut OsRng).to_bytes();
+    format!(
+        "{}-{}-{}",
+        nameplate, WORDS[bytes[0] as usize], WORDS[bytes[1] as usize]
+    )
+}
+
+/// The nameplate part of a code, or `None` if it doesn't look like one
+pub fn nameplate(code: &str) -> Option<&str> {
+    let (nameplate, words) = code.trim().split_once('-')?;
+    let valid =
+        !nameplate.is_empty() && nameplate.chars().all(|c| c.is_ascii_digit()) && !words.is_empty();
+    valid.then_some(nameplate)
+}
+
+fn point(hex: &str) -> ProjectivePoint {
+    let bytes: Vec<u8> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).expect("constant is hex"))
+        .collect();
+    let encoded = EncodedPoint::from_bytes(bytes).expect("constant is a SEC1 point");
+    Option::<AffinePoint>::from(AffinePoint::from_encoded_point(&encoded))
+        .expect("constant is on the curve")
+        .into()
+}
+
+fn encode(point: &ProjectivePoint) -> Vec<u8> {
+    point
+        .to_affine()
+        .to_encoded_point(false)
+        .as_bytes()
+        .to_vec()
+}
+
+/// One side of a SPAKE2 exchange
+pub struct Pake {
+    side: Side,
+    secret: NonZeroScalar,
+    password: Scalar,
+    message: Vec<u8>,
+}
+
+impl Pake {
+    pub fn start(side: Side, code: &str) -> Self {
+        let digest = Sha256::digest(format!(
+            "noplacelike wormhole v1:{}",
+            code.trim().to_lowercase()
+        ));
+        let password =
+            <Scalar as Reduce<U256>>::reduce_bytes(&FieldBytes::clone_from_slice(&digest));
+        let secret = NonZeroScalar::random(&mut OsRng);
+        let blind = match side {
+            Side::Sender => point(M),
+            Side::Receiver => point(N),
+        };
+        let message = encode(&(ProjectivePoint::GENERATOR * *secret + blind * password));
+        Self {
+            side,
+            secret,
+            password,
+            message,
+        }
+    }
+
+    /// This side's message, to send to the other side
+    pub fn message(&self) -> String {
+        BASE64.encode(&self.message)
+    }
+
+    /// Work out the shared keys from the other side's message
+    pub fn finish(self, peer: &str) -> Result<Keys, String> {
+        let bytes = BASE64
+            .decode(peer)
+            .map_err(|_| "Invalid key exchange message")?;
+        let peer_point = EncodedPoint::from_bytes(&bytes)
+            .ok()
+            .and_then(|p| Option::<AffinePoint>::from(AffinePoint::from_encoded_point(&p)))
+            .map(ProjectivePoint::from)
+            .filter(|p| *p != ProjectivePoint::IDENTITY)
+            .ok_or("Invalid key exchange message")?;
+
+        let (unblind, sender_message, receiver_message) = match self.side {
+            Side::Sender => (point(N), &self.message, &bytes),
+            Side::Receiver => (point(M), &bytes, &self.message),
+        };
+        let shared = (peer_point - unblind * self.password) * *self.secret;
+
+        let mut transcript = Sha256::new();
+        transcript.update(sender_message);
+        transcript.update(receiver_message);
+        transcript.update(encode(&shared));
+        transcript.update(self.password.to_bytes());
+        let hkdf = Hkdf::<Sha256>::new(None, &transcript.finalize());
+        let derive = |info: &str| {
+            let mut key = [0u8; 32];
+            hkdf.expand(info.as_bytes(), &mut key)
+                .expect("32 bytes is a valid length");
+            key
+        };
+
+        let (mine, theirs) = match self.side {
+            Side::Sender => ("sender", "receiver"),
+            Side::Receiver => ("receiver", "sender"),
+        };
+        Ok(Keys {
+            confirm: BASE64.encode(derive(&format!("noplacelike wormhole confirm {}", mine))),
+            expected: BASE64.encode(derive(&format!("noplacelike wormhole confirm {}", theirs))),
+            cipher: Aes256Gcm::new(&derive("noplacelike wormhole data").into()),
+        })
+    }
+}
+
+/// What both sides agreed on
+pub struct Keys {
+    /// Proof to send to the other side
+    pub confirm: String,
+    /// Proof the other side should send
+    pub expected: String,
+    cipher: Aes256Gcm,
+}
+
+impl Keys {
+    fn nonce(counter: u64) -> Nonce<aes_gcm::aead::consts::U12> {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        nonce.into()
+    }
+
+    /// Encrypt the sender's `counter`th message: the offer is 0 and file
+    /// chunks count up from 1. The last chunk is marked so a cut-off transfer
+    /// can't pass for a whole one.
+    pub fn seal(&self, counter: u64, data: &[u8], last: bool) -> Vec<u8> {
+        let aad = [u8::from(last)];
+        self.cipher
+            .encrypt(
+                &Self::nonce(counter),
+                Payload {
+                    msg: data,
+                    aad: &aad,
+                },
+            )
+            .expect("encrypting into a Vec can't fail")
+    }
+
+    pub fn open(&self, counter: u64, data: &[u8], last: bool) -> Result<Vec<u8>, String> {
+        let aad = [u8::from(last)];
+        self.cipher
+            .decrypt(
+                &Self::nonce(counter),
+                Payload {
+                    msg: data,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| "Received data that doesn't decrypt".to_string())
+    }
+
+    pub fn seal_offer(&self, offer: &Offer) -> String {
+        let json = serde_json::to_vec(offer).expect("an offer serializes");
+        BASE64.encode(self.seal(0, &json, false))
+    }
+
+    pub fn open_offer(&self, offer: &str) -> Result<Offer, String> {
+        let bytes = BASE64.decode(offer).map_err(|_| "Invalid offer")?;
+        serde_json::from_slice(&self.open(0, &bytes, false)?)
+            .map_err(|_| "Invalid offer".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(sender_code: &str, receiver_code: &str) -> (Keys, Keys) {
+        let sender = Pake::start(Side::Sender, sender_code);
+        let receiver = Pake::start(Side::Receiver, receiver_code);
+        let (to_receiver, to_sender) = (sender.message(), receiver.message());
+        (sender.finish(&to_sender).unwrap(), receiver.finish(&to_receiver).unwrap())
+    }
+
+    #[test]
+    fn both_sides_agree_on_the_same_code() {
+        // Codes are compared without case or surrounding spaces
+        let (sender, receiver) = exchange("7-guitar-orbit", " 7-Guitar-Orbit ");
+        assert_eq!(sender.confirm, receiver.expected);
+        assert_eq!(receiver.confirm, sender.expected);
+        assert_ne!(sender.confirm, receiver.confirm);
+
+        let offer = Offer {
+            name: "photo.jpg".to_string(),
+            size: 1234,
+        };
+        let opened = receiver.open_offer(&sender.seal_offer(&offer)).unwrap();
+        assert_eq!((opened.name.as_str(), opened.size), ("photo.jpg", 1234));
+
+        let sealed = sender.seal(1, b"first chunk", false);
+        assert_eq!(receiver.open(1, &sealed, false).unwrap(), b"first chunk");
+    }
+
+    #[test]
+    fn a_wrong_code_gives_different_keys() {
+        let (sender, receiver) = exchange("7-guitar-orbit", "7-guitar-orchid");
+        assert_ne!(sender.confirm, receiver.expected);
+        assert_ne!(receiver.confirm, sender.expected);
+        assert!(receiver.open(1, &sender.seal(1, b"data", false), false).is_err());
+    }
+
+    #[test]
+    fn chunks_only_open_in_their_place() {
+        let (sender, receiver) = exchange("3-apple-river", "3-apple-river");
+        let sealed = sender.seal(2, b"chunk", false);
+        assert!(receiver.open(3, &sealed, false).is_err());
+        // A cut-off transfer can't pass its last chunk off as the end
+        assert!(receiver.open(2, &sealed, true).is_err());
+    }
+
+    #[test]
+    fn bad_messages_are_refused() {
+        let pake = Pake::start(Side::Sender, "1-a-b");
+        assert!(Pake::start(Side::Receiver, "1-a-b").finish("not base64!").is_err());
+        assert!(pake.finish(&BASE64.encode([4u8; 65])).is_err());
+    }
+
+    #[test]
+    fn codes_start_with_their_nameplate() {
+        let code = generate_code("42");
+        assert_eq!(nameplate(&code), Some("42"));
+        assert_eq!(code.split('-').count(), 3);
+        assert_eq!(nameplate("x-guitar-orbit"), None);
+        assert_eq!(nameplate("42"), None);
+    }
+}
@@ -0,0 +1,263 @@
+// End-to-end encryption between paired devices, with WebCrypto.
+//
+// Each device keeps a P-256 key pair in IndexedDB and registers the public
+// half with the server. The group shares one AES-GCM key per epoch, which
+// members wrap for each other with an AES-KW key derived (ECDH + HKDF) from
+// their two key pairs. The server only ever sees public keys, wrapped keys
+// and ciphertext.
+const E2E = (() => {
+    const CHUNK_SIZE = 64 * 1024;
+    const TAG_SIZE = 16;
+    const FILE_MAGIC = new TextEncoder().encode('NPE1');
+    const TEXT_PREFIX = 'e2e1:';
+    const FILE_SUFFIX = '.e2e';
+
+    const state = {
+        enabled: false,
+        epoch: 0,
+        rotationNeeded: false,
+        devices: [],
+        // Group key per epoch, for those this device was given
+        groupKeys: new Map(),
+        keyPair: null,
+        publicKey: null,
+    };
+
+    const toBase64 = (bytes) => btoa(String.fromCharCode(...new Uint8Array(bytes)));
+    const fromBase64 = (text) => Uint8Array.from(atob(text), c => c.charCodeAt(0));
+
+    function openStore() {
+        return new Promise((resolve, reject) => {
+            const request = indexedDB.open('noplacelike-e2e', 1);
+            request.onupgradeneeded = () => request.result.createObjectStore('keys');
+            request.onsuccess = () => resolve(request.result);
+            request.onerror = () => reject(request.error);
+        });
+    }
+
+    async function storeRequest(mode, run) {
+        const db = await openStore();
+        return new Promise((resolve, reject) => {
+            const request = run(db.transaction('keys', mode).objectStore('keys'));
+            request.onsuccess = () => resolve(request.result);
+            request.onerror = () => reject(request.error);
+        });
+    }
+
+    // This device's key pair, created on first use. The private key can't be
+    // exported, even by this page.
+    async function loadKeyPair() {
+        let keyPair = await storeRequest('readonly', store => store.get('device'));
+        if (!keyPair) {
+            keyPair = await crypto.subtle.generateKey(
+                {name: 'ECDH', namedCurve: 'P-256'}, false, ['deriveBits']);
+            await storeRequest('readwrite', store => store.put(keyPair, 'device'));
+        }
+        return keyPair;
+    }
+
+    function importPublicKey(base64) {
+        return crypto.subtle.importKey(
+            'raw', fromBase64(base64), {name: 'ECDH', namedCurve: 'P-256'}, false, []);
+    }
+
+    // The key used to wrap group keys between this device and another
+    async function wrappingKey(otherPublicKey, epoch) {
+        const secret = await crypto.subtle.deriveBits(
+            {name: 'ECDH', public: await importPublicKey(otherPublicKey)},
+            state.keyPair.privateKey, 256);
+        const hkdf = await crypto.subtle.importKey('raw', secret, 'HKDF', false, ['deriveKey']);
+        return crypto.subtle.deriveKey(
+            {
+                name: 'HKDF',
+                hash: 'SHA-256',
+                salt: new TextEncoder().encode(`epoch ${epoch}`),
+                info: new TextEncoder().encode('noplacelike group key'),
+            },
+            hkdf, {name: 'AES-KW', length: 256}, false, ['wrapKey', 'unwrapKey']);
+    }
+
+    async function wrapFor(device, groupKey, epoch) {
+        const wrapped = await crypto.subtle.wrapKey(
+            'raw', groupKey, await wrappingKey(device.public_key, epoch), 'AES-KW');
+        return toBase64(wrapped);
+    }
+
+    async function unwrap(wrapped) {
+        return crypto.subtle.unwrapKey(
+            'raw', fromBase64(wrapped.key), await wrappingKey(wrapped.wrapper_key, wrapped.epoch),
+            'AES-KW', 'AES-GCM', true, ['encrypt', 'decrypt']);
+    }
+
+    async function api(path, options = {}) {
+        const res = await fetch('/api/v1/e2e' + path, Object.assign({}, options, {
+            headers: deviceHeaders(Object.assign({'Content-Type': 'application/json'}, options.headers || {})),
+        }));
+        if (!res.ok) {
+            throw new Error((await res.json()).error || 'Key request failed');
+        }
+        return res.status === 204 ? null : res.json();
+    }
+
+    // Create a new group key for the next epoch and give it to `members`
+    async function newGroupKey(members) {
+        const epoch = state.epoch + 1;
+        const groupKey = await crypto.subtle.generateKey(
+            {name: 'AES-GCM', length: 256}, true, ['encrypt', 'decrypt']);
+        const keys = {};
+        for (const device of members) {
+            keys[device.id] = await wrapFor(device, groupKey, epoch);
+        }
+        await api('/keys', {method: 'POST', body: JSON.stringify({epoch, keys})});
+    }
+
+    // Fetch the group and this device's keys, registering this device and
+    // creating or rotating the group key when that falls to it
+    async function init(retry = true) {
+        const keys = await api('/keys');
+        state.enabled = keys.enabled;
+        if (!state.enabled) return;
+
+        state.keyPair = state.keyPair || await loadKeyPair();
+        state.publicKey = toBase64(await crypto.subtle.exportKey('raw', state.keyPair.publicKey));
+        const me = keys.devices.find(d => d.id === getDeviceId());
+        if (!me || me.public_key !== state.publicKey) {
+            await api('/public-key', {method: 'PUT', body: JSON.stringify({public_key: state.publicKey})});
+            return init(retry);
+        }
+
+        Object.assign(state, {epoch: keys.epoch, rotationNeeded: keys.rotation_needed, devices: keys.devices});
+        for (const wrapped of keys.keys) {
+            if (!state.groupKeys.has(wrapped.epoch)) {
+                try {
+                    state.groupKeys.set(wrapped.epoch, await unwrap(wrapped));
+                } catch (error) {
+                    console.warn(`Can't unwrap the group key for epoch ${wrapped.epoch}:`, error);
+                }
+            }
+        }
+
+        const members = keys.devices.filter(d => d.member);
+        // Nobody holds a key yet, so start the group. A revoked device still
+        // has the current key, so a member replaces it.
+        const startGroup = members.length === 0;
+        const rotate = keys.rotation_needed && me.member;
+        if ((startGroup || rotate) && retry) {
+            try {
+                await newGroupKey(startGroup ? [me] : members);
+            } catch (error) {
+                // Most likely another device got there first
+                console.warn('Failed to replace the group key:', error);
+            }
+            return init(false);
+        }
+    }
+
+    // Give the current group key to a device after its fingerprint checks out
+    async function pair(id) {
+        await init();
+        const device = state.devices.find(d => d.id === id);
+        const groupKey = state.groupKeys.get(state.epoch);
+        if (!device || !groupKey) throw new Error('This device has no group key to share');
+        const key = await wrapFor(device, groupKey, state.epoch);
+        await api('/keys', {method: 'POST', body: JSON.stringify({epoch: state.epoch, keys: {[id]: key}})});
+        await init();
+    }
+
+    // A short hash of a public key, for comparing on both devices when pairing
+    async function fingerprint(publicKey) {
+        const hash = new Uint8Array(await crypto.subtle.digest('SHA-256', fromBase64(publicKey)));
+        return [...hash.slice(0, 8)].map(b => b.toString(16).padStart(2, '0')).join('')
+            .match(/.{4}/g).join(' ');
+    }
+
+    function currentKey() {
+        const key = state.groupKeys.get(state.epoch);
+        if (!key) throw new Error('This device has not been paired yet');
+        return key;
+    }
+
+    function keyFor(epoch) {
+        const key = state.groupKeys.get(epoch);
+        if (!key) throw new Error(`No key for epoch ${epoch} on this device`);
+        return key;
+    }
+
+    // Encrypted text is `e2e1:<epoch>:<iv>:<ciphertext>`
+    async function encryptText(text) {
+        const iv = crypto.getRandomValues(new Uint8Array(12));
+        const ciphertext = await crypto.subtle.encrypt(
+            {name: 'AES-GCM', iv}, currentKey(), new TextEncoder().encode(text));
+        return `${TEXT_PREFIX}${state.epoch}:${toBase64(iv)}:${toBase64(ciphertext)}`;
+    }
+
+    async function decryptText(text) {
+        const [epoch, iv, ciphertext] = text.slice(TEXT_PREFIX.length).split(':');
+        const plaintext = await crypto.subtle.decrypt(
+            {name: 'AES-GCM', iv: fromBase64(iv)}, keyFor(Number(epoch)), fromBase64(ciphertext));
+        return new TextDecoder().decode(plaintext);
+    }
+
+    // Each chunk gets the base IV with the chunk number in its last four
+    // bytes, and the last chunk is marked so a truncated file won't decrypt
+    function chunkParams(baseIv, index, last) {
+        const iv = baseIv.slice();
+        new DataView(iv.buffer).setUint32(8, new DataView(baseIv.buffer).getUint32(8) ^ index);
+        return {name: 'AES-GCM', iv, additionalData: new Uint8Array([last ? 1 : 0])};
+    }
+
+    // Encrypted files are `NPE1`, the epoch (4 bytes), a 12 byte base IV and
+    // then the file in encrypted chunks of CHUNK_SIZE
+    async function encryptFile(file) {
+        const key = currentKey();
+        const baseIv = crypto.getRandomValues(new Uint8Array(12));
+        const header = new Uint8Array(20);
+        header.set(FILE_MAGIC);
+        new DataView(header.buffer).setUint32(4, state.epoch);
+        header.set(baseIv, 8);
+
+        const parts = [header];
+        const chunks = Math.max(1, Math.ceil(file.size / CHUNK_SIZE));
+        for (let i = 0; i < chunks; i++) {
+            const plaintext = await file.slice(i * CHUNK_SIZE, (i + 1) * CHUNK_SIZE).arrayBuffer();
+            parts.push(await crypto.subtle.encrypt(chunkParams(baseIv, i, i === chunks - 1), key, plaintext));
+        }
+        return new Blob(parts);
+    }
+
+    async function decryptFile(buffer) {
+        const bytes = new Uint8Array(buffer);
+        if (bytes.length < 20 || !FILE_MAGIC.every((b, i) => bytes[i] === b)) {
+            throw new Error('Not an encrypted file');
+        }
+        const key = keyFor(new DataView(bytes.buffer).getUint32(4));
+        const baseIv = bytes.slice(8, 20);
+
+        const parts = [];
+        const sealed = CHUNK_SIZE + TAG_SIZE;
+        for (let offset = 20, i = 0; offset < bytes.length; offset += sealed, i++) {
+            const last = offset + sealed >= bytes.length;
+            parts.push(await crypto.subtle.decrypt(
+                chunkParams(baseIv, i, last), key, bytes.subarray(offset, offset + sealed)));
+        }
+        return new Blob(parts);
+    }
+
+    return {
+        init,
+        pair,
+        fingerprint,
+        encryptText,
+        decryptText,
+        encryptFile,
+        decryptFile,
+        isEncryptedText: (text) => text.startsWith(TEXT_PREFIX),
+        isEncryptedFile: (name) => name.endsWith(FILE_SUFFIX),
+        encryptedName: (name) => name + FILE_SUFFIX,
+        plainName: (name) => name.slice(0, -FILE_SUFFIX.length),
+        get enabled() { return state.enabled; },
+        get paired() { return state.groupKeys.has(state.epoch); },
+        get publicKey() { return state.publicKey; },
+        get devices() { return state.devices; },
+    };
+})();
@@ -0,0 +1,305 @@
+// Code-phrase transfers through the server's relay at /ws/wormhole.
+//
+// This follows the protocol in src/wormhole/mod.rs: SPAKE2 over P-256 with
+// the code as the password, a confirmation step so a wrong code fails
+// cleanly, then the file in AES-GCM chunks. WebCrypto can't add curve
+// points, so the key exchange does its own arithmetic with BigInt; it only
+// runs a few scalar multiplications per transfer.
+const Wormhole = (() => {
+    const CHUNK_SIZE = 64 * 1024;
+    const WINDOW = 16;
+
+    // P-256
+    const P = 0xffffffff00000001000000000000000000000000ffffffffffffffffffffffffn;
+    const ORDER = 0xffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632551n;
+    const B = 0x5ac635d8aa3a93e7b3ebbd55769886bc651d06b0cc53b0f63bce3c3e27d2604bn;
+    const G = {
+        x: 0x6b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296n,
+        y: 0x4fe342e2fe1a7f9b8ee7eb4a7c0f9e162bce33576b315ececbb6406837bf51f5n,
+    };
+    // SPAKE2 blinding points from RFC 9382
+    const M = {
+        x: 0x886e2f97ace46e55ba9dd7242579f2993b64e16ef3dcab95afd497333d8fa12fn,
+        y: 0x5ff355163e43ce224e0b0e65ff02ac8e5c7be09419c785e0ca547d55a12e2d20n,
+    };
+    const N = {
+        x: 0xd8bbd6c639c62937b04d997f38c3770719c629d7014d49a24b4f98baa1292b49n,
+        y: 0x07d60aa6bfade45008a636337f5168c64d9bd36034808cd564490b1e656edbe7n,
+    };
+
+    const encoder = new TextEncoder();
+    const toBase64 = (bytes) => btoa(String.fromCharCode(...new Uint8Array(bytes)));
+    const fromBase64 = (text) => Uint8Array.from(atob(text), c => c.charCodeAt(0));
+
+    const mod = (a, m = P) => ((a % m) + m) % m;
+
+    function invert(a, m = P) {
+        let [r0, r1, t0, t1] = [mod(a, m), m, 1n, 0n];
+        while (r1 !== 0n) {
+            const q = r0 / r1;
+            [r0, r1] = [r1, r0 - q * r1];
+            [t0, t1] = [t1, t0 - q * t1];
+        }
+        return mod(t0, m);
+    }
+
+    // Points are {x, y}, with null for the identity
+    function add(a, b) {
+        if (!a) return b;
+        if (!b) return a;
+        let slope;
+        if (a.x === b.x) {
+            if (mod(a.y + b.y) === 0n) return null;
+            slope = mod((3n * a.x * a.x - 3n) * invert(2n * a.y));
+        } else {
+            slope = mod((b.y - a.y) * invert(b.x - a.x));
+        }
+        const x = mod(slope * slope - a.x - b.x);
+        return {x, y: mod(slope * (a.x - x) - a.y)};
+    }
+
+    const negate = (point) => point && {x: point.x, y: mod(-point.y)};
+
+    function multiply(point, scalar) {
+        let result = null;
+        for (let bit = BigInt(scalar.toString(2).length) - 1n; bit >= 0n; bit--) {
+            result = add(result, result);
+            if ((scalar >> bit) & 1n) result = add(result, point);
+        }
+        return result;
+    }
+
+    function toBytes(value, length = 32) {
+        const bytes = new Uint8Array(length);
+        for (let i = length - 1; i >= 0; i--, value >>= 8n) bytes[i] = Number(value & 0xffn);
+        return bytes;
+    }
+
+    const fromBytes = (bytes) => bytes.reduce((value, b) => (value << 8n) | BigInt(b), 0n);
+
+    // Uncompressed SEC1, as the Rust side sends
+    function encodePoint(point) {
+        const bytes = new Uint8Array(65);
+        bytes[0] = 4;
+        bytes.set(toBytes(point.x), 1);
+        bytes.set(toBytes(point.y), 33);
+        return bytes;
+    }
+
+    function decodePoint(bytes) {
+        if (bytes.length !== 65 || bytes[0] !== 4) throw new Error('Invalid key exchange message');
+        const x = fromBytes(bytes.subarray(1, 33));
+        const y = fromBytes(bytes.subarray(33));
+        if (x >= P || y >= P || mod(y * y) !== mod(x * x * x - 3n * x + B)) {
+            throw new Error('Invalid key exchange message');
+        }
+        return {x, y};
+    }
+
+    const sha256 = async (data) => new Uint8Array(await crypto.subtle.digest('SHA-256', data));
+
+    function concat(...parts) {
+        const bytes = new Uint8Array(parts.reduce((sum, part) => sum + part.length, 0));
+        parts.reduce((offset, part) => (bytes.set(part, offset), offset + part.length), 0);
+        return bytes;
+    }
+
+    async function startPake(sender, code) {
+        const password = mod(fromBytes(await sha256(encoder.encode(
+            `noplacelike wormhole v1:${code.trim().toLowerCase()}`))), ORDER);
+        // Extra bytes make the bias from reducing negligible
+        const secret = mod(fromBytes(crypto.getRandomValues(new Uint8Array(48))), ORDER - 1n) + 1n;
+        const message = encodePoint(add(multiply(G, secret), multiply(sender ? M : N, password)));
+        return {sender, password, secret, message};
+    }
+
+    async function finishPake(pake, peerMessage) {
+        const peerBytes = fromBase64(peerMessage);
+        const peer = decodePoint(peerBytes);
+        const shared = multiply(add(peer, negate(multiply(pake.sender ? N : M, pake.password))), pake.secret);
+        if (!shared) throw new Error('Invalid key exchange message');
+
+        const [senderMessage, receiverMessage] = pake.sender
+            ? [pake.message, peerBytes] : [peerBytes, pake.message];
+        const transcript = await sha256(concat(
+            senderMessage, receiverMessage, encodePoint(shared), toBytes(pake.password)));
+        const hkdf = await crypto.subtle.importKey('raw', transcript, 'HKDF', false, ['deriveBits']);
+        const derive = async (info) => new Uint8Array(await crypto.subtle.deriveBits(
+            {name: 'HKDF', hash: 'SHA-256', salt: new Uint8Array(), info: encoder.encode(info)}, hkdf, 256));
+
+        const [mine, theirs] = pake.sender ? ['sender', 'receiver'] : ['receiver', 'sender'];
+        return {
+            confirm: toBase64(await derive(`noplacelike wormhole confirm ${mine}`)),
+            expected: toBase64(await derive(`noplacelike wormhole confirm ${theirs}`)),
+            key: await crypto.subtle.importKey(
+                'raw', await derive('noplacelike wormhole data'), 'AES-GCM', false, ['encrypt', 'decrypt']),
+        };
+    }
+
+    // The offer is message 0 and file chunks count up from 1. The last chunk
+    // is marked so a cut-off transfer can't pass for a whole one.
+    function cipherParams(counter, last) {
+        const iv = new Uint8Array(12);
+        new DataView(iv.buffer).setBigUint64(4, BigInt(counter));
+        return {name: 'AES-GCM', iv, additionalData: new Uint8Array([last ? 1 : 0])};
+    }
+
+    const seal = async (keys, counter, data, last) =>
+        new Uint8Array(await crypto.subtle.encrypt(cipherParams(counter, last), keys.key, data));
+
+    async function open(keys, counter, data, last) {
+        try {
+            return new Uint8Array(await crypto.subtle.decrypt(cipherParams(counter, last), keys.key, data));
+        } catch (error) {
+            throw new Error("Received data that doesn't decrypt");
+        }
+    }
+
+    // A relay connection read one frame at a time
+    function connect(query = '') {
+        const protocol = location.protocol === 'https:' ? 'wss:' : 'ws:';
+        const socket = new WebSocket(`${protocol}//${location.host}/ws/wormhole${query}`);
+        socket.binaryType = 'arraybuffer';
+        const frames = [];
+        const waiting = [];
+        let failure = null;
+
+        const deliver = (frame) => waiting.length ? waiting.shift().resolve(frame) : frames.push(frame);
+        const fail = (error) => {
+            failure = failure || error;
+            waiting.splice(0).forEach(w => w.reject(failure));
+        };
+
+        socket.onmessage = (event) => {
+            if (typeof event.data !== 'string') {
+                deliver({chunk: new Uint8Array(event.data)});
+                return;
+            }
+            const msg = JSON.parse(event.data);
+            if (msg.type === 'wormhole_peer_left') {
+                fail(new Error('The other side disconnected'));
+            } else if (msg.type === 'error') {
+                fail(new Error(msg.data));
+            } else {
+                deliver(msg);
+            }
+        };
+        // A receiver with an unknown nameplate is turned away before the
+        // socket opens, which browsers only report as a failed connection
+        socket.onerror = () => fail(new Error(query
+            ? 'No one is sending with that code' : "Can't reach the relay"));
+        socket.onclose = (event) => fail(new Error(event.reason || 'The relay closed the connection'));
+
+        return {
+            next(type) {
+                const frame = frames.length ? Promise.resolve(frames.shift())
+                    : failure ? Promise.reject(failure)
+                    : new Promise((resolve, reject) => waiting.push({resolve, reject}));
+                return frame.then(frame => {
+                    if (type && frame.type !== type) throw new Error('Unexpected message from the other side');
+                    return frame;
+                });
+            },
+            send: (type, data) => socket.send(JSON.stringify(data === undefined ? {type} : {type, data})),
+            sendChunk: (bytes) => socket.send(bytes),
+            close: () => socket.close(),
+        };
+    }
+
+    async function exchangeKeys(conn, sender, code) {
+        const pake = await startPake(sender, code);
+        conn.send('pake', toBase64(pake.message));
+        const keys = await finishPake(pake, (await conn.next('pake')).data);
+        conn.send('confirm', keys.confirm);
+        if ((await conn.next('confirm')).data !== keys.expected) {
+            const reason = "The code doesn't match, check it and try again";
+            conn.send('error', reason);
+            throw new Error(reason);
+        }
+        return keys;
+    }
+
+    const chunkCount = (size) => Math.max(1, Math.ceil(size / CHUNK_SIZE));
+
+    // Send `file`, calling `onCode` with the code to pass on and
+    // `onProgress` with the bytes the receiver has so far
+    async function send(file, {words, onCode, onProgress = () => {}}) {
+        const conn = connect();
+        try {
+            const {data: {nameplate}} = await conn.next('wormhole_allocated');
+            const random = crypto.getRandomValues(new Uint8Array(2));
+            const code = `${nameplate}-${words[random[0]]}-${words[random[1]]}`;
+            onCode(code);
+
+            await conn.next('wormhole_peer_joined');
+            const keys = await exchangeKeys(conn, true, code);
+            const offer = encoder.encode(JSON.stringify({name: file.name, size: file.size}));
+            conn.send('offer', toBase64(await seal(keys, 0, offer, false)));
+            const answer = await conn.next();
+            if (answer.type === 'reject') throw new Error('The receiver declined the file');
+            if (answer.type !== 'accept') throw new Error('Unexpected message from the other side');
+
+            const chunks = chunkCount(file.size);
+            let sent = 0;
+            let acked = 0;
+            while (acked < chunks) {
+                if (sent < chunks && sent - acked < WINDOW) {
+                    const data = new Uint8Array(
+                        await file.slice(sent * CHUNK_SIZE, (sent + 1) * CHUNK_SIZE).arrayBuffer());
+                    conn.sendChunk(await seal(keys, sent + 1, data, sent + 1 === chunks));
+                    sent++;
+                    continue;
+                }
+                const ack = await conn.next('ack');
+                if (ack.data <= acked || ack.data > sent) throw new Error('Unexpected message from the other side');
+                acked = ack.data;
+                onProgress(Math.min(acked * CHUNK_SIZE, file.size), file.size);
+            }
+            await conn.next('received');
+        } finally {
+            conn.close();
+        }
+    }
+
+    // Receive with `code`. `onOffer` is given the offered name and size and
+    // resolves to whether to accept; the file comes back as a Blob.
+    async function receive(code, {onOffer, onProgress = () => {}}) {
+        code = code.trim().toLowerCase();
+        const nameplate = code.split('-')[0];
+        if (!/^\d+-.+/.test(code)) throw new Error("That doesn't look like a code");
+
+        const conn = connect(`?nameplate=${nameplate}`);
+        try {
+            await conn.next('wormhole_peer_joined');
+            const keys = await exchangeKeys(conn, false, code);
+            const offer = JSON.parse(new TextDecoder().decode(
+                await open(keys, 0, fromBase64((await conn.next('offer')).data), false)));
+            if (!await onOffer(offer)) {
+                conn.send('reject');
+                return null;
+            }
+            conn.send('accept');
+
+            const chunks = chunkCount(offer.size);
+            const parts = [];
+            let bytes = 0;
+            for (let counter = 1; counter <= chunks; counter++) {
+                const {chunk} = await conn.next();
+                if (!chunk) throw new Error('Unexpected message from the other side');
+                const data = await open(keys, counter, chunk, counter === chunks);
+                bytes += data.length;
+                if (bytes > offer.size) throw new Error('The sender sent more than it offered');
+                parts.push(data);
+                conn.send('ack', counter);
+                onProgress(bytes, offer.size);
+            }
+            if (bytes !== offer.size) throw new Error('The sender sent less than it offered');
+            conn.send('received');
+            return {name: offer.name, blob: new Blob(parts)};
+        } finally {
+            conn.close();
+        }
+    }
+
+    return {send, receive};
+})();